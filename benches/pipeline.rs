@@ -0,0 +1,153 @@
+// Throughput of the full resolution conversion stages on synthetic frames the
+// size of a back camera capture.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use camcam::camera::{
+    bayer::{self, BayerFrame, CfaPattern, Packing, RawFormat, RawImage},
+    convert::{
+        color::{self, ColorTransform},
+        shading::{Correction, ShadingMap},
+        white_balance,
+        DemosaicMethod,
+        Pipeline,
+        WhiteBalance,
+        WhitePoints
+    }
+};
+
+const WIDTH: usize = 2592;
+const HEIGHT: usize = 1944;
+const METHODS: [DemosaicMethod; 3] = [DemosaicMethod::Bilinear, DemosaicMethod::Malvar, DemosaicMethod::EdgeDirected];
+
+// Gradients with some fine detail on top, so the demosaic branches and white
+// balance see something resembling a scene, packed like the sensor sends it.
+fn synthetic_frame() -> RawImage {
+    let format = RawFormat::new(CfaPattern::Bggr, 10, Packing::Mipi);
+    let stride = format.bytes_per_line(WIDTH);
+    let mut data = vec![0; stride * HEIGHT];
+
+    for row in 0..HEIGHT {
+        let line = &mut data[row * stride..(row + 1) * stride];
+        for group in 0..WIDTH / 4 {
+            let mut low = 0;
+            for i in 0..4 {
+                let col = group * 4 + i;
+                let base = (col * 700 / WIDTH + row * 300 / HEIGHT) as u16;
+                let detail = if (row / 8 + col / 8) % 2 == 0 { 20 } else { 0 };
+                let v = (base + detail + 16).min(1023);
+                line[group * 5 + i] = (v >> 2) as u8;
+                low |= ((v & 0x3) as u8) << (i * 2);
+            }
+            line[group * 5 + 4] = low;
+        }
+    }
+
+    RawImage::new(data, WIDTH, HEIGHT, stride, format)
+}
+
+// A gentle vignette, stronger towards the corners.
+fn synthetic_shading() -> ShadingMap {
+    let (columns, rows) = (16, 12);
+    let gains = (0..rows)
+        .flat_map(|row| (0..columns).map(move |col| {
+            let dx = (col as f32 + 0.5) / columns as f32 - 0.5;
+            let dy = (row as f32 + 0.5) / rows as f32 - 0.5;
+            let gain = 1.0 + 2.0 * (dx * dx + dy * dy);
+            [gain, gain * 0.95, gain * 1.05]
+        }))
+        .collect();
+
+    ShadingMap {
+        columns,
+        rows,
+        gains
+    }
+}
+
+fn stages(c: &mut Criterion) {
+    let raw = synthetic_frame();
+    let shading = synthetic_shading();
+    let format = raw.format;
+    let max = format.max_value();
+    let samples = raw.unpack();
+    let gains = WhiteBalance::GrayWorld.frame_gains(&BayerFrame::new(&samples, WIDTH, HEIGHT, format.pattern, format.bits), &WhitePoints::default());
+    let transform = ColorTransform::new(&color::profile("ov5640"));
+
+    let mut group = c.benchmark_group("stages");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((WIDTH * HEIGHT) as u64));
+
+    group.bench_function("unpack", |b| {
+        b.iter(|| bayer::unpack(&raw.data, WIDTH, HEIGHT, raw.stride, &format))
+    });
+
+    group.bench_function("shading", |b| {
+        let correction = Correction {
+            black_level: 16,
+            max,
+            map: Some(&shading),
+            keep_black: false
+        };
+        b.iter(|| {
+            let mut data = samples.clone();
+            correction.apply(&mut data, WIDTH, format.pattern, 0, HEIGHT);
+            data
+        })
+    });
+
+    group.bench_function("white_balance_estimate", |b| {
+        let frame = BayerFrame::new(&samples, WIDTH, HEIGHT, format.pattern, format.bits);
+        b.iter(|| WhiteBalance::GrayWorld.frame_gains(&frame, &WhitePoints::default()))
+    });
+
+    group.bench_function("white_balance_apply", |b| {
+        b.iter(|| {
+            let mut data = samples.clone();
+            white_balance::apply(&mut data, WIDTH, format.pattern, max, gains);
+            data
+        })
+    });
+
+    for method in METHODS.iter() {
+        let frame = BayerFrame::new(&samples, WIDTH, HEIGHT, format.pattern, format.bits);
+        let demosaicer = method.demosaicer();
+        group.bench_with_input(BenchmarkId::new("demosaic", format!("{:?}", method)), &frame, |b, frame| {
+            b.iter(|| demosaicer.demosaic(frame))
+        });
+    }
+
+    let rgb = DemosaicMethod::Bilinear.demosaicer()
+        .demosaic(&BayerFrame::new(&samples, WIDTH, HEIGHT, format.pattern, format.bits));
+    group.bench_function("color_transform", |b| {
+        b.iter(|| transform.apply(&rgb, max))
+    });
+
+    group.finish();
+}
+
+fn pipeline(c: &mut Criterion) {
+    let raw = synthetic_frame();
+    let shading = synthetic_shading();
+    let transform = ColorTransform::new(&color::profile("ov5640"));
+    let pipeline = Pipeline::new(&raw, 16, Some(&shading));
+
+    let mut group = c.benchmark_group("pipeline");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((WIDTH * HEIGHT) as u64));
+
+    group.bench_function("white_balance_gains", |b| {
+        b.iter(|| pipeline.white_balance_gains(WhiteBalance::GrayWorld, &WhitePoints::default()))
+    });
+
+    for method in METHODS.iter() {
+        let demosaicer = method.demosaicer();
+        let gains = pipeline.white_balance_gains(WhiteBalance::GrayWorld, &WhitePoints::default());
+        group.bench_function(BenchmarkId::new("full", format!("{:?}", method)), |b| {
+            b.iter(|| pipeline.run(&*demosaicer, gains, &transform))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, stages, pipeline);
+criterion_main!(benches);
@@ -0,0 +1,930 @@
+// camcam-ctl, media-ctl for the pipelines camcam drives. Prints the media
+// graph as text, DOT or JSON, and sets links, pad formats, frame intervals and
+// controls through the same code the app uses, so what works here works
+// there.
+use linux_media::*;
+use serde_json::{json, Value};
+use std::{collections::HashMap, env, error, path::PathBuf, process};
+
+use camcam::camera::{
+    controls::{ControlInfo, ControlKind, ControlValue, MenuLabel},
+    discovery::{self, EntityKind, Hop},
+    media_device::{get_device_path_from_interface, Info, MediaDevice},
+    subdevice::{self, FrameFormat, FrameInterval, Subdevice},
+    topology::*
+};
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+const USAGE: &str = "Usage: camcam-ctl [-d DEVICE] [OPTIONS]
+
+  -d, --device DEVICE    Media device to use, the first one by default
+  -p, --print            Print the topology with pad formats (the default)
+      --dot              Print the topology as a DOT graph
+      --json             Print the topology as JSON
+  -l, --links LINKS      Enable or disable links, comma separated:
+                           '\"Sensor A\":0 -> \"Debayer A\":0 [1]'
+  -V, --set-v4l2 FORMATS Set pad formats and frame intervals, comma separated:
+                           '\"Sensor A\":0 [fmt:SBGGR8_1X8/640x480@1/30]'
+  -c, --controls ENTITY  List the controls of an entity with their values
+  -C, --set-ctrl CTRLS   Set controls of an entity:
+                           '\"Sensor A\" exposure=100,gain=32'
+  -h, --help             Show this
+
+Entities are given by name in double quotes or by id. Options are carried out
+in order, printing comes last.";
+
+enum Output {
+    Text,
+    Dot,
+    Json
+}
+
+enum Action {
+    Links(String),
+    Formats(String),
+    ListControls(String),
+    SetControls(String)
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("camcam-ctl: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let mut device = None;
+    let mut output = None;
+    let mut actions = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-d" | "--device" => device = Some(PathBuf::from(value()?)),
+            "-p" | "--print" => output = Some(Output::Text),
+            "--dot" => output = Some(Output::Dot),
+            "--json" => output = Some(Output::Json),
+            "-l" | "--links" => actions.push(Action::Links(value()?)),
+            "-V" | "--set-v4l2" => actions.push(Action::Formats(value()?)),
+            "-c" | "--controls" => actions.push(Action::ListControls(value()?)),
+            "-C" | "--set-ctrl" => actions.push(Action::SetControls(value()?)),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            },
+            _ => return Err(format!("unknown option {}\n\n{}", arg, USAGE).into())
+        }
+    }
+
+    let path = match device {
+        Some(path) => path,
+        None => discovery::media_devices()?.into_iter().next()
+            .ok_or("no media devices, is the camera driver loaded?")?
+    };
+    let media_device = MediaDevice::open(&path)?;
+    let topology = media_device.topology()?;
+
+    for action in actions.iter() {
+        match action {
+            Action::Links(links) => {
+                for link in split_list(links) {
+                    setup_link(&media_device, &topology, link)?;
+                }
+            },
+            Action::Formats(formats) => {
+                for format in split_list(formats) {
+                    set_format(&topology, format)?;
+                }
+            },
+            Action::ListControls(entity) => list_controls(&topology, entity)?,
+            Action::SetControls(controls) => set_controls(&topology, controls)?
+        }
+    }
+
+    if actions.is_empty() || output.is_some() {
+        // Links may have changed since.
+        let graph = Graph::read(path, &media_device)?;
+        match output.unwrap_or(Output::Text) {
+            Output::Text => graph.print_text(),
+            Output::Dot => graph.print_dot(),
+            Output::Json => println!("{}", serde_json::to_string_pretty(&graph.to_json())?)
+        }
+    }
+
+    Ok(())
+}
+
+// Everything there is to print about a media device.
+struct Graph {
+    path: PathBuf,
+    info: Info,
+    topology: Topology,
+    // Device nodes by interface id.
+    nodes: HashMap<u32, PathBuf>,
+    // Active formats of subdevice pads by pad id, with the frame interval
+    // where the driver has one.
+    formats: HashMap<u32, (FrameFormat, Option<FrameInterval>)>
+}
+
+impl Graph {
+    fn read(path: PathBuf, media_device: &MediaDevice) -> Result<Self> {
+        let info = media_device.info()?;
+        let topology = media_device.topology()?;
+
+        let nodes = topology.interfaces.iter()
+            .filter_map(|i| get_device_path_from_interface(i).ok().map(|p| (i.id, p)))
+            .collect();
+
+        let mut formats = HashMap::new();
+        for entity in topology.entities.iter() {
+            let subdevice = match open_subdevice(&topology, entity) {
+                Ok(subdevice) => subdevice,
+                Err(_) => continue
+            };
+            for pad in topology.pads.iter().filter(|p| p.entity_id == entity.id) {
+                if let Ok(format) = subdevice.pad_format(pad.index) {
+                    formats.insert(pad.id, (format.format, subdevice.pad_interval(pad.index).ok()));
+                }
+            }
+        }
+
+        Ok(Graph {
+            path,
+            info,
+            topology,
+            nodes,
+            formats
+        })
+    }
+
+    fn device_node(&self, entity_id: u32) -> Option<&PathBuf> {
+        discovery::interface_of(&self.topology, entity_id).and_then(|i| self.nodes.get(&i.id))
+    }
+
+    fn pads(&self, entity_id: u32) -> impl Iterator<Item = &Pad> {
+        self.topology.pads.iter().filter(move |p| p.entity_id == entity_id)
+    }
+
+    fn data_links(&self) -> impl Iterator<Item = &Link> {
+        self.topology.links.iter().filter(|l| l.flags & MEDIA_LNK_FL_LINK_TYPE == MEDIA_LNK_FL_DATA_LINK)
+    }
+
+    fn pad(&self, id: u32) -> Option<&Pad> {
+        self.topology.pads.iter().find(|p| p.id == id)
+    }
+
+    fn entity_name(&self, id: u32) -> String {
+        self.topology.entities.iter()
+            .find(|e| e.id == id)
+            .map_or_else(|| format!("entity {}", id), |e| e.name.clone())
+    }
+
+    // Like media-ctl -p.
+    fn print_text(&self) {
+        let info = &self.info;
+        println!("Media device {}", self.path.to_string_lossy());
+        println!("  driver          {}", info.driver);
+        println!("  model           {}", info.model);
+        println!("  serial          {}", info.serial);
+        println!("  bus info        {}", info.bus_info);
+        println!("  hw revision     {:#x}", info.hw_revision);
+        println!("  driver version  {}", version(info.driver_version));
+        println!("  topology        {}", self.topology.version);
+
+        for entity in self.topology.entities.iter() {
+            let pads = self.pads(entity.id).collect::<Vec<&Pad>>();
+            let links = self.data_links()
+                .filter(|l| pads.iter().any(|p| p.id == l.source_id || p.id == l.sink_id))
+                .count();
+            println!();
+            println!("- entity {}: {} ({} pad{}, {} link{})", entity.id, entity.name,
+                pads.len(), plural(pads.len()), links, plural(links));
+            println!("            function {:#x} ({:?}) flags {:#x}", entity.function, EntityKind::of(entity), entity.flags);
+            if let Some(interface) = discovery::interface_of(&self.topology, entity.id) {
+                let node = self.nodes.get(&interface.id).map_or("?".into(), |p| p.to_string_lossy());
+                println!("            interface {} ({}), device node {}", interface.id, interface_type(interface.interface_type), node);
+            }
+
+            for pad in pads {
+                println!("\tpad{}: {}", pad.index, pad_direction(pad));
+                if let Some((format, interval)) = self.formats.get(&pad.id) {
+                    match interval {
+                        Some(i) if i.numerator != 0 => println!("\t\t[{}@{}/{}]", format, i.numerator, i.denominator),
+                        _ => println!("\t\t[{}]", format)
+                    }
+                }
+                for link in self.data_links() {
+                    let (arrow, other) = if link.source_id == pad.id {
+                        ("->", link.sink_id)
+                    } else if link.sink_id == pad.id {
+                        ("<-", link.source_id)
+                    } else {
+                        continue;
+                    };
+                    if let Some(other) = self.pad(other) {
+                        println!("\t\t{} \"{}\":{} [{}]", arrow, self.entity_name(other.entity_id), other.index, link_flags(link.flags).join(","));
+                    }
+                }
+            }
+        }
+    }
+
+    // Like media-ctl --print-dot, for `dot -Tpng`. Enabled links are solid,
+    // immutable ones bold.
+    fn print_dot(&self) {
+        println!("digraph board {{");
+        println!("\trankdir=TB");
+        for entity in self.topology.entities.iter() {
+            let ports = |flag: u32| self.pads(entity.id)
+                .filter(|p| p.flags & flag != 0)
+                .map(|p| format!("<port{}> {}", p.index, p.index))
+                .collect::<Vec<String>>()
+                .join(" | ");
+            let mut label = dot_escape(&entity.name);
+            if let Some(node) = self.device_node(entity.id) {
+                label = format!("{}\\n{}", label, dot_escape(&node.to_string_lossy()));
+            }
+            let color = match EntityKind::of(entity) {
+                EntityKind::VideoNode => "yellow",
+                _ if self.device_node(entity.id).is_some() => "green",
+                _ => "gray"
+            };
+            println!("\tn{:08x} [label=\"{{{{{}}} | {} | {{{}}}}}\", shape=Mrecord, style=filled, fillcolor={}]",
+                entity.id, ports(MEDIA_PAD_FL_SINK), label, ports(MEDIA_PAD_FL_SOURCE), color);
+        }
+        for link in self.data_links() {
+            let (source, sink) = match (self.pad(link.source_id), self.pad(link.sink_id)) {
+                (Some(source), Some(sink)) => (source, sink),
+                _ => continue
+            };
+            let style = if link.flags & MEDIA_LNK_FL_IMMUTABLE != 0 {
+                "bold"
+            } else if link.flags & MEDIA_LNK_FL_ENABLED != 0 {
+                "solid"
+            } else {
+                "dashed"
+            };
+            println!("\tn{:08x}:port{} -> n{:08x}:port{} [style={}]",
+                source.entity_id, source.index, sink.entity_id, sink.index, style);
+        }
+        println!("}}");
+    }
+
+    fn to_json(&self) -> Value {
+        let info = &self.info;
+        let node = |id: u32| self.nodes.get(&id).map(|p| p.to_string_lossy().to_string());
+
+        let entities = self.topology.entities.iter().map(|entity| {
+            let pads = self.pads(entity.id).map(|pad| {
+                let (format, interval) = match self.formats.get(&pad.id) {
+                    Some((format, interval)) => (format_json(format), interval.map_or(Value::Null, |i| json!({
+                        "numerator": i.numerator,
+                        "denominator": i.denominator
+                    }))),
+                    None => (Value::Null, Value::Null)
+                };
+                json!({
+                    "id": pad.id,
+                    "index": pad.index,
+                    "direction": pad_direction(pad).to_lowercase(),
+                    "flags": pad.flags,
+                    "format": format,
+                    "interval": interval
+                })
+            }).collect::<Vec<Value>>();
+            let interface = discovery::interface_of(&self.topology, entity.id);
+            json!({
+                "id": entity.id,
+                "name": entity.name,
+                "function": entity.function,
+                "kind": format!("{:?}", EntityKind::of(entity)),
+                "flags": entity.flags,
+                "interface": interface.map(|i| i.id),
+                "device_node": interface.and_then(|i| node(i.id)),
+                "pads": pads
+            })
+        }).collect::<Vec<Value>>();
+
+        let interfaces = self.topology.interfaces.iter().map(|interface| json!({
+            "id": interface.id,
+            "type": interface_type(interface.interface_type),
+            "flags": interface.flags,
+            "major": interface.major,
+            "minor": interface.minor,
+            "device_node": node(interface.id)
+        })).collect::<Vec<Value>>();
+
+        // Data links go between pads, interface links from an interface to
+        // its entity.
+        let links = self.topology.links.iter().map(|link| {
+            let mut value = json!({
+                "id": link.id,
+                "source": link.source_id,
+                "sink": link.sink_id,
+                "flags": link_flags(link.flags)
+            });
+            if link.flags & MEDIA_LNK_FL_LINK_TYPE == MEDIA_LNK_FL_DATA_LINK {
+                value["type"] = json!("data");
+                if let (Some(source), Some(sink)) = (self.pad(link.source_id), self.pad(link.sink_id)) {
+                    value["source_entity"] = json!(source.entity_id);
+                    value["source_pad"] = json!(source.index);
+                    value["sink_entity"] = json!(sink.entity_id);
+                    value["sink_pad"] = json!(sink.index);
+                }
+            } else if link.flags & MEDIA_LNK_FL_LINK_TYPE == MEDIA_LNK_FL_INTERFACE_LINK {
+                value["type"] = json!("interface");
+            } else {
+                value["type"] = json!("other");
+            }
+            value
+        }).collect::<Vec<Value>>();
+
+        json!({
+            "device": self.path.to_string_lossy(),
+            "driver": info.driver,
+            "model": info.model,
+            "serial": info.serial,
+            "bus_info": info.bus_info,
+            "hw_revision": info.hw_revision,
+            "driver_version": version(info.driver_version),
+            "media_version": version(info.media_version),
+            "topology_version": self.topology.version,
+            "entities": entities,
+            "interfaces": interfaces,
+            "links": links
+        })
+    }
+}
+
+fn setup_link(media_device: &MediaDevice, topology: &Topology, text: &str) -> Result<()> {
+    let (hop, enable) = parse_link(topology, text)?;
+    media_device.setup_link(&hop, enable)?;
+    Ok(())
+}
+
+// '"A":0 -> "B":0 [1]', with 0 disabling the link.
+fn parse_link(topology: &Topology, text: &str) -> Result<(Hop, bool)> {
+    let mut parser = Parser::new(text);
+    let (source, source_pad) = parser.pad(topology)?;
+    parser.expect("->")?;
+    let (sink, sink_pad) = parser.pad(topology)?;
+    parser.expect("[")?;
+    let enable = match parser.number()? {
+        0 => false,
+        1 => true,
+        _ => return Err(parser.error("link flags 0 or 1"))
+    };
+    parser.expect("]")?;
+    parser.end()?;
+
+    let link = topology.links.iter().find(|l| {
+        let pads = (pad_of(topology, l.source_id), pad_of(topology, l.sink_id));
+        matches!(pads, (Some(s), Some(d)) if (s.entity_id, s.index, d.entity_id, d.index) == (source.id, source_pad, sink.id, sink_pad))
+    }).ok_or_else(|| format!("no link from \"{}\":{} to \"{}\":{}", source.name, source_pad, sink.name, sink_pad))?;
+
+    let hop = Hop {
+        source: source.id,
+        source_pad,
+        sink: sink.id,
+        sink_pad,
+        immutable: link.flags & MEDIA_LNK_FL_IMMUTABLE != 0
+    };
+    Ok((hop, enable))
+}
+
+// What a --set-v4l2 entry asks of one pad.
+struct PadSettings<'t> {
+    entity: &'t Entity,
+    pad: u32,
+    // Code, width and height.
+    format: Option<(u32, u32, u32)>,
+    interval: Option<(u32, u32)>
+}
+
+fn set_format(topology: &Topology, text: &str) -> Result<()> {
+    let PadSettings { entity, pad, format, interval } = parse_format(topology, text)?;
+
+    let subdevice = open_subdevice(topology, entity)?;
+    if let Some((code, width, height)) = format {
+        let set = subdevice.set_pad_format(pad, width, height, code)?.format;
+        if set.code != code || (set.width, set.height) != (width, height) {
+            println!("\"{}\":{} went with {} instead", entity.name, pad, set);
+        }
+    }
+    if let Some((numerator, denominator)) = interval {
+        subdevice.set_pad_interval(pad, numerator, denominator)?;
+    }
+    Ok(())
+}
+
+// '"A":0 [fmt:SBGGR8_1X8/640x480@1/30]', either the format or the interval
+// may be left out.
+fn parse_format<'t>(topology: &'t Topology, text: &str) -> Result<PadSettings<'t>> {
+    let mut parser = Parser::new(text);
+    let (entity, pad) = parser.pad(topology)?;
+    parser.expect("[")?;
+    let format = if parser.eat("fmt:") {
+        let name = parser.word();
+        let code = subdevice::mbus_code(name)
+            .ok_or_else(|| format!("unknown media bus code {}", name))?;
+        parser.expect("/")?;
+        let width = parser.number()?;
+        parser.expect("x")?;
+        let height = parser.number()?;
+        Some((code, width, height))
+    } else {
+        None
+    };
+    let interval = if parser.eat("@") {
+        let numerator = parser.number()?;
+        parser.expect("/")?;
+        Some((numerator, parser.number()?))
+    } else {
+        None
+    };
+    parser.expect("]")?;
+    parser.end()?;
+    if format.is_none() && interval.is_none() {
+        return Err(parser.error("fmt: or @"));
+    }
+
+    Ok(PadSettings {
+        entity,
+        pad,
+        format,
+        interval
+    })
+}
+
+fn list_controls(topology: &Topology, text: &str) -> Result<()> {
+    let mut parser = Parser::new(text);
+    let entity = parser.entity(topology)?;
+    parser.end()?;
+
+    let subdevice = open_subdevice(topology, entity)?;
+    for control in subdevice.controls()? {
+        let range = match control.kind {
+            ControlKind::Boolean | ControlKind::Button => String::new(),
+            _ => format!(" {}..{} step {}", control.minimum, control.maximum, control.step)
+        };
+        let value = match control.kind {
+            ControlKind::Button | ControlKind::Other(_) => String::new(),
+            _ => match subdevice.get_controls(&[&control]) {
+                Ok(values) => format!(" = {}", value_text(&control, values[0])),
+                Err(e) => format!(" ({})", e)
+            }
+        };
+        let flags = if control.read_only() {
+            " read-only"
+        } else if control.inactive() {
+            " inactive"
+        } else {
+            ""
+        };
+        println!("{} ({:#010x}) {}{} default {}{}{}", control_name(&control.name), control.id,
+            kind_name(control.kind), range, control.default, value, flags);
+    }
+    Ok(())
+}
+
+// '"A" exposure=100,gain=32', set all at once. Values are clamped to the
+// range, menus take an index or an item name.
+fn set_controls(topology: &Topology, text: &str) -> Result<()> {
+    let mut parser = Parser::new(text);
+    let entity = parser.entity(topology)?;
+    let subdevice = open_subdevice(topology, entity)?;
+    let controls = subdevice.controls()?;
+
+    let mut values = Vec::new();
+    for (name, value) in assignments(parser.rest())? {
+        let control = controls.iter()
+            .find(|c| control_name(&c.name) == name || format!("{:#010x}", c.id) == name)
+            .ok_or_else(|| format!("{} has no control {}", entity.name, name))?;
+        if control.read_only() {
+            return Err(format!("{} is read-only", name).into());
+        }
+        values.push((control, control.value(parse_value(control, value)?)));
+    }
+
+    subdevice.set_controls(&values)?;
+    for (control, _) in values.iter() {
+        let value = subdevice.get_controls(&[control])?[0];
+        println!("{} = {}", control_name(&control.name), value_text(control, value));
+    }
+    Ok(())
+}
+
+// 'exposure=100,gain=32' as trimmed names and values.
+fn assignments(text: &str) -> Result<Vec<(&str, &str)>> {
+    text.split(',')
+        .map(|assignment| match assignment.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() && !value.trim().is_empty() => Ok((name.trim(), value.trim())),
+            _ => Err(format!("expected name=value, got '{}'", assignment.trim()).into())
+        })
+        .collect()
+}
+
+fn parse_value(control: &ControlInfo, text: &str) -> Result<i64> {
+    let menu_item = control.menu.iter().find(|m| match &m.label {
+        MenuLabel::Name(name) => control_name(name) == control_name(text),
+        MenuLabel::Value(_) => false
+    });
+    match (menu_item, text) {
+        (Some(item), _) => Ok(item.index as i64),
+        (None, "true") => Ok(1),
+        (None, "false") => Ok(0),
+        (None, _) => text.parse::<i64>()
+            .map_err(|_| format!("{} isn't a value for {}", text, control.name).into())
+    }
+}
+
+fn value_text(control: &ControlInfo, value: ControlValue) -> String {
+    match value {
+        ControlValue::Menu(index) => match control.menu.iter().find(|m| m.index == index).map(|m| &m.label) {
+            Some(MenuLabel::Name(name)) => format!("{} ({})", index, name),
+            Some(MenuLabel::Value(v)) => format!("{} ({})", index, v),
+            None => index.to_string()
+        },
+        ControlValue::Boolean(v) => v.to_string(),
+        _ => value.as_i64().to_string()
+    }
+}
+
+// "Analogue Gain" as analogue_gain, the way it's typed on the command line.
+fn control_name(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_ascii_lowercase())
+        .collect::<Vec<String>>()
+        .join("_")
+}
+
+fn kind_name(kind: ControlKind) -> String {
+    match kind {
+        ControlKind::Integer => "int".to_string(),
+        ControlKind::Boolean => "bool".to_string(),
+        ControlKind::Menu => "menu".to_string(),
+        ControlKind::IntegerMenu => "intmenu".to_string(),
+        ControlKind::Button => "button".to_string(),
+        ControlKind::Integer64 => "int64".to_string(),
+        ControlKind::Other(kind) => format!("type {}", kind)
+    }
+}
+
+// The subdevice node of entity, opened on its first pad. Every call says
+// which pad it means.
+fn open_subdevice(topology: &Topology, entity: &Entity) -> Result<Subdevice> {
+    let interface = discovery::interface_of(topology, entity.id)
+        .filter(|i| i.interface_type == MEDIA_INTF_T_V4L_SUBDEV)
+        .ok_or_else(|| format!("{} has no subdevice node", entity.name))?;
+    let pad = topology.pads.iter()
+        .find(|p| p.entity_id == entity.id)
+        .ok_or_else(|| format!("{} has no pads", entity.name))?;
+    let path = get_device_path_from_interface(interface)?;
+    Ok(Subdevice::open(&path, entity, interface, pad)?)
+}
+
+fn pad_of(topology: &Topology, id: u32) -> Option<&Pad> {
+    topology.pads.iter().find(|p| p.id == id)
+}
+
+fn pad_direction(pad: &Pad) -> &'static str {
+    if pad.flags & MEDIA_PAD_FL_SINK != 0 {
+        "Sink"
+    } else if pad.flags & MEDIA_PAD_FL_SOURCE != 0 {
+        "Source"
+    } else {
+        "Unknown"
+    }
+}
+
+fn link_flags(flags: u32) -> Vec<&'static str> {
+    [(MEDIA_LNK_FL_ENABLED, "ENABLED"), (MEDIA_LNK_FL_IMMUTABLE, "IMMUTABLE"), (MEDIA_LNK_FL_DYNAMIC, "DYNAMIC")].iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+fn interface_type(kind: u32) -> &'static str {
+    match kind {
+        MEDIA_INTF_T_V4L_VIDEO => "video",
+        MEDIA_INTF_T_V4L_SUBDEV => "v4l-subdev",
+        _ => "other"
+    }
+}
+
+fn format_json(format: &FrameFormat) -> Value {
+    json!({
+        "code": format.code,
+        "code_name": subdevice::mbus_code_name(format.code),
+        "width": format.width,
+        "height": format.height
+    })
+}
+
+fn version((major, minor, patch): (u8, u8, u8)) -> String {
+    format!("{}.{}.{}", major, minor, patch)
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 { "" } else { "s" }
+}
+
+fn dot_escape(text: &str) -> String {
+    text.chars()
+        .flat_map(|c| match c {
+            '"' | '{' | '}' | '|' | '<' | '>' => vec!['\\', c],
+            _ => vec![c]
+        })
+        .collect()
+}
+
+// Splits media-ctl style lists on commas outside quotes and brackets.
+fn split_list(text: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut depth) = (0, false, 0);
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '[' if !quoted => depth += 1,
+            ']' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                parts.push(&text[start..i]);
+                start = i + 1;
+            },
+            _ => ()
+        }
+    }
+    parts.push(&text[start..]);
+    parts.into_iter().filter(|p| !p.trim().is_empty()).collect()
+}
+
+// Reads the media-ctl link and format syntax a piece at a time.
+struct Parser<'a> {
+    text: &'a str,
+    rest: &'a str
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Parser {
+            text,
+            rest: text
+        }
+    }
+
+    fn error(&self, expected: &str) -> Box<dyn error::Error> {
+        let at = self.text.len() - self.rest.len();
+        format!("expected {} at '{}' in '{}'", expected, &self.text[at..], self.text).into()
+    }
+
+    fn rest(&self) -> &'a str {
+        self.rest
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.rest = self.rest.trim_start();
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            },
+            None => false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<()> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("'{}'", token)))
+        }
+    }
+
+    fn end(&mut self) -> Result<()> {
+        self.rest = self.rest.trim_start();
+        if self.rest.is_empty() {
+            Ok(())
+        } else {
+            Err(self.error("the end"))
+        }
+    }
+
+    // Up to the next character with a meaning of its own.
+    fn word(&mut self) -> &'a str {
+        self.rest = self.rest.trim_start();
+        let end = self.rest.find(|c: char| c.is_whitespace() || ":/[]@,=\"".contains(c)).unwrap_or(self.rest.len());
+        let (word, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        word
+    }
+
+    fn number(&mut self) -> Result<u32> {
+        self.rest = self.rest.trim_start();
+        let end = self.rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(self.rest.len());
+        let (digits, rest) = self.rest.split_at(end);
+        let number = digits.parse().map_err(|_| self.error("a number"))?;
+        self.rest = rest;
+        Ok(number)
+    }
+
+    // "name" in quotes, or an entity id.
+    fn entity<'t>(&mut self, topology: &'t Topology) -> Result<&'t Entity> {
+        if self.eat("\"") {
+            let end = self.rest.find('"').ok_or_else(|| self.error("closing '\"'"))?;
+            let name = &self.rest[..end];
+            self.rest = &self.rest[end + 1..];
+            topology.entities.iter()
+                .find(|e| e.name == name)
+                .ok_or_else(|| format!("no entity \"{}\"", name).into())
+        } else {
+            let id = self.number()?;
+            topology.entities.iter()
+                .find(|e| e.id == id)
+                .ok_or_else(|| format!("no entity {}", id).into())
+        }
+    }
+
+    fn pad<'t>(&mut self, topology: &'t Topology) -> Result<(&'t Entity, u32)> {
+        let entity = self.entity(topology)?;
+        self.expect(":")?;
+        let pad = self.number()?;
+        if !topology.pads.iter().any(|p| p.entity_id == entity.id && p.index == pad) {
+            return Err(format!("{} has no pad {}", entity.name, pad).into());
+        }
+        Ok((entity, pad))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use v4l_subdev::MEDIA_BUS_FMT_SBGGR8_1X8;
+    use camcam::camera::controls::MenuItem;
+
+    // "Sensor A":0 -> "Debayer A":0, switchable, and "Debayer A":1 ->
+    // "RGB Capture":0, which always is.
+    fn topology() -> Topology {
+        let entity = |id, name: &str| Entity {
+            id,
+            name: name.to_string(),
+            function: 0,
+            flags: 0
+        };
+        let pad = |id, entity_id, index, flags| Pad {
+            id,
+            entity_id,
+            flags,
+            index
+        };
+        let link = |id, source_id, sink_id, flags| Link {
+            id,
+            source_id,
+            sink_id,
+            flags
+        };
+        Topology {
+            version: 1,
+            entities: vec![entity(1, "Sensor A"), entity(3, "Debayer A"), entity(6, "RGB Capture")],
+            interfaces: Vec::new(),
+            pads: vec![
+                pad(2, 1, 0, MEDIA_PAD_FL_SOURCE),
+                pad(4, 3, 0, MEDIA_PAD_FL_SINK),
+                pad(5, 3, 1, MEDIA_PAD_FL_SOURCE),
+                pad(7, 6, 0, MEDIA_PAD_FL_SINK)
+            ],
+            links: vec![
+                link(8, 2, 4, MEDIA_LNK_FL_ENABLED),
+                link(9, 5, 7, MEDIA_LNK_FL_ENABLED | MEDIA_LNK_FL_IMMUTABLE)
+            ]
+        }
+    }
+
+    fn error<T>(result: Result<T>) -> String {
+        match result {
+            Ok(_) => panic!("parsed"),
+            Err(e) => e.to_string()
+        }
+    }
+
+    #[test]
+    fn splits_lists() {
+        assert_eq!(split_list("a,b, c"), ["a", "b", " c"]);
+        assert_eq!(split_list("\"A, B\":0 -> \"C\":0 [1],1:0->3:0[0]"), ["\"A, B\":0 -> \"C\":0 [1]", "1:0->3:0[0]"]);
+        assert_eq!(split_list("\"A\":0 [fmt:SBGGR8_1X8/640x480@1/30],\"B\":1 [@1,2]"), ["\"A\":0 [fmt:SBGGR8_1X8/640x480@1/30]", "\"B\":1 [@1,2]"]);
+        assert_eq!(split_list(",a,,"), ["a"]);
+        assert!(split_list("").is_empty());
+    }
+
+    #[test]
+    fn parses_links() {
+        let topology = topology();
+        let hop = |source, source_pad, sink, sink_pad, immutable| Hop {
+            source,
+            source_pad,
+            sink,
+            sink_pad,
+            immutable
+        };
+        assert_eq!(parse_link(&topology, "\"Sensor A\":0->\"Debayer A\":0[1]").unwrap(), (hop(1, 0, 3, 0, false), true));
+        assert_eq!(parse_link(&topology, " \"Sensor A\" : 0 -> \"Debayer A\" : 0 [ 0 ] ").unwrap(), (hop(1, 0, 3, 0, false), false));
+        assert_eq!(parse_link(&topology, "3:1 -> \"RGB Capture\":0 [1]").unwrap(), (hop(3, 1, 6, 0, true), true));
+    }
+
+    #[test]
+    fn rejects_broken_links() {
+        let topology = topology();
+        let error = |text| error(parse_link(&topology, text));
+        assert_eq!(error("\"Sensor B\":0 -> \"Debayer A\":0 [1]"), "no entity \"Sensor B\"");
+        assert_eq!(error("9:0 -> 3:0 [1]"), "no entity 9");
+        assert_eq!(error("\"Sensor A\":1 -> \"Debayer A\":0 [1]"), "Sensor A has no pad 1");
+        assert_eq!(error("\"Debayer A\":0 -> \"Sensor A\":0 [1]"), "no link from \"Debayer A\":0 to \"Sensor A\":0");
+        assert!(error("\"Sensor A\" -> \"Debayer A\":0 [1]").starts_with("expected ':' at '-> "));
+        assert!(error("\"Sensor A\":0 \"Debayer A\":0 [1]").starts_with("expected '->'"));
+        assert!(error("\"Sensor A\":0 -> \"Debayer A\":0").starts_with("expected '['"));
+        assert!(error("\"Sensor A\":0 -> \"Debayer A\":0 [2]").starts_with("expected link flags 0 or 1"));
+        assert!(error("\"Sensor A\":0 -> \"Debayer A\":0 [x]").starts_with("expected a number at 'x]'"));
+        assert!(error("\"Sensor A\":0 -> \"Debayer A\":0 [1] [1]").starts_with("expected the end at '[1]'"));
+        assert!(error("\"Sensor A:0 -> 3:0 [1]").starts_with("expected closing '\"'"));
+    }
+
+    #[test]
+    fn parses_formats() {
+        let topology = topology();
+        let settings = parse_format(&topology, "\"Sensor A\":0 [fmt:SBGGR8_1X8/640x480@1/30]").unwrap();
+        assert_eq!((settings.entity.id, settings.pad), (1, 0));
+        assert_eq!(settings.format, Some((MEDIA_BUS_FMT_SBGGR8_1X8, 640, 480)));
+        assert_eq!(settings.interval, Some((1, 30)));
+
+        let settings = parse_format(&topology, "3:1 [ fmt:sbggr8_1x8 / 1280 x 720 ]").unwrap();
+        assert_eq!((settings.entity.id, settings.pad), (3, 1));
+        assert_eq!(settings.format, Some((MEDIA_BUS_FMT_SBGGR8_1X8, 1280, 720)));
+        assert_eq!(settings.interval, None);
+
+        let settings = parse_format(&topology, "\"Sensor A\":0 [@1/15]").unwrap();
+        assert_eq!((settings.format, settings.interval), (None, Some((1, 15))));
+
+        let settings = parse_format(&topology, "\"Sensor A\":0 [fmt:0x3001/640x480]").unwrap();
+        assert_eq!(settings.format, Some((0x3001, 640, 480)));
+    }
+
+    #[test]
+    fn rejects_broken_formats() {
+        let topology = topology();
+        let error = |text| error(parse_format(&topology, text));
+        assert_eq!(error("\"Sensor A\":0 [fmt:NOPE/640x480]"), "unknown media bus code NOPE");
+        assert!(error("\"Sensor A\":0 []").starts_with("expected fmt: or @"));
+        assert!(error("\"Sensor A\":0 [fmt:SBGGR8_1X8/640]").starts_with("expected 'x' at ']'"));
+        assert!(error("\"Sensor A\":0 [fmt:SBGGR8_1X8 640x480]").starts_with("expected '/'"));
+        assert!(error("\"Sensor A\":0 [@30]").starts_with("expected '/' at ']'"));
+        assert!(error("\"Sensor A\":0 [fmt:SBGGR8_1X8/640x480").starts_with("expected ']'"));
+        assert!(error("\"Sensor A\":0 [fmt:SBGGR8_1X8/-640x480]").starts_with("expected a number at '-640x480]'"));
+        assert!(error("\"Sensor A\" [@1/30]").starts_with("expected ':'"));
+    }
+
+    #[test]
+    fn parses_controls() {
+        let topology = topology();
+        let mut parser = Parser::new("\"Sensor A\" exposure=100, gain = 32,test_pattern=Color Bars");
+        assert_eq!(parser.entity(&topology).unwrap().id, 1);
+        assert_eq!(assignments(parser.rest()).unwrap(), [("exposure", "100"), ("gain", "32"), ("test_pattern", "Color Bars")]);
+
+        assert_eq!(error(assignments(" exposure")), "expected name=value, got 'exposure'");
+        assert_eq!(error(assignments("exposure=100,")), "expected name=value, got ''");
+        assert_eq!(error(assignments("=100")), "expected name=value, got '=100'");
+        assert_eq!(error(assignments("gain=")), "expected name=value, got 'gain='");
+    }
+
+    #[test]
+    fn parses_control_values() {
+        let control = |kind, menu| ControlInfo {
+            id: 0x009e0906,
+            name: "Test Pattern".to_string(),
+            kind,
+            minimum: 0,
+            maximum: 2,
+            step: 1,
+            default: 0,
+            flags: 0,
+            menu
+        };
+        let menu = ["Disabled", "Color Bars"].iter()
+            .enumerate()
+            .map(|(i, name)| MenuItem {
+                index: i as u32,
+                label: MenuLabel::Name(name.to_string())
+            })
+            .collect();
+        let menu = control(ControlKind::Menu, menu);
+        assert_eq!(parse_value(&menu, "color_bars").unwrap(), 1);
+        assert_eq!(parse_value(&menu, "Color Bars").unwrap(), 1);
+        assert_eq!(parse_value(&menu, "0").unwrap(), 0);
+        let boolean = control(ControlKind::Boolean, Vec::new());
+        assert_eq!(parse_value(&boolean, "true").unwrap(), 1);
+        assert_eq!(parse_value(&boolean, "false").unwrap(), 0);
+        assert_eq!(error(parse_value(&boolean, "yes")), "yes isn't a value for Test Pattern");
+    }
+}
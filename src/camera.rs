@@ -1,359 +1,839 @@
 use crate::picture::Picture;
-use v4l::prelude::*;
-use v4l::context;
-use v4l::video::Capture;
-use v4l::buffer::Type;
-use v4l::io::traits::CaptureStream;
-use v4l::format::{Format, Flags, fourcc::FourCC, field::FieldOrder, colorspace::Colorspace, quantization::Quantization, transfer::TransferFunction};
 
+use chrono::Local;
 use relm::Sender;
-use std::{fs, io, path::{Path, PathBuf}, sync::{Arc, Mutex, RwLock}, thread};
-use media_device::MediaDevice;
-
-mod convert;
+use convert::{color::{self, ColorTransform}, shading, white_balance, CaptureInfo, ShadingMap};
+use std::{io, path::PathBuf, sync::{Arc, Mutex, RwLock}, thread};
+use bayer::{BayerFrame, RawImage};
+use auto::AutoControl;
+use backend::{CameraBackend, FrameFormat, Sensor};
+use buffer_pool::BufferPool;
+use controls::{ControlInfo, ControlValue, SensorControls};
+use exposure::{ExposureControls, ExposureLimits, ExposureValues};
+use focus::{FocusControls, FocusLoop, Region};
+use profile::{DeviceProfile, Mode};
+use v4l_subdev::{V4L2_CID_HFLIP, V4L2_CID_VFLIP};
+use stats::FrameStats;
+use watchdog::Watchdog;
+
+mod auto;
+pub mod autofocus;
+pub mod backend;
+pub mod bayer;
+mod buffer_pool;
+pub mod controls;
+pub mod convert;
+pub mod discovery;
+mod error;
+pub mod exposure;
+pub mod focus;
+mod media_backend;
 mod media_ioctl;
-mod media_device;
-mod topology;
-mod subdevice;
+pub mod media_device;
+pub mod profile;
+mod rotation;
+pub mod stats;
+pub mod synthetic;
+pub mod topology;
+pub mod subdevice;
 mod video_device;
-
-
-const CAMERA_NAME: &str = "sun6i-csi";
-
-#[derive(Clone, Copy)]
-enum Sensor {
-    Back,
-    Front
-}
+mod watchdog;
+
+pub use bayer::{Packing, RawFormat};
+pub use error::Error;
+pub use rotation::Rotation;
+pub use convert::{DemosaicMethod, OutputFormat, SaveOptions, WhiteBalance};
+pub use exposure::ExposureMode;
+pub use focus::{FocusMode, FocusStatus};
+pub use media_backend::MediaBackend;
+
+// Frames to drop after starting a stream for a capture, the first ones may
+// still have the old mode's exposure. Capture goes ahead after the most even
+// if the loops haven't settled.
+const MIN_SETTLE_FRAMES: u32 = 2;
+const MAX_SETTLE_FRAMES: u32 = 30;
+// Size of a tapped focus and metering region, as a share of the frame.
+const FOCUS_REGION_SIZE: f32 = 0.2;
 
 pub enum CamMsg {
     Ready(Camera),
     Pic(Picture),
-    Captured
+    // The capture is over, also after one that failed with Error.
+    Captured,
+    // Sent by the preview thread when it changes.
+    Focus(FocusStatus),
+    // The stream failed and is being set up again, frames coming through
+    // mean it worked.
+    Restarting {
+        attempt: u32,
+        error: Error
+    },
+    // Detecting, previewing or capturing failed for good. The preview stops,
+    // starting it again tries again.
+    Error(Error)
 }
 
 pub struct Camera {
-    main_device: Arc<RwLock<Device>>,
+    backend: Arc<dyn CameraBackend>,
+    // Device profiles of the backend's sensors, by the same index.
+    profiles: Vec<DeviceProfile>,
+    // Index of the camera in use.
+    current: usize,
     should_preview: Arc<RwLock<bool>>,
-    media_device: Arc<RwLock<MediaDevice>>,
     sender: Arc<Mutex<Sender<CamMsg>>>,
-    sensor: Sensor,
+    // What we ask the sensors for instead of the format in their device
+    // profile. Flips may shift the pattern and the sensor may not have the
+    // bit depth, so the real format is always read back from the negotiated
+    // one.
+    raw_format: Option<RawFormat>,
+    white_balance: Arc<RwLock<WhiteBalance>>,
+    // Largest preview frame the UI has room for.
+    preview_size: Arc<RwLock<Option<(usize, usize)>>>,
+    device_rotation: Arc<RwLock<Rotation>>,
+    // Shared with the preview thread, which runs software exposure for
+    // sensors that need it.
+    exposure_mode: Arc<RwLock<ExposureMode>>,
+    // Where software exposure last settled, streams start from there.
+    settled: Arc<RwLock<Option<ExposureValues>>>,
+    // What auto exposure picked before it was left, for compensation on
+    // sensors that can't do it themselves.
+    metered: Option<ExposureValues>,
+    // Shared with the preview thread, which runs software focus for lenses
+    // that need it.
+    focus_mode: Arc<RwLock<FocusMode>>,
+    // Where to meter and to focus in software, the whole frame without one.
+    region: Arc<RwLock<Option<Region>>>,
+    // Set when a search should start, for the preview thread to report it
+    // or run it.
+    focus_triggered: Arc<RwLock<bool>>,
+    // The preview or capture thread started last, the next one waits for it.
     thread_handle: Option<thread::JoinHandle<()>>
 }
 
 impl Camera {
-    // Highly Pinephone specific detection.
+    // Looks through every media device for sensors with a way to a video
+    // node. Sends CamMsg::Error if there are none or they can't be set up.
     pub fn detect(sender: Sender<CamMsg>) {
-        if let Some(d) = context::enum_devices().iter().find(|d| {
-            let name = d.name();
-            name.is_some() && name.unwrap() == CAMERA_NAME
-        }) {
-            let media_device_path = guess_media_device_path(&d.path()).expect("No media device path found.");
-            let mut media_device = MediaDevice::open(media_device_path).expect("Can't open media device");
-
-            media_device.setup();
-
-            // Device open will take ~10s if back camera is linked.
-            let device = Device::new(d.index()).expect("Couldn't get camera device.");
-
-            let sender = Arc::new(Mutex::new(sender));
-            let sender_copy = sender.clone();
-
-            let cam = Camera {
-                main_device: Arc::new(RwLock::new(device)),
-                should_preview: Arc::new(RwLock::new(false)),
-                media_device: Arc::new(RwLock::new(media_device)),
-                sender: sender,
-                sensor: Sensor::Back,
-                thread_handle: None
-            };
+        match MediaBackend::open() {
+            Ok(backend) => Camera::with_backend(Arc::new(backend), sender),
+            Err(e) => {
+                eprintln!("Can't set up cameras: {}", e);
+                if sender.send(CamMsg::Error(e)).is_err() {
+                    eprintln!("Nobody is waiting for the cameras any more.");
+                }
+            }
+        }
+    }
 
-            sender_copy.lock()
-                .expect("Can't lock cam msg sender.")
-                .send(CamMsg::Ready(cam))
-                .expect("Can't send camera ready.");
+    // Sends CamMsg::Ready with the cameras of backend, like a
+    // synthetic::SyntheticBackend for running without camera hardware.
+    pub fn with_backend(backend: Arc<dyn CameraBackend>, sender: Sender<CamMsg>) {
+        let cam = Camera::new(backend, sender.clone());
+        if sender.send(CamMsg::Ready(cam)).is_err() {
+            eprintln!("Nobody is waiting for the cameras any more.");
+        }
+    }
 
+    // The cameras of backend, with the back one current. Status goes to
+    // sender, which may be left unread when nothing is shown, like when
+    // capturing from the command line.
+    pub fn new(backend: Arc<dyn CameraBackend>, sender: Sender<CamMsg>) -> Self {
+        let profiles = (0..backend.count())
+            .map(|i| profile::for_sensor(backend.sensor(i).name()))
+            .collect();
+
+        Camera {
+            backend,
+            profiles,
+            current: 0,
+            should_preview: Arc::new(RwLock::new(false)),
+            sender: Arc::new(Mutex::new(sender)),
+            raw_format: None,
+            white_balance: Arc::new(RwLock::new(WhiteBalance::default())),
+            preview_size: Arc::new(RwLock::new(None)),
+            device_rotation: Arc::new(RwLock::new(Rotation::default())),
+            exposure_mode: Arc::new(RwLock::new(ExposureMode::default())),
+            settled: Arc::new(RwLock::new(None)),
+            metered: None,
+            focus_mode: Arc::new(RwLock::new(FocusMode::default())),
+            region: Arc::new(RwLock::new(None)),
+            focus_triggered: Arc::new(RwLock::new(false)),
+            thread_handle: None
         }
     }
 
     pub fn switch_sensor(&mut self) {
-        println!("Camera switching sensor.");
+        eprintln!("Camera switching sensor.");
         self.stop_preview();
-        println!("Preview stopped.");
-        self.sensor = match self.sensor {
-            Sensor::Back => Sensor::Front,
-            Sensor::Front => Sensor::Back
-        };
-        println!("Starting preview");
+        eprintln!("Preview stopped.");
+        self.select((self.current + 1) % self.backend.count());
+        eprintln!("Starting preview with {}", self.backend.name(self.current));
         self.start_preview();
-        println!("Preview started");
+        eprintln!("Preview started");
     }
 
-    pub fn stop_preview(&mut self) {
-        {
-            let mut sp = self.should_preview.write().unwrap();
-            *sp = false;
-        } // Drop write lock. Otherwise read can't happen in the preview loop.
-        if let Some(handle) = self.thread_handle.take() {
-            if let Err(_) = handle.join() {
+    // Cameras by index, like "ov5640 1-004c -> sun6i-csi", back facing ones
+    // first.
+    pub fn names(&self) -> Vec<String> {
+        (0..self.backend.count()).map(|i| self.backend.name(i)).collect()
+    }
 
-            }
-        }
+    // Makes camera index the current one. Doesn't touch the preview, stop it
+    // first.
+    pub fn select(&mut self, index: usize) {
+        self.current = index;
+        self.metered = None;
+        // Not reset in place, the previous camera's thread may still be
+        // winding down and writing to it.
+        self.settled = Arc::new(RwLock::new(None));
+        *self.region.write().unwrap() = None;
     }
 
-    pub fn start_preview(&mut self) {
-        let dev = self.main_device.clone();
-        let sender = self.sender.clone();
-        let preview_lock = self.should_preview.clone();
-        {
-            let mut sp = preview_lock.write().unwrap();
-            *sp = true;
+    // What photos of the current camera are taken in, before the sensor
+    // picks the closest mode it offers.
+    pub fn still_mode(&self) -> Mode {
+        self.profiles[self.current].still
+    }
+
+    // Instead of the device profile's still mode, for the current camera.
+    pub fn set_still_mode(&mut self, mode: Mode) {
+        self.profiles[self.current].still = mode;
+    }
+
+    fn sensor(&self) -> &dyn Sensor {
+        self.backend.sensor(self.current)
+    }
+
+    fn profile(&self) -> DeviceProfile {
+        self.profiles[self.current].clone()
+    }
+
+    fn exposure_controls(&self) -> io::Result<ExposureControls> {
+        Ok(ExposureControls::new(&self.sensor_controls()?, &self.profile()))
+    }
+
+    // Exposure time and ISO range of the current sensor, None if it can't
+    // do manual exposure.
+    pub fn exposure_limits(&self) -> Option<ExposureLimits> {
+        self.exposure_controls().ok()?.limits()
+    }
+
+    // Applied right away and kept when switching sensors or restarting the
+    // preview.
+    pub fn set_exposure_mode(&mut self, mode: ExposureMode) -> io::Result<()> {
+        let controls = self.exposure_controls()?;
+        let backend = self.backend.clone();
+        let sensor = backend.sensor(self.current);
+        let mut current = self.exposure_mode.write().unwrap();
+        if *current == ExposureMode::Auto {
+            self.metered = controls.read(sensor);
         }
-        let media_device = self.media_device.clone();
-        let sensor = self.sensor;
-
-        let (w, h, denominator) = match sensor {
-            Sensor::Back => (1280, 720, 30),
-            Sensor::Front => (1280, 960, 15)
-        };
-
-
-        let thread_handle = thread::spawn(move || {
-
-            let md = media_device.write()
-                .expect("Couldn't lock media device.");
-            match sensor {
-                Sensor::Back => {
-                    md.unlink_front_camera();
-                    md.link_back_camera();
-                    md.auto_focus(true);
-                    md.set_back_format(w, h);
-                    md.set_back_interval(1, denominator);
-                },
-                Sensor::Front => {
-                    md.unlink_back_camera();
-                    md.link_front_camera();
-                    md.set_front_format(w, h);
-                    md.set_front_interval(1, denominator);
-                    md.hflip_front(true);
-                }
-            }
+        *current = mode;
+        // The preview thread picks it up on the next frame.
+        if auto::owns_exposure(&self.profile(), mode) {
+            return Ok(());
+        }
+        controls.apply(sensor, mode, self.metered)
+    }
 
-            let format = Format {
-                width: w,
-                height: h,
-                fourcc: FourCC::new(b"BA81"),
-                field_order: FieldOrder::Progressive,
-                stride: w,
-                size: w * h,
-                flags: Flags::empty(),
-                colorspace: Colorspace::RAW,
-                quantization: Quantization::Default,
-                transfer: TransferFunction::None
-            };
+    fn focus_controls(&self) -> io::Result<FocusControls> {
+        Ok(FocusControls::new(&self.sensor_controls()?))
+    }
 
-            let num_bufs = 4;
-            let mut dev = dev.write().unwrap();
-            dev.set_format(&format).expect("Can't set video device buffer.");
-            let format = dev.format().expect("Couldn't get device format.");
-            println!("Device format: {:#?}", format);
-            let mut stream = MmapStream::with_buffers(&mut *dev, Type::VideoCapture, num_bufs)
-                .expect("Failed to create MmapStream!");
+    pub fn focus_mode(&self) -> FocusMode {
+        *self.focus_mode.read().unwrap()
+    }
 
-            // 1280 x 960 BA81
-            // BG
-            // GR
-            // 640x480 RGB
+    pub fn supports_focus(&self, mode: FocusMode) -> bool {
+        self.focus_controls().map_or(false, |c| c.supports(mode))
+    }
 
-            let width = format.width;
-            let height = format.height;
-            let stride = format.stride;
+    // Applied right away and kept when switching sensors or restarting the
+    // preview.
+    pub fn set_focus_mode(&mut self, mode: FocusMode) -> io::Result<()> {
+        let mut current = self.focus_mode.write().unwrap();
+        *current = mode;
+        self.focus_controls()?.apply(self.sensor(), mode)
+    }
 
-            println!("w: {}, h: {}, s: {}", width, height, stride);
+    // Starts a single shot search, the preview thread reports how it goes
+    // with CamMsg::Focus.
+    pub fn focus(&self) -> io::Result<()> {
+        self.focus_controls()?.trigger(self.sensor())?;
+        *self.focus_triggered.write().unwrap() = true;
+        Ok(())
+    }
 
+    // Meter around a point of the preview as shown, given as shares of its
+    // width and height, and focus there with software focus. Sensors that
+    // focus by themselves have no way to be told where, they only search
+    // again. Searches right away in single shot mode, and continuous
+    // software focus moves there.
+    pub fn set_focus_point(&self, x: f32, y: f32) -> io::Result<()> {
+        let rotation = self.profile().mount_rotation().minus(*self.device_rotation.read().unwrap());
+        let (x, y) = Region::point_from_preview(x, y, rotation);
+        *self.region.write().unwrap() = Some(Region::around(x, y, FOCUS_REGION_SIZE));
+
+        if self.focus_mode() == FocusMode::Single {
+            self.focus()
+        } else {
+            *self.focus_triggered.write().unwrap() = true;
+            Ok(())
+        }
+    }
 
-            // The driver seems to expect all buffers queued before start
-            // Otherwise it just gets stuck at first stream.next()
-            for i in 1..num_bufs as usize {
-                stream.queue(i).expect("Can't queue buffer");
-            }
+    // Back to the whole frame.
+    pub fn clear_focus_region(&self) {
+        *self.region.write().unwrap() = None;
+    }
 
-            //stream.start();
+    // Everything the current sensor offers.
+    pub fn controls(&self) -> io::Result<Vec<ControlInfo>> {
+        self.sensor().controls()
+    }
 
-            while *preview_lock.read().unwrap() == true {
-                let (buf, _meta) = stream.next()
-                    .expect("Failure when reading picture from MmapStream!");
-                let buf_len = buf.len();
-                if buf_len == 0 {
-                    continue;
-                }
+    // Exposure, gain, white balance, test pattern and focus controls of the
+    // current sensor, where it has them.
+    pub fn sensor_controls(&self) -> io::Result<SensorControls> {
+        Ok(SensorControls::new(&self.controls()?))
+    }
 
-                let data = debayer_superpixel(buf, width, height);
+    pub fn get_controls(&self, controls: &[&ControlInfo]) -> io::Result<Vec<ControlValue>> {
+        self.sensor().get_controls(controls)
+    }
 
-                let width = width / 2;
-                let height = height / 2;
-                let rowstride = width * 3;
+    // Applied right away, also while previewing.
+    pub fn set_controls(&self, values: &[(&ControlInfo, ControlValue)]) -> io::Result<()> {
+        self.sensor().set_controls(values)
+    }
 
-                let data = glib::Bytes::from_owned(data);
+    // Takes effect on the next preview start or capture.
+    pub fn set_raw_format(&mut self, format: RawFormat) {
+        self.raw_format = Some(format);
+    }
 
-                let data = Picture::new(
-                    width as i32,
-                    height as i32,
-                    rowstride as i32,
-                    data);
+    // Preview only, captures take theirs from SaveOptions.
+    pub fn set_white_balance(&self, white_balance: WhiteBalance) {
+        *self.white_balance.write().unwrap() = white_balance;
+    }
 
-                sender.lock().unwrap().send(CamMsg::Pic(data)).expect("Can't send picture buffer.");
+    // Preview frames are scaled down to fit, but never up from half the
+    // sensor mode. Takes effect on the next frame.
+    pub fn set_preview_size(&self, width: usize, height: usize) {
+        *self.preview_size.write().unwrap() = Some((width, height));
+    }
+
+    // Preview frames come out turned to match, takes effect on the next
+    // frame.
+    pub fn set_device_orientation(&self, orientation: &str) {
+        *self.device_rotation.write().unwrap() = Rotation::from_device_orientation(orientation);
+    }
 
+    // Returns right away, the preview thread stops on its next frame, or when
+    // its stream times out if it stalled.
+    pub fn stop_preview(&mut self) {
+        *self.should_preview.write().unwrap() = false;
+    }
+
+    pub fn start_preview(&mut self) {
+        let sender = self.sender.clone();
+        // A preview still running has to end for the new thread, which
+        // waits for it, to start.
+        self.stop_preview();
+        // A flag of its own, so a stopped thread still winding down isn't
+        // started again by it.
+        self.should_preview = Arc::new(RwLock::new(true));
+        let preview_lock = self.should_preview.clone();
+        let backend = self.backend.clone();
+        let index = self.current;
+        let profile = self.profile();
+        let requested = self.raw_format;
+        let exposure_mode_lock = self.exposure_mode.clone();
+        let settled_lock = self.settled.clone();
+        let metered = self.metered;
+        let focus_mode_lock = self.focus_mode.clone();
+        let region_lock = self.region.clone();
+        let focus_triggered_lock = self.focus_triggered.clone();
+        let white_balance_lock = self.white_balance.clone();
+        let preview_size_lock = self.preview_size.clone();
+        let device_rotation_lock = self.device_rotation.clone();
+
+        self.spawn(move || {
+            let sensor = backend.sensor(index);
+            let preview = |watchdog: &mut Watchdog| -> Result<(), Error> {
+                let mut exposure_mode = *exposure_mode_lock.read().unwrap();
+                let sensor_format = configure_sensor(&*backend, index, &profile, (profile.preview, profile.mbus_code), requested, (exposure_mode, metered))?;
+                let mut auto = auto_control(sensor, &profile, profile.preview, *settled_lock.read().unwrap());
+                auto.set_mode(sensor, exposure_mode);
+                let mut focus_mode = *focus_mode_lock.read().unwrap();
+                let focus_controls = FocusControls::new(&sensor_controls(sensor));
+                apply_focus(&focus_controls, sensor, focus_mode);
+                let mut focus = FocusLoop::new(focus_controls, sensor, focus_mode);
+
+                let raw_format = raw_format_of(&sensor_format, requested)?;
+
+                let color_profile = color::profile(sensor.model());
+                let color_transform = ColorTransform::new(&color_profile);
+                let black_level = color_profile.black_level(8) as u8;
+                let shading_map = ShadingMap::load(sensor.model());
+                // Frames are turned while they're debayered, so the map is
+                // turned along with them.
+                let mut turned_map = (Rotation::Normal, shading_map.clone());
+                let pool = BufferPool::new();
+
+                backend.stream(index, raw_format, &mut |frame| {
+                    if !*preview_lock.read().unwrap() {
+                        return Ok(false);
+                    }
+                    watchdog.frame();
+
+                    let (buf, stride, raw_format) = (frame.data, frame.stride, &frame.format);
+                    let source = (frame.width, frame.height);
+                    let raw_black_level = color_profile.black_level(raw_format.bits);
+
+                    let mode = *exposure_mode_lock.read().unwrap();
+                    if mode != exposure_mode {
+                        exposure_mode = mode;
+                        auto.set_mode(sensor, mode);
+                    }
+                    let region = *region_lock.read().unwrap();
+                    auto.set_region(region);
+                    let stats = FrameStats::from_raw(buf, stride, raw_format, source, raw_black_level);
+                    let gains = auto.update(sensor, &stats, *white_balance_lock.read().unwrap());
+                    if auto.converged() {
+                        if let Some(values) = auto.exposure() {
+                            *settled_lock.write().unwrap() = Some(values);
+                        }
+                    }
+
+                    let mode = *focus_mode_lock.read().unwrap();
+                    if mode != focus_mode {
+                        focus_mode = mode;
+                        focus.set_mode(sensor, mode);
+                    }
+                    if std::mem::replace(&mut *focus_triggered_lock.write().unwrap(), false) {
+                        focus.trigger(sensor);
+                    }
+                    if let Some(status) = focus.update(sensor, buf, stride, raw_format, source, region) {
+                        if !send(&sender, CamMsg::Focus(status)) {
+                            return Ok(false);
+                        }
+                    }
+
+                    let rotation = profile.mount_rotation().minus(*device_rotation_lock.read().unwrap());
+                    let fit = preview_size_lock.read().unwrap()
+                        .map(|(w, h)| if rotation.swaps_sides() { (h, w) } else { (w, h) });
+                    let (width, height) = preview_size(frame.width, frame.height, fit);
+                    if turned_map.0 != rotation {
+                        turned_map = (rotation, shading_map.as_ref().map(|m| m.rotated(rotation)));
+                    }
+
+                    let mut data = pool.get(width * height * 3);
+                    debayer_superpixel(buf, stride, raw_format, source, &mut data, (width, height), rotation);
+                    let (width, height) = if rotation.swaps_sides() { (height, width) } else { (width, height) };
+                    shading::correct_rgb8(&mut data, width, black_level, turned_map.1.as_ref());
+
+                    white_balance::apply_rgb8(&mut data, gains);
+                    color_transform.apply_rgb8(&mut data);
+
+                    let rowstride = width * 3;
+
+                    let data = glib::Bytes::from_owned(data);
+
+                    let data = Picture::new(
+                        width as i32,
+                        height as i32,
+                        rowstride as i32,
+                        data);
+
+                    Ok(send(&sender, CamMsg::Pic(data)))
+                })
+            };
+
+            // Stalls and errors get the pipeline set up again a few times,
+            // after that the UI hears about them.
+            let mut watchdog = Watchdog::new();
+            while let Err(e) = preview(&mut watchdog) {
+                // Stopped while stalled, nobody is waiting for frames.
+                if !*preview_lock.read().unwrap() {
+                    break;
+                }
+                let delay = match watchdog.restart(&e) {
+                    Some(delay) => delay,
+                    None => {
+                        eprintln!("Preview failed: {}", e);
+                        send(&sender, CamMsg::Error(e));
+                        break;
+                    }
+                };
+
+                eprintln!("Preview failed, restart {} of {}: {}", watchdog.restarts(), watchdog::MAX_RESTARTS, e);
+                if !send(&sender, CamMsg::Restarting { attempt: watchdog.restarts(), error: e }) {
+                    break;
+                }
+                thread::sleep(delay);
+                if let Err(e) = backend.reset(index) {
+                    eprintln!("Can't reset pipeline: {}", e);
+                }
             }
 
-            println!("Done with preview thread.");
+            eprintln!("Done with preview thread.");
 
         });
+    }
 
-        self.thread_handle = Some(thread_handle);
+    // Takes a photo on the camera thread once the preview there has
+    // stopped, saves it in the background and sends CamMsg::Captured.
+    pub fn capture(&mut self, orientation: String, options: SaveOptions) {
+        // Waits for the preview thread otherwise.
+        self.stop_preview();
+        let still = self.still();
+        self.spawn(move || {
+            match still.grab_still() {
+                Ok(raw) => {
+                    let info = still.capture_info(orientation);
+                    thread::spawn(move || {
+                        convert::save(raw, info, options);
+                    });
+                },
+                Err(e) => still.send_error(e)
+            }
+            send(&still.sender, CamMsg::Captured);
+        });
     }
 
-    pub fn capture(&self, orientation: String) {
-        let dev = self.main_device.clone();
-        let preview_lock = self.should_preview.clone();
-        {
-            let mut sp = preview_lock.write().unwrap();
-            *sp = true;
+    // Like capture, but on the calling thread, saving before returning
+    // instead of in the background. Returns the files written.
+    pub fn capture_to_files(&self, orientation: String, options: SaveOptions) -> Result<Vec<PathBuf>, Error> {
+        let still = self.still();
+        let raw = still.grab_still()?;
+        let info = still.capture_info(orientation);
+        Ok(convert::save(raw, info, options))
+    }
+
+    // Point the camera at an evenly lit, featureless surface (a sheet of
+    // paper over the lens in daylight works) and call this instead of
+    // capture. The computed map is picked up by preview and captures from
+    // then on.
+    pub fn calibrate_lens_shading(&mut self) {
+        // Waits for the preview thread otherwise.
+        self.stop_preview();
+        let still = self.still();
+        self.spawn(move || {
+            let raw = match still.grab_still() {
+                Ok(raw) => raw,
+                Err(e) => {
+                    still.send_error(e);
+                    send(&still.sender, CamMsg::Captured);
+                    return;
+                }
+            };
+            let samples = raw.unpack();
+            let frame = BayerFrame::new(&samples, raw.width, raw.height, raw.format.pattern, raw.format.bits);
+            let model = still.sensor().model();
+            let black_level = color::profile(model).black_level(raw.format.bits);
+
+            match ShadingMap::calibrate(&frame, black_level).save(model) {
+                Ok(path) => eprintln!("Saved lens shading map to {}", path.to_string_lossy()),
+                Err(e) => eprintln!("Couldn't save lens shading map: {}", e)
+            }
+            send(&still.sender, CamMsg::Captured);
+        });
+    }
+
+    // Everything a capture needs, as it is now.
+    fn still(&self) -> Still {
+        Still {
+            backend: self.backend.clone(),
+            index: self.current,
+            profile: self.profile(),
+            raw_format: self.raw_format,
+            exposure_mode: *self.exposure_mode.read().unwrap(),
+            metered: self.metered,
+            settled: self.settled.clone(),
+            region: *self.region.read().unwrap(),
+            focus_mode: self.focus_mode(),
+            white_balance: *self.white_balance.read().unwrap(),
+            sender: self.sender.clone()
         }
+    }
 
-        let (w, h, denominator) = match self.sensor {
-            Sensor::Back => (2592, 1944, 15),
-            Sensor::Front => (1600, 1200, 15)
-        };
-
-        let media_device = self.media_device.clone();
-        let md = media_device.write()
-            .expect("Couldn't lock media device.");
-
-        match self.sensor {
-            Sensor::Back => {
-                md.link_back_camera();
-                md.set_back_interval(1, denominator);
-                md.set_back_format(w, h);
-            },
-            Sensor::Front => {
-                md.link_front_camera();
-                md.set_front_interval(1, denominator);
-                md.set_front_format(w, h);
+    // Runs f on a thread of its own once the previous camera thread is done
+    // with the pipeline, so the UI thread never waits for a stream to wind
+    // down.
+    fn spawn<F: FnOnce() + Send + 'static>(&mut self, f: F) {
+        let previous = self.thread_handle.take();
+        self.thread_handle = Some(thread::spawn(move || {
+            if let Some(handle) = previous {
+                if handle.join().is_err() {
+                    eprintln!("Previous camera thread panicked.");
+                }
             }
+            f();
+        }));
+    }
+}
+
+// A capture of one camera, taken away from the UI thread.
+struct Still {
+    backend: Arc<dyn CameraBackend>,
+    index: usize,
+    profile: DeviceProfile,
+    raw_format: Option<RawFormat>,
+    exposure_mode: ExposureMode,
+    metered: Option<ExposureValues>,
+    // Shared with Camera, captures start from where the preview settled and
+    // leave theirs for the next preview.
+    settled: Arc<RwLock<Option<ExposureValues>>>,
+    region: Option<Region>,
+    focus_mode: FocusMode,
+    white_balance: WhiteBalance,
+    sender: Arc<Mutex<Sender<CamMsg>>>
+}
+
+impl Still {
+    fn sensor(&self) -> &dyn Sensor {
+        self.backend.sensor(self.index)
+    }
+
+    // Auto exposure included, for the EXIF data.
+    fn capture_info(&self, orientation: String) -> CaptureInfo {
+        let exposure = ExposureControls::new(&sensor_controls(self.sensor()), &self.profile)
+            .read(self.sensor());
+        CaptureInfo {
+            orientation,
+            sensor: self.sensor().model().to_string(),
+            time: Local::now(),
+            exposure
         }
+    }
 
-        let format = Format {
-            width: w,
-            height: h,
-            fourcc: FourCC::new(b"BA81"),
-            field_order: FieldOrder::Progressive,
-            stride: w,
-            size: w * h,
-            flags: Flags::empty(),
-            colorspace: Colorspace::RAW,
-            quantization: Quantization::Default,
-            transfer: TransferFunction::None
-        };
-
-        let num_bufs = 4;
-        let mut dev = dev.write().unwrap();
-        dev.set_format(&format).expect("Can't set video device format.");
-        let params = dev.params().expect("Couldn't get device params.");
-        println!("Device params: {:#?}", params);
-        let format = dev.format().expect("Couldn't get device format.");
-        println!("Device format: {:#?}", format);
-        let mut stream = MmapStream::with_buffers(&mut *dev, Type::VideoCapture, num_bufs)
-            .expect("Failed to create MmapStream!");
-
-        // 1280 x 960 BA81
-        // BG
-        // GR
-        // 640x480 RGB
-
-        let width = format.width;
-        let height = format.height;
-        let _stride = format.stride;
-
-        for i in 1..num_bufs as usize {
-            stream.queue(i).expect("Can't queue buffer");
+    fn send_error(&self, error: Error) {
+        eprintln!("Capture failed: {}", error);
+        send(&self.sender, CamMsg::Error(error));
+    }
+
+    // Full resolution frame from the sensor, once exposure and white balance
+    // have settled or given up trying. Restarts the stream a few times if it
+    // fails or stalls.
+    fn grab_still(&self) -> Result<RawImage, Error> {
+        let mut watchdog = Watchdog::new();
+        loop {
+            let e = match self.grab_frame() {
+                Ok(raw) => return Ok(raw),
+                Err(e) => e
+            };
+            let delay = match watchdog.restart(&e) {
+                Some(delay) => delay,
+                None => return Err(e)
+            };
+
+            eprintln!("Capture failed, restart {} of {}: {}", watchdog.restarts(), watchdog::MAX_RESTARTS, e);
+            send(&self.sender, CamMsg::Restarting { attempt: watchdog.restarts(), error: e });
+            thread::sleep(delay);
+            self.backend.reset(self.index)?;
         }
+    }
+
+    fn grab_frame(&self) -> Result<RawImage, Error> {
+        let profile = &self.profile;
+        let requested = self.raw_format;
+        let sensor = self.sensor();
 
+        let sensor_format = configure_sensor(&*self.backend, self.index, profile, (profile.still, profile.still_code()), requested, (self.exposure_mode, self.metered))?;
+        let mut auto = auto_control(sensor, profile, profile.still, *self.settled.read().unwrap());
+        auto.set_mode(sensor, self.exposure_mode);
+        auto.set_region(self.region);
+        apply_focus(&FocusControls::new(&sensor_controls(sensor)), sensor, self.focus_mode);
+
+        let raw_format = raw_format_of(&sensor_format, requested)?;
+        let color_profile = color::profile(sensor.model());
         let mut count = 0;
+        let mut still = None;
 
-        loop {
-            let (buf, _meta) = stream.next()
-                .expect("Failure when reading picture from MmapStream!");
-            let buf_len = buf.len();
-            assert!(buf_len % 4 == 0);
-
-            if count < 6 {
-                count += 1;
-                continue;
+        self.backend.stream(self.index, raw_format, &mut |frame| {
+            count += 1;
+
+            let black_level = color_profile.black_level(frame.format.bits);
+            let stats = FrameStats::from_raw(frame.data, frame.stride, &frame.format, (frame.width, frame.height), black_level);
+            auto.update(sensor, &stats, self.white_balance);
+            let settled = auto.converged() && count > MIN_SETTLE_FRAMES;
+            if !settled && count < MAX_SETTLE_FRAMES {
+                return Ok(true);
             }
 
-            let buf = buf.to_vec();
-            thread::spawn(move || {
-                convert::save(buf, width as usize, height as usize, orientation);
-            });
-            self.sender.clone().lock().unwrap().send(CamMsg::Captured).expect("Can't send status.");
-            break;
+            eprintln!("Capturing after {} frames, {}.", count, if settled { "settled" } else { "gave up settling" });
+            if let Some(values) = auto.exposure() {
+                *self.settled.write().unwrap() = Some(values);
+            }
+            still = Some(RawImage::new(frame.data.to_vec(), frame.width, frame.height, frame.stride, frame.format));
+            Ok(false)
+        })?;
+
+        still.ok_or(Error::StreamTimeout)
+    }
+}
+
+// False once the channel is closed, there is nobody left to tell and
+// streams can stop.
+fn send(sender: &Mutex<Sender<CamMsg>>, msg: CamMsg) -> bool {
+    sender.lock().unwrap().send(msg).is_ok()
+}
+
+// Exposure and white balance loop for a stream in the given mode.
+fn auto_control(sensor: &dyn Sensor, profile: &DeviceProfile, mode: Mode, start: Option<ExposureValues>) -> AutoControl {
+    let exposure = ExposureControls::new(&sensor_controls(sensor), profile);
+    AutoControl::new(exposure, profile, color::profile(sensor.model()).white_points, start, 1.0 / mode.fps.max(1) as f32)
+}
+
+// None of them if the driver won't list its controls.
+fn sensor_controls(sensor: &dyn Sensor) -> SensorControls {
+    let controls = sensor.controls().unwrap_or_else(|e| {
+        eprintln!("Can't list controls of {}: {}", sensor.name(), e);
+        Vec::new()
+    });
+    SensorControls::new(&controls)
+}
+
+// Sensors without focus are left alone, continuous focus being the default
+// there's nothing to complain about.
+fn apply_focus(focus: &FocusControls, sensor: &dyn Sensor, mode: FocusMode) {
+    if !focus.supports(mode) && mode == FocusMode::default() {
+        return;
+    }
+    if let Err(e) = focus.apply(sensor, mode) {
+        eprintln!("Can't set focus {:?}: {}", mode, e);
+    }
+}
+
+// Links up camera index and puts its sensor in the offered mode closest to
+// the given one, in the given bus format if it has it, with the profile's
+// controls. Returns the format it settled on.
+fn configure_sensor(backend: &dyn CameraBackend, index: usize, profile: &DeviceProfile, mode: (Mode, Option<u32>), requested: Option<RawFormat>, exposure: (ExposureMode, Option<ExposureValues>)) -> Result<FrameFormat, Error> {
+    let (mode, code) = mode;
+    backend.activate(index)?;
+
+    let sensor = backend.sensor(index);
+    let modes = sensor.modes().unwrap_or_else(|e| {
+        eprintln!("Can't list modes of {}: {}", sensor.name(), e);
+        Vec::new()
+    });
+
+    // What was asked for first, then any raw bayer format.
+    let mut codes = requested.map(|f| f.mbus_code())
+        .or(code)
+        .into_iter()
+        .collect::<Vec<u32>>();
+    codes.extend(modes.iter()
+        .map(|m| m.code)
+        .filter(|c| RawFormat::from_mbus_code(*c, Packing::Unpacked).is_some()));
+
+    match mode.best_of(&modes, &codes) {
+        Some(mut choice) => {
+            // Smaller sizes than the largest of a stepwise range may go
+            // faster.
+            if choice.size.largest() != (choice.width, choice.height) {
+                match sensor.frame_intervals(choice.code, choice.width, choice.height) {
+                    Ok(intervals) => choice.interval = mode.best_interval(&intervals).or(choice.interval),
+                    Err(e) => eprintln!("Can't list frame intervals of {}: {}", sensor.name(), e)
+                }
+            }
+            sensor.set_format(choice.width, choice.height, choice.code)?;
+            if let Some(interval) = choice.interval {
+                set_interval(sensor, interval.numerator, interval.denominator);
+            }
+        },
+        // Drivers that don't enumerate get what the profile says.
+        None => {
+            let code = match codes.first() {
+                Some(code) => *code,
+                None => sensor.format()?.code
+            };
+            sensor.set_format(mode.width, mode.height, code)?;
+            set_interval(sensor, 1, mode.fps);
         }
     }
+
+    let flips = [(V4L2_CID_HFLIP, profile.hflip as i32), (V4L2_CID_VFLIP, profile.vflip as i32)];
+    for (id, value) in flips.iter().cloned().chain(profile.control_values()) {
+        if let Err(e) = sensor.set_control(id, value) {
+            eprintln!("Can't set control {:#x} to {}: {}", id, value, e);
+        }
+    }
+
+    // Software exposure takes over once the stream runs.
+    let (exposure_mode, metered) = exposure;
+    if auto::owns_exposure(profile, exposure_mode) {
+        return sensor.format();
+    }
+    let applied = sensor.controls()
+        .and_then(|c| ExposureControls::new(&SensorControls::new(&c), profile).apply(sensor, exposure_mode, metered));
+    if let Err(e) = applied {
+        eprintln!("Can't set exposure {:?}: {}", exposure_mode, e);
+    }
+
+    sensor.format()
 }
 
-fn guess_media_device_path(camera_path: &Path) -> io::Result<PathBuf> {
-    let device_file = camera_path.file_name().unwrap();
-    let mut pb = PathBuf::from("/sys/class/video4linux");
-    pb.push(&device_file);
-    pb.push("device");
-    let path = pb.as_path();
-
-    let media_path = fs::read_dir(path)?
-        .filter(|e| e.is_ok())
-        .map(|e| e.unwrap().path())
-        .filter(|e| e.is_dir())
-        .map(|e| {
-            let file_name = e.file_name().unwrap();
-            file_name.to_string_lossy().to_string()
-        })
-        .find(|e| &e[0..5] == "media")
-        .expect("Failed to find media device name for video device");
-
-    let mut pb = PathBuf::from("/dev");
-    pb.push(&*media_path);
-    Ok(pb)
+// Not every driver lets the frame rate be picked, the stream still works.
+fn set_interval(sensor: &dyn Sensor, numerator: u32, denominator: u32) {
+    if let Err(e) = sensor.set_interval(numerator, denominator) {
+        eprintln!("Can't set frame interval of {}: {}", sensor.name(), e);
+    }
 }
 
-// < 30ms
-fn debayer_superpixel(data: &[u8], width: u32, height: u32) -> Vec<u8> {
-    // B G
-    // G R
-    // Slice access is 10x slower than vec access
-    let data = data.to_vec();
-    let width = width as usize;
-    let height = height as usize;
-    let out_w = width / 2;
-    let out_h = height / 2;
-    let mut out = Vec::with_capacity(out_w * out_h * 3);
-    let super_pix = [0, 1, width, width + 1];
-    let len = data.len();
-
-    for row in (0..len).step_by(width + width) {
-        for col in (0..width).step_by(2) {
-            let top_left = row + col;
-
-            out.push(data[super_pix[3] + top_left]);
-            /*let g = ((
-                    data[super_pix[1] + top_left] as usize +
-                    data[super_pix[2] + top_left] as usize
-                ) >> 1) as u8;
-            out.push(g);*/
-            out.push(data[super_pix[1] + top_left]);
-            out.push(data[super_pix[0] + top_left]);
-      }
-    }
-
-    out
+// What the sensor's media bus format comes to in memory.
+fn raw_format_of(format: &FrameFormat, requested: Option<RawFormat>) -> Result<RawFormat, Error> {
+    RawFormat::from_mbus_code(format.code, packing(requested))
+        .ok_or_else(|| Error::UnsupportedFormat(format!("media bus code {:#x} isn't raw bayer", format.code)))
 }
 
+// Unpacked unless asked otherwise, the video device format read back after
+// setting it has the final word.
+fn packing(requested: Option<RawFormat>) -> Packing {
+    requested.map_or(Packing::Unpacked, |f| f.packing)
+}
+
+// Half size of the sensor frame, or smaller to fit the UI, keeping the aspect
+// ratio.
+fn preview_size(width: usize, height: usize, fit: Option<(usize, usize)>) -> (usize, usize) {
+    let (width, height) = (width / 2, height / 2);
+    match fit {
+        Some((fit_width, fit_height)) if fit_width < width || fit_height < height => {
+            let scale = (fit_width as f32 / width as f32).min(fit_height as f32 / height as f32);
+            (((width as f32 * scale) as usize).max(1), ((height as f32 * scale) as usize).max(1))
+        },
+        _ => (width, height)
+    }
+}
+
+// One RGB pixel per 2x2 block, read straight from the mmapped buffer. When
+// the output is smaller than half the source, blocks in between are skipped.
+// Pixels go where rotation turns them, out has the sides of size swapped for
+// quarter turns.
+fn debayer_superpixel(data: &[u8], stride: usize, raw_format: &RawFormat, source: (usize, usize), out: &mut [u8], size: (usize, usize), rotation: Rotation) {
+    let (blocks_w, blocks_h) = (source.0 / 2, source.1 / 2);
+    let (out_w, out_h) = size;
+    let turned_w = if rotation.swaps_sides() { out_h } else { out_w };
+    let pattern = raw_format.pattern;
+    let (red_row, red_col) = pattern.red_position();
+    let (green_row, green_col) = pattern.green_position();
+    let (blue_row, blue_col) = pattern.blue_position();
+    let shift = raw_format.bits - 8;
+
+    for y in 0..out_h {
+        let row = y * blocks_h / out_h * 2;
+        let line = |r: usize| &data[(row + r) * stride..];
+        let (red_line, green_line, blue_line) = (line(red_row), line(green_row), line(blue_row));
+
+        for x in 0..out_w {
+            let col = x * blocks_w / out_w * 2;
+            let (turned_x, turned_y) = rotation.position(x, y, out_w, out_h);
+            let offset = (turned_y * turned_w + turned_x) * 3;
+            let p = &mut out[offset..offset + 3];
+            p[0] = (raw_format.sample(red_line, col + red_col) >> shift) as u8;
+            p[1] = (raw_format.sample(green_line, col + green_col) >> shift) as u8;
+            p[2] = (raw_format.sample(blue_line, col + blue_col) >> shift) as u8;
+        }
+    }
+}
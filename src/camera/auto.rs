@@ -0,0 +1,363 @@
+// Software automatic exposure and white balance, one step per frame from the
+// frame's statistics. For sensors whose own loops aren't any good, the
+// others only get their settling watched.
+use crate::camera::backend::Sensor;
+use crate::camera::convert::{WbGains, WhiteBalance, WhitePoints};
+use crate::camera::exposure::{ExposureControls, ExposureLimits, ExposureMode, ExposureValues};
+use crate::camera::focus::Region;
+use crate::camera::profile::DeviceProfile;
+use crate::camera::stats::FrameStats;
+
+// Mean luminance to aim for, linear before the tone curve.
+const TARGET_LUMA: f32 = 0.18;
+// The brightest couple of percent are kept below this rather than reaching
+// the target.
+const HIGHLIGHT_SHARE: f32 = 0.98;
+const HIGHLIGHT_LIMIT: f32 = 0.9;
+// Weight of the metering region against the whole frame.
+const REGION_WEIGHT: f32 = 0.75;
+// Closer than this counts as there.
+const TOLERANCE_STOPS: f32 = 0.15;
+// Share of the error corrected per step, all of it overshoots once the
+// sensor's latency gets involved.
+const DAMPING: f32 = 0.6;
+// Frames between writing exposure controls and seeing them in frames.
+const LATENCY: u32 = 2;
+// Largest change of a white balance gain that still counts as steady.
+const WB_TOLERANCE: f32 = 0.02;
+// Frames in a row within tolerance before calling it converged.
+const STEADY_FRAMES: u32 = 3;
+
+// Whether software exposure runs instead of the sensor's for this mode.
+pub fn owns_exposure(profile: &DeviceProfile, mode: ExposureMode) -> bool {
+    profile.software_ae && !matches!(mode, ExposureMode::Manual { .. })
+}
+
+pub struct AutoControl {
+    controls: ExposureControls,
+    // None turns software exposure off, it needs manual exposure to work.
+    limits: Option<ExposureLimits>,
+    software_ae: bool,
+    mode: ExposureMode,
+    // Metered on mostly, when set.
+    region: Option<Region>,
+    // What was last written to the sensor.
+    values: Option<ExposureValues>,
+    // Longest exposure that fits the frame rate.
+    max_time: f32,
+    // Frames until the last change shows.
+    wait: u32,
+    exposure_steady: u32,
+    last_luma: Option<f32>,
+    gains: Option<WbGains>,
+    wb_steady: u32,
+    // The sensor's, for presets.
+    white_points: WhitePoints
+}
+
+impl AutoControl {
+    // start is where a previous stream settled, the sensor's current values
+    // are used without one.
+    pub fn new(controls: ExposureControls, profile: &DeviceProfile, white_points: WhitePoints, start: Option<ExposureValues>, max_time: f32) -> Self {
+        let limits = controls.limits();
+        if profile.software_ae && limits.is_none() {
+            eprintln!("No manual exposure on this sensor, leaving exposure to it.");
+        }
+
+        AutoControl {
+            controls,
+            limits,
+            software_ae: profile.software_ae,
+            mode: ExposureMode::Auto,
+            region: None,
+            values: start,
+            max_time,
+            wait: LATENCY,
+            exposure_steady: 0,
+            last_luma: None,
+            gains: None,
+            wb_steady: 0,
+            white_points
+        }
+    }
+
+    // Call with the mode at the start and whenever it changes. Modes the
+    // software loop doesn't own are left to the sensor and ExposureControls.
+    pub fn set_mode(&mut self, sensor: &dyn Sensor, mode: ExposureMode) {
+        self.mode = mode;
+        self.wait = LATENCY;
+        self.exposure_steady = 0;
+        self.last_luma = None;
+
+        if !self.owns_exposure() {
+            return;
+        }
+        let values = match self.values.or_else(|| self.controls.read(sensor)) {
+            Some(values) => values,
+            None => return
+        };
+        // Also turns the sensor's own loop off.
+        match self.controls.set_manual(sensor, values.time, values.iso) {
+            Ok(_) => self.values = Some(values),
+            Err(e) => eprintln!("Can't take over exposure: {}", e)
+        }
+    }
+
+    pub fn set_region(&mut self, region: Option<Region>) {
+        if region != self.region {
+            self.region = region;
+            self.exposure_steady = 0;
+        }
+    }
+
+    // One step of both loops, returns the white balance gains for this frame.
+    pub fn update(&mut self, sensor: &dyn Sensor, stats: &FrameStats, white_balance: WhiteBalance) -> WbGains {
+        self.update_exposure(sensor, stats);
+        self.update_white_balance(stats, white_balance)
+    }
+
+    // Both loops have settled, or run into their limits.
+    pub fn converged(&self) -> bool {
+        self.exposure_steady >= STEADY_FRAMES && self.wb_steady >= STEADY_FRAMES
+    }
+
+    // Last values the software loop set, None while the sensor runs
+    // exposure.
+    pub fn exposure(&self) -> Option<ExposureValues> {
+        if self.owns_exposure() {
+            self.values
+        } else {
+            None
+        }
+    }
+
+    fn owns_exposure(&self) -> bool {
+        self.software_ae && self.limits.is_some() && !matches!(self.mode, ExposureMode::Manual { .. })
+    }
+
+    fn update_exposure(&mut self, sensor: &dyn Sensor, stats: &FrameStats) {
+        if self.wait > 0 {
+            self.wait -= 1;
+            return;
+        }
+
+        let luma = match self.region.and_then(|r| stats.region_luma(&r)) {
+            Some(region) => region * REGION_WEIGHT + stats.luma() * (1.0 - REGION_WEIGHT),
+            None => stats.luma()
+        }.max(1.0 / 1024.0);
+        let (limits, values) = match (self.limits, self.values) {
+            (Some(limits), Some(values)) if self.owns_exposure() => (limits, values),
+            // The sensor's loop or manual exposure, settled once frames stop
+            // changing.
+            _ => {
+                let change = self.last_luma.map_or(f32::MAX, |last| (luma / last).log2().abs());
+                self.last_luma = Some(luma);
+                self.exposure_steady = if change < TOLERANCE_STOPS { self.exposure_steady + 1 } else { 0 };
+                return;
+            }
+        };
+
+        let target = match self.mode {
+            ExposureMode::Compensated(stops) => TARGET_LUMA * 2f32.powf(stops),
+            _ => TARGET_LUMA
+        };
+        let highlights = stats.percentile(HIGHLIGHT_SHARE).max(1.0 / 1024.0);
+        let error = (target / luma).log2().min((HIGHLIGHT_LIMIT / highlights).log2());
+        if error.abs() < TOLERANCE_STOPS {
+            self.exposure_steady += 1;
+            return;
+        }
+
+        // Time first, gain for what doesn't fit.
+        let exposure = values.time * values.iso as f32 * 2f32.powf(error * DAMPING);
+        let time = (exposure / limits.iso.0.max(1) as f32)
+            .min(limits.time.1.min(self.max_time))
+            .max(limits.time.0);
+        let iso = ((exposure / time).round() as u32).max(limits.iso.0).min(limits.iso.1);
+
+        // Pinned to a limit, as good as it gets.
+        if (time - values.time).abs() < limits.time.0 / 2.0 && iso == values.iso {
+            self.exposure_steady += 1;
+            return;
+        }
+
+        match self.controls.set_manual(sensor, time, iso) {
+            Ok(_) => self.values = Some(ExposureValues { time, iso }),
+            Err(e) => eprintln!("Can't set exposure: {}", e)
+        }
+        self.wait = LATENCY;
+        self.exposure_steady = 0;
+    }
+
+    // Auto modes are eased into so the preview doesn't flicker, presets are
+    // there right away.
+    fn update_white_balance(&mut self, stats: &FrameStats, white_balance: WhiteBalance) -> WbGains {
+        let estimate = white_balance.gains(stats.samples.iter().cloned(), stats.max, &self.white_points);
+        let gains = match self.gains {
+            Some(previous) if white_balance.is_auto() => {
+                let blend = |p: f32, e: f32| p * 0.8 + e * 0.2;
+                WbGains {
+                    red: blend(previous.red, estimate.red),
+                    green: blend(previous.green, estimate.green),
+                    blue: blend(previous.blue, estimate.blue)
+                }
+            },
+            _ => estimate
+        };
+
+        let change = |a: f32, b: f32| (a / b - 1.0).abs();
+        let steady = match self.gains {
+            Some(previous) => change(gains.red, previous.red).max(change(gains.blue, previous.blue)) < WB_TOLERANCE,
+            None => !white_balance.is_auto()
+        };
+        self.wb_steady = if steady { self.wb_steady + 1 } else { 0 };
+        self.gains = Some(gains);
+        gains
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use v4l_subdev::{V4L2_CID_EXPOSURE, V4L2_CID_GAIN};
+    use crate::camera::bayer::{CfaPattern, Channel, Packing, RawFormat};
+    use crate::camera::controls::SensorControls;
+    use crate::camera::synthetic::{Pattern, SyntheticSensor};
+
+    const WIDTH: usize = 128;
+    const HEIGHT: usize = 96;
+    // Green over red and blue of the light, undone by gains of 2 and 1.25.
+    const CAST: [f32; 3] = [0.5, 1.0, 0.8];
+    const MAX_FRAMES: usize = 120;
+
+    // The synthetic sensor's controls in front of a scene that sees them
+    // LATENCY frames late, like a real sensor does.
+    struct SimulatedSensor {
+        sensor: SyntheticSensor,
+        // Light of the frames on their way, oldest first.
+        pending: VecDeque<f32>,
+        // Scene brightness, 1 is mid grey at 10ms and ISO 100.
+        scene: f32
+    }
+
+    impl SimulatedSensor {
+        fn new(scene: f32) -> Self {
+            SimulatedSensor {
+                sensor: SyntheticSensor::new("synthetic test", Pattern::ColorBars),
+                pending: VecDeque::new(),
+                scene
+            }
+        }
+
+        fn raw_format() -> RawFormat {
+            RawFormat::new(CfaPattern::Rggb, 10, Packing::Unpacked)
+        }
+
+        fn light(&self) -> f32 {
+            let controls = self.sensor.controls().unwrap();
+            let find = |id| controls.iter().find(|c| c.id == id).unwrap();
+            let values = self.sensor.get_controls(&[find(V4L2_CID_EXPOSURE), find(V4L2_CID_GAIN)]).unwrap();
+            values[0].as_i64() as f32 / 100.0 * values[1].as_i64() as f32 / 16.0
+        }
+
+        // A gradient of greys under the cast, with what the controls were
+        // set to LATENCY frames ago.
+        fn frame(&mut self) -> Vec<u8> {
+            let light = self.light();
+            while self.pending.len() < LATENCY as usize {
+                self.pending.push_back(light);
+            }
+            self.pending.push_back(light);
+            let light = self.pending.pop_front().unwrap() * self.scene;
+
+            let format = Self::raw_format();
+            let mut data = vec![0; WIDTH * HEIGHT * 2];
+            for row in 0..HEIGHT {
+                for col in 0..WIDTH {
+                    let reflectance = 0.05 + 0.3 * col as f32 / WIDTH as f32;
+                    let channel = match format.pattern.channel_at(row, col) {
+                        Channel::Red => 0,
+                        Channel::Green => 1,
+                        Channel::Blue => 2
+                    };
+                    let value = ((reflectance * CAST[channel] * light).min(1.0) * format.max_value() as f32) as u16;
+                    let i = (row * WIDTH + col) * 2;
+                    data[i..i + 2].copy_from_slice(&value.to_le_bytes());
+                }
+            }
+            data
+        }
+    }
+
+    fn auto_control(sensor: &SimulatedSensor) -> AutoControl {
+        let profile = DeviceProfile {
+            exposure_unit_us: Some(100.0),
+            base_gain: Some(16.0),
+            software_ae: true,
+            ..DeviceProfile::default()
+        };
+        let controls = ExposureControls::new(&SensorControls::new(&sensor.sensor.controls().unwrap()), &profile);
+        let mut auto = AutoControl::new(controls, &profile, WhitePoints::default(), None, 1.0 / 30.0);
+        auto.set_mode(&sensor.sensor, ExposureMode::Auto);
+        auto
+    }
+
+    // Runs the loop until it has converged and stayed so for as many frames
+    // again, returns the last frame's statistics and gains.
+    fn run(sensor: &mut SimulatedSensor, auto: &mut AutoControl) -> (FrameStats, WbGains) {
+        let mut converged_at = None;
+        for frame in 0..MAX_FRAMES {
+            let data = sensor.frame();
+            let stats = FrameStats::from_raw(&data, WIDTH * 2, &SimulatedSensor::raw_format(), (WIDTH, HEIGHT), 0);
+            let gains = auto.update(&sensor.sensor, &stats, WhiteBalance::GrayWorld);
+            match converged_at {
+                None if auto.converged() => converged_at = Some(frame),
+                Some(at) => {
+                    assert!(auto.converged(), "converged at frame {} and lost it at {}", at, frame);
+                    if frame >= at * 2 + 10 {
+                        return (stats, gains);
+                    }
+                },
+                None => ()
+            }
+        }
+        panic!("not converged after {} frames", MAX_FRAMES);
+    }
+
+    #[test]
+    fn converges_from_dark_and_bright() {
+        for &scene in [0.3, 1.0, 8.0].iter() {
+            let mut sensor = SimulatedSensor::new(scene);
+            let mut auto = auto_control(&sensor);
+            let (stats, gains) = run(&mut sensor, &mut auto);
+
+            let luma = stats.luma();
+            assert!((luma / TARGET_LUMA).log2().abs() < TOLERANCE_STOPS * 2.0, "scene {} ended at luma {}", scene, luma);
+            assert!(stats.clipped() == 0.0, "scene {}", scene);
+            assert!((gains.red - 2.0).abs() < 0.05 && (gains.blue - 1.25).abs() < 0.05, "scene {} {:?}", scene, gains);
+            assert!(auto.exposure().is_some());
+        }
+    }
+
+    #[test]
+    fn settles_at_the_limits_in_the_dark() {
+        // Too dark for the longest time and highest gain.
+        let mut sensor = SimulatedSensor::new(0.001);
+        let mut auto = auto_control(&sensor);
+        run(&mut sensor, &mut auto);
+        let values = auto.exposure().unwrap();
+        assert!((values.time - 1.0 / 30.0).abs() < 0.001, "{:?}", values);
+        assert_eq!(values.iso, 1600);
+    }
+
+    #[test]
+    fn presets_are_steady_at_once() {
+        let mut sensor = SimulatedSensor::new(1.0);
+        let mut auto = auto_control(&sensor);
+        let stats = FrameStats::from_raw(&sensor.frame(), WIDTH * 2, &SimulatedSensor::raw_format(), (WIDTH, HEIGHT), 0);
+        let gains = auto.update(&sensor.sensor, &stats, WhiteBalance::Daylight);
+        assert_eq!(gains, WhiteBalance::Daylight.gains(std::iter::empty(), stats.max, &WhitePoints::default()));
+        assert_eq!(auto.wb_steady, 1);
+    }
+}
@@ -0,0 +1,285 @@
+// Contrast detection autofocus for lenses the sensor can move but not focus
+// by itself. The lens is walked along its range while the sharpness of the
+// preview frames goes up, then back over the peak in smaller steps.
+use crate::camera::bayer::{Packing, RawFormat};
+use crate::camera::focus::{FocusStatus, Region};
+use v4l_subdev::MEDIA_BUS_FMT_SBGGR8_1X8;
+
+// First steps are this share of the lens range.
+const COARSE_STEPS: i64 = 10;
+// Searching stops once steps get below this share of the range.
+const FINE_STEPS: i64 = 100;
+// Frames for the lens to get where it was sent.
+const LENS_SETTLE_FRAMES: u32 = 2;
+// Give up after this many moves, the scene is probably moving.
+const MAX_MOVES: u32 = 40;
+// The sharpest position has to beat the blurriest by this much, less means
+// nothing to focus on.
+const MIN_CONTRAST: f32 = 1.15;
+// Blocks sampled across the region at most.
+const SHARPNESS_SAMPLES: usize = 160;
+
+// Sharpness of region of a raw frame, from the differences between
+// neighbouring green samples. Scaled by brightness so exposure changes
+// during the search don't count.
+pub fn sharpness(data: &[u8], stride: usize, raw_format: &RawFormat, source: (usize, usize), region: &Region) -> f32 {
+    let (blocks_w, blocks_h) = (source.0 / 2, source.1 / 2);
+    let (green_row, green_col) = raw_format.pattern.green_position();
+    let green = |bx: usize, by: usize| raw_format.sample(&data[(by * 2 + green_row) * stride..], bx * 2 + green_col) as f32;
+
+    let first_x = (region.x * blocks_w as f32) as usize;
+    let first_y = (region.y * blocks_h as f32) as usize;
+    let last_x = (((region.x + region.width) * blocks_w as f32) as usize).min(blocks_w).saturating_sub(1);
+    let last_y = (((region.y + region.height) * blocks_h as f32) as usize).min(blocks_h).saturating_sub(1);
+    let step = ((last_x - first_x.min(last_x)) / SHARPNESS_SAMPLES).max(1);
+
+    let mut gradient = 0.0;
+    let mut level = 0.0;
+    let mut count = 0;
+    for by in (first_y..last_y).step_by(step) {
+        for bx in (first_x..last_x).step_by(step) {
+            let g = green(bx, by);
+            let dx = green(bx + 1, by) - g;
+            let dy = green(bx, by + 1) - g;
+            gradient += dx * dx + dy * dy;
+            level += g;
+            count += 1;
+        }
+    }
+
+    if count == 0 {
+        return 0.0;
+    }
+    let mean = level / count as f32;
+    gradient / count as f32 / (mean * mean).max(1.0)
+}
+
+// One search, fed the sharpness of each frame.
+pub struct ContrastFocus {
+    range: (i64, i64),
+    fine_step: i64,
+    step: i64,
+    direction: i64,
+    // Where the lens was sent last.
+    position: i64,
+    best: Option<(i64, f32)>,
+    lowest: f32,
+    // Seen the sharpness drop after going up, or in both directions.
+    bracketed: bool,
+    moves: u32,
+    wait: u32,
+    status: FocusStatus
+}
+
+impl ContrastFocus {
+    // range is the lens control's, position where the lens is now.
+    pub fn new(range: (i64, i64), position: i64) -> Self {
+        let span = (range.1 - range.0).max(1);
+        // Towards the far end of the range first.
+        let direction = if position - range.0 > range.1 - position { -1 } else { 1 };
+
+        ContrastFocus {
+            range,
+            fine_step: (span / FINE_STEPS).max(1),
+            step: (span / COARSE_STEPS).max(1),
+            direction,
+            position,
+            best: None,
+            lowest: f32::MAX,
+            bracketed: false,
+            moves: 0,
+            wait: 0,
+            status: FocusStatus::Busy
+        }
+    }
+
+    // Busy until the search is over, then Reached or Failed.
+    pub fn status(&self) -> FocusStatus {
+        self.status
+    }
+
+    // Takes the sharpness of the latest frame, returns where to move the
+    // lens if anywhere.
+    pub fn next(&mut self, sharpness: f32) -> Option<i64> {
+        if self.status != FocusStatus::Busy {
+            return None;
+        }
+        if self.wait > 0 {
+            self.wait -= 1;
+            return None;
+        }
+
+        self.lowest = self.lowest.min(sharpness);
+        match self.best {
+            Some((_, best)) if sharpness <= best => {
+                // Past the peak or headed away from it, back over the best
+                // position the other way.
+                self.direction = -self.direction;
+                if self.bracketed {
+                    self.step /= 2;
+                }
+                self.bracketed = true;
+            },
+            _ => self.best = Some((self.position, sharpness))
+        }
+
+        let best = self.best.map_or(self.position, |(p, _)| p);
+        loop {
+            if self.step < self.fine_step || self.moves >= MAX_MOVES {
+                return Some(self.finish());
+            }
+
+            let target = (best + self.direction * self.step).max(self.range.0).min(self.range.1);
+            if target != best {
+                self.position = target;
+                self.moves += 1;
+                self.wait = LENS_SETTLE_FRAMES;
+                return Some(target);
+            }
+
+            // At the end of the range.
+            self.direction = -self.direction;
+            if self.bracketed {
+                self.step /= 2;
+            }
+            self.bracketed = true;
+        }
+    }
+
+    fn finish(&mut self) -> i64 {
+        let (position, sharpness) = self.best.unwrap_or((self.position, 0.0));
+        self.status = if sharpness > 0.0 && sharpness >= self.lowest * MIN_CONTRAST {
+            FocusStatus::Reached
+        } else {
+            FocusStatus::Failed
+        };
+        self.position = position;
+        position
+    }
+}
+
+// A lens in front of a synthetic scene, blurring it by how far the lens is
+// from sharp. For trying the search without a voice coil module at hand.
+pub struct SimulatedLens {
+    pub width: usize,
+    pub height: usize,
+    pub range: (i64, i64),
+    // Lens position the scene is sharp at.
+    pub sharp: i64,
+    pub position: i64,
+    // Blur radius in pixels per lens step away from sharp.
+    pub blur_per_step: f32
+}
+
+impl SimulatedLens {
+    pub fn raw_format() -> RawFormat {
+        RawFormat::from_mbus_code(MEDIA_BUS_FMT_SBGGR8_1X8, Packing::Unpacked).unwrap()
+    }
+
+    // A gray 8-bit Bayer frame as seen through the lens, width bytes per
+    // line.
+    pub fn frame(&self) -> Vec<u8> {
+        let radius = ((self.position - self.sharp).abs() as f32 * self.blur_per_step).round() as usize;
+        let scene = (0..self.width * self.height)
+            .map(|i| {
+                let (x, y) = ((i % self.width) as f32, (i / self.width) as f32);
+                // Checkers of a few sizes, so there's detail at every blur.
+                let checker = |size: f32| if ((x / size) as i64 + (y / size) as i64) % 2 == 0 { 1.0 } else { -1.0 };
+                32.0 + 48.0 * (checker(5.0) + checker(17.0) + 0.5 * checker(41.0) + 1.5)
+            })
+            .collect::<Vec<f32>>();
+
+        let blurred = box_blur(&box_blur(&scene, self.width, self.height, radius, true), self.width, self.height, radius, false);
+        blurred.iter().map(|v| v.round().clamp(0.0, 255.0) as u8).collect()
+    }
+}
+
+// Mean over 2 * radius + 1 pixels along rows, or columns.
+fn box_blur(data: &[f32], width: usize, height: usize, radius: usize, rows: bool) -> Vec<f32> {
+    if radius == 0 {
+        return data.to_vec();
+    }
+
+    let (length, lines) = if rows { (width, height) } else { (height, width) };
+    let at = |line: usize, i: usize| if rows { line * width + i } else { i * width + line };
+    let mut out = vec![0.0; data.len()];
+    for line in 0..lines {
+        for i in 0..length {
+            let (first, last) = (i.saturating_sub(radius), (i + radius).min(length - 1));
+            let sum = (first..=last).map(|j| data[at(line, j)]).sum::<f32>();
+            out[at(line, i)] = sum / (last - first + 1) as f32;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RANGE: (i64, i64) = (0, 1023);
+    // Enough for MAX_MOVES moves and the frames waited after each.
+    const MAX_FRAMES: u32 = MAX_MOVES * (LENS_SETTLE_FRAMES + 1) + 1;
+
+    fn lens(sharp: i64, position: i64) -> SimulatedLens {
+        SimulatedLens {
+            width: 160,
+            height: 120,
+            range: RANGE,
+            sharp,
+            position,
+            blur_per_step: 0.02
+        }
+    }
+
+    // Runs a search on frames through lens, moving it as told. Returns how
+    // it ended and after how many frames.
+    fn search(lens: &mut SimulatedLens, frame: impl Fn(&SimulatedLens) -> Vec<u8>) -> (FocusStatus, u32) {
+        let region = Region::around(0.5, 0.5, 0.5);
+        let mut focus = ContrastFocus::new(lens.range, lens.position);
+        for frames in 1..=MAX_FRAMES {
+            let sharpness = sharpness(&frame(lens), lens.width, &SimulatedLens::raw_format(), (lens.width, lens.height), &region);
+            if let Some(position) = focus.next(sharpness) {
+                assert!(position >= RANGE.0 && position <= RANGE.1, "sent the lens to {}", position);
+                lens.position = position;
+            }
+            assert!(focus.moves <= MAX_MOVES);
+            if focus.status() != FocusStatus::Busy {
+                return (focus.status(), frames);
+            }
+        }
+        panic!("still searching after {} frames", MAX_FRAMES);
+    }
+
+    #[test]
+    fn reaches_the_peak() {
+        // Sharp at the ends, in between and right where the lens starts.
+        for &(sharp, start) in [(0, 512), (150, 0), (512, 512), (700, 1023), (900, 100), (1023, 300)].iter() {
+            let mut lens = lens(sharp, start);
+            let (status, frames) = search(&mut lens, SimulatedLens::frame);
+            assert_eq!(status, FocusStatus::Reached, "sharp at {}, from {}", sharp, start);
+            // Frames only blur once the radius rounds to a pixel, 25 steps
+            // either side of sharp, and the search stops within a fine step.
+            assert!((lens.position - sharp).abs() <= 35, "sharp at {}, from {}, ended at {} after {} frames", sharp, start, lens.position, frames);
+        }
+    }
+
+    #[test]
+    fn fails_on_a_flat_scene() {
+        let mut lens = lens(512, 200);
+        let (status, _) = search(&mut lens, |lens| vec![128; lens.width * lens.height]);
+        assert_eq!(status, FocusStatus::Failed);
+    }
+
+    #[test]
+    fn sharper_near_the_peak() {
+        let region = Region::around(0.5, 0.5, 0.5);
+        let sharpness_at = |position| {
+            let lens = lens(400, position);
+            sharpness(&lens.frame(), lens.width, &SimulatedLens::raw_format(), (lens.width, lens.height), &region)
+        };
+        let samples = [0, 200, 350, 400, 450, 600, 1023].iter().map(|p| sharpness_at(*p)).collect::<Vec<f32>>();
+        assert!(samples[..4].windows(2).all(|w| w[0] <= w[1]), "{:?}", samples);
+        assert!(samples[3..].windows(2).all(|w| w[0] >= w[1]), "{:?}", samples);
+    }
+}
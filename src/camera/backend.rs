@@ -0,0 +1,76 @@
+// What the camera needs from whatever its frames come from. MediaBackend
+// drives real sensors through the media controller, SyntheticBackend makes
+// frames up for machines without camera hardware.
+use std::io;
+use crate::camera::{Error, RawFormat};
+use crate::camera::controls::{ControlInfo, ControlValue};
+
+pub use crate::camera::subdevice::{FrameFormat, FrameInterval, FrameSize, SensorMode};
+
+// Mode setting and controls of one sensor.
+pub trait Sensor: Send + Sync {
+    // The model followed by where it sits, like "ov5640 1-004c". Device
+    // profiles are matched against this.
+    fn name(&self) -> &str;
+
+    fn model(&self) -> &str {
+        self.name().split_whitespace().next().unwrap_or("")
+    }
+
+    // Every code, size and interval combination the sensor offers.
+    fn modes(&self) -> Result<Vec<SensorMode>, Error>;
+
+    // At one size, empty if the driver doesn't list them.
+    fn frame_intervals(&self, code: u32, width: u32, height: u32) -> Result<Vec<FrameInterval>, Error>;
+
+    // Returns the format the sensor went with, which may be a different one.
+    fn set_format(&self, width: u32, height: u32, code: u32) -> Result<FrameFormat, Error>;
+
+    // Flips may change the bayer order, so read this back after setting
+    // controls.
+    fn format(&self) -> Result<FrameFormat, Error>;
+
+    fn set_interval(&self, numerator: u32, denominator: u32) -> Result<(), Error>;
+
+    fn set_control(&self, id: u32, value: i32) -> Result<(), Error>;
+
+    fn controls(&self) -> io::Result<Vec<ControlInfo>>;
+
+    fn get_controls(&self, controls: &[&ControlInfo]) -> io::Result<Vec<ControlValue>>;
+
+    // All or nothing, a value out of range fails the lot.
+    fn set_controls(&self, values: &[(&ControlInfo, ControlValue)]) -> io::Result<()>;
+}
+
+// A raw frame, only there for as long as the stream callback runs.
+pub struct Frame<'a> {
+    pub data: &'a [u8],
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    // What the frame really came as, the requested one may not have been
+    // possible.
+    pub format: RawFormat
+}
+
+// Cameras by index, back facing ones first.
+pub trait CameraBackend: Send + Sync {
+    fn count(&self) -> usize;
+
+    // For the logs, like "ov5640 1-004c -> sun6i-csi".
+    fn name(&self, index: usize) -> String;
+
+    fn sensor(&self, index: usize) -> &dyn Sensor;
+
+    // Makes camera index the one that streams, taking down others it shares
+    // a path with.
+    fn activate(&self, index: usize) -> Result<(), Error>;
+
+    // Takes everything down and opens camera index again, for the next
+    // stream to start from scratch.
+    fn reset(&self, index: usize) -> Result<(), Error>;
+
+    // Streams frames of the sensor's current mode to on_frame, for as long
+    // as it returns true. raw_format is how they're asked to be laid out.
+    fn stream(&self, index: usize, raw_format: RawFormat, on_frame: &mut dyn FnMut(Frame) -> Result<bool, Error>) -> Result<(), Error>;
+}
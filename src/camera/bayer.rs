@@ -0,0 +1,337 @@
+use v4l_subdev::{
+    MEDIA_BUS_FMT_SBGGR8_1X8,
+    MEDIA_BUS_FMT_SGBRG8_1X8,
+    MEDIA_BUS_FMT_SGRBG8_1X8,
+    MEDIA_BUS_FMT_SRGGB8_1X8,
+    MEDIA_BUS_FMT_SBGGR10_1X10,
+    MEDIA_BUS_FMT_SGBRG10_1X10,
+    MEDIA_BUS_FMT_SGRBG10_1X10,
+    MEDIA_BUS_FMT_SRGGB10_1X10,
+    MEDIA_BUS_FMT_SBGGR12_1X12,
+    MEDIA_BUS_FMT_SGBRG12_1X12,
+    MEDIA_BUS_FMT_SGRBG12_1X12,
+    MEDIA_BUS_FMT_SRGGB12_1X12
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Red,
+    Green,
+    Blue
+}
+
+// Colour filter array layout, named after the top left 2x2 block read row by
+// row. BGGR is
+// B G
+// G R
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CfaPattern {
+    Bggr,
+    Gbrg,
+    Grbg,
+    Rggb
+}
+
+impl CfaPattern {
+    // (row, col) of the red sample inside the 2x2 block.
+    pub fn red_position(self) -> (usize, usize) {
+        match self {
+            CfaPattern::Bggr => (1, 1),
+            CfaPattern::Gbrg => (1, 0),
+            CfaPattern::Grbg => (0, 1),
+            CfaPattern::Rggb => (0, 0)
+        }
+    }
+
+    // (row, col) of the blue sample inside the 2x2 block.
+    pub fn blue_position(self) -> (usize, usize) {
+        let (row, col) = self.red_position();
+        (1 - row, 1 - col)
+    }
+
+    // (row, col) of the green sample on the same row as red.
+    pub fn green_position(self) -> (usize, usize) {
+        let (row, col) = self.red_position();
+        (row, 1 - col)
+    }
+
+    pub fn channel_at(self, row: usize, col: usize) -> Channel {
+        let pos = (row % 2, col % 2);
+        if pos == self.red_position() {
+            Channel::Red
+        } else if pos == self.blue_position() {
+            Channel::Blue
+        } else {
+            Channel::Green
+        }
+    }
+}
+
+// How samples wider than 8 bits are laid out in the buffer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Packing {
+    // One byte per 8-bit sample, two little endian bytes for anything wider.
+    Unpacked,
+    // MIPI CSI-2 packing: four 10-bit samples in five bytes, or two 12-bit
+    // samples in three bytes. High bits first, the low bits of the group in
+    // the last byte.
+    Mipi
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RawFormat {
+    pub pattern: CfaPattern,
+    pub bits: u8,
+    pub packing: Packing
+}
+
+// Indexed by pattern, in the same order as CfaPattern.
+const MBUS_CODES: [(u8, [u32; 4]); 3] = [
+    (8, [MEDIA_BUS_FMT_SBGGR8_1X8, MEDIA_BUS_FMT_SGBRG8_1X8, MEDIA_BUS_FMT_SGRBG8_1X8, MEDIA_BUS_FMT_SRGGB8_1X8]),
+    (10, [MEDIA_BUS_FMT_SBGGR10_1X10, MEDIA_BUS_FMT_SGBRG10_1X10, MEDIA_BUS_FMT_SGRBG10_1X10, MEDIA_BUS_FMT_SRGGB10_1X10]),
+    (12, [MEDIA_BUS_FMT_SBGGR12_1X12, MEDIA_BUS_FMT_SGBRG12_1X12, MEDIA_BUS_FMT_SGRBG12_1X12, MEDIA_BUS_FMT_SRGGB12_1X12])
+];
+
+const FOURCCS: [(u8, Packing, [&[u8; 4]; 4]); 5] = [
+    (8, Packing::Unpacked, [b"BA81", b"GBRG", b"GRBG", b"RGGB"]),
+    (10, Packing::Unpacked, [b"BG10", b"GB10", b"BA10", b"RG10"]),
+    (10, Packing::Mipi, [b"pBAA", b"pGAA", b"pgAA", b"pRAA"]),
+    (12, Packing::Unpacked, [b"BG12", b"GB12", b"BA12", b"RG12"]),
+    (12, Packing::Mipi, [b"pBCC", b"pGCC", b"pgCC", b"pRCC"])
+];
+
+const PATTERNS: [CfaPattern; 4] = [CfaPattern::Bggr, CfaPattern::Gbrg, CfaPattern::Grbg, CfaPattern::Rggb];
+
+impl RawFormat {
+    pub fn new(pattern: CfaPattern, bits: u8, packing: Packing) -> Self {
+        RawFormat {
+            pattern,
+            bits,
+            packing
+        }
+    }
+
+    // The bus code carries no packing, that is up to the video node. 8-bit
+    // formats are always unpacked.
+    pub fn from_mbus_code(code: u32, packing: Packing) -> Option<Self> {
+        MBUS_CODES.iter()
+            .flat_map(|(bits, codes)| codes.iter().zip(PATTERNS.iter()).map(move |(c, p)| (*bits, *c, *p)))
+            .find(|(_, c, _)| *c == code)
+            .map(|(bits, _, pattern)| {
+                let packing = if bits == 8 { Packing::Unpacked } else { packing };
+                RawFormat::new(pattern, bits, packing)
+            })
+    }
+
+    pub fn mbus_code(&self) -> u32 {
+        let (_, codes) = MBUS_CODES.iter()
+            .find(|(bits, _)| *bits == self.bits)
+            .expect("Unsupported bit depth.");
+        codes[self.pattern_index()]
+    }
+
+    pub fn from_fourcc(fourcc: &[u8; 4]) -> Option<Self> {
+        FOURCCS.iter()
+            .flat_map(|(bits, packing, codes)| codes.iter().zip(PATTERNS.iter()).map(move |(c, p)| (*bits, *packing, *c, *p)))
+            .find(|(_, _, c, _)| *c == fourcc)
+            .map(|(bits, packing, _, pattern)| RawFormat::new(pattern, bits, packing))
+    }
+
+    pub fn fourcc(&self) -> &'static [u8; 4] {
+        let (_, _, codes) = FOURCCS.iter()
+            .find(|(bits, packing, _)| *bits == self.bits && *packing == self.packing)
+            .expect("Unsupported bit depth and packing combination.");
+        codes[self.pattern_index()]
+    }
+
+    fn pattern_index(&self) -> usize {
+        PATTERNS.iter().position(|p| *p == self.pattern).unwrap()
+    }
+
+    pub fn max_value(&self) -> u16 {
+        ((1u32 << self.bits) - 1) as u16
+    }
+
+    // A partly filled group at the end of a line still takes all its bytes.
+    pub fn bytes_per_line(&self, width: usize) -> usize {
+        match (self.bits, self.packing) {
+            (8, _) => width,
+            (10, Packing::Mipi) => (width * 5).div_ceil(4),
+            (12, Packing::Mipi) => (width * 3).div_ceil(2),
+            _ => width * 2
+        }
+    }
+
+    // Reads one sample from a line of raw data.
+    #[inline]
+    pub fn sample(&self, line: &[u8], col: usize) -> u16 {
+        match (self.bits, self.packing) {
+            (8, _) => line[col] as u16,
+            (10, Packing::Mipi) => {
+                let base = col / 4 * 5;
+                let shift = (col % 4) * 2;
+                ((line[base + col % 4] as u16) << 2) | ((line[base + 4] >> shift) as u16 & 0x3)
+            },
+            (12, Packing::Mipi) => {
+                let base = col / 2 * 3;
+                let shift = (col % 2) * 4;
+                ((line[base + col % 2] as u16) << 4) | ((line[base + 2] >> shift) as u16 & 0xf)
+            },
+            _ => u16::from_le_bytes([line[col * 2], line[col * 2 + 1]])
+        }
+    }
+}
+
+// A raw buffer as it came out of the video device.
+#[derive(Clone, Debug)]
+pub struct RawImage {
+    pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    pub format: RawFormat
+}
+
+impl RawImage {
+    pub fn new(data: Vec<u8>, width: usize, height: usize, stride: usize, format: RawFormat) -> Self {
+        RawImage {
+            data,
+            width,
+            height,
+            stride,
+            format
+        }
+    }
+
+    pub fn unpack(&self) -> Vec<u16> {
+        unpack(&self.data, self.width, self.height, self.stride, &self.format)
+    }
+}
+
+// Unpacks raw lines into one u16 per sample, keeping the sensor's bit depth.
+pub fn unpack(data: &[u8], width: usize, height: usize, stride: usize, format: &RawFormat) -> Vec<u16> {
+    let mut out = Vec::with_capacity(width * height);
+
+    for row in 0..height {
+        let line = &data[row * stride..];
+        if format.bits == 8 {
+            out.extend(line[..width].iter().map(|v| *v as u16));
+        } else {
+            out.extend((0..width).map(|col| format.sample(line, col)));
+        }
+    }
+
+    out
+}
+
+// Unpacked samples of a full frame, with the layout needed to make sense of
+// them.
+#[derive(Clone, Copy, Debug)]
+pub struct BayerFrame<'a> {
+    pub data: &'a [u16],
+    pub width: usize,
+    pub height: usize,
+    pub pattern: CfaPattern,
+    pub bits: u8
+}
+
+impl<'a> BayerFrame<'a> {
+    pub fn new(data: &'a [u16], width: usize, height: usize, pattern: CfaPattern, bits: u8) -> Self {
+        BayerFrame {
+            data,
+            width,
+            height,
+            pattern,
+            bits
+        }
+    }
+
+    pub fn channel_at(&self, row: usize, col: usize) -> Channel {
+        self.pattern.channel_at(row, col)
+    }
+
+    pub fn max_value(&self) -> u16 {
+        ((1u32 << self.bits) - 1) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every sample different, with bits set in both the high and low parts.
+    fn samples(bits: u8, count: usize) -> Vec<u16> {
+        let max = (1u32 << bits) - 1;
+        (0..count as u32).map(|i| ((i * 0x9e37 + 0x155) % (max + 1)) as u16).collect()
+    }
+
+    // One line, packed by hand as the CSI-2 spec lays it out.
+    fn pack_line(line: &[u16], format: &RawFormat) -> Vec<u8> {
+        match (format.bits, format.packing) {
+            (8, _) => line.iter().map(|v| *v as u8).collect(),
+            (10, Packing::Mipi) => line.chunks(4).flat_map(|group| {
+                let mut bytes = group.iter().map(|v| (v >> 2) as u8).collect::<Vec<u8>>();
+                bytes.push(group.iter().enumerate().fold(0, |low, (i, v)| low | ((v & 0x3) as u8) << (i * 2)));
+                bytes
+            }).collect(),
+            (12, Packing::Mipi) => line.chunks(2).flat_map(|group| {
+                let mut bytes = group.iter().map(|v| (v >> 4) as u8).collect::<Vec<u8>>();
+                bytes.push(group.iter().enumerate().fold(0, |low, (i, v)| low | ((v & 0xf) as u8) << (i * 4)));
+                bytes
+            }).collect(),
+            _ => line.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+        }
+    }
+
+    // Lines padded to stride, like video nodes align them.
+    fn pack(samples: &[u16], width: usize, stride: usize, format: &RawFormat) -> Vec<u8> {
+        samples.chunks(width).flat_map(|line| {
+            let mut bytes = pack_line(line, format);
+            assert_eq!(bytes.len(), format.bytes_per_line(width));
+            bytes.resize(stride, 0xff);
+            bytes
+        }).collect()
+    }
+
+    #[test]
+    fn unpacks_every_layout() {
+        let (width, height) = (8, 3);
+        let layouts = [(8, Packing::Unpacked), (10, Packing::Unpacked), (10, Packing::Mipi), (12, Packing::Unpacked), (12, Packing::Mipi)];
+        for &(bits, packing) in layouts.iter() {
+            let format = RawFormat::new(CfaPattern::Rggb, bits, packing);
+            let stride = format.bytes_per_line(width) + 7;
+            let samples = samples(bits, width * height);
+            let data = pack(&samples, width, stride, &format);
+
+            assert_eq!(unpack(&data, width, height, stride, &format), samples, "{}-bit {:?}", bits, packing);
+            assert!(samples.iter().all(|s| *s <= format.max_value()));
+        }
+    }
+
+    #[test]
+    fn line_lengths() {
+        let mipi10 = RawFormat::new(CfaPattern::Bggr, 10, Packing::Mipi);
+        let mipi12 = RawFormat::new(CfaPattern::Bggr, 12, Packing::Mipi);
+        assert_eq!(mipi10.bytes_per_line(2592), 3240);
+        assert_eq!(mipi12.bytes_per_line(2592), 3888);
+        // Partial groups at the end.
+        assert_eq!(mipi10.bytes_per_line(1286), 1608);
+        assert_eq!(mipi12.bytes_per_line(1285), 1928);
+        assert_eq!(RawFormat::new(CfaPattern::Bggr, 10, Packing::Unpacked).bytes_per_line(1285), 2570);
+        assert_eq!(RawFormat::new(CfaPattern::Bggr, 8, Packing::Mipi).bytes_per_line(1285), 1285);
+    }
+
+    #[test]
+    fn codes_round_trip() {
+        for &pattern in PATTERNS.iter() {
+            for &(bits, packing) in [(8, Packing::Unpacked), (10, Packing::Mipi), (12, Packing::Unpacked)].iter() {
+                let format = RawFormat::new(pattern, bits, packing);
+                assert_eq!(RawFormat::from_fourcc(format.fourcc()), Some(format));
+                assert_eq!(RawFormat::from_mbus_code(format.mbus_code(), packing), Some(format));
+            }
+        }
+        assert_eq!(RawFormat::from_fourcc(b"pRAA"), Some(RawFormat::new(CfaPattern::Rggb, 10, Packing::Mipi)));
+        // 8-bit is never packed.
+        assert_eq!(RawFormat::from_mbus_code(MEDIA_BUS_FMT_SGRBG8_1X8, Packing::Mipi), Some(RawFormat::new(CfaPattern::Grbg, 8, Packing::Unpacked)));
+    }
+}
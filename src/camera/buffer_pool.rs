@@ -0,0 +1,72 @@
+// Preview frame buffers that find their way back to the camera thread once the
+// UI is done with them, instead of a new allocation for every frame.
+use std::{mem, ops::{Deref, DerefMut}, sync::{Arc, Mutex}};
+
+// One being filled, one on the way to the UI and one on screen is the steady
+// state. Anything past a few spare is dropped instead of kept around.
+const MAX_FREE: usize = 4;
+
+#[derive(Clone, Default)]
+pub struct BufferPool {
+    free: Arc<Mutex<Vec<Vec<u8>>>>
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        BufferPool::default()
+    }
+
+    // A buffer of len bytes, holding whatever the previous user left in it.
+    // Spare buffers of any other size are from before a resize and are
+    // thrown away.
+    pub fn get(&self, len: usize) -> PooledBuffer {
+        let reused = {
+            let mut free = self.free.lock().unwrap();
+            free.retain(|b| b.len() == len);
+            free.pop()
+        };
+
+        PooledBuffer {
+            data: reused.unwrap_or_else(|| vec![0; len]),
+            pool: self.free.clone()
+        }
+    }
+}
+
+// Goes back to its pool when dropped. glib::Bytes::from_owned takes these, so
+// that happens when GTK lets go of the pixbuf.
+pub struct PooledBuffer {
+    data: Vec<u8>,
+    pool: Arc<Mutex<Vec<Vec<u8>>>>
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let data = mem::take(&mut self.data);
+        if let Ok(mut free) = self.pool.lock() {
+            if free.len() < MAX_FREE {
+                free.push(data);
+            }
+        }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
@@ -0,0 +1,291 @@
+// V4L2 controls of a subdevice: what there is, with ranges and menus, and
+// typed reads and writes of their values.
+use std::{io, mem, os::raw::c_int};
+use v4l::v4l2;
+use v4l_subdev::*;
+use crate::camera::media_ioctl as ioctl;
+use crate::camera::topology::c_char_array_to_string;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlKind {
+    Integer,
+    Boolean,
+    Menu,
+    IntegerMenu,
+    Button,
+    Integer64,
+    // Strings, arrays and compound controls, listed but not handled.
+    Other(u32)
+}
+
+impl From<u32> for ControlKind {
+    #[allow(non_upper_case_globals)]
+    fn from(kind: u32) -> ControlKind {
+        match kind {
+            v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER => ControlKind::Integer,
+            v4l2_ctrl_type_V4L2_CTRL_TYPE_BOOLEAN => ControlKind::Boolean,
+            v4l2_ctrl_type_V4L2_CTRL_TYPE_MENU => ControlKind::Menu,
+            v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER_MENU => ControlKind::IntegerMenu,
+            v4l2_ctrl_type_V4L2_CTRL_TYPE_BUTTON => ControlKind::Button,
+            v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER64 => ControlKind::Integer64,
+            other => ControlKind::Other(other)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlValue {
+    Integer(i32),
+    Boolean(bool),
+    // Menu item index.
+    Menu(u32),
+    Integer64(i64),
+    // Buttons have no value, setting one presses it.
+    Button
+}
+
+impl ControlValue {
+    pub fn as_i64(&self) -> i64 {
+        match *self {
+            ControlValue::Integer(v) => v as i64,
+            ControlValue::Boolean(v) => v as i64,
+            ControlValue::Menu(v) => v as i64,
+            ControlValue::Integer64(v) => v,
+            ControlValue::Button => 0
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MenuLabel {
+    Name(String),
+    Value(i64)
+}
+
+#[derive(Clone, Debug)]
+pub struct MenuItem {
+    pub index: u32,
+    pub label: MenuLabel
+}
+
+#[derive(Clone, Debug)]
+pub struct ControlInfo {
+    pub id: u32,
+    pub name: String,
+    pub kind: ControlKind,
+    pub minimum: i64,
+    pub maximum: i64,
+    pub step: u64,
+    pub default: i64,
+    pub flags: u32,
+    // Only the items the driver has, menus may skip indices.
+    pub menu: Vec<MenuItem>
+}
+
+impl ControlInfo {
+    pub fn read_only(&self) -> bool {
+        self.flags & (V4L2_CTRL_FLAG_READ_ONLY | V4L2_CTRL_FLAG_DISABLED) != 0
+    }
+
+    // Set by the driver while an automatic mode owns the control.
+    pub fn inactive(&self) -> bool {
+        self.flags & V4L2_CTRL_FLAG_INACTIVE != 0
+    }
+
+    // The value as the type of this control, with integers clamped to the
+    // range and snapped to the step.
+    pub fn value(&self, value: i64) -> ControlValue {
+        match self.kind {
+            ControlKind::Boolean => ControlValue::Boolean(value != 0),
+            ControlKind::Menu | ControlKind::IntegerMenu => ControlValue::Menu(value.max(self.minimum).min(self.maximum) as u32),
+            ControlKind::Button => ControlValue::Button,
+            ControlKind::Integer64 => ControlValue::Integer64(self.clamp(value)),
+            _ => ControlValue::Integer(self.clamp(value) as i32)
+        }
+    }
+
+    pub fn default_value(&self) -> ControlValue {
+        self.value(self.default)
+    }
+
+    fn clamp(&self, value: i64) -> i64 {
+        let value = value.max(self.minimum).min(self.maximum);
+        let step = self.step.max(1) as i64;
+        self.minimum + (value - self.minimum) / step * step
+    }
+}
+
+// Every control the device has, control class headers left out.
+pub fn query_all(fd: c_int) -> io::Result<Vec<ControlInfo>> {
+    let mut controls = Vec::new();
+    let mut id = V4L2_CTRL_FLAG_NEXT_CTRL;
+
+    loop {
+        let query = unsafe {
+            let mut query: v4l2_query_ext_ctrl = mem::zeroed();
+            query.id = id;
+
+            match v4l2::ioctl(fd, ioctl::VIDIOC_QUERY_EXT_CTRL, &mut query as *mut _ as *mut std::os::raw::c_void) {
+                Ok(_) => query,
+                // Past the last one.
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => break,
+                Err(e) => return Err(e)
+            }
+        };
+        id = query.id | V4L2_CTRL_FLAG_NEXT_CTRL;
+
+        let kind = ControlKind::from(query.type_);
+        if kind == ControlKind::Other(v4l2_ctrl_type_V4L2_CTRL_TYPE_CTRL_CLASS) {
+            continue;
+        }
+
+        let menu = match kind {
+            ControlKind::Menu | ControlKind::IntegerMenu => query_menu(fd, &query, kind),
+            _ => Vec::new()
+        };
+
+        controls.push(ControlInfo {
+            id: query.id,
+            name: c_char_array_to_string(&query.name),
+            kind,
+            minimum: query.minimum,
+            maximum: query.maximum,
+            step: query.step,
+            default: query.default_value,
+            flags: query.flags,
+            menu
+        });
+    }
+
+    Ok(controls)
+}
+
+fn query_menu(fd: c_int, query: &v4l2_query_ext_ctrl, kind: ControlKind) -> Vec<MenuItem> {
+    (query.minimum..=query.maximum)
+        .filter_map(|index| unsafe {
+            let mut item: v4l2_querymenu = mem::zeroed();
+            item.id = query.id;
+            item.index = index as u32;
+
+            v4l2::ioctl(fd, ioctl::VIDIOC_QUERYMENU, &mut item as *mut _ as *mut std::os::raw::c_void).ok()?;
+
+            let label = if kind == ControlKind::IntegerMenu {
+                MenuLabel::Value(item.__bindgen_anon_1.value)
+            } else {
+                let name = item.__bindgen_anon_1.name;
+                let end = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+                MenuLabel::Name(String::from_utf8_lossy(&name[..end]).to_string())
+            };
+
+            Some(MenuItem {
+                index: index as u32,
+                label
+            })
+        })
+        .collect()
+}
+
+// Current values, in the order of controls.
+pub fn get(fd: c_int, controls: &[&ControlInfo]) -> io::Result<Vec<ControlValue>> {
+    let mut values = controls.iter()
+        .map(|c| ext_control(c.id))
+        .collect::<Vec<v4l2_ext_control>>();
+
+    ext_controls(fd, ioctl::VIDIOC_G_EXT_CTRLS, &mut values)?;
+
+    Ok(controls.iter().zip(values.iter())
+        .map(|(control, value)| unsafe {
+            match control.kind {
+                ControlKind::Integer64 => ControlValue::Integer64(value.__bindgen_anon_1.value64),
+                _ => control.value(value.__bindgen_anon_1.value as i64)
+            }
+        })
+        .collect())
+}
+
+// Sets all of them in one go. The driver applies either all or none.
+pub fn set(fd: c_int, values: &[(&ControlInfo, ControlValue)]) -> io::Result<()> {
+    let mut controls = values.iter()
+        .map(|(control, value)| {
+            let mut ext = ext_control(control.id);
+            match value {
+                ControlValue::Integer64(v) => ext.__bindgen_anon_1.value64 = *v,
+                v => ext.__bindgen_anon_1.value = v.as_i64() as i32
+            }
+            ext
+        })
+        .collect::<Vec<v4l2_ext_control>>();
+
+    ext_controls(fd, ioctl::VIDIOC_S_EXT_CTRLS, &mut controls)
+}
+
+fn ext_control(id: u32) -> v4l2_ext_control {
+    unsafe {
+        let mut control: v4l2_ext_control = mem::zeroed();
+        control.id = id;
+        control
+    }
+}
+
+fn ext_controls(fd: c_int, request: ioctl::_IOC_TYPE, controls: &mut [v4l2_ext_control]) -> io::Result<()> {
+    if controls.is_empty() {
+        return Ok(());
+    }
+
+    unsafe {
+        let mut ext: v4l2_ext_controls = mem::zeroed();
+        // Controls of any class.
+        ext.__bindgen_anon_1.which = V4L2_CTRL_WHICH_CUR_VAL;
+        ext.count = controls.len() as u32;
+        ext.controls = controls.as_mut_ptr();
+
+        v4l2::ioctl(fd, request, &mut ext as *mut _ as *mut std::os::raw::c_void)
+    }
+}
+
+// The controls the camera layer cares about, when the sensor has them.
+#[derive(Clone, Debug, Default)]
+pub struct SensorControls {
+    pub exposure: Option<ControlInfo>,
+    // In 100µs units, where the driver has it instead of or next to
+    // exposure.
+    pub exposure_absolute: Option<ControlInfo>,
+    pub auto_exposure: Option<ControlInfo>,
+    // Integer menu of EV adjustments in 1/1000 stops.
+    pub exposure_bias: Option<ControlInfo>,
+    pub gain: Option<ControlInfo>,
+    pub auto_gain: Option<ControlInfo>,
+    pub auto_white_balance: Option<ControlInfo>,
+    pub red_balance: Option<ControlInfo>,
+    pub blue_balance: Option<ControlInfo>,
+    pub test_pattern: Option<ControlInfo>,
+    pub focus_auto: Option<ControlInfo>,
+    pub focus_absolute: Option<ControlInfo>,
+    pub auto_focus_start: Option<ControlInfo>,
+    pub auto_focus_stop: Option<ControlInfo>,
+    pub auto_focus_status: Option<ControlInfo>
+}
+
+impl SensorControls {
+    pub fn new(controls: &[ControlInfo]) -> Self {
+        let find = |id: u32| controls.iter().find(|c| c.id == id).cloned();
+
+        SensorControls {
+            exposure: find(V4L2_CID_EXPOSURE),
+            exposure_absolute: find(V4L2_CID_EXPOSURE_ABSOLUTE),
+            auto_exposure: find(V4L2_CID_EXPOSURE_AUTO),
+            exposure_bias: find(V4L2_CID_AUTO_EXPOSURE_BIAS),
+            gain: find(V4L2_CID_GAIN),
+            auto_gain: find(V4L2_CID_AUTOGAIN),
+            auto_white_balance: find(V4L2_CID_AUTO_WHITE_BALANCE),
+            red_balance: find(V4L2_CID_RED_BALANCE),
+            blue_balance: find(V4L2_CID_BLUE_BALANCE),
+            test_pattern: find(V4L2_CID_TEST_PATTERN),
+            focus_auto: find(V4L2_CID_FOCUS_AUTO),
+            focus_absolute: find(V4L2_CID_FOCUS_ABSOLUTE),
+            auto_focus_start: find(V4L2_CID_AUTO_FOCUS_START),
+            auto_focus_stop: find(V4L2_CID_AUTO_FOCUS_STOP),
+            auto_focus_status: find(V4L2_CID_AUTO_FOCUS_STATUS)
+        }
+    }
+}
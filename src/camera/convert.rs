@@ -2,7 +2,22 @@ use chrono::prelude::*;
 use image;
 use lazy_static::lazy_static;
 use rexiv2::{Metadata, Orientation};
-use std::{env, path::{Path, PathBuf}};
+use std::{env, fs::File, io::BufWriter, path::{Path, PathBuf}};
+use crate::camera::bayer::{BayerFrame, RawImage};
+use crate::camera::exposure::ExposureValues;
+
+pub mod color;
+mod demosaic;
+mod dng;
+mod pipeline;
+pub mod shading;
+pub mod white_balance;
+use color::{ColorProfile, ColorTransform};
+pub use demosaic::{Demosaic, DemosaicMethod};
+use dng::DngInfo;
+pub use pipeline::Pipeline;
+pub use shading::ShadingMap;
+pub use white_balance::{WbGains, WhiteBalance, WhitePoints};
 
 lazy_static! {
     static ref PICTURES_DIR: PathBuf = match dirs::picture_dir() {
@@ -10,202 +25,163 @@ lazy_static! {
         None => {
             let home = env::var("HOME").expect("Can't get $HOME. This seems bad.");
             let home = Path::new(&home);
-            println!("Couldn't find configured pictures dir (XDG). Defaulting to $HOME/Pictures");
+            eprintln!("Couldn't find configured pictures dir (XDG). Defaulting to $HOME/Pictures");
             home.join("Pictures")
         }
     };
 }
 
-pub fn save(data: Vec<u8>, width: usize, height: usize, orientation: String) {
-    let (r, g, b) = separate_colors(&data, width, height);
-    let r = smudge_red(&r, width, height);
-    let g = smudge_green(&g, width, height);
-    let b = smudge_blue(&b, width, height);
-    let data = combine_rgb(&r, &g, &b);
-
-    let now = Local::now();
-    let time_part = now.format("%Y-%m-%d-%H-%M-%S");
-    let mut pic_path = PICTURES_DIR.clone();
-    pic_path.push(format!("camcam-{}.jpg", time_part));
-    if let Err(e) = image::save_buffer(&pic_path, &data, width as u32, height as u32, image::ColorType::Rgb8) {
-        println!("Error saving image: {}", e);
-    }
-
-    match Metadata::new_from_path(&pic_path) {
-        Ok(m) => {
-            let orientation = match orientation.as_str() {
-                "normal" => Orientation::Rotate90, // portrait, 8
-                "bottom-up" => Orientation::Rotate270, // upside down portrait, 6
-                "left-up" =>  Orientation::Rotate180, // Upside down landscape, 3
-                "right-up" => Orientation::Normal, // landscape, 1
-                _ => Orientation::Unspecified,
-            };
-            m.set_orientation(orientation);
-            if let Err(_) = m.save_to_file(&pic_path) {
-                println!("Saving exif to {} failed, image was saved though.", &pic_path.to_string_lossy());
-            }
-        },
-        Err(e) => println!("Failed reading exif data from {} which was just saved: {}", &pic_path.to_string_lossy(), e)
-    }
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Jpeg,
+    Dng,
+    Both
 }
 
-fn combine_rgb(r: &[u8], g: &[u8], b: &[u8]) -> Vec<u8> {
-    let mut out = vec![0; r.len() * 3];
-    assert_eq!(r.len(), g.len());
-    assert_eq!(g.len(), b.len());
-
-    for i in 0..r.len() {
-        let offset = i * 3;
-        out[offset] = r[i];
-        out[offset + 1] = g[i];
-        out[offset + 2] = b[i];
-    }
-
-    out
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SaveOptions {
+    pub demosaic: DemosaicMethod,
+    pub output: OutputFormat,
+    pub white_balance: WhiteBalance
 }
 
-fn smudge_red(data: &[u8], width: usize, height: usize) -> Vec<u8> {
-    let mut out = vec![0; width * height];
-
-    for row in (1..height).step_by(2) {
-        for col in 0..width {
-            let pos = row * width + col;
-            if col % 2 == 1 {
-                out[pos] = data[pos];
-            } else {
-                let mut count = 1;
-                let mut value = data[pos + 1] as usize;
-
-                if col > 0 {
-                    count += 1;
-                    value += data[pos - 1] as usize;
-                }
+// What is known about the shot at the moment it was taken. Saving happens
+// seconds later, so nothing here should be looked up in save.
+#[derive(Clone, Debug)]
+pub struct CaptureInfo {
+    pub orientation: String,
+    pub sensor: String,
+    pub time: DateTime<Local>,
+    pub exposure: Option<ExposureValues>
+}
 
-                out[pos] = (value / count) as u8;
-            }
+// Writes the photo to the pictures directory and returns the files written.
+// Ones that failed are left out, after printing why.
+pub fn save(raw: RawImage, info: CaptureInfo, options: SaveOptions) -> Vec<PathBuf> {
+    let (width, height) = (raw.width, raw.height);
+    let (pattern, bits) = (raw.format.pattern, raw.format.bits);
+    let profile = color::profile(&info.sensor);
+    let black_level = profile.black_level(bits);
+    let shading_map = ShadingMap::load(&info.sensor);
+    let pipeline = Pipeline::new(&raw, black_level, shading_map.as_ref());
+    let gains = pipeline.white_balance_gains(options.white_balance, &profile.white_points);
+
+    let time_part = info.time.format("%Y-%m-%d-%H-%M-%S");
+    let pic_path = unique_path(&PICTURES_DIR, &format!("camcam-{}", time_part));
+    let orientation = exif_orientation(&info.orientation);
+
+    let mut saved = Vec::new();
+
+    // DNG gets the samples as the sensor sent them, the black level and
+    // gains are only recorded. Raw developers correct lens shading their own
+    // way.
+    if options.output != OutputFormat::Jpeg {
+        let samples = raw.unpack();
+        let frame = BayerFrame::new(&samples, width, height, pattern, bits);
+        let dng_path = pic_path.with_extension("dng");
+        if save_dng(&frame, &dng_path, &info, orientation, black_level, gains, &profile) {
+            saved.push(dng_path);
         }
     }
 
-    for row in (0..height).step_by(2) {
-        for col in 0..width {
-            let pos = row * width + col;
-            let mut count = 1;
-            let mut value = out[pos + width] as usize;
-            if row > 0 {
-                count += 1;
-                value += out[pos - width] as usize;
-            }
-            out[pos] = (value / count) as u8;
+    if options.output != OutputFormat::Dng {
+        let jpeg_path = pic_path.with_extension("jpg");
+        if save_jpeg(&pipeline, &jpeg_path, orientation, info.exposure, options, gains, &profile) {
+            saved.push(jpeg_path);
         }
     }
 
-    out
+    saved
 }
 
-fn smudge_blue(data: &[u8], width: usize, height: usize) -> Vec<u8> {
-    let mut out = vec![0; width * height];
-
-    for row in (0..height).step_by(2) {
-        for col in 0..width {
-            let pos = row * width + col;
-            if col % 2 == 0 {
-                out[pos] = data[pos];
-            } else {
-                let mut count = 1;
-                let mut value = data[pos - 1] as usize;
-
-                if col < width - 1 {
-                    count += 1;
-                    value += data[pos + 1] as usize;
-                }
+// Photos taken within the same second get numbered, "camcam-...-1" and so
+// on.
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    (0..)
+        .map(|n| if n == 0 { dir.join(name) } else { dir.join(format!("{}-{}", name, n)) })
+        .find(|p| !p.with_extension("jpg").exists() && !p.with_extension("dng").exists())
+        .unwrap()
+}
 
-                out[pos] = (value / count) as u8;
-            }
-        }
+// False if the image couldn't be written, missing EXIF data only gets a
+// complaint.
+fn save_jpeg(pipeline: &Pipeline, pic_path: &Path, orientation: Orientation, exposure: Option<ExposureValues>, options: SaveOptions, gains: WbGains, profile: &ColorProfile) -> bool {
+    let (width, height) = pipeline.size();
+    let demosaicer = options.demosaic.demosaicer();
+    let data = pipeline.run(&*demosaicer, gains, &ColorTransform::new(profile));
+
+    if let Err(e) = image::save_buffer(pic_path, &data, width as u32, height as u32, image::ColorType::Rgb8) {
+        eprintln!("Error saving image: {}", e);
+        return false;
     }
 
-    for row in (1..height).step_by(2) {
-        for col in 0..width {
-            let pos = row * width + col;
-            let mut count = 1;
-            let mut value = out[pos - width] as usize;
-            if row < height - 1 {
-                count += 1;
-                value += out[pos + width] as usize;
+    match Metadata::new_from_path(pic_path) {
+        Ok(m) => {
+            m.set_orientation(orientation);
+            if let Some(exposure) = exposure {
+                let (numerator, denominator) = exposure_time_fraction(exposure.time);
+                let time = format!("{}/{}", numerator, denominator);
+                let written = m.set_tag_string("Exif.Photo.ExposureTime", &time)
+                    .and_then(|_| m.set_tag_numeric("Exif.Photo.ISOSpeedRatings", exposure.iso as i32));
+                if let Err(e) = written {
+                    eprintln!("Couldn't set exposure exif tags: {}", e);
+                }
             }
-            out[pos] = (value / count) as u8;
-        }
+            if let Err(_) = m.save_to_file(pic_path) {
+                eprintln!("Saving exif to {} failed, image was saved though.", &pic_path.to_string_lossy());
+            }
+        },
+        Err(e) => eprintln!("Failed reading exif data from {} which was just saved: {}", &pic_path.to_string_lossy(), e)
     }
-
-    out
+    true
 }
 
-fn smudge_green(data: &[u8], width: usize, height: usize) -> Vec<u8> {
-    let mut out = vec![0; width * height];
-
-    for row in 0..height {
-        for col in 0..width {
-            let rem =  row % 2;
-
-            if col % 2 == rem {
-                let mut count = 0;
-                let mut value = 0usize;
-                if row > 0 {
-                    count += 1;
-                    value += data[(row - 1) * width + col] as usize;
-                }
-
-                if col < width - 1 {
-                    count += 1;
-                    value += data[row * width + col + 1] as usize;
-                }
-
-                if row < height - 1 {
-                    count += 1;
-                    value += data[(row + 1) * width + col] as usize;
-                }
+fn save_dng(frame: &BayerFrame, pic_path: &Path, info: &CaptureInfo, orientation: Orientation, black_level: u16, gains: WbGains, profile: &ColorProfile) -> bool {
+    let model = format!("PinePhone {}", info.sensor);
+    let dng_info = DngInfo {
+        make: "PINE64",
+        model: &model,
+        orientation: orientation as u16,
+        time: info.time,
+        black_level,
+        color_matrix: profile.dng_color_matrix(&dng::SRGB_COLOR_MATRIX),
+        as_shot_neutral: [
+            1.0 / gains.red as f64,
+            1.0 / gains.green as f64,
+            1.0 / gains.blue as f64
+        ],
+        exposure_time: info.exposure.map(|e| exposure_time_fraction(e.time)),
+        iso: info.exposure.map(|e| e.iso.min(u16::MAX as u32) as u16)
+    };
 
-                if col > 0 {
-                    count += 1;
-                    value += data[row * width + col - 1] as usize;
-                }
+    let result = File::create(pic_path)
+        .and_then(|f| dng::write(BufWriter::new(f), frame, &dng_info));
 
-                out[row * width + col] = (value / count) as u8;
-            } else {
-                out[row * width + col] = data[row * width + col];
-            }
+    match result {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("Error saving DNG to {}: {}", &pic_path.to_string_lossy(), e);
+            false
         }
     }
-
-    out
 }
 
-fn separate_colors(data: &[u8], width: usize, height: usize) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
-    let mut r = vec![0; width * height];
-    let mut g = vec![0; width * height];
-    let mut b = vec![0; width * height];
-
-    for row in 0..height {
-        if row % 2 == 0 {
-            for col in 0..width {
-                if col % 2 == 0 {
-                    b[row * width + col] = data[row * width + col];
-                } else {
-                    g[row * width + col] = data[row * width + col];
-                }
-            }
-        } else {
-            for col in 0..width {
-                if col % 2 == 0 {
-                    g[row * width + col] = data[row * width + col];
-                } else {
-                    r[row * width + col] = data[row * width + col];
-                }
-            }
-        }
+// Shutter speeds are written 1/250 and so on, long ones in tenths.
+fn exposure_time_fraction(time: f32) -> (u32, u32) {
+    if time > 0.0 && time < 1.0 {
+        (1, (1.0 / time).round() as u32)
+    } else {
+        ((time * 10.0).round() as u32, 10)
     }
-
-
-    (r, g, b)
 }
 
+fn exif_orientation(orientation: &str) -> Orientation {
+    match orientation {
+        "normal" => Orientation::Rotate90, // portrait, 8
+        "bottom-up" => Orientation::Rotate270, // upside down portrait, 6
+        "left-up" =>  Orientation::Rotate180, // Upside down landscape, 3
+        "right-up" => Orientation::Normal, // landscape, 1
+        _ => Orientation::Unspecified,
+    }
+}
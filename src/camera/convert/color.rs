@@ -0,0 +1,430 @@
+// Colour correction and tone mapping from white balanced camera RGB to
+// 8-bit sRGB.
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::{collections::HashMap, convert::{TryFrom, TryInto}, fs};
+use super::white_balance::WhitePoints;
+
+const BUILTIN_PROFILES: &str = include_str!("../../../data/color_profiles.toml");
+const LUT_SIZE: usize = 4096;
+// Pixels the colour matrix is applied to at a time.
+const LANES: usize = 8;
+
+lazy_static! {
+    static ref PROFILES: HashMap<String, ColorProfile> = load_profiles();
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ColorProfile {
+    pub ccm: [[f32; 3]; 3],
+    #[serde(default)]
+    pub tone_curve: Option<Vec<[f32; 2]>>,
+    // In 8-bit units, scaled to the sensor's bit depth when used.
+    #[serde(default)]
+    pub black_level: f32,
+    // For white balance presets.
+    #[serde(default)]
+    pub white_points: WhitePoints
+}
+
+impl Default for ColorProfile {
+    fn default() -> Self {
+        ColorProfile {
+            ccm: [
+                [1.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0],
+                [0.0, 0.0, 1.0]
+            ],
+            tone_curve: None,
+            black_level: 0.0,
+            white_points: WhitePoints::default()
+        }
+    }
+}
+
+impl ColorProfile {
+    pub fn black_level(&self, bits: u8) -> u16 {
+        (self.black_level * (1u32 << (bits - 8)) as f32).round() as u16
+    }
+
+    // DNG wants the opposite direction, XYZ to camera RGB.
+    pub fn dng_color_matrix(&self, xyz_to_srgb: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+        let mut ccm = [[0.0; 3]; 3];
+        for (row, values) in self.ccm.iter().enumerate() {
+            for (col, v) in values.iter().enumerate() {
+                ccm[row][col] = *v as f64;
+            }
+        }
+
+        match invert(&ccm) {
+            Some(inverse) => multiply(&inverse, xyz_to_srgb),
+            None => *xyz_to_srgb
+        }
+    }
+}
+
+// Built-in profiles, overridden per sensor by the user's config file.
+fn load_profiles() -> HashMap<String, ColorProfile> {
+    let mut profiles: HashMap<String, ColorProfile> = toml::from_str(BUILTIN_PROFILES)
+        .expect("Built-in color profiles are broken.");
+
+    if let Some(mut path) = dirs::config_dir() {
+        path.push("camcam");
+        path.push("color_profiles.toml");
+
+        if let Ok(data) = fs::read_to_string(&path) {
+            match toml::from_str::<HashMap<String, ColorProfile>>(&data) {
+                Ok(user) => {
+                    eprintln!("Loaded color profiles from {}", path.to_string_lossy());
+                    profiles.extend(user);
+                },
+                Err(e) => eprintln!("Ignoring broken color profiles in {}: {}", path.to_string_lossy(), e)
+            }
+        }
+    }
+
+    profiles
+}
+
+pub fn profile(sensor: &str) -> ColorProfile {
+    match PROFILES.get(sensor) {
+        Some(p) => p.clone(),
+        None => {
+            eprintln!("No color profile for {}, colors will be off.", sensor);
+            ColorProfile::default()
+        }
+    }
+}
+
+// Colour matrix followed by a lookup table for the sRGB transfer curve and
+// the tone curve.
+pub struct ColorTransform {
+    ccm: [[f32; 3]; 3],
+    lut: Box<[u8; LUT_SIZE]>
+}
+
+impl ColorTransform {
+    pub fn new(profile: &ColorProfile) -> Self {
+        let mut lut = Box::new([0; LUT_SIZE]);
+        let values = (0..LUT_SIZE)
+            .map(|i| {
+                let linear = i as f32 / (LUT_SIZE - 1) as f32;
+                let mut v = linear_to_srgb(linear);
+                if let Some(curve) = &profile.tone_curve {
+                    v = apply_curve(curve, v);
+                }
+                (v * 255.0).round().max(0.0).min(255.0) as u8
+            });
+        for (entry, value) in lut.iter_mut().zip(values) {
+            *entry = value;
+        }
+
+        ColorTransform {
+            ccm: profile.ccm,
+            lut
+        }
+    }
+
+    // LANES pixels of interleaved RGB, scaled to 0..1 by scale, into out.
+    // The matrix and the clamping go over all of them in fixed size arrays
+    // that the compiler vectorizes, only the table lookups are one by one.
+    #[inline]
+    fn block<T: Copy + Into<f32>>(
+        &self,
+        rgb: &[T; LANES * 3],
+        scale: f32,
+        out: &mut [u8; LANES * 3]
+    ) {
+        let mut planes = [[0.0f32; LANES]; 3];
+        for i in 0..LANES {
+            for channel in 0..3 {
+                planes[channel][i] = rgb[i * 3 + channel].into() * scale;
+            }
+        }
+
+        let last = (LUT_SIZE - 1) as f32;
+        for (channel, row) in self.ccm.iter().enumerate() {
+            // u16 is plenty and converts from f32 faster than usize.
+            let mut index = [0u16; LANES];
+            for i in 0..LANES {
+                let v = row[0] * planes[0][i] + row[1] * planes[1][i] + row[2] * planes[2][i];
+                index[i] = (v.max(0.0).min(1.0) * last) as u16;
+            }
+            for i in 0..LANES {
+                out[i * 3 + channel] = self.lut[index[i] as usize % LUT_SIZE];
+            }
+        }
+    }
+
+    // Interleaved RGB samples up to max, to interleaved 8-bit sRGB.
+    pub fn apply(&self, rgb: &[u16], max: u16) -> Vec<u8> {
+        let mut out = vec![0; rgb.len()];
+        self.apply_into(rgb, max, &mut out);
+        out
+    }
+
+    // Same, into a slice of the same length.
+    pub fn apply_into(&self, rgb: &[u16], max: u16, out: &mut [u8]) {
+        let scale = 1.0 / max as f32;
+
+        let mut src = rgb.chunks_exact(LANES * 3);
+        let mut dst = out.chunks_exact_mut(LANES * 3);
+        for (s, d) in (&mut src).zip(&mut dst) {
+            self.block(s.try_into().unwrap(), scale, d.try_into().unwrap());
+        }
+        self.last_block(src.remainder(), scale, dst.into_remainder());
+    }
+
+    // In place on linear 8-bit RGB, used on preview frames.
+    pub fn apply_rgb8(&self, data: &mut [u8]) {
+        let scale = 1.0 / 255.0;
+
+        let mut chunks = data.chunks_exact_mut(LANES * 3);
+        for chunk in &mut chunks {
+            let rgb = <[u8; LANES * 3]>::try_from(&*chunk).unwrap();
+            self.block(&rgb, scale, chunk.try_into().unwrap());
+        }
+        let rest = chunks.into_remainder();
+        let rgb = rest.to_vec();
+        self.last_block(&rgb, scale, rest);
+    }
+
+    // Fewer than LANES pixels, padded to a block.
+    fn last_block<T: Copy + Default + Into<f32>>(&self, rgb: &[T], scale: f32, out: &mut [u8]) {
+        if rgb.is_empty() {
+            return;
+        }
+        let mut padded = [T::default(); LANES * 3];
+        padded[..rgb.len()].copy_from_slice(rgb);
+        let mut converted = [0; LANES * 3];
+        self.block(&padded, scale, &mut converted);
+        out.copy_from_slice(&converted[..out.len()]);
+    }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Linear interpolation between the curve points. Points are expected in
+// increasing input order.
+fn apply_curve(curve: &[[f32; 2]], v: f32) -> f32 {
+    if curve.is_empty() {
+        return v;
+    }
+
+    if v <= curve[0][0] {
+        return curve[0][1];
+    }
+
+    for pair in curve.windows(2) {
+        let ([x0, y0], [x1, y1]) = (pair[0], pair[1]);
+        if v <= x1 {
+            if x1 <= x0 {
+                return y1;
+            }
+            return y0 + (v - x0) / (x1 - x0) * (y1 - y0);
+        }
+    }
+
+    curve[curve.len() - 1][1]
+}
+
+fn multiply(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut out = [[0.0; 3]; 3];
+    for row in 0..3 {
+        for col in 0..3 {
+            out[row][col] = (0..3).map(|i| a[row][i] * b[i][col]).sum();
+        }
+    }
+    out
+}
+
+fn invert(m: &[[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+
+    if det.abs() < 1e-9 {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    Some([
+        [
+            (m[1][1] * m[2][2] - m[1][2] * m[2][1]) * inv_det,
+            (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * inv_det,
+            (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * inv_det
+        ],
+        [
+            (m[1][2] * m[2][0] - m[1][0] * m[2][2]) * inv_det,
+            (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * inv_det,
+            (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * inv_det
+        ],
+        [
+            (m[1][0] * m[2][1] - m[1][1] * m[2][0]) * inv_det,
+            (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * inv_det,
+            (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * inv_det
+        ]
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDENTITY: [[f64; 3]; 3] = [
+        [1.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0],
+        [0.0, 0.0, 1.0]
+    ];
+
+    fn encoded(linear: f32) -> u8 {
+        (linear_to_srgb(linear) * 255.0).round() as u8
+    }
+
+    #[test]
+    fn identity_ccm_only_encodes() {
+        let transform = ColorTransform::new(&ColorProfile::default());
+        let max = 1023;
+        let rgb = [0, 0, 0, 1023, 1023, 1023, 1023, 0, 0, 100, 500, 900, 40, 40, 40];
+        let out = transform.apply(&rgb, max);
+        let expected = rgb.iter()
+            .map(|&v| {
+                // Same truncation into the table as block().
+                let index = (v as f32 / max as f32 * (LUT_SIZE - 1) as f32) as usize;
+                encoded(index as f32 / (LUT_SIZE - 1) as f32)
+            })
+            .collect::<Vec<u8>>();
+        assert_eq!(out, expected);
+
+        // Channels stay apart and grays stay gray.
+        assert_eq!(&out[6..9], &[255, 0, 0]);
+        assert!(out[12] == out[13] && out[13] == out[14]);
+    }
+
+    #[test]
+    fn ccm_mixes_channels() {
+        let profile = ColorProfile {
+            ccm: [
+                [1.5, -0.5, 0.0],
+                [0.0, 1.0, 0.0],
+                [0.0, -0.5, 1.5]
+            ],
+            ..ColorProfile::default()
+        };
+        let transform = ColorTransform::new(&profile);
+        // Rows summing to 1 keep white white, pure green loses red and blue.
+        assert_eq!(transform.apply(&[255, 255, 255], 255), [255, 255, 255]);
+        assert_eq!(transform.apply(&[0, 255, 0], 255), [0, 255, 0]);
+        let mut red = [128, 64, 64];
+        transform.apply_rgb8(&mut red);
+        // Red pulls away from the grays, which stay where they were.
+        assert!(red[0] > encoded(128.0 / 255.0), "{:?}", red);
+        assert!((red[1] as i32 - red[2] as i32).abs() <= 1, "{:?}", red);
+    }
+
+    #[test]
+    fn blocks_match_single_pixels() {
+        let profile = ColorProfile {
+            ccm: [
+                [1.6, -0.4, -0.2],
+                [-0.3, 1.5, -0.2],
+                [0.0, -0.6, 1.6]
+            ],
+            ..ColorProfile::default()
+        };
+        let transform = ColorTransform::new(&profile);
+        // Not a whole number of blocks.
+        let rgb = (0..13 * 3).map(|i| (i * 97 % 1024) as u16).collect::<Vec<u16>>();
+        let single = rgb.chunks_exact(3).flat_map(|p| transform.apply(p, 1023)).collect::<Vec<u8>>();
+        assert_eq!(transform.apply(&rgb, 1023), single);
+
+        let mut rgb8 = rgb.iter().map(|v| (v / 4) as u8).collect::<Vec<u8>>();
+        let single = rgb8.chunks_exact(3)
+            .flat_map(|p| {
+                let mut p = p.to_vec();
+                transform.apply_rgb8(&mut p);
+                p
+            })
+            .collect::<Vec<u8>>();
+        transform.apply_rgb8(&mut rgb8);
+        assert_eq!(rgb8, single);
+    }
+
+    #[test]
+    fn lut_is_monotonic_with_fixed_ends() {
+        let curves = [
+            None,
+            Some(vec![[0.0, 0.0], [0.25, 0.2], [0.75, 0.85], [1.0, 1.0]])
+        ];
+        for curve in curves.iter() {
+            let profile = ColorProfile {
+                tone_curve: curve.clone(),
+                ..ColorProfile::default()
+            };
+            let transform = ColorTransform::new(&profile);
+            assert_eq!(transform.lut.len(), LUT_SIZE);
+            assert_eq!(transform.lut[0], 0);
+            assert_eq!(transform.lut[LUT_SIZE - 1], 255);
+            assert!(transform.lut.windows(2).all(|w| w[0] <= w[1]), "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn tone_curve_interpolates() {
+        let curve = [[0.0, 0.0], [0.5, 0.25], [1.0, 1.0]];
+        assert_eq!(apply_curve(&curve, -0.1), 0.0);
+        assert_eq!(apply_curve(&curve, 0.25), 0.125);
+        assert_eq!(apply_curve(&curve, 0.75), 0.625);
+        assert_eq!(apply_curve(&curve, 1.5), 1.0);
+        assert_eq!(apply_curve(&[], 0.3), 0.3);
+    }
+
+    #[test]
+    fn unknown_sensor_gets_the_default_profile() {
+        let profile = profile("no such sensor");
+        let default = ColorProfile::default();
+        assert_eq!(profile.ccm, default.ccm);
+        assert!(profile.tone_curve.is_none());
+        assert_eq!(profile.black_level, 0.0);
+        assert_eq!(profile.white_points, default.white_points);
+    }
+
+    #[test]
+    fn builtin_profiles_load() {
+        let profiles: HashMap<String, ColorProfile> = toml::from_str(BUILTIN_PROFILES).unwrap();
+        assert!(!profiles.is_empty());
+        for (sensor, profile) in profiles.iter() {
+            let transform = ColorTransform::new(profile);
+            assert!(transform.lut.windows(2).all(|w| w[0] <= w[1]), "{}", sensor);
+            assert!(invert(&profile.dng_color_matrix(&IDENTITY)).is_some(), "{}", sensor);
+        }
+    }
+
+    #[test]
+    fn dng_matrix_inverts_the_ccm() {
+        assert_eq!(ColorProfile::default().dng_color_matrix(&IDENTITY), IDENTITY);
+
+        let profile = ColorProfile {
+            ccm: [
+                [1.6, -0.4, -0.2],
+                [-0.3, 1.5, -0.2],
+                [0.0, -0.6, 1.6]
+            ],
+            ..ColorProfile::default()
+        };
+        let inverse = profile.dng_color_matrix(&IDENTITY);
+        let ccm = profile.ccm.map(|row| row.map(|v| v as f64));
+        let product = multiply(&ccm, &inverse);
+        for row in 0..3 {
+            for col in 0..3 {
+                assert!((product[row][col] - IDENTITY[row][col]).abs() < 1e-6, "{:?}", product);
+            }
+        }
+    }
+}
@@ -0,0 +1,658 @@
+// Demosaicing of unpacked Bayer frames into interleaved RGB at the sensor's
+// bit depth.
+use crate::camera::bayer::{BayerFrame, Channel};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DemosaicMethod {
+    Bilinear,
+    #[default]
+    Malvar,
+    EdgeDirected
+}
+
+impl DemosaicMethod {
+    pub fn demosaicer(self) -> Box<dyn Demosaic> {
+        match self {
+            DemosaicMethod::Bilinear => Box::new(Bilinear),
+            DemosaicMethod::Malvar => Box::new(Malvar),
+            DemosaicMethod::EdgeDirected => Box::new(EdgeDirected)
+        }
+    }
+}
+
+// Sync so that strips of one frame can be demosaiced on several threads.
+pub trait Demosaic: Sync {
+    // Takes a full Bayer frame, returns width * height * 3 samples of RGB
+    // in the same range as the input.
+    fn demosaic(&self, frame: &BayerFrame) -> Vec<u16>;
+}
+
+// Reads a pixel, mirroring coordinates over the frame edges. Mirroring by
+// two keeps the Bayer phase intact, so the kernels see the right colours.
+#[inline]
+fn px(data: &[u16], width: usize, height: usize, row: isize, col: isize) -> i32 {
+    let row = mirror(row, height);
+    let col = mirror(col, width);
+    data[row * width + col] as i32
+}
+
+#[inline]
+fn mirror(i: isize, len: usize) -> usize {
+    let len = len as isize;
+    let i = if i < 0 {
+        -i
+    } else if i >= len {
+        2 * (len - 1) - i
+    } else {
+        i
+    };
+    i.max(0).min(len - 1) as usize
+}
+
+#[inline]
+fn clamp(v: i32, max: i32) -> u16 {
+    v.max(0).min(max) as u16
+}
+
+// The original "smudge" demosaic. Fast, but soft and zippered on edges.
+pub struct Bilinear;
+
+impl Demosaic for Bilinear {
+    fn demosaic(&self, frame: &BayerFrame) -> Vec<u16> {
+        let (width, height) = (frame.width, frame.height);
+        let (r, g, b) = separate_colors(frame);
+        let (red_row, red_col) = frame.pattern.red_position();
+        let (blue_row, blue_col) = frame.pattern.blue_position();
+        let r = smudge_sparse(&r, width, height, red_row, red_col);
+        let g = smudge_green(&g, frame);
+        let b = smudge_sparse(&b, width, height, blue_row, blue_col);
+        combine_rgb(&r, &g, &b)
+    }
+}
+
+// Malvar, He & Cutler: "High-quality linear interpolation for demosaicing of
+// Bayer-patterned color images", 2004. Bilinear interpolation corrected with
+// the laplacian of the channel that is known at the pixel.
+pub struct Malvar;
+
+impl Demosaic for Malvar {
+    fn demosaic(&self, frame: &BayerFrame) -> Vec<u16> {
+        let (width, height) = (frame.width, frame.height);
+        let mut out = vec![0; width * height * 3];
+        let mut kernels = MalvarKernels::new(width.saturating_sub(2 * EDGE));
+
+        for (row, line) in out.chunks_exact_mut(width * 3).enumerate() {
+            let inner = row >= EDGE && row + EDGE < height && width > 2 * EDGE;
+            let edges = if inner { [0..EDGE, width - EDGE..width] } else { [0..width, width..width] };
+            for col in edges.iter().cloned().flatten() {
+                let (r, g, b) = malvar_pixel(frame, row, col);
+                line[col * 3..col * 3 + 3].copy_from_slice(&[r, g, b]);
+            }
+            if inner {
+                kernels.compute(frame, row);
+                kernels.combine(frame, row, &mut line[EDGE * 3..(width - EDGE) * 3]);
+            }
+        }
+
+        out
+    }
+}
+
+// Kernels reach this far, pixels closer to the frame edges are mirrored one
+// at a time by malvar_pixel.
+const EDGE: usize = 2;
+
+// The n inner pixels of a row, moved dr rows and dc columns. Only for rows
+// EDGE away from the top and bottom.
+#[inline]
+fn shifted<'a>(frame: &BayerFrame<'a>, row: usize, n: usize, dr: isize, dc: isize) -> &'a [u16] {
+    let start = (row as isize + dr) as usize * frame.width + (EDGE as isize + dc) as usize;
+    &frame.data[start..start + n]
+}
+
+// Every Malvar kernel over the inner pixels of a row, in plain loops over
+// slices of the same length so the compiler vectorizes them. Each pixel only
+// needs two of the four, computing all of them is still cheaper than picking.
+struct MalvarKernels {
+    green_at_rb: Vec<i32>,
+    along_row: Vec<i32>,
+    along_col: Vec<i32>,
+    opposite: Vec<i32>
+}
+
+impl MalvarKernels {
+    fn new(len: usize) -> Self {
+        MalvarKernels {
+            green_at_rb: vec![0; len],
+            along_row: vec![0; len],
+            along_col: vec![0; len],
+            opposite: vec![0; len]
+        }
+    }
+
+    // row has to be EDGE rows away from the top and bottom.
+    fn compute(&mut self, frame: &BayerFrame, row: usize) {
+        let n = self.green_at_rb.len();
+        let at = |dr, dc| shifted(frame, row, n, dr, dc);
+        let (up2, up1, down1, down2) = (at(-2, 0), at(-1, 0), at(1, 0), at(2, 0));
+        let (up1_left, up1_right, down1_left, down1_right) = (at(-1, -1), at(-1, 1), at(1, -1), at(1, 1));
+        let (left2, left1, center, right1, right2) = (at(0, -2), at(0, -1), at(0, 0), at(0, 1), at(0, 2));
+        let (green_at_rb, along_row) = (&mut self.green_at_rb[..n], &mut self.along_row[..n]);
+        let (along_col, opposite) = (&mut self.along_col[..n], &mut self.opposite[..n]);
+
+        for i in 0..n {
+            let c = center[i] as i32;
+            let horiz1 = left1[i] as i32 + right1[i] as i32;
+            let horiz2 = left2[i] as i32 + right2[i] as i32;
+            let vert1 = up1[i] as i32 + down1[i] as i32;
+            let vert2 = up2[i] as i32 + down2[i] as i32;
+            let diag = up1_left[i] as i32 + up1_right[i] as i32 + down1_left[i] as i32 + down1_right[i] as i32;
+            // Same as in malvar_pixel.
+            green_at_rb[i] = (8 * c + 4 * (vert1 + horiz1) - 2 * (vert2 + horiz2)) / 16;
+            along_row[i] = (10 * c + 8 * horiz1 - 2 * diag - 2 * horiz2 + vert2) / 16;
+            along_col[i] = (10 * c + 8 * vert1 - 2 * diag - 2 * vert2 + horiz2) / 16;
+            opposite[i] = (12 * c + 4 * diag - 3 * (vert2 + horiz2)) / 16;
+        }
+    }
+
+    // Picks what each pixel of the row needs into out, interleaved RGB of
+    // the inner pixels.
+    fn combine(&self, frame: &BayerFrame, row: usize, out: &mut [u16]) {
+        let max = frame.max_value() as i32;
+        let n = self.green_at_rb.len();
+        let center = &frame.data[row * frame.width + EDGE..][..n];
+        // Red, green and blue as indices into the values below, for even and
+        // odd pixels.
+        let pick = |col: usize| match frame.channel_at(row, col) {
+            Channel::Red => [0, 1, 4],
+            Channel::Blue => [4, 1, 0],
+            Channel::Green if frame.channel_at(row, col + 1) == Channel::Red => [2, 0, 3],
+            Channel::Green => [3, 0, 2]
+        };
+        let picks = [pick(EDGE), pick(EDGE + 1)];
+
+        for (i, p) in out.chunks_exact_mut(3).enumerate() {
+            let values = [center[i] as i32, self.green_at_rb[i], self.along_row[i], self.along_col[i], self.opposite[i]];
+            let pick = picks[i % 2];
+            p[0] = clamp(values[pick[0]], max);
+            p[1] = clamp(values[pick[1]], max);
+            p[2] = clamp(values[pick[2]], max);
+        }
+    }
+}
+
+fn malvar_pixel(frame: &BayerFrame, row: usize, col: usize) -> (u16, u16, u16) {
+    let (data, width, height) = (frame.data, frame.width, frame.height);
+    let max = frame.max_value() as i32;
+    let clamp = |v: i32| clamp(v, max);
+    let p = |dr: isize, dc: isize| px(data, width, height, row as isize + dr, col as isize + dc);
+
+    let center = p(0, 0);
+    let cross1 = p(-1, 0) + p(1, 0) + p(0, -1) + p(0, 1);
+    let cross2 = p(-2, 0) + p(2, 0) + p(0, -2) + p(0, 2);
+    let diag = p(-1, -1) + p(-1, 1) + p(1, -1) + p(1, 1);
+    let horiz1 = p(0, -1) + p(0, 1);
+    let horiz2 = p(0, -2) + p(0, 2);
+    let vert1 = p(-1, 0) + p(1, 0);
+    let vert2 = p(-2, 0) + p(2, 0);
+
+    // All kernels are scaled by 16 to keep the half weights in integers.
+    // Green at red and blue pixels.
+    let green_at_rb = || (8 * center + 4 * cross1 - 2 * cross2) / 16;
+    // Red/blue at green pixels, when the wanted colour is on the same row.
+    let along_row = || (10 * center + 8 * horiz1 - 2 * diag - 2 * horiz2 + vert2) / 16;
+    // Red/blue at green pixels, when the wanted colour is on the same column.
+    let along_col = || (10 * center + 8 * vert1 - 2 * diag - 2 * vert2 + horiz2) / 16;
+    // Red at blue pixels and blue at red pixels.
+    let opposite = || (12 * center + 4 * diag - 3 * cross2) / 16;
+
+    match frame.channel_at(row, col) {
+        Channel::Red => (clamp(center), clamp(green_at_rb()), clamp(opposite())),
+        Channel::Blue => (clamp(opposite()), clamp(green_at_rb()), clamp(center)),
+        Channel::Green => {
+            let row_channel = frame.channel_at(row, col + 1);
+            if row_channel == Channel::Red {
+                (clamp(along_row()), clamp(center), clamp(along_col()))
+            } else {
+                (clamp(along_col()), clamp(center), clamp(along_row()))
+            }
+        }
+    }
+}
+
+// Edge-directed demosaic in the spirit of AHD. Green is interpolated along the
+// direction with the smaller gradient (Hamilton-Adams), red and blue are then
+// filled in from the colour difference to green, which follows edges far
+// better than interpolating the sparse red and blue samples on their own.
+pub struct EdgeDirected;
+
+impl Demosaic for EdgeDirected {
+    fn demosaic(&self, frame: &BayerFrame) -> Vec<u16> {
+        let (data, width, height) = (frame.data, frame.width, frame.height);
+        let green = interpolate_green(frame);
+
+        // Colour differences R - G and B - G at the sites where they are known.
+        let mut red_diff = vec![0i32; width * height];
+        let mut blue_diff = vec![0i32; width * height];
+
+        for row in 0..height {
+            for col in 0..width {
+                let pos = row * width + col;
+                match frame.channel_at(row, col) {
+                    Channel::Red => red_diff[pos] = data[pos] as i32 - green[pos],
+                    Channel::Blue => blue_diff[pos] = data[pos] as i32 - green[pos],
+                    Channel::Green => ()
+                }
+            }
+        }
+
+        let red_diff = fill_difference(&red_diff, frame, Channel::Red);
+        let blue_diff = fill_difference(&blue_diff, frame, Channel::Blue);
+
+        let max = frame.max_value() as i32;
+        let clamp = |v: i32| clamp(v, max);
+        let mut out = vec![0; width * height * 3];
+        for pos in 0..width * height {
+            let offset = pos * 3;
+            out[offset] = clamp(green[pos] + red_diff[pos]);
+            out[offset + 1] = clamp(green[pos]);
+            out[offset + 2] = clamp(green[pos] + blue_diff[pos]);
+        }
+
+        out
+    }
+}
+
+fn interpolate_green(frame: &BayerFrame) -> Vec<i32> {
+    let (width, height) = (frame.width, frame.height);
+    let mut green = vec![0i32; width * height];
+
+    for (row, line) in green.chunks_exact_mut(width).enumerate() {
+        let inner = row >= EDGE && row + EDGE < height && width > 2 * EDGE;
+        let edges = if inner { [0..EDGE, width - EDGE..width] } else { [0..width, width..width] };
+        for col in edges.iter().cloned().flatten() {
+            line[col] = green_pixel(frame, row, col);
+        }
+        if inner {
+            green_row(frame, row, &mut line[EDGE..width - EDGE]);
+        }
+    }
+
+    green
+}
+
+fn green_pixel(frame: &BayerFrame, row: usize, col: usize) -> i32 {
+    let (data, width, height) = (frame.data, frame.width, frame.height);
+    let p = |dr: isize, dc: isize| px(data, width, height, row as isize + dr, col as isize + dc);
+    let center = p(0, 0);
+    if frame.channel_at(row, col) == Channel::Green {
+        return center;
+    }
+
+    let h_grad = (p(0, -1) - p(0, 1)).abs() + (2 * center - p(0, -2) - p(0, 2)).abs();
+    let v_grad = (p(-1, 0) - p(1, 0)).abs() + (2 * center - p(-2, 0) - p(2, 0)).abs();
+
+    let h_est = (2 * (p(0, -1) + p(0, 1)) + 2 * center - p(0, -2) - p(0, 2)) / 4;
+    let v_est = (2 * (p(-1, 0) + p(1, 0)) + 2 * center - p(-2, 0) - p(2, 0)) / 4;
+
+    if h_grad < v_grad {
+        h_est
+    } else if v_grad < h_grad {
+        v_est
+    } else {
+        (h_est + v_est) / 2
+    }
+}
+
+// Same for the inner pixels of a row, out. Every pixel is estimated in one
+// loop over slices that the compiler vectorizes, then the green ones get
+// their own values back.
+fn green_row(frame: &BayerFrame, row: usize, out: &mut [i32]) {
+    let n = out.len();
+    let at = |dr, dc| shifted(frame, row, n, dr, dc);
+    let (up2, up1, down1, down2) = (at(-2, 0), at(-1, 0), at(1, 0), at(2, 0));
+    let (left2, left1, center, right1, right2) = (at(0, -2), at(0, -1), at(0, 0), at(0, 1), at(0, 2));
+
+    for i in 0..n {
+        let c = center[i] as i32;
+        let (left1, right1, left2, right2) = (left1[i] as i32, right1[i] as i32, left2[i] as i32, right2[i] as i32);
+        let (up1, down1, up2, down2) = (up1[i] as i32, down1[i] as i32, up2[i] as i32, down2[i] as i32);
+
+        let h_grad = (left1 - right1).abs() + (2 * c - left2 - right2).abs();
+        let v_grad = (up1 - down1).abs() + (2 * c - up2 - down2).abs();
+        let h_est = (2 * (left1 + right1) + 2 * c - left2 - right2) / 4;
+        let v_est = (2 * (up1 + down1) + 2 * c - up2 - down2) / 4;
+
+        out[i] = if h_grad < v_grad {
+            h_est
+        } else if v_grad < h_grad {
+            v_est
+        } else {
+            (h_est + v_est) / 2
+        };
+    }
+
+    let first_green = if frame.channel_at(row, EDGE) == Channel::Green { 0 } else { 1 };
+    for i in (first_green..n).step_by(2) {
+        out[i] = center[i] as i32;
+    }
+}
+
+// Spreads a sparse colour difference plane over the whole frame. Known values
+// sit on every other row and column, so each missing value is the average of
+// either two or four known neighbours.
+fn fill_difference(diff: &[i32], frame: &BayerFrame, channel: Channel) -> Vec<i32> {
+    let (width, height) = (frame.width, frame.height);
+    let mut out = diff.to_vec();
+    let at = |row: isize, col: isize| {
+        diff[mirror(row, height) * width + mirror(col, width)]
+    };
+
+    for row in 0..height {
+        for col in 0..width {
+            let here = frame.channel_at(row, col);
+            if here == channel {
+                continue;
+            }
+
+            let (r, c) = (row as isize, col as isize);
+            let value = if here == Channel::Green {
+                if frame.channel_at(row, col + 1) == channel {
+                    (at(r, c - 1) + at(r, c + 1)) / 2
+                } else {
+                    (at(r - 1, c) + at(r + 1, c)) / 2
+                }
+            } else {
+                (at(r - 1, c - 1) + at(r - 1, c + 1) + at(r + 1, c - 1) + at(r + 1, c + 1)) / 4
+            };
+
+            out[row * width + col] = value;
+        }
+    }
+
+    out
+}
+
+fn combine_rgb(r: &[u16], g: &[u16], b: &[u16]) -> Vec<u16> {
+    let mut out = vec![0; r.len() * 3];
+    assert_eq!(r.len(), g.len());
+    assert_eq!(g.len(), b.len());
+
+    for i in 0..r.len() {
+        let offset = i * 3;
+        out[offset] = r[i];
+        out[offset + 1] = g[i];
+        out[offset + 2] = b[i];
+    }
+
+    out
+}
+
+// Fills a plane that has samples on one pixel of every 2x2 block: first
+// along the rows that have samples, then down the columns in between.
+fn smudge_sparse(data: &[u16], width: usize, height: usize, row_phase: usize, col_phase: usize) -> Vec<u16> {
+    let mut out = vec![0; width * height];
+
+    for row in (row_phase..height).step_by(2) {
+        for col in 0..width {
+            let pos = row * width + col;
+            if col % 2 == col_phase {
+                out[pos] = data[pos];
+            } else {
+                let mut count = 0;
+                let mut value = 0usize;
+
+                if col > 0 {
+                    count += 1;
+                    value += data[pos - 1] as usize;
+                }
+
+                if col < width - 1 {
+                    count += 1;
+                    value += data[pos + 1] as usize;
+                }
+
+                out[pos] = (value / count) as u16;
+            }
+        }
+    }
+
+    for row in ((1 - row_phase)..height).step_by(2) {
+        for col in 0..width {
+            let pos = row * width + col;
+            let mut count = 0;
+            let mut value = 0usize;
+
+            if row > 0 {
+                count += 1;
+                value += out[pos - width] as usize;
+            }
+
+            if row < height - 1 {
+                count += 1;
+                value += out[pos + width] as usize;
+            }
+
+            out[pos] = (value / count) as u16;
+        }
+    }
+
+    out
+}
+
+fn smudge_green(data: &[u16], frame: &BayerFrame) -> Vec<u16> {
+    let (width, height) = (frame.width, frame.height);
+    let mut out = vec![0; width * height];
+
+    for row in 0..height {
+        for col in 0..width {
+            if frame.channel_at(row, col) != Channel::Green {
+                let mut count = 0;
+                let mut value = 0usize;
+                if row > 0 {
+                    count += 1;
+                    value += data[(row - 1) * width + col] as usize;
+                }
+
+                if col < width - 1 {
+                    count += 1;
+                    value += data[row * width + col + 1] as usize;
+                }
+
+                if row < height - 1 {
+                    count += 1;
+                    value += data[(row + 1) * width + col] as usize;
+                }
+
+                if col > 0 {
+                    count += 1;
+                    value += data[row * width + col - 1] as usize;
+                }
+
+                out[row * width + col] = (value / count) as u16;
+            } else {
+                out[row * width + col] = data[row * width + col];
+            }
+        }
+    }
+
+    out
+}
+
+fn separate_colors(frame: &BayerFrame) -> (Vec<u16>, Vec<u16>, Vec<u16>) {
+    let (width, height) = (frame.width, frame.height);
+    let mut r = vec![0; width * height];
+    let mut g = vec![0; width * height];
+    let mut b = vec![0; width * height];
+
+    for row in 0..height {
+        for col in 0..width {
+            let pos = row * width + col;
+            match frame.channel_at(row, col) {
+                Channel::Red => r[pos] = frame.data[pos],
+                Channel::Green => g[pos] = frame.data[pos],
+                Channel::Blue => b[pos] = frame.data[pos]
+            }
+        }
+    }
+
+    (r, g, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::bayer::CfaPattern;
+
+    const PATTERNS: [CfaPattern; 4] = [CfaPattern::Bggr, CfaPattern::Gbrg, CfaPattern::Grbg, CfaPattern::Rggb];
+    const METHODS: [DemosaicMethod; 3] = [DemosaicMethod::Bilinear, DemosaicMethod::Malvar, DemosaicMethod::EdgeDirected];
+    const SIZE: usize = 16;
+    const BITS: u8 = 10;
+    const DARK: u16 = 100;
+    const BRIGHT: u16 = 900;
+
+    // What the sensor sees of an RGB scene through the pattern.
+    fn mosaic(pattern: CfaPattern, scene: impl Fn(usize, usize) -> [u16; 3]) -> Vec<u16> {
+        let mut data = vec![0; SIZE * SIZE];
+        for row in 0..SIZE {
+            for col in 0..SIZE {
+                let rgb = scene(row, col);
+                data[row * SIZE + col] = match pattern.channel_at(row, col) {
+                    Channel::Red => rgb[0],
+                    Channel::Green => rgb[1],
+                    Channel::Blue => rgb[2]
+                };
+            }
+        }
+        data
+    }
+
+    fn demosaic(method: DemosaicMethod, pattern: CfaPattern, scene: impl Fn(usize, usize) -> [u16; 3]) -> Vec<u16> {
+        let data = mosaic(pattern, scene);
+        method.demosaicer().demosaic(&BayerFrame::new(&data, SIZE, SIZE, pattern, BITS))
+    }
+
+    fn pixel(rgb: &[u16], row: usize, col: usize) -> [u16; 3] {
+        let offset = (row * SIZE + col) * 3;
+        [rgb[offset], rgb[offset + 1], rgb[offset + 2]]
+    }
+
+    // Grey, dark above row 7 and bright from there.
+    fn horizontal_edge(row: usize, _: usize) -> [u16; 3] {
+        [if row < 7 { DARK } else { BRIGHT }; 3]
+    }
+
+    // Grey, dark left of column 7 and bright from there.
+    fn vertical_edge(_: usize, col: usize) -> [u16; 3] {
+        horizontal_edge(col, 0)
+    }
+
+    fn mean_error(rgb: &[u16], scene: impl Fn(usize, usize) -> [u16; 3]) -> f32 {
+        let mut sum = 0;
+        for row in 0..SIZE {
+            for col in 0..SIZE {
+                let (got, want) = (pixel(rgb, row, col), scene(row, col));
+                sum += (0..3).map(|c| (got[c] as i32 - want[c] as i32).abs()).sum::<i32>();
+            }
+        }
+        sum as f32 / (SIZE * SIZE * 3) as f32
+    }
+
+    #[test]
+    fn flat_field_is_exact() {
+        let colour = |_, _| [300, 500, 200];
+        for &method in METHODS.iter() {
+            for &pattern in PATTERNS.iter() {
+                let rgb = demosaic(method, pattern, colour);
+                for row in 0..SIZE {
+                    for col in 0..SIZE {
+                        assert_eq!(pixel(&rgb, row, col), [300, 500, 200], "{:?} {:?} at {},{}", method, pattern, row, col);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn edges_are_exact_away_from_them() {
+        for &method in METHODS.iter() {
+            for &pattern in PATTERNS.iter() {
+                let across = demosaic(method, pattern, horizontal_edge);
+                let down = demosaic(method, pattern, vertical_edge);
+                for row in (0..SIZE).filter(|r| *r + 3 < 7 || *r >= 7 + 3) {
+                    for col in 0..SIZE {
+                        assert_eq!(pixel(&across, row, col), horizontal_edge(row, col), "{:?} {:?} at {},{}", method, pattern, row, col);
+                        assert_eq!(pixel(&down, col, row), vertical_edge(col, row), "{:?} {:?} at {},{}", method, pattern, col, row);
+                    }
+                }
+            }
+        }
+    }
+
+    // Fine detail with every gradient direction, for the inner and edge
+    // paths to disagree on if they could.
+    fn noise(width: usize, height: usize) -> Vec<u16> {
+        let mut state = 12345u32;
+        (0..width * height)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u16 % (1 << BITS)
+            })
+            .collect()
+    }
+
+    // The row at a time paths give what the pixel at a time ones do, edges
+    // and all.
+    #[test]
+    fn rows_match_pixels() {
+        for &(width, height) in [(5, 5), (6, 7), (17, 13), (32, 9)].iter() {
+            let data = noise(width, height);
+            for &pattern in PATTERNS.iter() {
+                let frame = BayerFrame::new(&data, width, height, pattern, BITS);
+                let malvar = Malvar.demosaic(&frame);
+                let green = interpolate_green(&frame);
+                for row in 0..height {
+                    for col in 0..width {
+                        let (r, g, b) = malvar_pixel(&frame, row, col);
+                        let offset = (row * width + col) * 3;
+                        assert_eq!(&malvar[offset..offset + 3], &[r, g, b], "Malvar {:?} {}x{} at {},{}", pattern, width, height, row, col);
+                        assert_eq!(green[row * width + col], green_pixel(&frame, row, col), "green {:?} {}x{} at {},{}", pattern, width, height, row, col);
+                    }
+                }
+            }
+        }
+    }
+
+    // Green is interpolated along the edge, never across it.
+    #[test]
+    fn edge_directed_keeps_green_along_edges() {
+        for &pattern in PATTERNS.iter() {
+            let across = demosaic(DemosaicMethod::EdgeDirected, pattern, horizontal_edge);
+            let down = demosaic(DemosaicMethod::EdgeDirected, pattern, vertical_edge);
+            for row in 0..SIZE {
+                for col in 0..SIZE {
+                    assert_eq!(pixel(&across, row, col)[1], horizontal_edge(row, col)[1], "{:?} at {},{}", pattern, row, col);
+                    assert_eq!(pixel(&down, row, col)[1], vertical_edge(row, col)[1], "{:?} at {},{}", pattern, row, col);
+                }
+            }
+        }
+    }
+
+    // Bilinear and Malvar blur grey edges a little, the edge-directed one
+    // gets them right since the colour differences are flat.
+    #[test]
+    fn edge_errors_are_bounded() {
+        // About 3% of the step.
+        let bound = (BRIGHT - DARK) as f32 * 0.03;
+        for &pattern in PATTERNS.iter() {
+            for &scene in [horizontal_edge, vertical_edge].iter() {
+                let error = |method| mean_error(&demosaic(method, pattern, scene), scene);
+                let (bilinear, malvar) = (error(DemosaicMethod::Bilinear), error(DemosaicMethod::Malvar));
+                assert!(bilinear < bound, "Bilinear {:?} is off by {} on average", pattern, bilinear);
+                assert!(malvar < bound, "Malvar {:?} is off by {} on average", pattern, malvar);
+                assert!(malvar <= bilinear, "Malvar {:?} is off by {}, more than bilinear's {}", pattern, malvar, bilinear);
+                assert_eq!(error(DemosaicMethod::EdgeDirected), 0.0, "EdgeDirected {:?}", pattern);
+            }
+        }
+    }
+}
@@ -0,0 +1,412 @@
+// Minimal DNG writer. Stores the CFA samples untouched as 16-bit values in a
+// single uncompressed strip, with enough metadata for raw developers to take
+// it from there.
+use chrono::prelude::*;
+use std::io::{self, Write};
+use crate::camera::bayer::{BayerFrame, Channel};
+
+// XYZ (D65) to linear sRGB. Combined with the inverse of a sensor's colour
+// correction matrix this gives the XYZ to camera matrix DNG wants.
+pub const SRGB_COLOR_MATRIX: [[f64; 3]; 3] = [
+    [3.2406, -1.5372, -0.4986],
+    [-0.9689, 1.8758, 0.0415],
+    [0.0557, -0.2040, 1.0570]
+];
+
+pub struct DngInfo<'a> {
+    pub make: &'a str,
+    pub model: &'a str,
+    // EXIF orientation value, 1-8. Anything else, like 0 for unknown, is
+    // written as 1, the tag has no value for unknown.
+    pub orientation: u16,
+    pub time: DateTime<Local>,
+    pub black_level: u16,
+    pub color_matrix: [[f64; 3]; 3],
+    // Camera RGB of a neutral object, the inverse of the white balance gains.
+    pub as_shot_neutral: [f64; 3],
+    // Seconds as a fraction.
+    pub exposure_time: Option<(u32, u32)>,
+    pub iso: Option<u16>
+}
+
+pub fn write<W: Write>(mut out: W, frame: &BayerFrame, info: &DngInfo) -> io::Result<()> {
+    let width = frame.width as u32;
+    let height = frame.height as u32;
+    let image_size = width * height * 2;
+    let time = info.time.format("%Y:%m:%d %H:%M:%S").to_string();
+
+    let cfa_pattern = [(0, 0), (0, 1), (1, 0), (1, 1)].iter()
+        .map(|(row, col)| match frame.channel_at(*row, *col) {
+            Channel::Red => 0,
+            Channel::Green => 1,
+            Channel::Blue => 2
+        })
+        .collect::<Vec<u8>>();
+
+    let color_matrix = info.color_matrix.iter()
+        .flat_map(|row| row.iter())
+        .map(|v| ((v * 10000.0).round() as i32, 10000))
+        .collect::<Vec<(i32, i32)>>();
+
+    let as_shot_neutral = info.as_shot_neutral.iter()
+        .map(|v| ((v * 10000.0).round() as u32, 10000))
+        .collect::<Vec<(u32, u32)>>();
+
+    let mut ifd = Ifd::new();
+    ifd.long(254, &[0]); // NewSubfileType, main image
+    ifd.long(256, &[width]);
+    ifd.long(257, &[height]);
+    ifd.short(258, &[16]); // BitsPerSample
+    ifd.short(259, &[1]); // Compression, none
+    ifd.short(262, &[32803]); // PhotometricInterpretation, CFA
+    ifd.ascii(271, info.make);
+    ifd.ascii(272, info.model);
+    ifd.long(273, &[0]); // StripOffsets, filled in by encode
+    ifd.short(274, &[if (1..=8).contains(&info.orientation) { info.orientation } else { 1 }]);
+    ifd.short(277, &[1]); // SamplesPerPixel
+    ifd.long(278, &[height]); // RowsPerStrip
+    ifd.long(279, &[image_size]); // StripByteCounts
+    ifd.short(284, &[1]); // PlanarConfiguration, chunky
+    ifd.ascii(305, "camcam");
+    ifd.ascii(306, &time);
+    ifd.short(33421, &[2, 2]); // CFARepeatPatternDim
+    ifd.byte(33422, &cfa_pattern);
+    if let Some(exposure_time) = info.exposure_time {
+        ifd.rational(33434, &[exposure_time]); // ExposureTime
+    }
+    if let Some(iso) = info.iso {
+        ifd.short(34855, &[iso]); // ISOSpeedRatings
+    }
+    ifd.ascii(36867, &time); // DateTimeOriginal
+    ifd.byte(50706, &[1, 4, 0, 0]); // DNGVersion
+    ifd.byte(50707, &[1, 1, 0, 0]); // DNGBackwardVersion
+    ifd.ascii(50708, &format!("{} {}", info.make, info.model)); // UniqueCameraModel
+    ifd.byte(50710, &[0, 1, 2]); // CFAPlaneColor
+    ifd.short(50711, &[1]); // CFALayout, rectangular
+    ifd.long(50714, &[info.black_level as u32]);
+    ifd.long(50717, &[frame.max_value() as u32]); // WhiteLevel
+    ifd.srational(50721, &color_matrix); // ColorMatrix1
+    ifd.rational(50728, &as_shot_neutral); // AsShotNeutral
+    ifd.short(50778, &[21]); // CalibrationIlluminant1, D65
+
+    let (header, strip_offset) = ifd.encode();
+    out.write_all(&header)?;
+
+    debug_assert_eq!(header.len() as u32, strip_offset);
+
+    let mut strip = Vec::with_capacity(image_size as usize);
+    for sample in frame.data.iter() {
+        strip.extend_from_slice(&sample.to_le_bytes());
+    }
+    out.write_all(&strip)?;
+    out.flush()
+}
+
+const BYTE: u16 = 1;
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+const SRATIONAL: u16 = 10;
+
+struct Entry {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+    data: Vec<u8>
+}
+
+// A single little endian IFD, followed by the data that didn't fit in the
+// entries and then the image strip.
+struct Ifd {
+    entries: Vec<Entry>
+}
+
+impl Ifd {
+    fn new() -> Self {
+        Ifd {
+            entries: Vec::new()
+        }
+    }
+
+    fn push(&mut self, tag: u16, field_type: u16, count: usize, data: Vec<u8>) {
+        self.entries.push(Entry {
+            tag,
+            field_type,
+            count: count as u32,
+            data
+        });
+    }
+
+    fn byte(&mut self, tag: u16, values: &[u8]) {
+        self.push(tag, BYTE, values.len(), values.to_vec());
+    }
+
+    fn ascii(&mut self, tag: u16, value: &str) {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.push(tag, ASCII, data.len(), data);
+    }
+
+    fn short(&mut self, tag: u16, values: &[u16]) {
+        let data = values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect();
+        self.push(tag, SHORT, values.len(), data);
+    }
+
+    fn long(&mut self, tag: u16, values: &[u32]) {
+        let data = values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect();
+        self.push(tag, LONG, values.len(), data);
+    }
+
+    fn rational(&mut self, tag: u16, values: &[(u32, u32)]) {
+        let data = values.iter()
+            .flat_map(|(n, d)| n.to_le_bytes().iter().chain(d.to_le_bytes().iter()).copied().collect::<Vec<u8>>())
+            .collect();
+        self.push(tag, RATIONAL, values.len(), data);
+    }
+
+    fn srational(&mut self, tag: u16, values: &[(i32, i32)]) {
+        let data = values.iter()
+            .flat_map(|(n, d)| n.to_le_bytes().iter().chain(d.to_le_bytes().iter()).copied().collect::<Vec<u8>>())
+            .collect();
+        self.push(tag, SRATIONAL, values.len(), data);
+    }
+
+    // Lays out header, IFD and overflow data. Returns the bytes and the
+    // offset where the image strip starts, which is also patched into
+    // StripOffsets.
+    fn encode(mut self) -> (Vec<u8>, u32) {
+        self.entries.sort_by_key(|e| e.tag);
+
+        let ifd_offset = 8u32;
+        let ifd_size = 2 + 12 * self.entries.len() as u32 + 4;
+        let mut extra_offset = ifd_offset + ifd_size;
+
+        // Values longer than four bytes live after the IFD, word aligned.
+        let mut offsets = Vec::with_capacity(self.entries.len());
+        for entry in self.entries.iter() {
+            if entry.data.len() > 4 {
+                offsets.push(Some(extra_offset));
+                extra_offset += entry.data.len() as u32;
+                extra_offset += extra_offset % 2;
+            } else {
+                offsets.push(None);
+            }
+        }
+        let strip_offset = extra_offset;
+
+        let mut out = Vec::with_capacity(strip_offset as usize);
+        out.extend_from_slice(b"II");
+        out.extend_from_slice(&42u16.to_le_bytes());
+        out.extend_from_slice(&ifd_offset.to_le_bytes());
+
+        out.extend_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        for (entry, offset) in self.entries.iter().zip(offsets.iter()) {
+            out.extend_from_slice(&entry.tag.to_le_bytes());
+            out.extend_from_slice(&entry.field_type.to_le_bytes());
+            out.extend_from_slice(&entry.count.to_le_bytes());
+            match offset {
+                Some(offset) => out.extend_from_slice(&offset.to_le_bytes()),
+                None if entry.tag == 273 => out.extend_from_slice(&strip_offset.to_le_bytes()),
+                None => {
+                    let mut value = [0u8; 4];
+                    value[..entry.data.len()].copy_from_slice(&entry.data);
+                    out.extend_from_slice(&value);
+                }
+            }
+        }
+        // No next IFD.
+        out.extend_from_slice(&0u32.to_le_bytes());
+
+        for entry in self.entries.iter().filter(|e| e.data.len() > 4) {
+            out.extend_from_slice(&entry.data);
+            if out.len() % 2 == 1 {
+                out.push(0);
+            }
+        }
+
+        (out, strip_offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::camera::bayer::CfaPattern;
+
+    const WIDTH: usize = 6;
+    const HEIGHT: usize = 4;
+
+    // Field type, count and value bytes of every tag, and the whole file.
+    struct Parsed {
+        tags: HashMap<u16, (u16, u32, Vec<u8>)>,
+        order: Vec<u16>,
+        file: Vec<u8>
+    }
+
+    impl Parsed {
+        fn new(file: Vec<u8>) -> Self {
+            let u16_at = |at: usize| u16::from_le_bytes([file[at], file[at + 1]]);
+            let u32_at = |at: usize| u32::from_le_bytes([file[at], file[at + 1], file[at + 2], file[at + 3]]);
+            assert_eq!(&file[..4], b"II\x2a\x00");
+
+            let ifd = u32_at(4) as usize;
+            let mut tags = HashMap::new();
+            let mut order = Vec::new();
+            for i in 0..u16_at(ifd) as usize {
+                let entry = ifd + 2 + i * 12;
+                let (tag, field_type, count) = (u16_at(entry), u16_at(entry + 2), u32_at(entry + 4));
+                let size = count as usize * match field_type {
+                    BYTE | ASCII => 1,
+                    SHORT => 2,
+                    LONG => 4,
+                    _ => 8
+                };
+                let at = if size > 4 {
+                    let offset = u32_at(entry + 8) as usize;
+                    assert_eq!(offset % 2, 0, "tag {} isn't word aligned", tag);
+                    offset
+                } else {
+                    entry + 8
+                };
+                tags.insert(tag, (field_type, count, file[at..at + size].to_vec()));
+                order.push(tag);
+            }
+            assert_eq!(u32_at(ifd + 2 + order.len() * 12), 0, "more than one IFD");
+
+            Parsed {
+                tags,
+                order,
+                file
+            }
+        }
+
+        fn bytes(&self, tag: u16, field_type: u16) -> &[u8] {
+            let (found, _, data) = self.tags.get(&tag).unwrap_or_else(|| panic!("no tag {}", tag));
+            assert_eq!(*found, field_type, "type of tag {}", tag);
+            data
+        }
+
+        fn longs(&self, tag: u16) -> Vec<u32> {
+            self.bytes(tag, LONG).chunks(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()
+        }
+
+        fn shorts(&self, tag: u16) -> Vec<u16> {
+            self.bytes(tag, SHORT).chunks(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect()
+        }
+
+        fn ascii(&self, tag: u16) -> &str {
+            let data = self.bytes(tag, ASCII);
+            assert_eq!(data.last(), Some(&0), "tag {} isn't terminated", tag);
+            std::str::from_utf8(&data[..data.len() - 1]).unwrap()
+        }
+
+        fn srationals(&self, tag: u16) -> Vec<(i32, i32)> {
+            self.bytes(tag, SRATIONAL).chunks(8)
+                .map(|b| (i32::from_le_bytes([b[0], b[1], b[2], b[3]]), i32::from_le_bytes([b[4], b[5], b[6], b[7]])))
+                .collect()
+        }
+
+        fn rationals(&self, tag: u16) -> Vec<(u32, u32)> {
+            self.bytes(tag, RATIONAL).chunks(8)
+                .map(|b| (u32::from_le_bytes([b[0], b[1], b[2], b[3]]), u32::from_le_bytes([b[4], b[5], b[6], b[7]])))
+                .collect()
+        }
+    }
+
+    fn info() -> DngInfo<'static> {
+        DngInfo {
+            // Odd lengths, for the padding after them.
+            make: "Pine64",
+            model: "ov5640",
+            orientation: 6,
+            time: Local.ymd(2021, 3, 4).and_hms(5, 6, 7),
+            black_level: 16,
+            color_matrix: SRGB_COLOR_MATRIX,
+            as_shot_neutral: [0.5, 1.0, 0.25],
+            exposure_time: None,
+            iso: None
+        }
+    }
+
+    fn write_frame(pattern: CfaPattern, bits: u8, info: &DngInfo) -> (Vec<u16>, Parsed) {
+        let samples = (0..(WIDTH * HEIGHT) as u16).map(|i| i * 37 % (1 << bits)).collect::<Vec<u16>>();
+        let mut file = Vec::new();
+        write(&mut file, &BayerFrame::new(&samples, WIDTH, HEIGHT, pattern, bits), info).unwrap();
+        (samples, Parsed::new(file))
+    }
+
+    #[test]
+    fn image_and_layout() {
+        let (samples, dng) = write_frame(CfaPattern::Rggb, 10, &info());
+
+        let mut sorted = dng.order.clone();
+        sorted.sort();
+        assert_eq!(dng.order, sorted, "tags must be in ascending order");
+
+        assert_eq!(dng.longs(256), vec![WIDTH as u32]);
+        assert_eq!(dng.longs(257), vec![HEIGHT as u32]);
+        assert_eq!(dng.shorts(258), vec![16]);
+        assert_eq!(dng.shorts(262), vec![32803]);
+        assert_eq!(dng.shorts(274), vec![6]);
+
+        let offset = dng.longs(273)[0] as usize;
+        let size = dng.longs(279)[0] as usize;
+        assert_eq!(size, WIDTH * HEIGHT * 2);
+        assert_eq!(offset + size, dng.file.len());
+        let strip = dng.file[offset..].chunks(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect::<Vec<u16>>();
+        assert_eq!(strip, samples);
+    }
+
+    #[test]
+    fn unknown_orientation_is_normal() {
+        for &orientation in [0, 9].iter() {
+            let (_, dng) = write_frame(CfaPattern::Rggb, 10, &DngInfo { orientation, ..info() });
+            assert_eq!(dng.shorts(274), vec![1]);
+        }
+    }
+
+    #[test]
+    fn cfa_pattern_and_levels() {
+        let patterns = [
+            (CfaPattern::Bggr, [2, 1, 1, 0]),
+            (CfaPattern::Gbrg, [1, 2, 0, 1]),
+            (CfaPattern::Grbg, [1, 0, 2, 1]),
+            (CfaPattern::Rggb, [0, 1, 1, 2])
+        ];
+        for &(pattern, cfa) in patterns.iter() {
+            for &bits in [8, 10, 12].iter() {
+                let (_, dng) = write_frame(pattern, bits, &info());
+                assert_eq!(dng.shorts(33421), vec![2, 2]);
+                assert_eq!(dng.bytes(33422, BYTE), &cfa[..], "{:?}", pattern);
+                assert_eq!(dng.longs(50714), vec![16]);
+                assert_eq!(dng.longs(50717), vec![(1 << bits) - 1], "{}-bit", bits);
+            }
+        }
+    }
+
+    #[test]
+    fn metadata() {
+        let (_, dng) = write_frame(CfaPattern::Bggr, 10, &info());
+        assert_eq!(dng.ascii(271), "Pine64");
+        assert_eq!(dng.ascii(272), "ov5640");
+        assert_eq!(dng.ascii(50708), "Pine64 ov5640");
+        assert_eq!(dng.ascii(306), "2021:03:04 05:06:07");
+        assert_eq!(dng.ascii(36867), "2021:03:04 05:06:07");
+        assert_eq!(dng.bytes(50706, BYTE), &[1, 4, 0, 0]);
+        assert_eq!(dng.srationals(50721)[..3], [(32406, 10000), (-15372, 10000), (-4986, 10000)]);
+        assert_eq!(dng.rationals(50728), vec![(5000, 10000), (10000, 10000), (2500, 10000)]);
+        assert!(!dng.tags.contains_key(&33434));
+        assert!(!dng.tags.contains_key(&34855));
+
+        let with_exposure = DngInfo {
+            exposure_time: Some((1, 120)),
+            iso: Some(400),
+            ..info()
+        };
+        let (_, dng) = write_frame(CfaPattern::Bggr, 10, &with_exposure);
+        assert_eq!(dng.rationals(33434), vec![(1, 120)]);
+        assert_eq!(dng.shorts(34855), vec![400]);
+    }
+}
@@ -0,0 +1,168 @@
+// Full resolution conversion from a raw buffer to 8-bit sRGB.
+//
+// The frame is cut into horizontal strips that are converted in parallel.
+// Each strip goes through unpacking, black level and shading correction,
+// white balance, demosaicing and the colour transform before anything else
+// happens to it, so only a few strips worth of intermediate buffers are alive
+// at a time and the data stays in cache between the stages.
+use rayon::prelude::*;
+use crate::camera::bayer::{self, BayerFrame, RawImage};
+use super::{
+    color::ColorTransform,
+    demosaic::Demosaic,
+    shading::{Correction, ShadingMap},
+    white_balance::{self, WbGains, WhiteBalance, WhitePoints}
+};
+
+// Rows converted per strip. Even, so every strip starts on the same CFA
+// phase.
+const STRIP_ROWS: usize = 64;
+// Extra rows above and below a strip for the demosaic kernels to read from.
+// Edge-directed demosaicing reaches three rows away, and this has to be even
+// as well.
+const HALO_ROWS: usize = 4;
+// Every n:th 2x2 block in both directions is looked at for white balance,
+// same as WhiteBalance::frame_gains.
+const WB_BLOCK_STEP: usize = 4;
+
+pub struct Pipeline<'a> {
+    raw: &'a RawImage,
+    correction: Correction<'a>
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new(raw: &'a RawImage, black_level: u16, shading: Option<&'a ShadingMap>) -> Self {
+        Pipeline {
+            raw,
+            correction: Correction {
+                black_level,
+                max: raw.format.max_value(),
+                map: shading,
+                keep_black: false
+            }
+        }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.raw.width, self.raw.height)
+    }
+
+    // Rows first..last unpacked and corrected.
+    pub fn rows(&self, first: usize, last: usize) -> Vec<u16> {
+        let raw = self.raw;
+        let mut samples = bayer::unpack(&raw.data[first * raw.stride..], raw.width, last - first, raw.stride, &raw.format);
+        self.correction.apply(&mut samples, raw.width, raw.format.pattern, first, raw.height);
+        samples
+    }
+
+    // Estimates the gains from a sample of the corrected frame. Only the
+    // rows that are looked at get unpacked.
+    pub fn white_balance_gains(&self, white_balance: WhiteBalance, white_points: &WhitePoints) -> WbGains {
+        let raw = self.raw;
+        if !white_balance.is_auto() {
+            return white_balance.gains(std::iter::empty(), raw.format.max_value(), white_points);
+        }
+
+        let block_rows = raw.height / 2;
+        let pixels = (0..(block_rows + WB_BLOCK_STEP - 1) / WB_BLOCK_STEP)
+            .into_par_iter()
+            .flat_map(|i| {
+                let row = i * WB_BLOCK_STEP * 2;
+                let samples = self.rows(row, row + 2);
+                let frame = BayerFrame::new(&samples, raw.width, 2, raw.format.pattern, raw.format.bits);
+                white_balance::superpixels(&frame, WB_BLOCK_STEP).collect::<Vec<[u16; 3]>>()
+            })
+            .collect::<Vec<[u16; 3]>>();
+
+        white_balance.gains(pixels.into_iter(), raw.format.max_value(), white_points)
+    }
+
+    // Interleaved 8-bit sRGB of the whole frame.
+    pub fn run(&self, demosaic: &dyn Demosaic, gains: WbGains, transform: &ColorTransform) -> Vec<u8> {
+        let raw = self.raw;
+        let (width, height) = (raw.width, raw.height);
+        let (pattern, bits) = (raw.format.pattern, raw.format.bits);
+        let max = raw.format.max_value();
+        let mut out = vec![0; width * height * 3];
+
+        out.par_chunks_mut(STRIP_ROWS * width * 3)
+            .enumerate()
+            .for_each(|(i, strip)| {
+                let first = i * STRIP_ROWS;
+                let rows = strip.len() / (width * 3);
+                let top = first.saturating_sub(HALO_ROWS);
+                let bottom = (first + rows + HALO_ROWS).min(height);
+
+                let mut samples = self.rows(top, bottom);
+                white_balance::apply(&mut samples, width, pattern, max, gains);
+                let frame = BayerFrame::new(&samples, width, bottom - top, pattern, bits);
+                let rgb = demosaic.demosaic(&frame);
+
+                let start = (first - top) * width * 3;
+                transform.apply_into(&rgb[start..start + strip.len()], max, strip);
+            });
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::bayer::{CfaPattern, Packing, RawFormat};
+    use crate::camera::convert::{color::ColorProfile, DemosaicMethod};
+
+    // More than a few strips, the last one short.
+    const WIDTH: usize = 24;
+    const HEIGHT: usize = STRIP_ROWS * 3 + 10;
+
+    // A gradient with noise on top, unpacked 10-bit.
+    fn raw() -> RawImage {
+        let format = RawFormat::new(CfaPattern::Grbg, 10, Packing::Unpacked);
+        let stride = format.bytes_per_line(WIDTH);
+        let mut state = 12345u32;
+        let data = (0..WIDTH * HEIGHT)
+            .flat_map(|i| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let value = (i % WIDTH * 8 + i / WIDTH * 2) as u16 + (state >> 16) as u16 % 256;
+                value.min(1023).to_le_bytes().to_vec()
+            })
+            .collect();
+        RawImage::new(data, WIDTH, HEIGHT, stride, format)
+    }
+
+    fn shading() -> ShadingMap {
+        ShadingMap {
+            columns: 4,
+            rows: 3,
+            gains: (0..12).map(|i| [1.0 + i as f32 * 0.1, 1.0 + i as f32 * 0.05, 1.2]).collect()
+        }
+    }
+
+    // Strips with their halos give exactly what converting the frame in one
+    // go does, for every demosaic method.
+    #[test]
+    fn strips_match_the_whole_frame() {
+        let raw = raw();
+        let shading = shading();
+        let pipeline = Pipeline::new(&raw, 64, Some(&shading));
+        let gains = WbGains {
+            red: 1.8,
+            green: 1.0,
+            blue: 1.4
+        };
+        let transform = ColorTransform::new(&ColorProfile::default());
+        let max = raw.format.max_value();
+
+        for method in &[DemosaicMethod::Bilinear, DemosaicMethod::Malvar, DemosaicMethod::EdgeDirected] {
+            let demosaic = method.demosaicer();
+
+            let mut samples = pipeline.rows(0, HEIGHT);
+            white_balance::apply(&mut samples, WIDTH, raw.format.pattern, max, gains);
+            let frame = BayerFrame::new(&samples, WIDTH, HEIGHT, raw.format.pattern, raw.format.bits);
+            let whole = transform.apply(&demosaic.demosaic(&frame), max);
+
+            assert!(pipeline.run(demosaic.as_ref(), gains, &transform) == whole, "{:?}", method);
+        }
+    }
+}
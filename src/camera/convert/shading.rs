@@ -0,0 +1,451 @@
+// Black level subtraction and lens shading (vignetting) correction on the raw
+// samples, before white balance and demosaicing.
+//
+// The gain map is a coarse grid of per channel gains over the whole frame,
+// interpolated between cell centres. Grid positions are relative to the frame
+// size, so one map covers every mode of a sensor that isn't cropped.
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::PathBuf};
+use crate::camera::Rotation;
+use crate::camera::bayer::{BayerFrame, CfaPattern, Channel};
+
+const GRID_COLUMNS: usize = 16;
+const GRID_ROWS: usize = 12;
+// Corners of a phone lens lose a couple of stops at most, anything more is a
+// bad flat-field frame.
+const MAX_GAIN: f32 = 4.0;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ShadingMap {
+    pub columns: usize,
+    pub rows: usize,
+    // [r, g, b] gains, row by row.
+    pub gains: Vec<[f32; 3]>
+}
+
+impl ShadingMap {
+    // Computes the map from a frame of an evenly lit, featureless surface.
+    // Every channel is normalised against its own brightest cell, so the
+    // centre of the frame keeps its colour.
+    pub fn calibrate(frame: &BayerFrame, black_level: u16) -> Self {
+        let (columns, rows) = (GRID_COLUMNS, GRID_ROWS);
+        let mut sums = vec![[0u64; 3]; columns * rows];
+        let mut counts = vec![[0u64; 3]; columns * rows];
+
+        for row in 0..frame.height {
+            let cell_row = row * rows / frame.height;
+            let line = &frame.data[row * frame.width..(row + 1) * frame.width];
+            for (col, v) in line.iter().enumerate() {
+                let cell = cell_row * columns + col * columns / frame.width;
+                let channel = channel_index(frame.channel_at(row, col));
+                sums[cell][channel] += v.saturating_sub(black_level) as u64;
+                counts[cell][channel] += 1;
+            }
+        }
+
+        let means = sums.iter().zip(counts.iter())
+            .map(|(s, c)| {
+                let mut mean = [0.0; 3];
+                for channel in 0..3 {
+                    if c[channel] > 0 {
+                        mean[channel] = s[channel] as f32 / c[channel] as f32;
+                    }
+                }
+                mean
+            })
+            .collect::<Vec<[f32; 3]>>();
+
+        let mut peak = [0.0f32; 3];
+        for mean in means.iter() {
+            for channel in 0..3 {
+                peak[channel] = peak[channel].max(mean[channel]);
+            }
+        }
+
+        let gains = means.iter()
+            .map(|mean| {
+                let mut gain = [1.0; 3];
+                for channel in 0..3 {
+                    if mean[channel] > 0.0 {
+                        gain[channel] = (peak[channel] / mean[channel]).min(MAX_GAIN);
+                    }
+                }
+                gain
+            })
+            .collect();
+
+        ShadingMap {
+            columns,
+            rows,
+            gains
+        }
+    }
+
+    pub fn load(sensor: &str) -> Option<Self> {
+        let path = map_path(sensor)?;
+        let data = fs::read_to_string(&path).ok()?;
+
+        match toml::from_str::<ShadingMap>(&data) {
+            Ok(map) if map.columns > 0 && map.rows > 0 && map.gains.len() == map.columns * map.rows => Some(map),
+            Ok(_) => {
+                eprintln!("Ignoring lens shading map {} with a bad grid size.", path.to_string_lossy());
+                None
+            },
+            Err(e) => {
+                eprintln!("Ignoring broken lens shading map {}: {}", path.to_string_lossy(), e);
+                None
+            }
+        }
+    }
+
+    // Writes the map where load looks for it and returns the path.
+    pub fn save(&self, sensor: &str) -> io::Result<PathBuf> {
+        let path = map_path(sensor)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config dir"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let data = toml::to_string(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(&path, data)?;
+        Ok(path)
+    }
+
+    // The map for frames turned by rotation, like the preview's.
+    pub fn rotated(&self, rotation: Rotation) -> Self {
+        let (columns, rows) = if rotation.swaps_sides() { (self.rows, self.columns) } else { (self.columns, self.rows) };
+        let mut gains = vec![[1.0; 3]; self.gains.len()];
+        for (i, gain) in self.gains.iter().enumerate() {
+            let (x, y) = rotation.position(i % self.columns, i / self.columns, self.columns, self.rows);
+            gains[y * columns + x] = *gain;
+        }
+
+        ShadingMap {
+            columns,
+            rows,
+            gains
+        }
+    }
+
+    // Gains along one line of the frame, y from 0 to 1, interpolated
+    // between the rows of the grid.
+    fn line_gains(&self, y: f32) -> Vec<[f32; 3]> {
+        let (top, bottom, t) = cell_position(y, self.rows);
+        (0..self.columns)
+            .map(|col| {
+                let a = self.gains[top * self.columns + col];
+                let b = self.gains[bottom * self.columns + col];
+                [
+                    a[0] + (b[0] - a[0]) * t,
+                    a[1] + (b[1] - a[1]) * t,
+                    a[2] + (b[2] - a[2]) * t
+                ]
+            })
+            .collect()
+    }
+}
+
+fn map_path(sensor: &str) -> Option<PathBuf> {
+    let mut path = dirs::config_dir()?;
+    path.push("camcam");
+    path.push("lens_shading");
+    path.push(format!("{}.toml", sensor));
+    Some(path)
+}
+
+fn channel_index(channel: Channel) -> usize {
+    match channel {
+        Channel::Red => 0,
+        Channel::Green => 1,
+        Channel::Blue => 2
+    }
+}
+
+// Cells on both sides of position v (0 to 1) and how far between their
+// centres it is. Outside the outermost centres the slope carries on to the
+// edge of the frame, where vignetting is steepest.
+#[inline]
+fn cell_position(v: f32, cells: usize) -> (usize, usize, f32) {
+    if cells < 2 {
+        return (0, 0, 0.0);
+    }
+    let pos = v * cells as f32 - 0.5;
+    let first = (pos.max(0.0) as usize).min(cells - 2);
+    (first, first + 1, pos - first as f32)
+}
+
+#[inline]
+fn gain_at(line: &[[f32; 3]], x: f32, channel: usize) -> f32 {
+    let (left, right, t) = cell_position(x, line.len());
+    let (a, b) = (line[left][channel], line[right][channel]);
+    a + (b - a) * t
+}
+
+// Black level subtraction and the gain map, for one frame.
+#[derive(Clone, Copy)]
+pub struct Correction<'a> {
+    pub black_level: u16,
+    pub max: u16,
+    pub map: Option<&'a ShadingMap>,
+    // Adds the black level back afterwards, for DNG which records it.
+    // Otherwise the result is stretched back to the full range.
+    pub keep_black: bool
+}
+
+impl<'a> Correction<'a> {
+    // Corrects rows first_row.. of a frame that is height rows tall, in
+    // place.
+    pub fn apply(&self, data: &mut [u16], width: usize, pattern: CfaPattern, first_row: usize, height: usize) {
+        if self.black_level == 0 && self.map.is_none() {
+            return;
+        }
+
+        let black = self.black_level as f32;
+        let max = self.max as f32;
+        let (offset, stretch) = if self.keep_black {
+            (black, 1.0)
+        } else {
+            (0.0, max / (max - black).max(1.0))
+        };
+
+        // Where every column falls on the grid, the same for all rows.
+        let columns = self.map
+            .map(|m| (0..width).map(|col| cell_position((col as f32 + 0.5) / width as f32, m.columns)).collect::<Vec<_>>());
+
+        for (i, line) in data.chunks_mut(width).enumerate() {
+            let row = first_row + i;
+            let channels = [channel_index(pattern.channel_at(row, 0)), channel_index(pattern.channel_at(row, 1))];
+            match (self.map, &columns) {
+                (Some(map), Some(columns)) => {
+                    let gains = map.line_gains((row as f32 + 0.5) / height as f32);
+                    for (col, (v, (left, right, t))) in line.iter_mut().zip(columns.iter()).enumerate() {
+                        let channel = channels[col % 2];
+                        let (a, b) = (gains[*left][channel], gains[*right][channel]);
+                        let signal = (*v as f32 - black).max(0.0);
+                        *v = (signal * (a + (b - a) * t) * stretch + offset).min(max) as u16;
+                    }
+                },
+                _ => {
+                    for v in line.iter_mut() {
+                        let signal = (*v as f32 - black).max(0.0);
+                        *v = (signal * stretch + offset).min(max) as u16;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Same for interleaved 8-bit RGB, used on preview frames.
+pub fn correct_rgb8(data: &mut [u8], width: usize, black_level: u8, map: Option<&ShadingMap>) {
+    if black_level == 0 && map.is_none() {
+        return;
+    }
+
+    let height = data.len() / 3 / width;
+    let black = black_level as f32;
+    let stretch = 255.0 / (255.0 - black);
+
+    for (row, line) in data.chunks_mut(width * 3).enumerate() {
+        let gains = map.map(|m| m.line_gains((row as f32 + 0.5) / height as f32));
+        for (col, p) in line.chunks_exact_mut(3).enumerate() {
+            let x = (col as f32 + 0.5) / width as f32;
+            for channel in 0..3 {
+                let gain = match &gains {
+                    Some(g) => gain_at(g, x, channel),
+                    None => 1.0
+                };
+                let signal = (p[channel] as f32 - black).max(0.0);
+                p[channel] = (signal * gain * stretch).min(255.0) as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 128;
+    const HEIGHT: usize = 96;
+    const BLACK: u16 = 64;
+    const MAX: u16 = 1023;
+    // Flat grey seen through the colour filters.
+    const LEVELS: [f32; 3] = [300.0, 600.0, 400.0];
+
+    // An evenly lit surface through a lens that loses 40% to the corners.
+    fn flat_field() -> Vec<u16> {
+        let mut data = vec![0; WIDTH * HEIGHT];
+        for row in 0..HEIGHT {
+            for col in 0..WIDTH {
+                let x = (col as f32 + 0.5) / WIDTH as f32 * 2.0 - 1.0;
+                let y = (row as f32 + 0.5) / HEIGHT as f32 * 2.0 - 1.0;
+                let falloff = 1.0 - 0.4 * (x * x + y * y) / 2.0;
+                let channel = channel_index(CfaPattern::Rggb.channel_at(row, col));
+                data[row * WIDTH + col] = BLACK + (LEVELS[channel] * falloff).round() as u16;
+            }
+        }
+        data
+    }
+
+    // Smallest and largest value of every channel.
+    fn ranges(data: &[u16]) -> [(u16, u16); 3] {
+        let mut ranges = [(u16::MAX, 0); 3];
+        for (i, v) in data.iter().enumerate() {
+            let channel = channel_index(CfaPattern::Rggb.channel_at(i / WIDTH, i % WIDTH));
+            ranges[channel] = (ranges[channel].0.min(*v), ranges[channel].1.max(*v));
+        }
+        ranges
+    }
+
+    // Within a few percent, what is left comes from the grid being coarse
+    // and red and blue sitting half a pixel off the cell centres.
+    fn assert_flat(data: &[u16], offset: u16) {
+        for (channel, (low, high)) in ranges(data).iter().enumerate() {
+            let (low, high) = ((low - offset) as f32, (high - offset) as f32);
+            assert!(high / low < 1.05, "channel {} from {} to {}", channel, low, high);
+        }
+    }
+
+    #[test]
+    fn calibrated_map_flattens_the_flat_field() {
+        let mut data = flat_field();
+        let (low, high) = ranges(&data)[1];
+        assert!((high - BLACK) as f32 / (low - BLACK) as f32 > 1.5);
+
+        let map = ShadingMap::calibrate(&BayerFrame::new(&data, WIDTH, HEIGHT, CfaPattern::Rggb, 10), BLACK);
+        assert_eq!(map.gains.len(), map.columns * map.rows);
+        assert!(map.gains.iter().flatten().all(|g| (1.0..=MAX_GAIN).contains(g)));
+
+        let correction = Correction {
+            black_level: BLACK,
+            max: MAX,
+            map: Some(&map),
+            keep_black: false
+        };
+        correction.apply(&mut data, WIDTH, CfaPattern::Rggb, 0, HEIGHT);
+        assert_flat(&data, 0);
+
+        // The brightest spot keeps its level, only stretched for the black level.
+        let stretch = MAX as f32 / (MAX - BLACK) as f32;
+        let (_, high) = ranges(&data)[1];
+        assert!((high as f32 / (LEVELS[1] * stretch) - 1.0).abs() < 0.02, "{}", high);
+    }
+
+    #[test]
+    fn strips_match_the_whole_frame() {
+        let whole_frame = flat_field();
+        let map = ShadingMap::calibrate(&BayerFrame::new(&whole_frame, WIDTH, HEIGHT, CfaPattern::Rggb, 10), BLACK);
+        let correction = Correction {
+            black_level: BLACK,
+            max: MAX,
+            map: Some(&map),
+            keep_black: false
+        };
+        let mut whole = whole_frame.clone();
+        correction.apply(&mut whole, WIDTH, CfaPattern::Rggb, 0, HEIGHT);
+        let mut strips = whole_frame;
+        for (i, strip) in strips.chunks_mut(WIDTH * 10).enumerate() {
+            correction.apply(strip, WIDTH, CfaPattern::Rggb, i * 10, HEIGHT);
+        }
+        assert_eq!(strips, whole);
+    }
+
+    #[test]
+    fn keep_black_keeps_the_black_level() {
+        let mut data = flat_field();
+        let map = ShadingMap::calibrate(&BayerFrame::new(&data, WIDTH, HEIGHT, CfaPattern::Rggb, 10), BLACK);
+        // A dark patch in the corner, where the gains are largest.
+        for row in 0..4 {
+            data[row * WIDTH..row * WIDTH + 4].copy_from_slice(&[BLACK; 4]);
+        }
+        data[WIDTH * 5] = BLACK - 10;
+
+        let correction = Correction {
+            black_level: BLACK,
+            max: MAX,
+            map: Some(&map),
+            keep_black: true
+        };
+        correction.apply(&mut data, WIDTH, CfaPattern::Rggb, 0, HEIGHT);
+        for row in 0..4 {
+            assert_eq!(&data[row * WIDTH..row * WIDTH + 4], &[BLACK; 4]);
+        }
+        // Noise under the black level is clipped to it.
+        assert_eq!(data[WIDTH * 5], BLACK);
+        // The rest is flattened on top of it.
+        assert_flat(&data[WIDTH * 8..], BLACK);
+    }
+
+    #[test]
+    fn black_level_alone_stretches_to_full_range() {
+        let mut data = vec![0, BLACK, BLACK + 1, 500, MAX];
+        let correction = Correction {
+            black_level: BLACK,
+            max: MAX,
+            map: None,
+            keep_black: false
+        };
+        correction.apply(&mut data, 5, CfaPattern::Rggb, 0, 1);
+        assert_eq!(&data[..3], &[0, 0, 1]);
+        assert_eq!(data[4], MAX);
+
+        let mut untouched = vec![0, 5, MAX];
+        let correction = Correction {
+            black_level: 0,
+            ..correction
+        };
+        correction.apply(&mut untouched, 3, CfaPattern::Rggb, 0, 1);
+        assert_eq!(untouched, [0, 5, MAX]);
+    }
+
+    #[test]
+    fn rotated_map_fits_the_turned_frame() {
+        let map = ShadingMap::calibrate(&BayerFrame::new(&flat_field(), WIDTH, HEIGHT, CfaPattern::Rggb, 10), BLACK);
+        let (width, height) = (24, 18);
+        let frame = (0..width * height * 3).map(|i| (i * 7 % 200) as u8).collect::<Vec<u8>>();
+        let turn = |data: &[u8], rotation: Rotation| {
+            let turned_width = if rotation.swaps_sides() { height } else { width };
+            let mut out = vec![0; data.len()];
+            for (i, p) in data.chunks_exact(3).enumerate() {
+                let (x, y) = rotation.position(i % width, i / width, width, height);
+                out[(y * turned_width + x) * 3..][..3].copy_from_slice(p);
+            }
+            out
+        };
+
+        let mut corrected = frame.clone();
+        correct_rgb8(&mut corrected, width, 8, Some(&map));
+        for &rotation in [Rotation::Clockwise, Rotation::UpsideDown, Rotation::Counterclockwise].iter() {
+            let mut turned = turn(&frame, rotation);
+            let turned_width = if rotation.swaps_sides() { height } else { width };
+            correct_rgb8(&mut turned, turned_width, 8, Some(&map.rotated(rotation)));
+            let expected = turn(&corrected, rotation);
+            // Interpolation in the other order may round the other way.
+            assert!(turned.iter().zip(expected.iter()).all(|(a, b)| (*a as i32 - *b as i32).abs() <= 1), "{:?}", rotation);
+        }
+    }
+
+    #[test]
+    fn preview_correction_uses_the_same_map() {
+        let map = ShadingMap::calibrate(&BayerFrame::new(&flat_field(), WIDTH, HEIGHT, CfaPattern::Rggb, 10), BLACK);
+        // Superpixel preview of the flat field, half the size.
+        let (width, height) = (WIDTH / 2, HEIGHT / 2);
+        let mut rgb = Vec::with_capacity(width * height * 3);
+        for row in 0..height {
+            for col in 0..width {
+                let x = (col as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+                let y = (row as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+                let falloff = 1.0 - 0.4 * (x * x + y * y) / 2.0;
+                rgb.extend(LEVELS.iter().map(|l| (l / 4.0 * falloff).round() as u8));
+            }
+        }
+        correct_rgb8(&mut rgb, width, 0, Some(&map));
+        for channel in 0..3 {
+            let values = rgb.iter().skip(channel).step_by(3);
+            let (low, high) = (*values.clone().min().unwrap(), *values.max().unwrap());
+            assert!(high as f32 / (low as f32) < 1.08, "channel {} from {} to {}", channel, low, high);
+        }
+    }
+}
@@ -0,0 +1,342 @@
+// White balance estimation and correction. Gains are applied to the raw
+// samples before demosaicing, green is always left alone.
+use serde::Deserialize;
+use crate::camera::bayer::{BayerFrame, CfaPattern, Channel};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WhiteBalance {
+    // Assumes the scene averages to gray.
+    #[default]
+    GrayWorld,
+    // Assumes the brightest parts of the scene are white.
+    WhitePatch,
+    Daylight,
+    Cloudy,
+    Tungsten,
+    Fluorescent,
+    Kelvin(u32)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WbGains {
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32
+}
+
+impl Default for WbGains {
+    fn default() -> Self {
+        WbGains {
+            red: 1.0,
+            green: 1.0,
+            blue: 1.0
+        }
+    }
+}
+
+// Raw gains, [red, blue] with green at 1, that make a white card neutral
+// under two reference lights. They differ a lot between sensors, so every
+// colour profile has its own. Presets and colour temperatures are found
+// between them.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct WhitePoints {
+    // D65, 6500K.
+    pub daylight: [f32; 2],
+    // Standard illuminant A, 2856K.
+    pub tungsten: [f32; 2]
+}
+
+// Typical of small Bayer sensors without an IR cut tuned to them.
+impl Default for WhitePoints {
+    fn default() -> Self {
+        WhitePoints {
+            daylight: [1.9, 1.6],
+            tungsten: [1.15, 2.7]
+        }
+    }
+}
+
+const DAYLIGHT_KELVIN: f32 = 6500.0;
+const TUNGSTEN_KELVIN: f32 = 2856.0;
+// How far past the two reference lights temperatures are extrapolated, as a
+// share of the distance between them in mireds.
+const MAX_EXTRAPOLATION: f32 = 0.5;
+
+// Channel means are skipped once this close to clipping, clipped pixels
+// have lost their colour.
+const CLIP_THRESHOLD: f32 = 0.95;
+// Share of the brightest pixels used as the white reference.
+const WHITE_PATCH_SHARE: f32 = 0.01;
+// Anything outside this is a broken estimate, not a light source.
+const MIN_GAIN: f32 = 0.25;
+const MAX_GAIN: f32 = 8.0;
+
+impl WhiteBalance {
+    // "auto" or "gray-world", "white-patch", a preset like "daylight", or a
+    // colour temperature like "5500K".
+    pub fn from_name(name: &str) -> Option<Self> {
+        let white_balance = match name.to_lowercase().as_str() {
+            "auto" | "gray-world" => WhiteBalance::GrayWorld,
+            "white-patch" => WhiteBalance::WhitePatch,
+            "daylight" => WhiteBalance::Daylight,
+            "cloudy" => WhiteBalance::Cloudy,
+            "tungsten" => WhiteBalance::Tungsten,
+            "fluorescent" => WhiteBalance::Fluorescent,
+            other => WhiteBalance::Kelvin(other.strip_suffix('k')?.parse().ok()?)
+        };
+        Some(white_balance)
+    }
+
+    pub fn is_auto(self) -> bool {
+        matches!(self, WhiteBalance::GrayWorld | WhiteBalance::WhitePatch)
+    }
+
+    // Gains for the given pixels. Presets ignore the pixels, so an empty
+    // iterator is fine for those, and take the sensor's white points
+    // instead.
+    pub fn gains<I: Iterator<Item = [u16; 3]>>(self, pixels: I, max: u16, white_points: &WhitePoints) -> WbGains {
+        match self {
+            WhiteBalance::GrayWorld => gray_world(pixels, max),
+            WhiteBalance::WhitePatch => white_patch(pixels, max),
+            WhiteBalance::Daylight => white_points.kelvin_gains(5500),
+            WhiteBalance::Cloudy => white_points.kelvin_gains(6500),
+            WhiteBalance::Tungsten => white_points.kelvin_gains(3200),
+            WhiteBalance::Fluorescent => {
+                // Tubes have a green spike on top of their colour temperature.
+                let gains = white_points.kelvin_gains(4000);
+                WbGains {
+                    red: gains.red / 0.9,
+                    green: 1.0,
+                    blue: gains.blue / 0.9
+                }
+            },
+            WhiteBalance::Kelvin(k) => white_points.kelvin_gains(k)
+        }
+    }
+
+    pub fn frame_gains(self, frame: &BayerFrame, white_points: &WhitePoints) -> WbGains {
+        self.gains(superpixels(frame, 4), frame.max_value(), white_points)
+    }
+}
+
+impl WhitePoints {
+    // The light's colour in camera RGB is taken to move in a straight line
+    // between the two reference lights, in mireds like DNG interpolates its
+    // calibrations.
+    pub fn kelvin_gains(&self, kelvin: u32) -> WbGains {
+        let mired = |k: f32| 1.0e6 / k;
+        let share = (mired(kelvin.max(1000) as f32) - mired(DAYLIGHT_KELVIN)) / (mired(TUNGSTEN_KELVIN) - mired(DAYLIGHT_KELVIN));
+        let share = share.max(-MAX_EXTRAPOLATION).min(1.0 + MAX_EXTRAPOLATION);
+        // Camera RGB of a neutral object, which the gains undo.
+        let neutral = |daylight: f32, tungsten: f32| {
+            let (daylight, tungsten) = (1.0 / daylight, 1.0 / tungsten);
+            daylight + (tungsten - daylight) * share
+        };
+
+        gains_from_means(neutral(self.daylight[0], self.tungsten[0]), 1.0, neutral(self.daylight[1], self.tungsten[1]))
+    }
+}
+
+// One [r, g, b] per 2x2 block, taking every step:th block in both directions.
+pub fn superpixels<'a>(frame: &'a BayerFrame, step: usize) -> impl Iterator<Item = [u16; 3]> + 'a {
+    let (red_row, red_col) = frame.pattern.red_position();
+    let (green_row, green_col) = frame.pattern.green_position();
+    let (blue_row, blue_col) = frame.pattern.blue_position();
+    let width = frame.width;
+    let at = move |row: usize, col: usize| frame.data[row * width + col];
+
+    (0..frame.height / 2).step_by(step).flat_map(move |block_row| {
+        (0..width / 2).step_by(step).map(move |block_col| {
+            let (row, col) = (block_row * 2, block_col * 2);
+            [
+                at(row + red_row, col + red_col),
+                at(row + green_row, col + green_col),
+                at(row + blue_row, col + blue_col)
+            ]
+        })
+    })
+}
+
+fn gray_world<I: Iterator<Item = [u16; 3]>>(pixels: I, max: u16) -> WbGains {
+    let clip = (max as f32 * CLIP_THRESHOLD) as u16;
+    let mut sums = [0u64; 3];
+
+    for p in pixels.filter(|p| p.iter().all(|v| *v < clip)) {
+        sums[0] += p[0] as u64;
+        sums[1] += p[1] as u64;
+        sums[2] += p[2] as u64;
+    }
+
+    gains_from_means(sums[0] as f32, sums[1] as f32, sums[2] as f32)
+}
+
+fn white_patch<I: Iterator<Item = [u16; 3]>>(pixels: I, max: u16) -> WbGains {
+    let clip = (max as f32 * CLIP_THRESHOLD) as u16;
+    let mut pixels = pixels
+        .filter(|p| p.iter().all(|v| *v < clip))
+        .collect::<Vec<[u16; 3]>>();
+
+    if pixels.is_empty() {
+        return WbGains::default();
+    }
+
+    let brightness = |p: &[u16; 3]| p[0] as u32 + 2 * p[1] as u32 + p[2] as u32;
+    pixels.sort_unstable_by_key(|p| std::cmp::Reverse(brightness(p)));
+    let count = ((pixels.len() as f32 * WHITE_PATCH_SHARE) as usize).max(1);
+
+    let mut sums = [0u64; 3];
+    for p in pixels.iter().take(count) {
+        sums[0] += p[0] as u64;
+        sums[1] += p[1] as u64;
+        sums[2] += p[2] as u64;
+    }
+
+    gains_from_means(sums[0] as f32, sums[1] as f32, sums[2] as f32)
+}
+
+fn gains_from_means(red: f32, green: f32, blue: f32) -> WbGains {
+    if red <= 0.0 || green <= 0.0 || blue <= 0.0 {
+        return WbGains::default();
+    }
+
+    WbGains {
+        red: (green / red).max(MIN_GAIN).min(MAX_GAIN),
+        green: 1.0,
+        blue: (green / blue).max(MIN_GAIN).min(MAX_GAIN)
+    }
+}
+
+// Scales red and blue samples in place.
+pub fn apply(data: &mut [u16], width: usize, pattern: CfaPattern, max: u16, gains: WbGains) {
+    let red = (gains.red * 256.0) as u32;
+    let blue = (gains.blue * 256.0) as u32;
+    let max = max as u32;
+
+    for (row, line) in data.chunks_mut(width).enumerate() {
+        for (col, v) in line.iter_mut().enumerate() {
+            let gain = match pattern.channel_at(row, col) {
+                Channel::Red => red,
+                Channel::Blue => blue,
+                Channel::Green => continue
+            };
+            *v = ((*v as u32 * gain) >> 8).min(max) as u16;
+        }
+    }
+}
+
+// Same for interleaved 8-bit RGB, used on preview frames.
+pub fn apply_rgb8(data: &mut [u8], gains: WbGains) {
+    let red = (gains.red * 256.0) as u32;
+    let blue = (gains.blue * 256.0) as u32;
+
+    for p in data.chunks_exact_mut(3) {
+        p[0] = ((p[0] as u32 * red) >> 8).min(255) as u8;
+        p[2] = ((p[2] as u32 * blue) >> 8).min(255) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 64;
+    const HEIGHT: usize = 48;
+    const MAX: u16 = 1023;
+
+    // A scene of grays seen through a colour cast, the gains that undo it
+    // are the inverse of the cast. Every 2x2 block gets one gray level.
+    fn cast_frame(cast: [f32; 3], level: impl Fn(usize, usize) -> f32) -> Vec<u16> {
+        let mut data = vec![0; WIDTH * HEIGHT];
+        for row in 0..HEIGHT {
+            for col in 0..WIDTH {
+                let channel = match CfaPattern::Rggb.channel_at(row, col) {
+                    Channel::Red => 0,
+                    Channel::Green => 1,
+                    Channel::Blue => 2
+                };
+                data[row * WIDTH + col] = (level(row / 2, col / 2) * cast[channel] * MAX as f32).round() as u16;
+            }
+        }
+        data
+    }
+
+    fn assert_gains(gains: WbGains, red: f32, blue: f32) {
+        assert!((gains.red - red).abs() < 0.02, "red {} instead of {}", gains.red, red);
+        assert_eq!(gains.green, 1.0);
+        assert!((gains.blue - blue).abs() < 0.02, "blue {} instead of {}", gains.blue, blue);
+    }
+
+    #[test]
+    fn gray_world_undoes_a_cast() {
+        let data = cast_frame([0.5, 1.0, 0.7], |row, col| 0.1 + 0.6 * ((row * 7 + col * 3) % 11) as f32 / 10.0);
+        let frame = BayerFrame::new(&data, WIDTH, HEIGHT, CfaPattern::Rggb, 10);
+        let gains = WhiteBalance::GrayWorld.frame_gains(&frame, &WhitePoints::default());
+        assert_gains(gains, 2.0, 1.0 / 0.7);
+    }
+
+    #[test]
+    fn white_patch_undoes_a_cast() {
+        // Mostly a red wall, which fools gray world, with a white card.
+        let card = |row: usize, col: usize| row < 4 && col < 4;
+        let mut data = cast_frame([0.5, 1.0, 0.7], |row, col| if card(row, col) { 0.8 } else { 0.3 });
+        for row in 8..HEIGHT {
+            for col in 0..WIDTH {
+                if CfaPattern::Rggb.channel_at(row, col) != Channel::Red {
+                    data[row * WIDTH + col] /= 3;
+                }
+            }
+        }
+        let frame = BayerFrame::new(&data, WIDTH, HEIGHT, CfaPattern::Rggb, 10);
+        let pixels = superpixels(&frame, 1);
+        assert_gains(WhiteBalance::WhitePatch.gains(pixels, MAX, &WhitePoints::default()), 2.0, 1.0 / 0.7);
+    }
+
+    #[test]
+    fn clipped_pixels_are_left_out() {
+        let data = cast_frame([0.5, 1.0, 0.7], |row, _| if row < 4 { 1.5 } else { 0.4 });
+        let frame = BayerFrame::new(&data, WIDTH, HEIGHT, CfaPattern::Rggb, 10);
+        let pixels = superpixels(&frame, 1).map(|p| [p[0].min(MAX), p[1].min(MAX), p[2].min(MAX)]);
+        assert_gains(WhiteBalance::GrayWorld.gains(pixels, MAX, &WhitePoints::default()), 2.0, 1.0 / 0.7);
+    }
+
+    #[test]
+    fn presets_come_from_the_white_points() {
+        let points = WhitePoints {
+            daylight: [1.8, 1.5],
+            tungsten: [1.1, 2.6]
+        };
+        let gains = |wb: WhiteBalance| wb.gains(std::iter::empty(), MAX, &points);
+        assert_gains(gains(WhiteBalance::Kelvin(6500)), 1.8, 1.5);
+        assert_gains(gains(WhiteBalance::Cloudy), 1.8, 1.5);
+        assert_gains(gains(WhiteBalance::Kelvin(2856)), 1.1, 2.6);
+
+        // Warmer light needs less red and more blue, all the way.
+        let mut last = gains(WhiteBalance::Kelvin(12000));
+        for kelvin in (2000..12000).rev().step_by(500) {
+            let next = gains(WhiteBalance::Kelvin(kelvin));
+            assert!(next.red <= last.red && next.blue >= last.blue, "{}K", kelvin);
+            last = next;
+        }
+        let daylight = gains(WhiteBalance::Daylight);
+        assert!(daylight.red > 1.5 && daylight.blue > 1.5, "{:?}", daylight);
+    }
+
+    #[test]
+    fn names() {
+        let name = |n: &str| WhiteBalance::from_name(n);
+        assert_eq!(name("auto"), Some(WhiteBalance::GrayWorld));
+        assert_eq!(name("gray-world"), Some(WhiteBalance::GrayWorld));
+        assert_eq!(name("white-patch"), Some(WhiteBalance::WhitePatch));
+        assert_eq!(name("Daylight"), Some(WhiteBalance::Daylight));
+        assert_eq!(name("cloudy"), Some(WhiteBalance::Cloudy));
+        assert_eq!(name("tungsten"), Some(WhiteBalance::Tungsten));
+        assert_eq!(name("fluorescent"), Some(WhiteBalance::Fluorescent));
+        assert_eq!(name("5500K"), Some(WhiteBalance::Kelvin(5500)));
+        assert_eq!(name("3200k"), Some(WhiteBalance::Kelvin(3200)));
+        assert_eq!(name("5500"), None);
+        assert_eq!(name("K"), None);
+        assert_eq!(name("sunny"), None);
+        assert!(WhiteBalance::GrayWorld.is_auto() && WhiteBalance::WhitePatch.is_auto());
+        assert!(!WhiteBalance::Kelvin(5500).is_auto());
+    }
+}
@@ -0,0 +1,660 @@
+// Finding camera sensors and the video nodes their frames can reach, from the
+// media controller graph of every media device in the system. Nothing here
+// knows about particular sensors or boards, entities are told apart by their
+// function.
+use linux_media::*;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc
+};
+use v4l_subdev::{V4L2_CAMERA_ORIENTATION_BACK, V4L2_CAMERA_ORIENTATION_FRONT, V4L2_CID_CAMERA_ORIENTATION};
+use crate::camera::Error;
+use crate::camera::media_device::{get_device_path_from_interface, MediaDevice};
+use crate::camera::subdevice::{FrameFormat, Lens, Subdevice};
+use crate::camera::topology::*;
+use crate::camera::video_device::VideoDevice;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntityKind {
+    Sensor,
+    // A /dev/video* node frames come out of.
+    VideoNode,
+    Isp,
+    // Debayers, scalers and format converters, like the ones of vimc.
+    Processing,
+    // CSI receivers, parallel bridges and muxes.
+    Bridge,
+    // Focus lens drivers, linked to their sensor by an ancillary link.
+    Lens,
+    Other
+}
+
+impl EntityKind {
+    pub fn of(entity: &Entity) -> Self {
+        match entity.function {
+            MEDIA_ENT_F_CAM_SENSOR => EntityKind::Sensor,
+            MEDIA_ENT_F_IO_V4L => EntityKind::VideoNode,
+            MEDIA_ENT_F_PROC_VIDEO_ISP => EntityKind::Isp,
+            MEDIA_ENT_F_PROC_VIDEO_PIXEL_ENC_CONV | MEDIA_ENT_F_PROC_VIDEO_PIXEL_FORMATTER |
+            MEDIA_ENT_F_PROC_VIDEO_SCALER | MEDIA_ENT_F_PROC_VIDEO_LUT => EntityKind::Processing,
+            MEDIA_ENT_F_VID_IF_BRIDGE | MEDIA_ENT_F_VID_MUX => EntityKind::Bridge,
+            MEDIA_ENT_F_LENS => EntityKind::Lens,
+            _ => EntityKind::Other
+        }
+    }
+}
+
+// A data link between two entities, by entity id and pad index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hop {
+    pub source: u32,
+    pub source_pad: u32,
+    pub sink: u32,
+    pub sink_pad: u32,
+    pub immutable: bool
+}
+
+// Which way a sensor faces, when the driver knows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Facing {
+    Back,
+    Front,
+    Unknown
+}
+
+// An entity between the sensor and the video node that has a subdevice node,
+// with the pads frames go in and out of on the way.
+pub struct Stage {
+    pub subdevice: Subdevice,
+    pub sink_pad: u32,
+    pub source_pad: u32
+}
+
+// A sensor, the video node its frames end up in and every link in between.
+pub struct CapturePipeline {
+    pub media_device: Arc<MediaDevice>,
+    pub media_path: PathBuf,
+    pub sensor: Arc<Subdevice>,
+    // Where focus_absolute is for sensors with a separate lens driver.
+    pub lens: Option<Arc<Lens>>,
+    pub facing: Facing,
+    pub video: VideoDevice,
+    pub video_path: PathBuf,
+    // Entities between the sensor and the video node, in order.
+    pub entities: Vec<(Entity, EntityKind)>,
+    // The ones of them whose formats can be set, in the same order.
+    pub stages: Vec<Stage>,
+    pub hops: Vec<Hop>
+}
+
+impl CapturePipeline {
+    // Entity names are the model followed by the bus address, like
+    // "ov5640 1-004c".
+    pub fn sensor_model(&self) -> &str {
+        self.sensor.entity.name.split_whitespace().next().unwrap_or("")
+    }
+
+    pub fn name(&self) -> String {
+        format!("{} -> {}", self.sensor.entity.name, self.video.entity.name)
+    }
+
+    // Carries format, what the sensor sends, through every stage the way
+    // media-ctl -V does and returns what comes out of the last one for the
+    // video node. Each stage gets it on its sink pad and is asked to pass it
+    // on unchanged, drivers that convert or scale change it on the source
+    // pad. Links that end up with different formats on their two ends fail
+    // here instead of when streaming starts.
+    pub fn propagate(&self, format: &FrameFormat) -> Result<FrameFormat, Error> {
+        let mut format = format.clone();
+        for stage in self.stages.iter() {
+            let sub = &stage.subdevice;
+            sub.set_pad_format(stage.sink_pad, format.width, format.height, format.code)?;
+            format = sub.set_pad_format(stage.source_pad, format.width, format.height, format.code)?.format;
+        }
+
+        for hop in self.hops.iter() {
+            let source = self.pad_format(hop.source, hop.source_pad)?;
+            let sink = self.pad_format(hop.sink, hop.sink_pad)?;
+            let (source, sink) = match (source, sink) {
+                (Some(source), Some(sink)) => (source, sink),
+                // The video node is checked when its format is set.
+                _ => continue
+            };
+            if !source.matches(&sink) {
+                return Err(Error::UnsupportedFormat(format!("{} pad {} sends {} but {} pad {} takes {}",
+                    self.entity_name(hop.source), hop.source_pad, source, self.entity_name(hop.sink), hop.sink_pad, sink)));
+            }
+        }
+
+        Ok(format)
+    }
+
+    // Active format on a pad of the sensor or a stage, None for entities
+    // without a subdevice node.
+    fn pad_format(&self, entity: u32, pad: u32) -> Result<Option<FrameFormat>, Error> {
+        let subdevice = std::iter::once(&*self.sensor)
+            .chain(self.stages.iter().map(|s| &s.subdevice))
+            .find(|s| s.entity.id == entity);
+        match subdevice {
+            Some(subdevice) => Ok(Some(subdevice.pad_format(pad)?.format)),
+            None => Ok(None)
+        }
+    }
+
+    fn disable(&self, keep: &[Hop]) -> Result<(), Error> {
+        for hop in self.hops.iter().filter(|h| !h.immutable && !keep.contains(h)) {
+            self.setup_link(hop, false)?;
+        }
+        Ok(())
+    }
+
+    fn enable(&self) -> Result<(), Error> {
+        for hop in self.hops.iter().filter(|h| !h.immutable) {
+            self.setup_link(hop, true)?;
+        }
+        Ok(())
+    }
+
+    fn setup_link(&self, hop: &Hop, enable: bool) -> Result<(), Error> {
+        self.media_device.setup_link(hop, enable).map_err(|e| Error::Link {
+            source: self.entity_name(hop.source),
+            sink: self.entity_name(hop.sink),
+            errno: e.errno().unwrap_or(0)
+        })
+    }
+
+    fn entity_name(&self, id: u32) -> String {
+        let sensor = &self.sensor.entity;
+        let video = &self.video.entity;
+        std::iter::once(sensor)
+            .chain(self.entities.iter().map(|(e, _)| e))
+            .chain(std::iter::once(video))
+            .find(|e| e.id == id)
+            .map_or_else(|| format!("entity {}", id), |e| e.name.clone())
+    }
+}
+
+// Makes pipelines[index] the only one streaming on its media device. Links of
+// the others are taken down first, bridges usually take one source at a time.
+pub fn activate(pipelines: &[Arc<CapturePipeline>], index: usize) -> Result<(), Error> {
+    let active = &pipelines[index];
+
+    for (i, pipeline) in pipelines.iter().enumerate() {
+        if i != index && pipeline.media_path == active.media_path {
+            pipeline.disable(&active.hops)?;
+        }
+    }
+
+    active.enable()
+}
+
+// Takes down every link that can be, so that no sensor is streaming.
+pub fn deactivate_all(pipelines: &[Arc<CapturePipeline>]) -> Result<(), Error> {
+    for pipeline in pipelines.iter() {
+        pipeline.disable(&[])?;
+    }
+    Ok(())
+}
+
+// The /dev/media* nodes, in order.
+pub fn media_devices() -> io::Result<Vec<PathBuf>> {
+    let mut paths = fs::read_dir("/dev")?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.file_name().map_or(false, |n| n.to_string_lossy().starts_with("media")))
+        .collect::<Vec<PathBuf>>();
+    paths.sort();
+    Ok(paths)
+}
+
+// Every capture pipeline on every media device, back facing sensors first.
+pub fn discover() -> Vec<CapturePipeline> {
+    let paths = match media_devices() {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("Can't list /dev: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut pipelines = Vec::new();
+    for path in paths {
+        let found = MediaDevice::open(&path)
+            .and_then(|md| find_pipelines(Arc::new(md), &path));
+
+        match found {
+            Ok(found) => pipelines.extend(found),
+            Err(e) => eprintln!("Skipping media device {}: {}", path.to_string_lossy(), e)
+        }
+    }
+
+    // Stable, so discovery order decides between sensors facing the same way.
+    pipelines.sort_by_key(|p| match p.facing {
+        Facing::Back => 0,
+        Facing::Unknown => 1,
+        Facing::Front => 2
+    });
+
+    for p in pipelines.iter() {
+        eprintln!("Found capture pipeline {} ({:?}) on {}", p.name(), p.facing, p.media_path.to_string_lossy());
+    }
+
+    pipelines
+}
+
+fn find_pipelines(media_device: Arc<MediaDevice>, path: &Path) -> Result<Vec<CapturePipeline>, Error> {
+    let topology = media_device.topology()?;
+    let entities = topology.entities.iter()
+        .map(|e| (e.id, e))
+        .collect::<HashMap<u32, &Entity>>();
+    let hops = data_hops(&topology);
+
+    let mut pipelines = Vec::new();
+
+    for sensor_entity in topology.entities.iter().filter(|e| EntityKind::of(e) == EntityKind::Sensor) {
+        let routes = routes_to_video_nodes(sensor_entity.id, &hops, &entities);
+        if routes.is_empty() {
+            continue;
+        }
+
+        let sensor = match open_sensor(&topology, sensor_entity) {
+            Ok(sensor) => Arc::new(sensor),
+            Err(e) => {
+                eprintln!("Can't open sensor {}: {}", sensor_entity.name, e);
+                continue;
+            }
+        };
+        let facing = match sensor.control(V4L2_CID_CAMERA_ORIENTATION) {
+            Ok(v) if v as u32 == V4L2_CAMERA_ORIENTATION_BACK => Facing::Back,
+            Ok(v) if v as u32 == V4L2_CAMERA_ORIENTATION_FRONT => Facing::Front,
+            _ => Facing::Unknown
+        };
+        let lens = match lens_of(&topology, &entities, sensor_entity.id) {
+            Some(entity) => match open_lens(&topology, entity) {
+                Ok(lens) => Some(Arc::new(lens)),
+                Err(e) => {
+                    eprintln!("Can't open lens {} of {}: {}", entity.name, sensor_entity.name, e);
+                    None
+                }
+            },
+            None => None
+        };
+
+        for (video_id, route) in routes {
+            match build_pipeline(&media_device, path, &topology, &entities, &sensor, facing, route) {
+                Ok(pipeline) => pipelines.push(CapturePipeline { lens: lens.clone(), ..pipeline }),
+                Err(e) => eprintln!("Skipping route from {} to {}: {}", sensor_entity.name, entities[&video_id].name, e)
+            }
+        }
+    }
+
+    Ok(pipelines)
+}
+
+// Puts together the pipeline along route, a way from sensor to a video node.
+// Every subdevice on the way is opened on the pads the route goes through,
+// for formats to be set pad by pad.
+fn build_pipeline(media_device: &Arc<MediaDevice>, path: &Path, topology: &Topology, entities: &HashMap<u32, &Entity>, sensor: &Arc<Subdevice>, facing: Facing, route: Vec<Hop>) -> Result<CapturePipeline, Error> {
+    let last = route.last().ok_or_else(|| Error::DeviceNotFound(format!("no route from {}", sensor.entity.name)))?;
+    let video_entity = entities[&last.sink];
+    let not_found = |what: &str| Error::DeviceNotFound(format!("no {} for {}", what, video_entity.name));
+    let video_pad = topology.pads.iter()
+        .find(|p| p.entity_id == last.sink && p.index == last.sink_pad)
+        .ok_or_else(|| not_found("sink pad"))?;
+    let video_interface = interface_of(topology, last.sink)
+        .ok_or_else(|| not_found("device node"))?;
+    let video_path = get_device_path_from_interface(video_interface)?;
+
+    let between = route.iter()
+        .skip(1)
+        .map(|hop| entities[&hop.source])
+        .map(|e| (e.clone(), EntityKind::of(e)))
+        .collect();
+    let stages = open_stages(topology, &route)?;
+
+    Ok(CapturePipeline {
+        media_device: media_device.clone(),
+        media_path: path.to_path_buf(),
+        sensor: sensor.clone(),
+        // Found by the caller, it isn't on the route.
+        lens: None,
+        facing,
+        video: VideoDevice::new(video_entity, video_interface, video_pad),
+        video_path,
+        entities: between,
+        stages,
+        hops: route
+    })
+}
+
+// The data links that frames can take. Links that are off for good can't be
+// part of a route.
+fn data_hops(topology: &Topology) -> Vec<Hop> {
+    let pads = topology.pads.iter()
+        .map(|p| (p.id, p))
+        .collect::<HashMap<u32, &Pad>>();
+
+    topology.links.iter()
+        .filter(|l| l.flags & MEDIA_LNK_FL_LINK_TYPE == MEDIA_LNK_FL_DATA_LINK)
+        .filter(|l| l.flags & MEDIA_LNK_FL_IMMUTABLE == 0 || l.flags & MEDIA_LNK_FL_ENABLED != 0)
+        .filter_map(|l| {
+            let source = pads.get(&l.source_id)?;
+            let sink = pads.get(&l.sink_id)?;
+            Some(Hop {
+                source: source.entity_id,
+                source_pad: source.index,
+                sink: sink.entity_id,
+                sink_pad: sink.index,
+                immutable: l.flags & MEDIA_LNK_FL_IMMUTABLE != 0
+            })
+        })
+        .collect()
+}
+
+// Shortest route over data links from the sensor to every video node it can
+// reach, without passing through other sensors.
+fn routes_to_video_nodes(sensor: u32, hops: &[Hop], entities: &HashMap<u32, &Entity>) -> Vec<(u32, Vec<Hop>)> {
+    let mut came_by: HashMap<u32, &Hop> = HashMap::new();
+    let mut queue = VecDeque::new();
+    let mut found = Vec::new();
+    queue.push_back(sensor);
+
+    while let Some(id) = queue.pop_front() {
+        for hop in hops.iter().filter(|h| h.source == id) {
+            let kind = match entities.get(&hop.sink) {
+                Some(e) => EntityKind::of(e),
+                None => continue
+            };
+            if hop.sink == sensor || kind == EntityKind::Sensor || came_by.contains_key(&hop.sink) {
+                continue;
+            }
+
+            came_by.insert(hop.sink, hop);
+            if kind == EntityKind::VideoNode {
+                found.push(hop.sink);
+            } else {
+                queue.push_back(hop.sink);
+            }
+        }
+    }
+
+    found.into_iter()
+        .map(|video| {
+            let mut route = Vec::new();
+            let mut id = video;
+            while let Some(hop) = came_by.get(&id) {
+                route.push((*hop).clone());
+                id = hop.source;
+            }
+            route.reverse();
+            (video, route)
+        })
+        .collect()
+}
+
+// The device node interface of an entity, if it has one.
+pub fn interface_of<'a>(topology: &'a Topology, entity_id: u32) -> Option<&'a Interface> {
+    topology.links.iter()
+        .filter(|l| l.flags & MEDIA_LNK_FL_LINK_TYPE == MEDIA_LNK_FL_INTERFACE_LINK)
+        .find(|l| l.sink_id == entity_id)
+        .and_then(|l| topology.interfaces.iter().find(|i| i.id == l.source_id))
+}
+
+fn open_sensor(topology: &Topology, entity: &Entity) -> Result<Subdevice, Error> {
+    let not_found = |what: &str| Error::DeviceNotFound(format!("no {} for {}", what, entity.name));
+    let pad = topology.pads.iter()
+        .find(|p| p.entity_id == entity.id && p.flags & MEDIA_PAD_FL_SOURCE != 0)
+        .ok_or_else(|| not_found("source pad"))?;
+    let interface = interface_of(topology, entity.id)
+        .ok_or_else(|| not_found("subdevice node"))?;
+    let path = get_device_path_from_interface(interface)?;
+
+    Subdevice::open(&path, entity, interface, pad)
+}
+
+// The lens entity the sensor has an ancillary link to, if any. The link goes
+// from the sensor to the lens, by entity ids rather than pads.
+fn lens_of<'a>(topology: &Topology, entities: &HashMap<u32, &'a Entity>, sensor: u32) -> Option<&'a Entity> {
+    topology.links.iter()
+        .filter(|l| l.flags & MEDIA_LNK_FL_LINK_TYPE == MEDIA_LNK_FL_ANCILLARY_LINK)
+        .filter(|l| l.source_id == sensor)
+        .filter_map(|l| entities.get(&l.sink_id).copied())
+        .find(|e| EntityKind::of(e) == EntityKind::Lens)
+}
+
+fn open_lens(topology: &Topology, entity: &Entity) -> Result<Lens, Error> {
+    let interface = interface_of(topology, entity.id)
+        .ok_or_else(|| Error::DeviceNotFound(format!("no subdevice node for {}", entity.name)))?;
+    let path = get_device_path_from_interface(interface)?;
+
+    Lens::open(&path, entity)
+}
+
+// Subdevices along route past the sensor, opened on the pad the route leaves
+// them by. Entities without a subdevice node are left out, there is nothing
+// to set on them.
+fn open_stages(topology: &Topology, route: &[Hop]) -> Result<Vec<Stage>, Error> {
+    let mut stages = Vec::new();
+    for (incoming, out) in route.iter().zip(route.iter().skip(1)) {
+        let entity = match topology.entities.iter().find(|e| e.id == out.source) {
+            Some(entity) => entity,
+            None => continue
+        };
+        let interface = match interface_of(topology, entity.id) {
+            Some(interface) => interface,
+            None => {
+                eprintln!("{} has no subdevice node, leaving its formats alone.", entity.name);
+                continue;
+            }
+        };
+        let pad = topology.pads.iter()
+            .find(|p| p.entity_id == entity.id && p.index == out.source_pad)
+            .ok_or_else(|| Error::DeviceNotFound(format!("no pad {} on {}", out.source_pad, entity.name)))?;
+        let path = get_device_path_from_interface(interface)?;
+
+        stages.push(Stage {
+            subdevice: Subdevice::open(&path, entity, interface, pad)?,
+            sink_pad: incoming.sink_pad,
+            source_pad: out.source_pad
+        });
+    }
+    Ok(stages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENSOR_A: u32 = 1;
+    const SENSOR_B: u32 = 2;
+    const BRIDGE: u32 = 3;
+    const DEBAYER: u32 = 4;
+    const RAW_CAPTURE: u32 = 5;
+    const RGB_CAPTURE: u32 = 6;
+    const LENS: u32 = 7;
+    // Wired to the bridge by a link that is off for good.
+    const SENSOR_C: u32 = 8;
+
+    // Two sensors behind a bridge, which feeds a raw capture node directly
+    // and an RGB one through a debayer. The debayer can also feed the raw
+    // node, the long way round. Sensor A has a lens.
+    fn topology() -> Topology {
+        let entity = |id, name: &str, function| Entity {
+            id,
+            name: name.to_string(),
+            function,
+            flags: 0
+        };
+        let pad = |id, entity_id, index, flags| Pad {
+            id,
+            entity_id,
+            flags,
+            index
+        };
+        let mut next_link = 300;
+        let mut link = |source_id, sink_id, flags| {
+            next_link += 1;
+            Link {
+                id: next_link,
+                source_id,
+                sink_id,
+                flags
+            }
+        };
+        let (enabled, immutable) = (MEDIA_LNK_FL_ENABLED, MEDIA_LNK_FL_IMMUTABLE);
+
+        Topology {
+            version: 1,
+            entities: vec![
+                entity(SENSOR_A, "ov5640 1-003c", MEDIA_ENT_F_CAM_SENSOR),
+                entity(SENSOR_B, "gc2145 1-003d", MEDIA_ENT_F_CAM_SENSOR),
+                entity(BRIDGE, "csi", MEDIA_ENT_F_VID_IF_BRIDGE),
+                entity(DEBAYER, "debayer", MEDIA_ENT_F_PROC_VIDEO_PIXEL_ENC_CONV),
+                entity(RAW_CAPTURE, "raw capture", MEDIA_ENT_F_IO_V4L),
+                entity(RGB_CAPTURE, "rgb capture", MEDIA_ENT_F_IO_V4L),
+                entity(LENS, "dw9714 1-000c", MEDIA_ENT_F_LENS),
+                entity(SENSOR_C, "ov8858 1-0036", MEDIA_ENT_F_CAM_SENSOR)
+            ],
+            interfaces: vec![Interface {
+                id: 200,
+                interface_type: MEDIA_INTF_T_V4L_VIDEO,
+                flags: 0,
+                major: 81,
+                minor: 3
+            }],
+            pads: vec![
+                pad(101, SENSOR_A, 0, MEDIA_PAD_FL_SOURCE),
+                pad(102, SENSOR_B, 0, MEDIA_PAD_FL_SOURCE),
+                pad(103, BRIDGE, 0, MEDIA_PAD_FL_SINK),
+                pad(104, BRIDGE, 1, MEDIA_PAD_FL_SINK),
+                pad(105, BRIDGE, 2, MEDIA_PAD_FL_SOURCE),
+                pad(106, DEBAYER, 0, MEDIA_PAD_FL_SINK),
+                pad(107, DEBAYER, 1, MEDIA_PAD_FL_SOURCE),
+                pad(108, RAW_CAPTURE, 0, MEDIA_PAD_FL_SINK),
+                pad(109, RGB_CAPTURE, 0, MEDIA_PAD_FL_SINK),
+                pad(110, SENSOR_C, 0, MEDIA_PAD_FL_SOURCE)
+            ],
+            links: vec![
+                link(101, 103, enabled),
+                // Disabled, but can be switched on.
+                link(102, 104, 0),
+                link(110, 104, immutable),
+                link(105, 108, enabled | immutable),
+                link(105, 106, enabled),
+                link(107, 109, enabled | immutable),
+                link(107, 108, 0),
+                link(SENSOR_A, LENS, MEDIA_LNK_FL_ANCILLARY_LINK),
+                link(200, RAW_CAPTURE, MEDIA_LNK_FL_INTERFACE_LINK | enabled | immutable)
+            ]
+        }
+    }
+
+    fn entities(topology: &Topology) -> HashMap<u32, &Entity> {
+        topology.entities.iter().map(|e| (e.id, e)).collect()
+    }
+
+    // Entities along a route, from the sensor to the video node.
+    fn path(route: &[Hop]) -> Vec<u32> {
+        std::iter::once(route[0].source).chain(route.iter().map(|h| h.sink)).collect()
+    }
+
+    #[test]
+    fn data_hops_leave_out_dead_and_other_links() {
+        let hops = data_hops(&topology());
+        assert_eq!(hops.len(), 6);
+        assert!(!hops.iter().any(|h| h.source == SENSOR_C));
+        // Neither the ancillary nor the interface link.
+        assert!(!hops.iter().any(|h| h.source == SENSOR_A && h.sink == LENS));
+        assert!(!hops.iter().any(|h| h.source == 200));
+        let bridge_out = hops.iter().find(|h| h.source == BRIDGE && h.sink == RAW_CAPTURE).unwrap();
+        assert_eq!(*bridge_out, Hop {
+            source: BRIDGE,
+            source_pad: 2,
+            sink: RAW_CAPTURE,
+            sink_pad: 0,
+            immutable: true
+        });
+        assert!(!hops.iter().find(|h| h.source == SENSOR_B).unwrap().immutable);
+    }
+
+    #[test]
+    fn routes_reach_every_video_node() {
+        let topology = topology();
+        let entities = entities(&topology);
+        let hops = data_hops(&topology);
+
+        let mut routes = routes_to_video_nodes(SENSOR_A, &hops, &entities);
+        routes.sort_by_key(|(video, _)| *video);
+        let paths = routes.iter().map(|(video, route)| (*video, path(route))).collect::<Vec<_>>();
+        // The raw node directly, not through the debayer.
+        assert_eq!(paths, [
+            (RAW_CAPTURE, vec![SENSOR_A, BRIDGE, RAW_CAPTURE]),
+            (RGB_CAPTURE, vec![SENSOR_A, BRIDGE, DEBAYER, RGB_CAPTURE])
+        ]);
+        assert_eq!(routes[1].1[0].sink_pad, 0);
+
+        // Through the link that is off for now, on the other bridge pad.
+        let routes = routes_to_video_nodes(SENSOR_B, &hops, &entities);
+        assert_eq!(routes.len(), 2);
+        assert!(routes.iter().all(|(_, route)| route[0].sink_pad == 1 && !route[0].immutable));
+
+        assert!(routes_to_video_nodes(SENSOR_C, &hops, &entities).is_empty());
+    }
+
+    #[test]
+    fn routes_stop_at_other_sensors() {
+        let mut topology = topology();
+        // Sensor B passing frames through, like a sensor with a bridge
+        // built in, and the only way on from sensor A.
+        topology.pads.push(Pad {
+            id: 111,
+            entity_id: SENSOR_B,
+            flags: MEDIA_PAD_FL_SINK,
+            index: 1
+        });
+        topology.links.retain(|l| l.source_id != 101);
+        topology.links.push(Link {
+            id: 400,
+            source_id: 101,
+            sink_id: 111,
+            flags: 0
+        });
+        let entities = entities(&topology);
+        assert!(routes_to_video_nodes(SENSOR_A, &data_hops(&topology), &entities).is_empty());
+    }
+
+    #[test]
+    fn finds_the_lens() {
+        let topology = topology();
+        let entities = entities(&topology);
+        assert_eq!(lens_of(&topology, &entities, SENSOR_A).map(|e| e.id), Some(LENS));
+        assert!(lens_of(&topology, &entities, SENSOR_B).is_none());
+        // The link goes from the sensor, not the other way.
+        assert!(lens_of(&topology, &entities, LENS).is_none());
+    }
+
+    #[test]
+    fn finds_interfaces() {
+        let topology = topology();
+        assert_eq!(interface_of(&topology, RAW_CAPTURE).map(|i| (i.major, i.minor)), Some((81, 3)));
+        assert!(interface_of(&topology, RGB_CAPTURE).is_none());
+    }
+
+    #[test]
+    fn entity_kinds() {
+        let kinds = topology().entities.iter().map(EntityKind::of).collect::<Vec<_>>();
+        assert_eq!(kinds, [
+            EntityKind::Sensor,
+            EntityKind::Sensor,
+            EntityKind::Bridge,
+            EntityKind::Processing,
+            EntityKind::VideoNode,
+            EntityKind::VideoNode,
+            EntityKind::Lens,
+            EntityKind::Sensor
+        ]);
+    }
+}
@@ -0,0 +1,83 @@
+// What can go wrong with the camera hardware, for the UI to tell the user
+// instead of the app going down with it.
+use std::{error, fmt, io, path::Path};
+
+#[derive(Debug)]
+pub enum Error {
+    // No camera at all, or a device node that has gone away.
+    DeviceNotFound(String),
+    // The kernel turned an ioctl down, request is its name.
+    Ioctl {
+        request: &'static str,
+        errno: i32
+    },
+    // The sensor or video device can't do what raw capture needs.
+    UnsupportedFormat(String),
+    // Linking or unlinking two entities of a pipeline failed, usually
+    // because another one is streaming.
+    Link {
+        source: String,
+        sink: String,
+        errno: i32
+    },
+    // No frame from the video device in time.
+    StreamTimeout,
+    Io(io::Error)
+}
+
+impl Error {
+    pub fn ioctl(request: &'static str, error: io::Error) -> Self {
+        match error.raw_os_error() {
+            Some(errno) => Error::Ioctl { request, errno },
+            None if error.kind() == io::ErrorKind::TimedOut => Error::StreamTimeout,
+            None => Error::Io(error)
+        }
+    }
+
+    // Opening a device node, one that isn't there is reported as such.
+    pub fn open(path: &Path, error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => Error::DeviceNotFound(path.to_string_lossy().to_string()),
+            _ => Error::Io(error)
+        }
+    }
+
+    // The errno of a failed ioctl or link, like EBUSY when another
+    // program has the camera.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Error::Ioctl { errno, .. } | Error::Link { errno, .. } => Some(*errno),
+            Error::Io(e) => e.raw_os_error(),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let os_error = |errno: i32| io::Error::from_raw_os_error(errno);
+        match self {
+            Error::DeviceNotFound(what) => write!(f, "Camera not found: {}", what),
+            Error::Ioctl { request, errno } => write!(f, "{} failed: {}", request, os_error(*errno)),
+            Error::UnsupportedFormat(what) => write!(f, "Unsupported format: {}", what),
+            Error::Link { source, sink, errno } => write!(f, "Can't link {} to {}: {}", source, sink, os_error(*errno)),
+            Error::StreamTimeout => write!(f, "The camera stopped sending frames"),
+            Error::Io(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
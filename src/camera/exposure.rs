@@ -0,0 +1,176 @@
+// Auto, compensated and manual exposure on top of the sensor's exposure and
+// gain controls. Times are in seconds, gain is given as ISO with ISO 100 at
+// the profile's base gain.
+use std::io;
+use v4l_subdev::{v4l2_exposure_auto_type_V4L2_EXPOSURE_AUTO, v4l2_exposure_auto_type_V4L2_EXPOSURE_MANUAL};
+use crate::camera::backend::Sensor;
+use crate::camera::controls::{ControlInfo, ControlValue, MenuLabel, SensorControls};
+use crate::camera::profile::DeviceProfile;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExposureMode {
+    #[default]
+    Auto,
+    // Stops brighter than auto exposure would pick, negative for darker.
+    Compensated(f32),
+    Manual {
+        time: f32,
+        iso: u32
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExposureValues {
+    pub time: f32,
+    pub iso: u32
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ExposureLimits {
+    pub time: (f32, f32),
+    pub iso: (u32, u32)
+}
+
+pub struct ExposureControls {
+    exposure: Option<ControlInfo>,
+    // Seconds per step of exposure.
+    unit: f32,
+    auto_exposure: Option<ControlInfo>,
+    bias: Option<ControlInfo>,
+    gain: Option<ControlInfo>,
+    // Gain control value at ISO 100.
+    base_gain: f32,
+    auto_gain: Option<ControlInfo>
+}
+
+impl ExposureControls {
+    pub fn new(controls: &SensorControls, profile: &DeviceProfile) -> Self {
+        // Plain exposure has driver specific units, only usable when the
+        // profile knows them.
+        let (exposure, unit) = match (&controls.exposure, profile.exposure_unit_us, &controls.exposure_absolute) {
+            (Some(exposure), Some(unit_us), _) => (Some(exposure.clone()), unit_us / 1_000_000.0),
+            (_, _, Some(absolute)) => (Some(absolute.clone()), 0.0001),
+            _ => (None, 0.0)
+        };
+        let base_gain = profile.base_gain
+            .or_else(|| controls.gain.as_ref().map(|g| g.minimum.max(1) as f32))
+            .unwrap_or(1.0);
+
+        ExposureControls {
+            exposure,
+            unit,
+            auto_exposure: controls.auto_exposure.clone(),
+            bias: controls.exposure_bias.clone(),
+            gain: controls.gain.clone(),
+            base_gain,
+            auto_gain: controls.auto_gain.clone()
+        }
+    }
+
+    // None when the sensor can't do manual exposure.
+    pub fn limits(&self) -> Option<ExposureLimits> {
+        let exposure = self.exposure.as_ref()?;
+        let gain = self.gain.as_ref()?;
+
+        Some(ExposureLimits {
+            time: (exposure.minimum.max(1) as f32 * self.unit, exposure.maximum as f32 * self.unit),
+            iso: (self.iso(gain.minimum), self.iso(gain.maximum))
+        })
+    }
+
+    // What the sensor is doing now, auto exposure included where the driver
+    // reports it.
+    pub fn read(&self, sensor: &dyn Sensor) -> Option<ExposureValues> {
+        let exposure = self.exposure.as_ref()?;
+        let gain = self.gain.as_ref()?;
+        let values = sensor.get_controls(&[exposure, gain]).ok()?;
+
+        Some(ExposureValues {
+            time: values[0].as_i64() as f32 * self.unit,
+            iso: self.iso(values[1].as_i64())
+        })
+    }
+
+    // metered is what auto exposure picked last, compensation without a bias
+    // control works from that.
+    pub fn apply(&self, sensor: &dyn Sensor, mode: ExposureMode, metered: Option<ExposureValues>) -> io::Result<()> {
+        match mode {
+            ExposureMode::Auto => {
+                self.set_auto(sensor, true)?;
+                self.set_bias(sensor, 0.0)
+            },
+            ExposureMode::Compensated(stops) if self.bias.is_some() => {
+                self.set_auto(sensor, true)?;
+                self.set_bias(sensor, stops)
+            },
+            // Without a bias control, lock on what auto exposure picked and
+            // adjust that. Time first, gain for what doesn't fit.
+            ExposureMode::Compensated(stops) => {
+                let metered = metered.or_else(|| self.read(sensor)).ok_or_else(unsupported)?;
+                let limits = self.limits().ok_or_else(unsupported)?;
+                let wanted = metered.time * 2f32.powf(stops);
+                let time = wanted.max(limits.time.0).min(limits.time.1);
+                let iso = (metered.iso as f32 * wanted / time) as u32;
+                self.set_manual(sensor, time, iso)
+            },
+            ExposureMode::Manual { time, iso } => self.set_manual(sensor, time, iso)
+        }
+    }
+
+    fn iso(&self, gain: i64) -> u32 {
+        (gain as f32 / self.base_gain * 100.0).round() as u32
+    }
+
+    fn set_auto(&self, sensor: &dyn Sensor, auto: bool) -> io::Result<()> {
+        let mut values = Vec::new();
+        if let Some(control) = &self.auto_exposure {
+            let mode = if auto {
+                v4l2_exposure_auto_type_V4L2_EXPOSURE_AUTO
+            } else {
+                v4l2_exposure_auto_type_V4L2_EXPOSURE_MANUAL
+            };
+            values.push((control, ControlValue::Menu(mode)));
+        }
+        if let Some(control) = &self.auto_gain {
+            values.push((control, ControlValue::Boolean(auto)));
+        }
+        sensor.set_controls(&values)
+    }
+
+    // Nearest step of the bias menu.
+    fn set_bias(&self, sensor: &dyn Sensor, stops: f32) -> io::Result<()> {
+        let control = match &self.bias {
+            Some(control) => control,
+            None => return Ok(())
+        };
+        let milli_stops = (stops * 1000.0).round() as i64;
+        let item = control.menu.iter()
+            .filter_map(|item| match item.label {
+                MenuLabel::Value(v) => Some((item.index, (v - milli_stops).abs())),
+                MenuLabel::Name(_) => None
+            })
+            .min_by_key(|(_, distance)| *distance);
+
+        match item {
+            Some((index, _)) => sensor.set_controls(&[(control, ControlValue::Menu(index))]),
+            None => Ok(())
+        }
+    }
+
+    // Turns the sensor's automatic modes off.
+    pub fn set_manual(&self, sensor: &dyn Sensor, time: f32, iso: u32) -> io::Result<()> {
+        let exposure = self.exposure.as_ref().ok_or_else(unsupported)?;
+        let gain = self.gain.as_ref().ok_or_else(unsupported)?;
+
+        // Manual values are ignored while the automatic modes are on.
+        self.set_auto(sensor, false)?;
+        sensor.set_controls(&[
+            (exposure, exposure.value((time / self.unit).round() as i64)),
+            (gain, gain.value((iso as f32 / 100.0 * self.base_gain).round() as i64))
+        ])
+    }
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "No manual exposure on this sensor")
+}
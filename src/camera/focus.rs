@@ -0,0 +1,322 @@
+// Continuous, single shot and manual focus on top of the sensor's focus
+// controls, and the region of the frame to meter on, which software focus
+// also focuses on. Lenses without focusing of their own get contrast
+// detection in software.
+use std::io;
+use v4l_subdev::{V4L2_AUTO_FOCUS_STATUS_BUSY, V4L2_AUTO_FOCUS_STATUS_FAILED, V4L2_AUTO_FOCUS_STATUS_REACHED};
+use crate::camera::Rotation;
+use crate::camera::autofocus::{self, ContrastFocus};
+use crate::camera::backend::Sensor;
+use crate::camera::bayer::RawFormat;
+use crate::camera::controls::{ControlInfo, ControlValue, SensorControls};
+
+// Single shot hardware searches still going after this many frames are reported as
+// failed, also when the sensor has no status to tell.
+const TIMEOUT_FRAMES: u32 = 90;
+// The sensor's status may still be the last search's this many frames after
+// triggering a new one.
+const STALE_FRAMES: u32 = 3;
+// Software focus looks at this much of the middle without a region.
+const DEFAULT_REGION_SIZE: f32 = 0.3;
+// Continuous software focus searches again once sharpness has stayed below
+// this share of what it locked at for a while.
+const REFOCUS_DROP: f32 = 0.6;
+const REFOCUS_FRAMES: u32 = 10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FocusMode {
+    #[default]
+    Continuous,
+    // Focuses once when triggered, then stays put.
+    Single,
+    // Lens position from 0 for the farthest to 1 for the closest.
+    Manual(f32)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FocusStatus {
+    Idle,
+    Busy,
+    Reached,
+    Failed
+}
+
+// Part of the frame as shares of its width and height, in sensor
+// orientation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32
+}
+
+impl Region {
+    // A square-ish region of size around a point, moved in to fit the frame.
+    pub fn around(x: f32, y: f32, size: f32) -> Self {
+        let size = size.clamp(0.0, 1.0);
+        Region {
+            x: (x - size / 2.0).max(0.0).min(1.0 - size),
+            y: (y - size / 2.0).max(0.0).min(1.0 - size),
+            width: size,
+            height: size
+        }
+    }
+
+    // Point on a preview frame turned by rotation, as shares of its width and
+    // height, back in sensor orientation.
+    pub fn point_from_preview(x: f32, y: f32, rotation: Rotation) -> (f32, f32) {
+        match rotation {
+            Rotation::Normal => (x, y),
+            Rotation::Clockwise => (y, 1.0 - x),
+            Rotation::UpsideDown => (1.0 - x, 1.0 - y),
+            Rotation::Counterclockwise => (1.0 - y, x)
+        }
+    }
+
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+pub struct FocusControls {
+    focus_auto: Option<ControlInfo>,
+    focus_absolute: Option<ControlInfo>,
+    start: Option<ControlInfo>,
+    stop: Option<ControlInfo>,
+    status: Option<ControlInfo>,
+    // A lens to move but no focusing on the sensor.
+    software: bool
+}
+
+impl FocusControls {
+    pub fn new(controls: &SensorControls) -> Self {
+        FocusControls {
+            focus_auto: controls.focus_auto.clone(),
+            focus_absolute: controls.focus_absolute.clone(),
+            start: controls.auto_focus_start.clone(),
+            stop: controls.auto_focus_stop.clone(),
+            status: controls.auto_focus_status.clone(),
+            software: controls.focus_absolute.is_some() && controls.focus_auto.is_none() && controls.auto_focus_start.is_none()
+        }
+    }
+
+    pub fn supports(&self, mode: FocusMode) -> bool {
+        match mode {
+            FocusMode::Continuous => self.focus_auto.is_some() || self.software,
+            FocusMode::Single => self.start.is_some() || self.software,
+            FocusMode::Manual(_) => self.focus_absolute.is_some()
+        }
+    }
+
+    // Focusing is left to FocusLoop.
+    pub fn software(&self) -> bool {
+        self.software
+    }
+
+    pub fn apply(&self, sensor: &dyn Sensor, mode: FocusMode) -> io::Result<()> {
+        if !self.supports(mode) {
+            return Err(unsupported(mode));
+        }
+
+        let mut values = Vec::new();
+        if let Some(control) = &self.focus_auto {
+            values.push((control, ControlValue::Boolean(mode == FocusMode::Continuous)));
+        }
+        if let (FocusMode::Manual(position), Some(control)) = (mode, &self.focus_absolute) {
+            let range = (control.maximum - control.minimum) as f32;
+            let value = control.minimum + (position.clamp(0.0, 1.0) * range).round() as i64;
+            values.push((control, control.value(value)));
+        }
+        // Leaving single shot, a search in progress would move the lens
+        // after this.
+        if let (FocusMode::Manual(_), Some(control)) = (mode, &self.stop) {
+            values.push((control, ControlValue::Button));
+        }
+        sensor.set_controls(&values)
+    }
+
+    // Starts a single shot search, follow it with status. Software searches
+    // are started by FocusLoop instead.
+    pub fn trigger(&self, sensor: &dyn Sensor) -> io::Result<()> {
+        if self.software {
+            return Ok(());
+        }
+        let control = self.start.as_ref().ok_or_else(|| unsupported(FocusMode::Single))?;
+        sensor.set_controls(&[(control, ControlValue::Button)])
+    }
+
+    fn lens_range(&self) -> Option<(i64, i64)> {
+        self.focus_absolute.as_ref().map(|c| (c.minimum, c.maximum))
+    }
+
+    fn lens_position(&self, sensor: &dyn Sensor) -> Option<i64> {
+        let control = self.focus_absolute.as_ref()?;
+        Some(sensor.get_controls(&[control]).ok()?[0].as_i64())
+    }
+
+    fn move_lens(&self, sensor: &dyn Sensor, position: i64) -> io::Result<()> {
+        let control = self.focus_absolute.as_ref().ok_or_else(|| unsupported(FocusMode::Manual(0.0)))?;
+        sensor.set_controls(&[(control, control.value(position))])
+    }
+
+    // None if the sensor doesn't tell.
+    pub fn status(&self, sensor: &dyn Sensor) -> Option<FocusStatus> {
+        let control = self.status.as_ref()?;
+        let value = sensor.get_controls(&[control]).ok()?[0].as_i64() as u32;
+
+        // A bitmask, busy wins.
+        Some(if value & V4L2_AUTO_FOCUS_STATUS_BUSY != 0 {
+            FocusStatus::Busy
+        } else if value & V4L2_AUTO_FOCUS_STATUS_FAILED != 0 {
+            FocusStatus::Failed
+        } else if value & V4L2_AUTO_FOCUS_STATUS_REACHED != 0 {
+            FocusStatus::Reached
+        } else {
+            FocusStatus::Idle
+        })
+    }
+}
+
+// Focus as the preview thread sees it: software searches run from the
+// frames, hardware ones are watched, and status changes come out to report.
+pub struct FocusLoop {
+    controls: FocusControls,
+    mode: FocusMode,
+    search: Option<ContrastFocus>,
+    // Sharpness after the last software lock, None until measured.
+    locked: Option<f32>,
+    soft_frames: u32,
+    // Frames since a single shot hardware search was triggered, None when
+    // there isn't one going.
+    search_frames: Option<u32>,
+    status: Option<FocusStatus>
+}
+
+impl FocusLoop {
+    // The mode is expected to be applied to the sensor already.
+    pub fn new(controls: FocusControls, sensor: &dyn Sensor, mode: FocusMode) -> Self {
+        let mut focus = FocusLoop {
+            controls,
+            mode,
+            search: None,
+            locked: None,
+            soft_frames: 0,
+            search_frames: None,
+            status: None
+        };
+        focus.set_mode(sensor, mode);
+        focus
+    }
+
+    // Call when the mode changes, after applying it.
+    pub fn set_mode(&mut self, sensor: &dyn Sensor, mode: FocusMode) {
+        self.mode = mode;
+        self.search = None;
+        self.search_frames = None;
+        if self.controls.software() && mode == FocusMode::Continuous {
+            self.start_search(sensor);
+        }
+    }
+
+    // Single shot search, or a new region to focus on.
+    pub fn trigger(&mut self, sensor: &dyn Sensor) {
+        if self.controls.software() {
+            if !matches!(self.mode, FocusMode::Manual(_)) {
+                self.start_search(sensor);
+            }
+        } else if self.mode == FocusMode::Single {
+            self.search_frames = Some(0);
+        }
+    }
+
+    // Once per frame, returns the status when it changes.
+    pub fn update(&mut self, sensor: &dyn Sensor, data: &[u8], stride: usize, raw_format: &RawFormat, source: (usize, usize), region: Option<Region>) -> Option<FocusStatus> {
+        let status = if self.controls.software() {
+            let region = region.unwrap_or_else(|| Region::around(0.5, 0.5, DEFAULT_REGION_SIZE));
+            self.update_software(sensor, data, stride, raw_format, source, &region)
+        } else {
+            self.update_hardware(sensor)
+        };
+
+        match status {
+            Some(status) if Some(status) != self.status => {
+                self.status = Some(status);
+                Some(status)
+            },
+            _ => None
+        }
+    }
+
+    fn start_search(&mut self, sensor: &dyn Sensor) {
+        if let Some(range) = self.controls.lens_range() {
+            let position = self.controls.lens_position(sensor).unwrap_or(range.0);
+            self.search = Some(ContrastFocus::new(range, position));
+            self.locked = None;
+            self.soft_frames = 0;
+        }
+    }
+
+    fn update_software(&mut self, sensor: &dyn Sensor, data: &[u8], stride: usize, raw_format: &RawFormat, source: (usize, usize), region: &Region) -> Option<FocusStatus> {
+        if self.search.is_none() && self.mode != FocusMode::Continuous {
+            return self.status;
+        }
+        let sharpness = autofocus::sharpness(data, stride, raw_format, source, region);
+
+        if let Some(search) = self.search.as_mut() {
+            if let Some(position) = search.next(sharpness) {
+                if let Err(e) = self.controls.move_lens(sensor, position) {
+                    eprintln!("Can't move lens: {}", e);
+                }
+            }
+            let status = search.status();
+            if status != FocusStatus::Busy {
+                self.search = None;
+            }
+            return Some(status);
+        }
+
+        // Continuous, search again once the scene has gone soft.
+        match self.locked {
+            Some(locked) if sharpness < locked * REFOCUS_DROP => {
+                self.soft_frames += 1;
+                if self.soft_frames >= REFOCUS_FRAMES {
+                    self.start_search(sensor);
+                    return Some(FocusStatus::Busy);
+                }
+            },
+            Some(_) => self.soft_frames = 0,
+            None => self.locked = Some(sharpness)
+        }
+        self.status
+    }
+
+    fn update_hardware(&mut self, sensor: &dyn Sensor) -> Option<FocusStatus> {
+        let status = self.controls.status(sensor);
+        let frames = match self.search_frames.as_mut() {
+            Some(frames) => {
+                *frames += 1;
+                *frames
+            },
+            // Continuous, whatever the sensor says.
+            None => return status
+        };
+
+        let status = match status {
+            Some(FocusStatus::Reached) | Some(FocusStatus::Failed) if frames <= STALE_FRAMES => FocusStatus::Busy,
+            Some(FocusStatus::Reached) => FocusStatus::Reached,
+            Some(FocusStatus::Failed) => FocusStatus::Failed,
+            // Still busy, idle or not telling.
+            _ if frames >= TIMEOUT_FRAMES => FocusStatus::Failed,
+            _ => FocusStatus::Busy
+        };
+        if status != FocusStatus::Busy {
+            self.search_frames = None;
+        }
+        Some(status)
+    }
+}
+
+fn unsupported(mode: FocusMode) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("No {:?} focus on this sensor", mode))
+}
@@ -0,0 +1,297 @@
+// Real sensors, found through the media controller and streamed from their
+// V4L2 video nodes.
+use v4l::prelude::*;
+use v4l::video::Capture;
+use v4l::buffer::Type;
+use v4l::io::traits::{CaptureStream, Stream};
+use v4l::format::{Format, Flags, fourcc::FourCC, field::FieldOrder, colorspace::Colorspace, quantization::Quantization, transfer::TransferFunction};
+
+use std::{collections::HashMap, io, os::raw::c_int, path::PathBuf, sync::{Arc, RwLock}, time::Duration};
+use crate::camera::{Error, RawFormat};
+use crate::camera::backend::{CameraBackend, Frame, FrameFormat, FrameInterval, Sensor, SensorMode};
+use crate::camera::controls::{ControlInfo, ControlValue};
+use crate::camera::discovery::{self, CapturePipeline};
+use crate::camera::subdevice::Subdevice;
+
+// A stream that sends nothing for this long is given up on.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+const NUM_BUFFERS: u32 = 4;
+
+pub struct MediaBackend {
+    pipelines: Vec<Arc<CapturePipeline>>,
+    // Video devices by path. Sensors sharing a bridge share the device.
+    devices: HashMap<PathBuf, RwLock<Device>>
+}
+
+impl MediaBackend {
+    // Looks through every media device for sensors with a way to a video
+    // node.
+    pub fn open() -> Result<Self, Error> {
+        // A sensor may reach several video nodes, the nearest one is the raw
+        // one.
+        let mut pipelines: Vec<Arc<CapturePipeline>> = Vec::new();
+        for pipeline in discovery::discover() {
+            let seen = pipelines.iter().any(|p| {
+                p.media_path == pipeline.media_path && p.sensor.entity.id == pipeline.sensor.entity.id
+            });
+            if !seen {
+                pipelines.push(Arc::new(pipeline));
+            }
+        }
+
+        if pipelines.is_empty() {
+            return Err(Error::DeviceNotFound("no sensor linked to a video node".to_string()));
+        }
+
+        // Opening the video device takes ~10s on the PinePhone if the back
+        // camera is linked.
+        discovery::deactivate_all(&pipelines)?;
+
+        let mut devices = HashMap::new();
+        for pipeline in pipelines.iter() {
+            if !devices.contains_key(&pipeline.video_path) {
+                let device = Device::with_path(&pipeline.video_path)
+                    .map_err(|e| Error::open(&pipeline.video_path, e))?;
+                devices.insert(pipeline.video_path.clone(), RwLock::new(device));
+            }
+        }
+
+        Ok(MediaBackend {
+            pipelines,
+            devices
+        })
+    }
+}
+
+impl CameraBackend for MediaBackend {
+    fn count(&self) -> usize {
+        self.pipelines.len()
+    }
+
+    fn name(&self, index: usize) -> String {
+        self.pipelines[index].name()
+    }
+
+    fn sensor(&self, index: usize) -> &dyn Sensor {
+        &*self.pipelines[index]
+    }
+
+    fn activate(&self, index: usize) -> Result<(), Error> {
+        discovery::activate(&self.pipelines, index)
+    }
+
+    fn reset(&self, index: usize) -> Result<(), Error> {
+        discovery::deactivate_all(&self.pipelines)?;
+        let path = &self.pipelines[index].video_path;
+        *self.devices[path].write().unwrap() = Device::with_path(path).map_err(|e| Error::open(path, e))?;
+        Ok(())
+    }
+
+    fn stream(&self, index: usize, raw_format: RawFormat, on_frame: &mut dyn FnMut(Frame) -> Result<bool, Error>) -> Result<(), Error> {
+        let pipeline = &self.pipelines[index];
+        // Whatever is in between has to pass raw frames on for us to get
+        // them.
+        let format = pipeline.propagate(&pipeline.sensor.format()?.format)?;
+        let raw_format = RawFormat::from_mbus_code(format.code, raw_format.packing)
+            .ok_or_else(|| Error::UnsupportedFormat(format!("{} gets media bus code {:#x}, not raw bayer", pipeline.video.entity.name, format.code)))?;
+
+        let dev = self.devices[&pipeline.video_path].read().unwrap();
+        dev.set_format(&bayer_format(format.width, format.height, &raw_format)).map_err(|e| Error::ioctl("VIDIOC_S_FMT", e))?;
+        let video_format = dev.format().map_err(|e| Error::ioctl("VIDIOC_G_FMT", e))?;
+        // The last link, from the last subdevice to the video node.
+        if (video_format.width, video_format.height) != (format.width, format.height) {
+            return Err(Error::UnsupportedFormat(format!("{} gets {} but takes {}x{}",
+                pipeline.video.entity.name, format, video_format.width, video_format.height)));
+        }
+        let format = video_format;
+        let raw_format = RawFormat::from_fourcc(&format.fourcc.repr).unwrap_or(raw_format);
+        let handle = dev.handle();
+        let mut stream = MmapStream::with_buffers(&dev, Type::VideoCapture, NUM_BUFFERS)
+            .map_err(|e| Error::ioctl("VIDIOC_REQBUFS", e))?;
+
+        // The driver seems to expect all buffers queued before start
+        // Otherwise it just gets stuck at the first dequeue
+        for i in 0..NUM_BUFFERS as usize {
+            stream.queue(i).map_err(|e| Error::ioctl("VIDIOC_QBUF", e))?;
+        }
+        stream.start().map_err(|e| Error::ioctl("VIDIOC_STREAMON", e))?;
+
+        loop {
+            wait_for_frame(handle.fd(), FRAME_TIMEOUT)?;
+            let index = stream.dequeue()
+                .map_err(|e| Error::ioctl("VIDIOC_DQBUF", e))?;
+            let used = stream.get_meta(index).map_or(0, |m| m.bytesused);
+
+            if used > 0 {
+                let frame = Frame {
+                    data: stream.get(index).unwrap_or(&[]),
+                    width: format.width as usize,
+                    height: format.height as usize,
+                    stride: format.stride as usize,
+                    format: raw_format
+                };
+                if !on_frame(frame)? {
+                    return Ok(());
+                }
+            }
+
+            stream.queue(index).map_err(|e| Error::ioctl("VIDIOC_QBUF", e))?;
+        }
+    }
+}
+
+// Blocks until the driver has a frame to dequeue. Dequeueing right away
+// would block for as long as a stalled driver stays quiet, this gives up
+// after timeout instead.
+fn wait_for_frame(fd: c_int, timeout: Duration) -> Result<(), Error> {
+    let mut poll_fd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0
+    };
+    loop {
+        match unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as c_int) } {
+            0 => return Err(Error::StreamTimeout),
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(Error::Io(e));
+                }
+            },
+            _ => return Ok(())
+        }
+    }
+}
+
+impl Sensor for Subdevice {
+    fn name(&self) -> &str {
+        &self.entity.name
+    }
+
+    fn modes(&self) -> Result<Vec<SensorMode>, Error> {
+        Subdevice::modes(self)
+    }
+
+    fn frame_intervals(&self, code: u32, width: u32, height: u32) -> Result<Vec<FrameInterval>, Error> {
+        Subdevice::frame_intervals(self, self.pad.index, code, width, height)
+    }
+
+    fn set_format(&self, width: u32, height: u32, code: u32) -> Result<FrameFormat, Error> {
+        Subdevice::set_format(self, width, height, code).map(|f| f.format)
+    }
+
+    fn format(&self) -> Result<FrameFormat, Error> {
+        Subdevice::format(self).map(|f| f.format)
+    }
+
+    fn set_interval(&self, numerator: u32, denominator: u32) -> Result<(), Error> {
+        Subdevice::set_interval(self, numerator, denominator)
+    }
+
+    fn set_control(&self, id: u32, value: i32) -> Result<(), Error> {
+        Subdevice::set_control(self, id, value)
+    }
+
+    fn controls(&self) -> io::Result<Vec<ControlInfo>> {
+        Subdevice::controls(self)
+    }
+
+    fn get_controls(&self, controls: &[&ControlInfo]) -> io::Result<Vec<ControlValue>> {
+        Subdevice::get_controls(self, controls)
+    }
+
+    fn set_controls(&self, values: &[(&ControlInfo, ControlValue)]) -> io::Result<()> {
+        Subdevice::set_controls(self, values)
+    }
+}
+
+// The sensor with the controls of its lens, when it has one, for focus to
+// move it like a lens built into the sensor.
+impl Sensor for CapturePipeline {
+    fn name(&self) -> &str {
+        &self.sensor.entity.name
+    }
+
+    fn modes(&self) -> Result<Vec<SensorMode>, Error> {
+        self.sensor.modes()
+    }
+
+    fn frame_intervals(&self, code: u32, width: u32, height: u32) -> Result<Vec<FrameInterval>, Error> {
+        Sensor::frame_intervals(&*self.sensor, code, width, height)
+    }
+
+    fn set_format(&self, width: u32, height: u32, code: u32) -> Result<FrameFormat, Error> {
+        Sensor::set_format(&*self.sensor, width, height, code)
+    }
+
+    fn format(&self) -> Result<FrameFormat, Error> {
+        Sensor::format(&*self.sensor)
+    }
+
+    fn set_interval(&self, numerator: u32, denominator: u32) -> Result<(), Error> {
+        self.sensor.set_interval(numerator, denominator)
+    }
+
+    fn set_control(&self, id: u32, value: i32) -> Result<(), Error> {
+        self.sensor.set_control(id, value)
+    }
+
+    // The lens's win over ones of the same id on the sensor.
+    fn controls(&self) -> io::Result<Vec<ControlInfo>> {
+        let mut controls = self.sensor.controls()?;
+        if let Some(lens) = &self.lens {
+            controls.retain(|c| !lens.has_control(c.id));
+            controls.extend(lens.controls()?);
+        }
+        Ok(controls)
+    }
+
+    fn get_controls(&self, controls: &[&ControlInfo]) -> io::Result<Vec<ControlValue>> {
+        let lens = match &self.lens {
+            Some(lens) => lens,
+            None => return self.sensor.get_controls(controls)
+        };
+        let (on_lens, on_sensor): (Vec<&ControlInfo>, Vec<&ControlInfo>) = controls.iter()
+            .partition(|c| lens.has_control(c.id));
+        let mut from_lens = lens.get_controls(&on_lens)?.into_iter();
+        let mut from_sensor = self.sensor.get_controls(&on_sensor)?.into_iter();
+        // Back in the order they were asked for.
+        Ok(controls.iter()
+            .filter_map(|c| if lens.has_control(c.id) { from_lens.next() } else { from_sensor.next() })
+            .collect())
+    }
+
+    // All or nothing on each device, the sensor's are set first.
+    fn set_controls(&self, values: &[(&ControlInfo, ControlValue)]) -> io::Result<()> {
+        let lens = match &self.lens {
+            Some(lens) => lens,
+            None => return self.sensor.set_controls(values)
+        };
+        let (on_lens, on_sensor): (Vec<_>, Vec<_>) = values.iter()
+            .cloned()
+            .partition(|(c, _)| lens.has_control(c.id));
+        if !on_sensor.is_empty() {
+            self.sensor.set_controls(&on_sensor)?;
+        }
+        if !on_lens.is_empty() {
+            lens.set_controls(&on_lens)?;
+        }
+        Ok(())
+    }
+}
+
+fn bayer_format(width: u32, height: u32, raw_format: &RawFormat) -> Format {
+    let stride = raw_format.bytes_per_line(width as usize) as u32;
+    Format {
+        width,
+        height,
+        fourcc: FourCC::new(raw_format.fourcc()),
+        field_order: FieldOrder::Progressive,
+        stride,
+        size: stride * height,
+        flags: Flags::empty(),
+        colorspace: Colorspace::RAW,
+        quantization: Quantization::Default,
+        transfer: TransferFunction::None
+    }
+}
@@ -4,31 +4,25 @@ use regex::Regex;
 use std::path::PathBuf;
 use std::{alloc::{alloc_zeroed, Layout}, fs, io, mem, path::Path, slice, sync::Arc};
 use v4l::{v4l2};
+use crate::camera::Error;
+use crate::camera::discovery::Hop;
 use crate::camera::media_ioctl as ioctl;
-use crate::camera::video_device::VideoDevice;
-pub use crate::camera::subdevice::Subdevice;
 use crate::camera::topology::*;
 
 pub struct MediaDevice {
-    handle: Arc<Handle>,
-    video_device: Option<VideoDevice>,
-    front_camera: Option<Subdevice>,
-    back_camera: Option<Subdevice>
+    handle: Arc<Handle>
 }
 
 impl MediaDevice {
-    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-        let fd = v4l2::open(&path, libc::O_RDWR)?;
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let fd = v4l2::open(&path, libc::O_RDWR).map_err(|e| Error::open(path.as_ref(), e))?;
 
         if fd == -1 {
-            return Err(io::Error::last_os_error());
+            return Err(Error::open(path.as_ref(), io::Error::last_os_error()));
         }
 
         Ok(MediaDevice {
-            handle: Arc::new(Handle { fd }),
-            video_device: None,
-            front_camera: None,
-            back_camera: None
+            handle: Arc::new(Handle { fd })
         })
     }
 
@@ -36,7 +30,7 @@ impl MediaDevice {
         self.handle.clone()
     }
 
-    pub fn info(&self) -> io::Result<Info> {
+    pub fn info(&self) -> Result<Info, Error> {
         unsafe {
             let mut device_info: media_device_info = mem::zeroed();
 
@@ -44,126 +38,13 @@ impl MediaDevice {
                 self.handle().fd(),
                 ioctl::MEDIA_IOC_DEVICE_INFO,
                 &mut device_info as *mut _ as *mut std::os::raw::c_void
-            )?;
+            ).map_err(|e| Error::ioctl("MEDIA_IOC_DEVICE_INFO", e))?;
 
             Ok(Info::from(device_info))
         }
     }
 
-    pub fn setup(&mut self) {
-        let topology = self.topology().expect("Couldn't get topology.");
-
-        let entities = topology.entities;
-
-        let mut back_camera_entity = None;
-        let mut front_camera_entity = None;
-        let mut video_entity = None;
-
-
-        entities.iter().for_each(|e| {
-            match &e.name[0..6] {
-                "ov5640" => back_camera_entity = Some(e.clone()),
-                "gc2145" => front_camera_entity = Some(e.clone()),
-                "sun6i-" => video_entity = Some(e.clone()),
-                _ => println!("Unknown entity name: {}", e.name)
-            }
-        });
-
-        let interfaces = topology.interfaces;
-        let pads = topology.pads;
-        let links = topology.links;
-
-        let back_camera_entity = back_camera_entity.expect("Back camera entity not set!");
-        let back_camera_pad = pads.iter().find(|p| p.entity_id == back_camera_entity.id)
-            .expect("Can't find pad for back camera sensor.");
-
-        let back_camera_interface_link = links.iter().find(|l| l.sink_id == back_camera_entity.id)
-            .expect("Can't find link between back camera entity and interface.");
-
-        let back_camera_interface = interfaces.iter().find(|i| i.id == back_camera_interface_link.source_id)
-            .expect("Can't find back camera interface.");
-
-        let path = get_device_path_from_interface(&back_camera_interface);
-
-        let back_camera = Subdevice::open(
-            &path,
-            &back_camera_entity,
-            back_camera_interface,
-            back_camera_pad
-        ).expect("Can't open back camera.");
-
-        self.back_camera = Some(back_camera);
-
-        let front_camera_entity = front_camera_entity.expect("front camera entity not set!");
-        let front_camera_pad = pads.iter().find(|p| p.entity_id == front_camera_entity.id)
-            .expect("Can't find pad for front camera sensor.");
-
-        let front_camera_interface_link = links.iter().find(|l| l.sink_id == front_camera_entity.id)
-            .expect("Can't find link between front camera entity and interface.");
-
-        let front_camera_interface = interfaces.iter().find(|i| i.id == front_camera_interface_link.source_id)
-            .expect("Can't find front camera interface.");
-
-        let path = get_device_path_from_interface(&front_camera_interface);
-
-        let front_camera = Subdevice::open(
-            &path,
-            &front_camera_entity,
-            front_camera_interface,
-            front_camera_pad
-        ).expect("Can't open front camera.");
-
-        self.front_camera = Some(front_camera);
-
-        let video_entity = video_entity.expect("Couldn't get video entity.");
-        let video_pad = pads.iter().find(|p| p.entity_id == video_entity.id)
-            .expect("Can't find video device pad.");
-        let video_interface_link = links.iter().find(|l| l.sink_id == video_entity.id)
-            .expect("Can't find video device interface link.");
-        let video_interface = interfaces.iter().find(|i| i.id == video_interface_link.source_id)
-            .expect("Can't find video device interface.");
-
-        let video_device = VideoDevice::new(
-            &video_entity,
-            &video_interface,
-            &video_pad
-        );
-        self.video_device = Some(video_device);
-
-        self.unlink_front_camera();
-        self.unlink_back_camera();
-        self.link_front_camera();
-    }
-
-    pub fn set_back_format(&self, width: u32, height: u32) {
-        self.back_camera.as_ref().unwrap().set_format(width, height);
-    }
-
-    pub fn set_back_interval(&self, numerator: u32, denominator: u32) {
-        self.back_camera.as_ref().unwrap().set_interval(numerator, denominator);
-    }
-
-    pub fn set_front_format(&self, width: u32, height: u32) {
-        self.front_camera.as_ref().unwrap().set_format(width, height);
-    }
-
-    pub fn set_front_interval(&self, numerator: u32, denominator: u32) {
-        self.front_camera.as_ref().unwrap().set_interval(numerator, denominator);
-    }
-
-    pub fn auto_focus(&self, enable: bool) {
-        self.back_camera.as_ref().unwrap().auto_focus(enable);
-    }
-
-    pub fn hflip_front(&self, enable: bool) {
-        self.front_camera.as_ref().unwrap().hflip(enable);
-    }
-
-    pub fn vflip_front(&self, enable: bool) {
-        self.front_camera.as_ref().unwrap().vflip(enable);
-    }
-
-    pub fn topology(&self) -> io::Result<Topology> {
+    pub fn topology(&self) -> Result<Topology, Error> {
         unsafe {
             let mut topology: media_v2_topology = mem::zeroed();
 
@@ -171,7 +52,7 @@ impl MediaDevice {
                 self.handle().fd(),
                 ioctl::MEDIA_IOC_G_TOPOLOGY,
                 &mut topology as *mut _ as *mut std::os::raw::c_void
-            )?;
+            ).map_err(|e| Error::ioctl("MEDIA_IOC_G_TOPOLOGY", e))?;
 
             let entity_count = topology.num_entities as usize;
             let interface_count = topology.num_interfaces as usize;
@@ -195,7 +76,7 @@ impl MediaDevice {
                 self.handle().fd(),
                 ioctl::MEDIA_IOC_G_TOPOLOGY,
                 &mut topology as *mut _ as *mut std::os::raw::c_void
-            )?;
+            ).map_err(|e| Error::ioctl("MEDIA_IOC_G_TOPOLOGY", e))?;
 
             let entities = slice::from_raw_parts::<media_v2_entity>(topology.ptr_entities as *const media_v2_entity, entity_count);
             let interfaces = slice::from_raw_parts::<media_v2_interface>(topology.ptr_interfaces as *const media_v2_interface, interface_count);
@@ -206,37 +87,7 @@ impl MediaDevice {
         }
     }
 
-    pub fn link_back_camera(&self) {
-        let cam_dev = self.back_camera.as_ref().expect("Back camera not set.");
-        let video_dev = self.video_device.as_ref().expect("Video device not set.");
-        self.setup_link(cam_dev.entity.id, video_dev.entity.id, true)
-            .expect("Can't link back camera.");
-    }
-
-    pub fn unlink_back_camera(&self) {
-        let cam_dev = self.back_camera.as_ref().expect("Back camera not set.");
-        let video_dev = self.video_device.as_ref().expect("Video device not set.");
-        self.setup_link(cam_dev.entity.id, video_dev.entity.id, false)
-            .expect("Can't unlink back camera.");
-    }
-
-    pub fn link_front_camera(&self) {
-        let cam_dev = self.front_camera.as_ref().expect("Front camera not set.");
-        let video_dev = self.video_device.as_ref().expect("Video device not set.");
-        self.setup_link(cam_dev.entity.id, video_dev.entity.id, true)
-            .expect("Can't link front camera.");
-        cam_dev.set_format(1280, 960);
-        cam_dev.set_interval(1, 15);
-    }
-
-    pub fn unlink_front_camera(&self) {
-        let cam_dev = self.front_camera.as_ref().expect("Front camera not set.");
-        let video_dev = self.video_device.as_ref().expect("Video device not set.");
-        self.setup_link(cam_dev.entity.id, video_dev.entity.id, false)
-            .expect("Can't unlink front camera.");
-    }
-
-    fn setup_link(&self, source_id: u32, sink_id: u32, enable: bool) -> io::Result<()> {
+    pub fn setup_link(&self, hop: &Hop, enable: bool) -> Result<(), Error> {
         let flags = if enable {
             MEDIA_LNK_FL_ENABLED
         } else {
@@ -244,14 +95,14 @@ impl MediaDevice {
         };
         let mut link = media_link_desc {
             source: media_pad_desc {
-                entity: source_id,
-                index: 0,
+                entity: hop.source,
+                index: hop.source_pad as u16,
                 flags: 0,
                 reserved: [0; 2]
             },
             sink: media_pad_desc {
-                entity: sink_id,
-                index: 0,
+                entity: hop.sink,
+                index: hop.sink_pad as u16,
                 flags: 0,
                 reserved: [0; 2]
             },
@@ -264,7 +115,7 @@ impl MediaDevice {
                 self.handle().fd(),
                 ioctl::MEDIA_IOC_SETUP_LINK,
                 &mut link as *mut _ as *mut std::os::raw::c_void
-            )?;
+            ).map_err(|e| Error::ioctl("MEDIA_IOC_SETUP_LINK", e))?;
         }
 
         Ok(())
@@ -318,7 +169,7 @@ impl From<media_device_info> for Info {
     }
 }
 
-fn get_device_path_from_interface(interface: &Interface) -> PathBuf {
+pub fn get_device_path_from_interface(interface: &Interface) -> Result<PathBuf, Error> {
     lazy_static! {
         static ref DEVNAME_REGEX: Regex = Regex::new(r"(?m)^DEVNAME=(.+)$").unwrap();
     }
@@ -330,15 +181,12 @@ fn get_device_path_from_interface(interface: &Interface) -> PathBuf {
     let path_string = path.to_string_lossy().to_string();
 
     let ue = fs::read_to_string(path)
-        .expect(&format!("Couldn't read file at {}", &path_string));
+        .map_err(|_| Error::DeviceNotFound(path_string.clone()))?;
 
-    let caps = DEVNAME_REGEX.captures(&ue).unwrap();
+    let caps = DEVNAME_REGEX.captures(&ue)
+        .ok_or_else(|| Error::DeviceNotFound(format!("no device name in {}", path_string)))?;
 
     let devname = &caps[1].to_string();
 
-    let out = PathBuf::from(format!("/dev/{}", &devname));
-
-    println!("Found device in {:#?}", &out);
-
-    out
+    Ok(PathBuf::from(format!("/dev/{}", &devname)))
 }
@@ -96,3 +96,9 @@ pub const VIDIOC_SUBDEV_G_CROP:              _IOC_TYPE = _IOWR!(b'V', 59, v4l2_s
 pub const VIDIOC_SUBDEV_S_CROP:              _IOC_TYPE = _IOWR!(b'V', 60, v4l2_subdev_crop);
 pub const VIDIOC_SUBDEV_G_SELECTION:         _IOC_TYPE = _IOWR!(b'V', 61, v4l2_subdev_selection);
 pub const VIDIOC_SUBDEV_S_SELECTION:         _IOC_TYPE = _IOWR!(b'V', 62, v4l2_subdev_selection);
+
+//linux/videodev2.h, the ones v4l doesn't have or has with its own types
+pub const VIDIOC_QUERYMENU:      _IOC_TYPE = _IOWR!(b'V', 37,  v4l2_querymenu);
+pub const VIDIOC_G_EXT_CTRLS:    _IOC_TYPE = _IOWR!(b'V', 71,  v4l2_ext_controls);
+pub const VIDIOC_S_EXT_CTRLS:    _IOC_TYPE = _IOWR!(b'V', 72,  v4l2_ext_controls);
+pub const VIDIOC_QUERY_EXT_CTRL: _IOC_TYPE = _IOWR!(b'V', 103, v4l2_query_ext_ctrl);
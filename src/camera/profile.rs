@@ -0,0 +1,364 @@
+// Per sensor modes, mounting and default controls, matched to sensors by
+// their entity name. Built-in profiles cover the PinePhone, more can be
+// shipped as files without touching the code.
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+use v4l_subdev::*;
+use crate::camera::Rotation;
+use crate::camera::subdevice::{FrameInterval, FrameSize, SensorMode};
+
+const BUILTIN_PROFILES: &str = include_str!("../../data/device_profiles.toml");
+const SYSTEM_PROFILES: &str = "/usr/share/camcam/device_profiles";
+const AUTO_CONTROLS: [u32; 4] = [V4L2_CID_AUTOGAIN, V4L2_CID_EXPOSURE_AUTO, V4L2_CID_AUTO_WHITE_BALANCE, V4L2_CID_FOCUS_AUTO];
+
+lazy_static! {
+    static ref PROFILES: Vec<(String, Regex, DeviceProfile)> = load_profiles();
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Mode {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32
+}
+
+impl Mode {
+    // The offered mode closest to this one, in the first of codes that has
+    // any. The smallest size covering the wanted one or else the largest,
+    // then its best_interval. Stepwise sizes come with the intervals of their
+    // largest size, look them up again when a smaller one is picked.
+    pub fn best_of(&self, modes: &[SensorMode], codes: &[u32]) -> Option<ModeChoice> {
+        let code = codes.iter().find(|c| modes.iter().any(|m| m.code == **c))?;
+        let candidates = modes.iter()
+            .filter(|m| m.code == *code)
+            .map(|m| {
+                let (width, height) = m.size.nearest(self.width, self.height);
+                (m, width, height)
+            })
+            .collect::<Vec<(&SensorMode, u32, u32)>>();
+
+        let covers = |w: u32, h: u32| w >= self.width && h >= self.height;
+        let area = |w: u32, h: u32| w as u64 * h as u64;
+        let (mode, width, height) = match candidates.iter().filter(|(_, w, h)| covers(*w, *h)).min_by_key(|(_, w, h)| area(*w, *h)) {
+            Some(c) => *c,
+            None => *candidates.iter().max_by_key(|(_, w, h)| area(*w, *h))?
+        };
+
+        Some(ModeChoice {
+            code: *code,
+            size: mode.size,
+            width,
+            height,
+            interval: self.best_interval(&mode.intervals)
+        })
+    }
+
+    // The slowest interval at least as fast as wanted or else the fastest.
+    // None if the driver doesn't list them.
+    pub fn best_interval(&self, intervals: &[FrameInterval]) -> Option<FrameInterval> {
+        let fps = self.fps as f32;
+        match intervals.iter().filter(|i| i.fps() >= fps).min_by(|a, b| a.fps().partial_cmp(&b.fps()).unwrap()) {
+            Some(i) => Some(*i),
+            None => intervals.iter().cloned().max_by(|a, b| a.fps().partial_cmp(&b.fps()).unwrap())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ModeChoice {
+    pub code: u32,
+    // The offered size width and height were picked from.
+    pub size: FrameSize,
+    pub width: u32,
+    pub height: u32,
+    pub interval: Option<FrameInterval>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DeviceProfile {
+    // Regular expression for the sensor entity name.
+    pub entity: String,
+    #[serde(default)]
+    pub mbus_code: Option<u32>,
+    // For photos, mbus_code if not given.
+    #[serde(default)]
+    pub still_mbus_code: Option<u32>,
+    pub preview: Mode,
+    pub still: Mode,
+    // Degrees clockwise.
+    #[serde(default)]
+    pub rotation: u32,
+    #[serde(default)]
+    pub hflip: bool,
+    #[serde(default)]
+    pub vflip: bool,
+    #[serde(default)]
+    pub controls: HashMap<String, i32>,
+    // Microseconds per step of the exposure control, for drivers that count
+    // in lines.
+    #[serde(default)]
+    pub exposure_unit_us: Option<f32>,
+    // Gain control value that counts as ISO 100.
+    #[serde(default)]
+    pub base_gain: Option<f32>,
+    // Run auto exposure in software from preview statistics instead of on
+    // the sensor.
+    #[serde(default)]
+    pub software_ae: bool
+}
+
+impl Default for DeviceProfile {
+    fn default() -> Self {
+        let mode = Mode {
+            width: 1280,
+            height: 720,
+            fps: 30
+        };
+
+        DeviceProfile {
+            entity: String::new(),
+            mbus_code: None,
+            still_mbus_code: None,
+            preview: mode,
+            still: mode,
+            rotation: 0,
+            hflip: false,
+            vflip: false,
+            controls: HashMap::new(),
+            exposure_unit_us: None,
+            base_gain: None,
+            software_ae: false
+        }
+    }
+}
+
+impl DeviceProfile {
+    pub fn mount_rotation(&self) -> Rotation {
+        match self.rotation % 360 {
+            90 => Rotation::Clockwise,
+            180 => Rotation::UpsideDown,
+            270 => Rotation::Counterclockwise,
+            _ => Rotation::Normal
+        }
+    }
+
+    pub fn still_code(&self) -> Option<u32> {
+        self.still_mbus_code.or(self.mbus_code)
+    }
+
+    // Default controls as (control id, value), skipping names we don't know.
+    pub fn control_values(&self) -> Vec<(u32, i32)> {
+        let mut controls = self.controls.iter()
+            .filter_map(|(name, value)| match control_id(name) {
+                Some(id) => Some((id, *value)),
+                None => {
+                    eprintln!("Unknown control {} in device profile, skipping.", name);
+                    None
+                }
+            })
+            .collect::<Vec<(u32, i32)>>();
+        // Automatic modes go first, manual values only stick once they're
+        // off.
+        controls.sort_by_key(|(id, _)| !AUTO_CONTROLS.contains(id));
+        controls
+    }
+}
+
+// The profile for a sensor entity name, or the generic one.
+pub fn for_sensor(entity_name: &str) -> DeviceProfile {
+    match PROFILES.iter().find(|(_, regex, _)| regex.is_match(entity_name)) {
+        Some((name, _, profile)) => {
+            eprintln!("Using device profile {} for {}", name, entity_name);
+            profile.clone()
+        },
+        None => {
+            eprintln!("No device profile for {}, using generic modes.", entity_name);
+            DeviceProfile::default()
+        }
+    }
+}
+
+fn control_id(name: &str) -> Option<u32> {
+    let id = match name {
+        "brightness" => V4L2_CID_BRIGHTNESS,
+        "contrast" => V4L2_CID_CONTRAST,
+        "saturation" => V4L2_CID_SATURATION,
+        "hue" => V4L2_CID_HUE,
+        "gain" => V4L2_CID_GAIN,
+        "exposure" => V4L2_CID_EXPOSURE,
+        "auto_gain" => V4L2_CID_AUTOGAIN,
+        "auto_exposure" => V4L2_CID_EXPOSURE_AUTO,
+        "auto_white_balance" => V4L2_CID_AUTO_WHITE_BALANCE,
+        "focus_auto" => V4L2_CID_FOCUS_AUTO,
+        "focus_absolute" => V4L2_CID_FOCUS_ABSOLUTE,
+        "power_line_frequency" => V4L2_CID_POWER_LINE_FREQUENCY,
+        "test_pattern" => V4L2_CID_TEST_PATTERN,
+        _ => return None
+    };
+    Some(id)
+}
+
+// Built-in profiles, then system wide ones, then the user's. Later files
+// replace profiles of the same name. Profiles are tried in name order.
+fn load_profiles() -> Vec<(String, Regex, DeviceProfile)> {
+    let mut profiles: HashMap<String, DeviceProfile> = toml::from_str(BUILTIN_PROFILES)
+        .expect("Built-in device profiles are broken.");
+
+    let mut directories = vec![PathBuf::from(SYSTEM_PROFILES)];
+    if let Some(mut path) = dirs::config_dir() {
+        path.push("camcam");
+        path.push("device_profiles");
+        directories.push(path);
+    }
+
+    for dir in directories {
+        for path in profile_files(&dir) {
+            let parsed = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|data| toml::from_str::<HashMap<String, DeviceProfile>>(&data).map_err(|e| e.to_string()));
+
+            match parsed {
+                Ok(loaded) => {
+                    eprintln!("Loaded device profiles from {}", path.to_string_lossy());
+                    profiles.extend(loaded);
+                },
+                Err(e) => eprintln!("Ignoring broken device profiles in {}: {}", path.to_string_lossy(), e)
+            }
+        }
+    }
+
+    let mut profiles = profiles.into_iter()
+        .filter_map(|(name, profile)| match Regex::new(&profile.entity) {
+            Ok(regex) => Some((name, regex, profile)),
+            Err(e) => {
+                eprintln!("Ignoring device profile {} with a bad entity pattern: {}", name, e);
+                None
+            }
+        })
+        .collect::<Vec<(String, Regex, DeviceProfile)>>();
+    profiles.sort_by(|a, b| a.0.cmp(&b.0));
+    profiles
+}
+
+fn profile_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().map_or(false, |e| e == "toml"))
+            .collect::<Vec<PathBuf>>(),
+        Err(_) => Vec::new()
+    };
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW8: u32 = MEDIA_BUS_FMT_SBGGR8_1X8;
+    const RAW10: u32 = MEDIA_BUS_FMT_SBGGR10_1X10;
+
+    fn interval(fps: u32) -> FrameInterval {
+        FrameInterval {
+            numerator: 1,
+            denominator: fps
+        }
+    }
+
+    fn discrete(code: u32, width: u32, height: u32, rates: &[u32]) -> SensorMode {
+        SensorMode {
+            code,
+            size: FrameSize::Discrete { width, height },
+            intervals: rates.iter().map(|fps| interval(*fps)).collect()
+        }
+    }
+
+    fn mode(width: u32, height: u32, fps: u32) -> Mode {
+        Mode { width, height, fps }
+    }
+
+    // Like the PinePhone's back camera.
+    fn ov5640() -> Vec<SensorMode> {
+        vec![
+            discrete(RAW8, 640, 480, &[15, 30]),
+            discrete(RAW8, 1280, 720, &[15, 30]),
+            discrete(RAW8, 1920, 1080, &[15, 30]),
+            discrete(RAW8, 2592, 1944, &[15]),
+            discrete(RAW10, 1280, 720, &[30]),
+            discrete(RAW10, 2592, 1944, &[15])
+        ]
+    }
+
+    fn size_of(choice: &ModeChoice) -> (u32, u32, u32) {
+        (choice.code, choice.width, choice.height)
+    }
+
+    #[test]
+    fn smallest_size_covering_the_wanted_one() {
+        let choice = mode(1280, 720, 30).best_of(&ov5640(), &[RAW8]).unwrap();
+        assert_eq!(size_of(&choice), (RAW8, 1280, 720));
+        let choice = mode(1300, 720, 30).best_of(&ov5640(), &[RAW8]).unwrap();
+        assert_eq!(size_of(&choice), (RAW8, 1920, 1080));
+    }
+
+    #[test]
+    fn largest_size_without_one_covering() {
+        let choice = mode(4000, 3000, 15).best_of(&ov5640(), &[RAW8]).unwrap();
+        assert_eq!(size_of(&choice), (RAW8, 2592, 1944));
+    }
+
+    #[test]
+    fn first_code_that_has_modes() {
+        let choice = mode(2592, 1944, 15).best_of(&ov5640(), &[RAW10, RAW8]).unwrap();
+        assert_eq!(size_of(&choice), (RAW10, 2592, 1944));
+        // The sensor doesn't have 12-bit, 8-bit takes over.
+        let choice = mode(1920, 1080, 30).best_of(&ov5640(), &[MEDIA_BUS_FMT_SBGGR12_1X12, RAW8]).unwrap();
+        assert_eq!(size_of(&choice), (RAW8, 1920, 1080));
+        assert!(mode(1920, 1080, 30).best_of(&ov5640(), &[MEDIA_BUS_FMT_SBGGR12_1X12]).is_none());
+        assert!(mode(1920, 1080, 30).best_of(&[], &[RAW8]).is_none());
+    }
+
+    #[test]
+    fn intervals() {
+        let wanted = mode(640, 480, 24);
+        // The slowest one fast enough.
+        assert_eq!(wanted.best_interval(&[interval(15), interval(60), interval(30)]), Some(interval(30)));
+        // Or the fastest there is.
+        assert_eq!(wanted.best_interval(&[interval(15), interval(10)]), Some(interval(15)));
+        assert_eq!(wanted.best_interval(&[]), None);
+
+        let choice = mode(2592, 1944, 30).best_of(&ov5640(), &[RAW8]).unwrap();
+        assert_eq!(choice.interval, Some(interval(15)));
+        let choice = mode(640, 480, 20).best_of(&ov5640(), &[RAW8]).unwrap();
+        assert_eq!(choice.interval, Some(interval(30)));
+        let choice = mode(640, 480, 20).best_of(&[discrete(RAW8, 640, 480, &[])], &[RAW8]).unwrap();
+        assert_eq!(choice.interval, None);
+    }
+
+    #[test]
+    fn stepwise_sizes() {
+        let stepwise = SensorMode {
+            code: RAW8,
+            size: FrameSize::Stepwise {
+                min_width: 16,
+                max_width: 4096,
+                min_height: 16,
+                max_height: 2160
+            },
+            intervals: vec![interval(30)]
+        };
+        let modes = [discrete(RAW8, 4096, 2160, &[30]), stepwise.clone()];
+
+        let choice = mode(1280, 720, 60).best_of(&modes, &[RAW8]).unwrap();
+        assert_eq!(size_of(&choice), (RAW8, 1280, 720));
+        assert_eq!(choice.size, stepwise.size);
+        assert_eq!(choice.size.largest(), (4096, 2160));
+        // Clamped into the range.
+        let choice = mode(8, 9000, 30).best_of(&[stepwise], &[RAW8]).unwrap();
+        assert_eq!(size_of(&choice), (RAW8, 16, 2160));
+    }
+}
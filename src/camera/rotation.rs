@@ -0,0 +1,80 @@
+// Turning preview frames to the way the screen is held, in the camera thread
+// so the UI only has to draw them.
+
+// Clockwise quarter turns.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Rotation {
+    #[default]
+    Normal,
+    Clockwise,
+    UpsideDown,
+    Counterclockwise
+}
+
+const ROTATIONS: [Rotation; 4] = [Rotation::Normal, Rotation::Clockwise, Rotation::UpsideDown, Rotation::Counterclockwise];
+
+impl Rotation {
+    // How far the screen contents have turned from portrait, from the
+    // orientation iio-sensor-proxy reports. Agrees with the EXIF orientation
+    // captures get in convert.
+    pub fn from_device_orientation(orientation: &str) -> Self {
+        match orientation {
+            "right-up" => Rotation::Clockwise,
+            "bottom-up" => Rotation::UpsideDown,
+            "left-up" => Rotation::Counterclockwise,
+            _ => Rotation::Normal
+        }
+    }
+
+    fn quarter_turns(self) -> usize {
+        ROTATIONS.iter().position(|r| *r == self).unwrap()
+    }
+
+    // This rotation undone by other.
+    pub fn minus(self, other: Rotation) -> Self {
+        ROTATIONS[(self.quarter_turns() + 4 - other.quarter_turns()) % 4]
+    }
+
+    pub fn swaps_sides(self) -> bool {
+        self == Rotation::Clockwise || self == Rotation::Counterclockwise
+    }
+
+    // Where x, y of a width x height image ends up once it's turned.
+    #[inline]
+    pub fn position(self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            Rotation::Normal => (x, y),
+            Rotation::Clockwise => (height - 1 - y, x),
+            Rotation::UpsideDown => (width - 1 - x, height - 1 - y),
+            Rotation::Counterclockwise => (y, width - 1 - x)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions() {
+        // Top left and top right of a 4x2 image.
+        let corners = |rotation: Rotation| (rotation.position(0, 0, 4, 2), rotation.position(3, 0, 4, 2));
+        assert_eq!(corners(Rotation::Normal), ((0, 0), (3, 0)));
+        assert_eq!(corners(Rotation::Clockwise), ((1, 0), (1, 3)));
+        assert_eq!(corners(Rotation::UpsideDown), ((3, 1), (0, 1)));
+        assert_eq!(corners(Rotation::Counterclockwise), ((0, 3), (0, 0)));
+    }
+
+    #[test]
+    fn turns_add_up() {
+        for &a in ROTATIONS.iter() {
+            assert_eq!(a.minus(a), Rotation::Normal);
+            for &b in ROTATIONS.iter() {
+                // Turning by a - b and then by b is turning by a.
+                let (x, y) = a.minus(b).position(1, 0, 3, 2);
+                let (w, h) = if a.minus(b).swaps_sides() { (2, 3) } else { (3, 2) };
+                assert_eq!(b.position(x, y, w, h), a.position(1, 0, 3, 2), "{:?} {:?}", a, b);
+            }
+        }
+    }
+}
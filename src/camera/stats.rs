@@ -0,0 +1,225 @@
+// Frame statistics for the automatic exposure and white balance loop, read
+// from a coarse grid of the raw Bayer frame straight out of the video device.
+use crate::camera::bayer::RawFormat;
+use crate::camera::focus::Region;
+
+pub const HISTOGRAM_BINS: usize = 64;
+// Superpixels sampled across and down, enough for metering and cheap enough
+// for every preview frame.
+const GRID_WIDTH: usize = 64;
+const GRID_HEIGHT: usize = 48;
+
+#[derive(Clone, Debug)]
+pub struct FrameStats {
+    // Luminance of the samples, black to white in equal bins.
+    pub histogram: [u32; HISTOGRAM_BINS],
+    // Red, green and blue means, 0 for black and 1 for white.
+    pub mean: [f32; 3],
+    // Black level subtracted [r, g, b] of each sample, row by row.
+    pub samples: Vec<[u16; 3]>,
+    // Samples across and down.
+    pub grid: (usize, usize),
+    // White after subtracting the black level.
+    pub max: u16
+}
+
+impl FrameStats {
+    // source is the frame size in pixels, black_level in the frame's bit
+    // depth.
+    pub fn from_raw(data: &[u8], stride: usize, raw_format: &RawFormat, source: (usize, usize), black_level: u16) -> Self {
+        let (blocks_w, blocks_h) = (source.0 / 2, source.1 / 2);
+        let (grid_w, grid_h) = (GRID_WIDTH.min(blocks_w), GRID_HEIGHT.min(blocks_h));
+        let pattern = raw_format.pattern;
+        let (red_row, red_col) = pattern.red_position();
+        let (green_row, green_col) = pattern.green_position();
+        let (blue_row, blue_col) = pattern.blue_position();
+        let max = raw_format.max_value().saturating_sub(black_level).max(1);
+
+        let mut samples = Vec::with_capacity(grid_w * grid_h);
+        let mut histogram = [0; HISTOGRAM_BINS];
+        let mut sums = [0u64; 3];
+
+        for y in 0..grid_h {
+            // Middle of each grid cell.
+            let row = (y * 2 + 1) * blocks_h / (grid_h * 2) * 2;
+            let line = |r: usize| &data[(row + r) * stride..];
+            let (red_line, green_line, blue_line) = (line(red_row), line(green_row), line(blue_row));
+
+            for x in 0..grid_w {
+                let col = (x * 2 + 1) * blocks_w / (grid_w * 2) * 2;
+                let sample = [
+                    raw_format.sample(red_line, col + red_col).saturating_sub(black_level),
+                    raw_format.sample(green_line, col + green_col).saturating_sub(black_level),
+                    raw_format.sample(blue_line, col + blue_col).saturating_sub(black_level)
+                ];
+
+                for (sum, value) in sums.iter_mut().zip(sample.iter()) {
+                    *sum += *value as u64;
+                }
+                let bin = luma(sample) as usize * HISTOGRAM_BINS / (max as usize + 1);
+                histogram[bin.min(HISTOGRAM_BINS - 1)] += 1;
+                samples.push(sample);
+            }
+        }
+
+        let count = samples.len().max(1) as f32;
+        let mean = [
+            sums[0] as f32 / count / max as f32,
+            sums[1] as f32 / count / max as f32,
+            sums[2] as f32 / count / max as f32
+        ];
+
+        FrameStats {
+            histogram,
+            mean,
+            samples,
+            grid: (grid_w, grid_h),
+            max
+        }
+    }
+
+    // Mean luminance, 0 to 1.
+    pub fn luma(&self) -> f32 {
+        (self.mean[0] + self.mean[1] * 2.0 + self.mean[2]) / 4.0
+    }
+
+    // Mean luminance of the samples in region, None if it has none.
+    pub fn region_luma(&self, region: &Region) -> Option<f32> {
+        let (grid_w, grid_h) = self.grid;
+        let (sum, count) = self.samples.iter().enumerate()
+            .filter(|(i, _)| {
+                let x = (i % grid_w) as f32 + 0.5;
+                let y = (i / grid_w) as f32 + 0.5;
+                region.contains(x / grid_w as f32, y / grid_h as f32)
+            })
+            .fold((0u64, 0u64), |(sum, count), (_, sample)| (sum + luma(*sample) as u64, count + 1));
+
+        if count == 0 {
+            None
+        } else {
+            Some(sum as f32 / count as f32 / self.max as f32)
+        }
+    }
+
+    // Luminance below which the given share of the samples are, 0 to 1.
+    pub fn percentile(&self, share: f32) -> f32 {
+        let total = self.histogram.iter().sum::<u32>();
+        let wanted = (total as f32 * share).ceil() as u32;
+        let mut seen = 0;
+        for (bin, count) in self.histogram.iter().enumerate() {
+            seen += count;
+            if seen >= wanted {
+                return (bin + 1) as f32 / HISTOGRAM_BINS as f32;
+            }
+        }
+        1.0
+    }
+
+    // Share of the samples in the top bin, blown out or nearly.
+    pub fn clipped(&self) -> f32 {
+        let total = self.histogram.iter().sum::<u32>().max(1);
+        self.histogram[HISTOGRAM_BINS - 1] as f32 / total as f32
+    }
+}
+
+// Camera RGB has two greens for every red and blue, weigh them the same.
+fn luma(sample: [u16; 3]) -> u16 {
+    ((sample[0] as u32 + sample[1] as u32 * 2 + sample[2] as u32) / 4) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::bayer::{CfaPattern, Channel, Packing};
+
+    const WIDTH: usize = 160;
+    const HEIGHT: usize = 120;
+    const BLACK: u16 = 16;
+
+    fn format() -> RawFormat {
+        RawFormat::new(CfaPattern::Grbg, 8, Packing::Unpacked)
+    }
+
+    // 8-bit frame with each channel at its own level, the left half at
+    // dark and the right at bright, both [r, g, b].
+    fn frame(dark: [u8; 3], bright: [u8; 3]) -> Vec<u8> {
+        let pattern = format().pattern;
+        let mut data = vec![0; WIDTH * HEIGHT];
+        for row in 0..HEIGHT {
+            for col in 0..WIDTH {
+                let levels = if col < WIDTH / 2 { dark } else { bright };
+                data[row * WIDTH + col] = match pattern.channel_at(row, col) {
+                    Channel::Red => levels[0],
+                    Channel::Green => levels[1],
+                    Channel::Blue => levels[2]
+                };
+            }
+        }
+        data
+    }
+
+    fn stats(data: &[u8]) -> FrameStats {
+        FrameStats::from_raw(data, WIDTH, &format(), (WIDTH, HEIGHT), BLACK)
+    }
+
+    #[test]
+    fn channel_means() {
+        let stats = stats(&frame([56, 136, 96], [56, 136, 96]));
+        let max = (255 - BLACK) as f32;
+        assert_eq!(stats.max, 255 - BLACK);
+        assert_eq!(stats.grid, (64, 48));
+        assert_eq!(stats.samples.len(), 64 * 48);
+        assert!(stats.samples.iter().all(|s| *s == [40, 120, 80]));
+        for (mean, expected) in stats.mean.iter().zip([40.0, 120.0, 80.0].iter()) {
+            assert!((mean - expected / max).abs() < 1e-6, "{:?}", stats.mean);
+        }
+        assert!((stats.luma() - 90.0 / max).abs() < 1e-6);
+    }
+
+    #[test]
+    fn histogram() {
+        // Luma 0 on the left and 180 on the right.
+        let halves = stats(&frame([BLACK as u8; 3], [196; 3]));
+        let bright_bin = 180 * HISTOGRAM_BINS / 240;
+        let half = (64 * 48 / 2) as u32;
+        assert_eq!(halves.histogram.iter().sum::<u32>(), half * 2);
+        assert_eq!(halves.histogram[0], half);
+        assert_eq!(halves.histogram[bright_bin], half);
+        assert_eq!(halves.percentile(0.5), 1.0 / HISTOGRAM_BINS as f32);
+        assert_eq!(halves.percentile(0.75), (bright_bin + 1) as f32 / HISTOGRAM_BINS as f32);
+        assert_eq!(halves.clipped(), 0.0);
+
+        // Under the black level counts as black, white as clipped.
+        let clipped = stats(&frame([0; 3], [255; 3]));
+        assert_eq!(clipped.histogram[0], half);
+        assert_eq!(clipped.clipped(), 0.5);
+        assert_eq!(clipped.percentile(1.0), 1.0);
+    }
+
+    #[test]
+    fn region_luma() {
+        let stats = stats(&frame([BLACK as u8 + 20; 3], [BLACK as u8 + 200; 3]));
+        let max = (255 - BLACK) as f32;
+        let left = Region::around(0.25, 0.5, 0.4);
+        let right = Region::around(0.75, 0.5, 0.4);
+        assert!((stats.region_luma(&left).unwrap() - 20.0 / max).abs() < 1e-6);
+        assert!((stats.region_luma(&right).unwrap() - 200.0 / max).abs() < 1e-6);
+        assert!((stats.luma() - 110.0 / max).abs() < 1e-6);
+        let nowhere = Region {
+            x: 0.0,
+            y: 0.0,
+            width: 0.0,
+            height: 0.0
+        };
+        assert_eq!(stats.region_luma(&nowhere), None);
+    }
+
+    #[test]
+    fn small_frames_sample_every_block() {
+        let (width, height) = (20, 10);
+        let data = vec![BLACK as u8 + 100; width * height];
+        let stats = FrameStats::from_raw(&data, width, &format(), (width, height), BLACK);
+        assert_eq!(stats.grid, (10, 5));
+        assert_eq!(stats.samples.len(), 50);
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    fmt,
     io,
     mem,
     path::Path,
@@ -6,6 +7,8 @@ use std::{
 };
 use v4l::{v4l2};
 use v4l_subdev::*;
+use crate::camera::Error;
+use crate::camera::controls::{self, ControlInfo, ControlValue};
 use crate::camera::media_device::Handle;
 use crate::camera::media_ioctl as ioctl;
 use crate::camera::topology::*;
@@ -18,11 +21,11 @@ pub struct Subdevice {
 }
 
 impl Subdevice {
-    pub fn open<P: AsRef<Path>>(path: P, entity: &Entity, interface: &Interface, pad: &Pad) -> io::Result<Self> {
-        let fd = v4l2::open(&path, libc::O_RDWR)?;
+    pub fn open<P: AsRef<Path>>(path: P, entity: &Entity, interface: &Interface, pad: &Pad) -> Result<Self, Error> {
+        let fd = v4l2::open(&path, libc::O_RDWR).map_err(|e| Error::open(path.as_ref(), e))?;
 
         if fd == -1 {
-            return Err(io::Error::last_os_error());
+            return Err(Error::open(path.as_ref(), io::Error::last_os_error()));
         }
 
         Ok(Subdevice {
@@ -37,33 +40,65 @@ impl Subdevice {
         self.handle.clone()
     }
 
-    pub fn set_format(&self, width: u32, height: u32) {
+    pub fn set_format(&self, width: u32, height: u32, code: u32) -> Result<SubdevFormat, Error> {
+        self.set_pad_format(self.pad.index, width, height, code)
+    }
+
+    // Active format on the source pad. Flips may change the bayer order, so
+    // read this back after setting controls.
+    pub fn format(&self) -> Result<SubdevFormat, Error> {
+        self.pad_format(self.pad.index)
+    }
+
+    // Like set_format, for entities with more pads than the one they were
+    // opened with. Returns what the driver made of it.
+    pub fn set_pad_format(&self, pad: u32, width: u32, height: u32, code: u32) -> Result<SubdevFormat, Error> {
         unsafe {
             let mut format: v4l2_subdev_format = mem::zeroed();
             format.which = 1;
-            format.pad = 0;
+            format.pad = pad;
             format.format.width = width;
             format.format.height = height;
-            format.format.code = 0x3001; //<- BGGR 0x3014; <- RGGB
+            format.format.code = code;
             format.format.field = 0;
             format.format.colorspace = v4l2_colorspace_V4L2_COLORSPACE_RAW;
-            println!("Setting subdevice format {}x{}", width, height);
+
             v4l2::ioctl(
                 self.handle().fd(),
                 ioctl::VIDIOC_SUBDEV_S_FMT,
                 &mut format as *mut _ as *mut std::os::raw::c_void
-            ).expect("Failed setting subdevice format.");
+            ).map_err(|e| Error::ioctl("VIDIOC_SUBDEV_S_FMT", e))?;
 
-            let format = SubdevFormat::from(&format);
+            Ok(SubdevFormat::from(&format))
+        }
+    }
+
+    pub fn pad_format(&self, pad: u32) -> Result<SubdevFormat, Error> {
+        unsafe {
+            let mut format: v4l2_subdev_format = mem::zeroed();
+
+            format.pad = pad;
+            format.which = v4l2_subdev_format_whence_V4L2_SUBDEV_FORMAT_ACTIVE;
+
+            v4l2::ioctl(
+                self.handle().fd(),
+                ioctl::VIDIOC_SUBDEV_G_FMT,
+                &mut format as *mut _ as *mut std::os::raw::c_void
+            ).map_err(|e| Error::ioctl("VIDIOC_SUBDEV_G_FMT", e))?;
 
-            println!("Set subdevice format: {:#?}", format);
+            Ok(SubdevFormat::from(&format))
         }
     }
 
-    pub fn set_interval(&self, numerator: u32, denominator: u32) {
+    pub fn set_interval(&self, numerator: u32, denominator: u32) -> Result<(), Error> {
+        self.set_pad_interval(self.pad.index, numerator, denominator)
+    }
+
+    pub fn set_pad_interval(&self, pad: u32, numerator: u32, denominator: u32) -> Result<(), Error> {
         unsafe {
             let mut interval: v4l2_subdev_frame_interval = mem::zeroed();
 
+            interval.pad = pad;
             interval.interval.numerator = numerator;
             interval.interval.denominator = denominator;
 
@@ -71,61 +106,172 @@ impl Subdevice {
                 self.handle().fd(),
                 ioctl::VIDIOC_SUBDEV_S_FRAME_INTERVAL,
                 &mut interval as *mut _ as *mut std::os::raw::c_void
-            ).expect("Failed querying subdevice interval");
-
-            let numerator = interval.interval.numerator;
-            let denominator = interval.interval.denominator;
-
-            println!("Set subdevice interval: {}/{}", numerator, denominator);
+            ).map_err(|e| Error::ioctl("VIDIOC_SUBDEV_S_FRAME_INTERVAL", e))?;
         }
+
+        Ok(())
     }
 
-    pub fn auto_focus(&self, enable: bool) {
+    pub fn pad_interval(&self, pad: u32) -> Result<FrameInterval, Error> {
         unsafe {
-            let mut val = v4l2_control {
-                id: V4L2_CID_FOCUS_AUTO,
-                value: if enable { 1 } else { 0 }
-            };
+            let mut interval: v4l2_subdev_frame_interval = mem::zeroed();
+
+            interval.pad = pad;
 
             v4l2::ioctl(
                 self.handle().fd(),
-                v4l2::vidioc::VIDIOC_S_CTRL,
-                &mut val as *mut _ as *mut std::os::raw::c_void
-            ).expect("Failed setting subdev autofocus.");
+                ioctl::VIDIOC_SUBDEV_G_FRAME_INTERVAL,
+                &mut interval as *mut _ as *mut std::os::raw::c_void
+            ).map_err(|e| Error::ioctl("VIDIOC_SUBDEV_G_FRAME_INTERVAL", e))?;
+
+            Ok(FrameInterval {
+                numerator: interval.interval.numerator,
+                denominator: interval.interval.denominator
+            })
         }
     }
 
-    pub fn hflip(&self, enable: bool) {
+    pub fn control(&self, id: u32) -> Result<i32, Error> {
         unsafe {
             let mut val = v4l2_control {
-                id: V4L2_CID_HFLIP,
-                value: if enable { 1 } else { 0 }
+                id,
+                value: 0
             };
 
             v4l2::ioctl(
                 self.handle().fd(),
-                v4l2::vidioc::VIDIOC_S_CTRL,
+                v4l2::vidioc::VIDIOC_G_CTRL,
                 &mut val as *mut _ as *mut std::os::raw::c_void
-            ).expect("Failed setting subdev hflip.");
+            ).map_err(|e| Error::ioctl("VIDIOC_G_CTRL", e))?;
+
+            Ok(val.value)
         }
     }
 
-    pub fn vflip(&self, enable: bool) {
+    pub fn set_control(&self, id: u32, value: i32) -> Result<(), Error> {
         unsafe {
             let mut val = v4l2_control {
-                id: V4L2_CID_VFLIP,
-                value: if enable { 1 } else { 0 }
+                id,
+                value
             };
 
             v4l2::ioctl(
                 self.handle().fd(),
                 v4l2::vidioc::VIDIOC_S_CTRL,
                 &mut val as *mut _ as *mut std::os::raw::c_void
-            ).expect("Failed setting subdev vflip.");
+            ).map_err(|e| Error::ioctl("VIDIOC_S_CTRL", e))?;
+
+            Ok(())
+        }
+    }
+
+    pub fn controls(&self) -> io::Result<Vec<ControlInfo>> {
+        controls::query_all(self.handle().fd())
+    }
+
+    pub fn get_controls(&self, controls: &[&ControlInfo]) -> io::Result<Vec<ControlValue>> {
+        controls::get(self.handle().fd(), controls)
+    }
+
+    // All or nothing, a value out of range fails the lot.
+    pub fn set_controls(&self, values: &[(&ControlInfo, ControlValue)]) -> io::Result<()> {
+        controls::set(self.handle().fd(), values)
+    }
+
+    // Media bus codes the pad can output.
+    pub fn mbus_codes(&self, pad: u32) -> Result<Vec<u32>, Error> {
+        let mut codes = Vec::new();
+
+        for index in 0.. {
+            unsafe {
+                let mut code_enum: v4l2_subdev_mbus_code_enum = mem::zeroed();
+                code_enum.pad = pad;
+                code_enum.index = index;
+                code_enum.which = v4l2_subdev_format_whence_V4L2_SUBDEV_FORMAT_ACTIVE;
+
+                if !enumerate(self.handle().fd(), ioctl::VIDIOC_SUBDEV_ENUM_MBUS_CODE, "VIDIOC_SUBDEV_ENUM_MBUS_CODE", &mut code_enum)? {
+                    break;
+                }
+
+                codes.push(code_enum.code);
+            }
+        }
+
+        Ok(codes)
+    }
+
+    pub fn frame_sizes(&self, pad: u32, code: u32) -> Result<Vec<FrameSize>, Error> {
+        let mut sizes = Vec::new();
+
+        for index in 0.. {
+            unsafe {
+                let mut size_enum: v4l2_subdev_frame_size_enum = mem::zeroed();
+                size_enum.pad = pad;
+                size_enum.index = index;
+                size_enum.code = code;
+                size_enum.which = v4l2_subdev_format_whence_V4L2_SUBDEV_FORMAT_ACTIVE;
+
+                if !enumerate(self.handle().fd(), ioctl::VIDIOC_SUBDEV_ENUM_FRAME_SIZE, "VIDIOC_SUBDEV_ENUM_FRAME_SIZE", &mut size_enum)? {
+                    break;
+                }
+
+                sizes.push(FrameSize::from(&size_enum));
+            }
+        }
+
+        Ok(sizes)
+    }
+
+    pub fn frame_intervals(&self, pad: u32, code: u32, width: u32, height: u32) -> Result<Vec<FrameInterval>, Error> {
+        let mut intervals = Vec::new();
+
+        for index in 0.. {
+            unsafe {
+                let mut interval_enum: v4l2_subdev_frame_interval_enum = mem::zeroed();
+                interval_enum.pad = pad;
+                interval_enum.index = index;
+                interval_enum.code = code;
+                interval_enum.width = width;
+                interval_enum.height = height;
+                interval_enum.which = v4l2_subdev_format_whence_V4L2_SUBDEV_FORMAT_ACTIVE;
+
+                if !enumerate(self.handle().fd(), ioctl::VIDIOC_SUBDEV_ENUM_FRAME_INTERVAL, "VIDIOC_SUBDEV_ENUM_FRAME_INTERVAL", &mut interval_enum)? {
+                    break;
+                }
+
+                intervals.push(FrameInterval {
+                    numerator: interval_enum.interval.numerator,
+                    denominator: interval_enum.interval.denominator
+                });
+            }
+        }
+
+        Ok(intervals)
+    }
+
+    // Every code, size and interval combination the source pad offers.
+    // Stepwise sizes are represented by their largest one, intervals are
+    // empty when the driver doesn't list any.
+    pub fn modes(&self) -> Result<Vec<SensorMode>, Error> {
+        let pad = self.pad.index;
+        let mut modes = Vec::new();
+
+        for code in self.mbus_codes(pad)? {
+            for size in self.frame_sizes(pad, code)? {
+                let (width, height) = size.largest();
+                let intervals = self.frame_intervals(pad, code, width, height)?;
+                modes.push(SensorMode {
+                    code,
+                    size,
+                    intervals
+                });
+            }
         }
+
+        Ok(modes)
     }
 
-    pub fn print_interval(&self) {
+    pub fn print_interval(&self) -> Result<(), Error> {
         unsafe {
             let mut interval: v4l2_subdev_frame_interval = mem::zeroed();
 
@@ -133,16 +279,18 @@ impl Subdevice {
                 self.handle().fd(),
                 ioctl::VIDIOC_SUBDEV_G_FRAME_INTERVAL,
                 &mut interval as *mut _ as *mut std::os::raw::c_void
-            ).expect("Failed querying subdevice interval");
+            ).map_err(|e| Error::ioctl("VIDIOC_SUBDEV_G_FRAME_INTERVAL", e))?;
 
             let numerator = interval.interval.numerator;
             let denominator = interval.interval.denominator;
 
-            println!("Subdevice interval: {}/{}", numerator, denominator);
+            eprintln!("Subdevice interval: {}/{}", numerator, denominator);
         }
+
+        Ok(())
     }
 
-    pub fn print_format(&self) {
+    pub fn print_format(&self) -> Result<(), Error> {
         unsafe {
             let mut format: v4l2_subdev_format = mem::zeroed();
 
@@ -153,15 +301,147 @@ impl Subdevice {
                 self.handle().fd(),
                 ioctl::VIDIOC_SUBDEV_G_FMT,
                 &mut format as *mut _ as *mut std::os::raw::c_void
-            ).expect("Failed reading subdevice format.");
+            ).map_err(|e| Error::ioctl("VIDIOC_SUBDEV_G_FMT", e))?;
 
             let format = SubdevFormat::from(&format);
 
-            println!("Subdevice format: {:#?}", format);
+            eprintln!("Subdevice format: {:#?}", format);
+        }
+
+        Ok(())
+    }
+}
+
+// A voice coil driver moving a sensor's lens, like the dw9714 or ad5820. It
+// has a subdevice node for its controls but no pads.
+pub struct Lens {
+    pub handle: Arc<Handle>,
+    pub entity: Entity,
+    // Read once, to tell its controls from the sensor's.
+    pub control_ids: Vec<u32>
+}
+
+impl Lens {
+    pub fn open<P: AsRef<Path>>(path: P, entity: &Entity) -> Result<Self, Error> {
+        let fd = v4l2::open(&path, libc::O_RDWR).map_err(|e| Error::open(path.as_ref(), e))?;
+
+        if fd == -1 {
+            return Err(Error::open(path.as_ref(), io::Error::last_os_error()));
+        }
+
+        let handle = Arc::new(Handle::new(fd));
+        let control_ids = controls::query_all(handle.fd())?.iter().map(|c| c.id).collect();
+        Ok(Lens {
+            handle,
+            entity: entity.clone(),
+            control_ids
+        })
+    }
+
+    pub fn has_control(&self, id: u32) -> bool {
+        self.control_ids.contains(&id)
+    }
+
+    pub fn controls(&self) -> io::Result<Vec<ControlInfo>> {
+        controls::query_all(self.handle.fd())
+    }
+
+    pub fn get_controls(&self, controls: &[&ControlInfo]) -> io::Result<Vec<ControlValue>> {
+        controls::get(self.handle.fd(), controls)
+    }
+
+    pub fn set_controls(&self, values: &[(&ControlInfo, ControlValue)]) -> io::Result<()> {
+        controls::set(self.handle.fd(), values)
+    }
+}
+
+// Runs one step of an enumeration ioctl. False once index runs past the end,
+// or right away for drivers without the ioctl, like vimc's sensors for frame
+// intervals.
+unsafe fn enumerate<T>(fd: std::os::raw::c_int, request: ioctl::_IOC_TYPE, name: &'static str, arg: &mut T) -> Result<bool, Error> {
+    match v4l2::ioctl(fd, request, arg as *mut _ as *mut std::os::raw::c_void) {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) || e.raw_os_error() == Some(libc::ENOTTY) => Ok(false),
+        Err(e) => Err(Error::ioctl(name, e))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameSize {
+    Discrete {
+        width: u32,
+        height: u32
+    },
+    // Anything in between, the driver rounds to what it can do.
+    Stepwise {
+        min_width: u32,
+        max_width: u32,
+        min_height: u32,
+        max_height: u32
+    }
+}
+
+impl FrameSize {
+    pub fn largest(&self) -> (u32, u32) {
+        match *self {
+            FrameSize::Discrete { width, height } => (width, height),
+            FrameSize::Stepwise { max_width, max_height, .. } => (max_width, max_height)
+        }
+    }
+
+    // The size closest to width x height this allows.
+    pub fn nearest(&self, width: u32, height: u32) -> (u32, u32) {
+        match *self {
+            FrameSize::Discrete { width, height } => (width, height),
+            FrameSize::Stepwise { min_width, max_width, min_height, max_height } => (
+                width.max(min_width).min(max_width),
+                height.max(min_height).min(max_height)
+            )
+        }
+    }
+}
+
+impl From<&v4l2_subdev_frame_size_enum> for FrameSize {
+    fn from(size: &v4l2_subdev_frame_size_enum) -> FrameSize {
+        if size.min_width == size.max_width && size.min_height == size.max_height {
+            FrameSize::Discrete {
+                width: size.max_width,
+                height: size.max_height
+            }
+        } else {
+            FrameSize::Stepwise {
+                min_width: size.min_width,
+                max_width: size.max_width,
+                min_height: size.min_height,
+                max_height: size.max_height
+            }
+        }
+    }
+}
+
+// Seconds per frame as a fraction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameInterval {
+    pub numerator: u32,
+    pub denominator: u32
+}
+
+impl FrameInterval {
+    pub fn fps(&self) -> f32 {
+        if self.numerator == 0 {
+            return 0.0;
         }
+        self.denominator as f32 / self.numerator as f32
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct SensorMode {
+    pub code: u32,
+    pub size: FrameSize,
+    pub intervals: Vec<FrameInterval>
+}
+
 #[derive(Clone, Debug)]
 pub struct SubdevFormat {
     pub which: u32,
@@ -203,3 +483,52 @@ impl From<&v4l2_mbus_framefmt> for FrameFormat {
         }
     }
 }
+
+// Like media-ctl prints them, "fmt:SBGGR8_1X8/1280x720".
+impl fmt::Display for FrameFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match mbus_code_name(self.code) {
+            Some(name) => write!(f, "fmt:{}/{}x{}", name, self.width, self.height),
+            None => write!(f, "fmt:{:#x}/{}x{}", self.code, self.width, self.height)
+        }
+    }
+}
+
+impl FrameFormat {
+    // What the kernel compares on both ends of a link before streaming.
+    pub fn matches(&self, other: &FrameFormat) -> bool {
+        self.width == other.width && self.height == other.height && self.code == other.code
+    }
+}
+
+// The media-ctl name of a media bus code, like "SBGGR8_1X8".
+pub fn mbus_code_name(code: u32) -> Option<&'static str> {
+    MBUS_CODE_NAMES.iter().find(|(c, _)| *c == code).map(|(_, name)| *name)
+}
+
+// The other way around, numbers like "0x3001" work too.
+pub fn mbus_code(name: &str) -> Option<u32> {
+    match name.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => MBUS_CODE_NAMES.iter().find(|(_, n)| n.eq_ignore_ascii_case(name)).map(|(code, _)| *code)
+    }
+}
+
+const MBUS_CODE_NAMES: [(u32, &str); 16] = [
+    (MEDIA_BUS_FMT_SBGGR8_1X8, "SBGGR8_1X8"),
+    (MEDIA_BUS_FMT_SGBRG8_1X8, "SGBRG8_1X8"),
+    (MEDIA_BUS_FMT_SGRBG8_1X8, "SGRBG8_1X8"),
+    (MEDIA_BUS_FMT_SRGGB8_1X8, "SRGGB8_1X8"),
+    (MEDIA_BUS_FMT_SBGGR10_1X10, "SBGGR10_1X10"),
+    (MEDIA_BUS_FMT_SGBRG10_1X10, "SGBRG10_1X10"),
+    (MEDIA_BUS_FMT_SGRBG10_1X10, "SGRBG10_1X10"),
+    (MEDIA_BUS_FMT_SRGGB10_1X10, "SRGGB10_1X10"),
+    (MEDIA_BUS_FMT_SBGGR12_1X12, "SBGGR12_1X12"),
+    (MEDIA_BUS_FMT_SGBRG12_1X12, "SGBRG12_1X12"),
+    (MEDIA_BUS_FMT_SGRBG12_1X12, "SGRBG12_1X12"),
+    (MEDIA_BUS_FMT_SRGGB12_1X12, "SRGGB12_1X12"),
+    (MEDIA_BUS_FMT_RGB888_1X24, "RGB888_1X24"),
+    (MEDIA_BUS_FMT_UYVY8_2X8, "UYVY8_2X8"),
+    (MEDIA_BUS_FMT_YUYV8_2X8, "YUYV8_2X8"),
+    (MEDIA_BUS_FMT_YUYV8_1X16, "YUYV8_1X16")
+];
@@ -0,0 +1,405 @@
+// Cameras that make their frames up, so the app runs on machines without
+// camera hardware. Frames are raw Bayer test patterns that get brighter and
+// darker with the exposure and gain controls, at the rate of the frame
+// interval the sensor is set to.
+use std::{
+    collections::HashMap,
+    io,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant}
+};
+use v4l_subdev::{
+    v4l2_colorspace_V4L2_COLORSPACE_RAW,
+    v4l2_field_V4L2_FIELD_NONE,
+    MEDIA_BUS_FMT_SBGGR8_1X8,
+    MEDIA_BUS_FMT_SBGGR10_1X10,
+    V4L2_CID_EXPOSURE,
+    V4L2_CID_GAIN,
+    V4L2_CID_HFLIP,
+    V4L2_CID_TEST_PATTERN,
+    V4L2_CID_VFLIP
+};
+use crate::camera::{Error, Packing, RawFormat};
+use crate::camera::backend::{CameraBackend, Frame, FrameFormat, FrameInterval, FrameSize, Sensor, SensorMode};
+use crate::camera::controls::{ControlInfo, ControlKind, ControlValue, MenuItem, MenuLabel};
+
+const CODES: [u32; 2] = [MEDIA_BUS_FMT_SBGGR8_1X8, MEDIA_BUS_FMT_SBGGR10_1X10];
+// Width, height and the frame rates offered at that size, in every one of
+// CODES.
+const SIZES: [(u32, u32, &[u32]); 3] = [
+    (1280, 960, &[15, 30, 60]),
+    (1920, 1080, &[30]),
+    (2592, 1944, &[15])
+];
+
+// In 100µs steps, see the synthetic device profile.
+const DEFAULT_EXPOSURE: i64 = 100;
+// In 1/16 steps.
+const DEFAULT_GAIN: i64 = 16;
+// Share of full scale white comes out at with the default exposure and gain.
+const WHITE_LEVEL: f32 = 0.25;
+
+// White, yellow, cyan, green, magenta, red, blue and black.
+const BARS: [[f32; 3]; 8] = [
+    [1.0, 1.0, 1.0],
+    [1.0, 1.0, 0.0],
+    [0.0, 1.0, 1.0],
+    [0.0, 1.0, 0.0],
+    [1.0, 0.0, 1.0],
+    [1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0],
+    [0.0, 0.0, 0.0]
+];
+
+// What the frames show, also the items of the test pattern control in this
+// order.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Pattern {
+    #[default]
+    ColorBars,
+    Gradient,
+    // A square bouncing from side to side and a disc going round, for
+    // seeing the preview keep up.
+    Moving
+}
+
+const PATTERNS: [(Pattern, &str, &str); 3] = [
+    (Pattern::ColorBars, "bars", "Color Bars"),
+    (Pattern::Gradient, "gradient", "Gradient"),
+    (Pattern::Moving, "moving", "Moving Objects")
+];
+
+impl Pattern {
+    // Parses the short names, "bars", "gradient" or "moving".
+    pub fn from_name(name: &str) -> Option<Self> {
+        PATTERNS.iter().find(|(_, n, _)| *n == name).map(|(p, _, _)| *p)
+    }
+
+    fn index(self) -> usize {
+        PATTERNS.iter().position(|(p, _, _)| *p == self).unwrap()
+    }
+
+    // Colour at x, y given as shares of the frame width and height, with
+    // seconds since the stream started for the moving one.
+    fn color(self, x: f32, y: f32, aspect: f32, time: f32) -> [f32; 3] {
+        match self {
+            Pattern::ColorBars => BARS[((x * 8.0) as usize).min(7)],
+            // Red grows to the right, blue downwards and green along the
+            // diagonal.
+            Pattern::Gradient => [x, (x + y) / 2.0, y],
+            Pattern::Moving => {
+                let square = (0.1 + 0.8 * triangle(time / 4.0), 0.5);
+                if ((x - square.0) * aspect).abs() < 0.08 && (y - square.1).abs() < 0.08 {
+                    return [1.0, 1.0, 1.0];
+                }
+                let disc = (0.5 + 0.3 * time.cos() / aspect, 0.5 + 0.3 * time.sin());
+                if ((x - disc.0) * aspect).hypot(y - disc.1) < 0.1 {
+                    return [0.9, 0.3, 0.1];
+                }
+                let gray = 0.15 + 0.2 * y;
+                [gray, gray, gray]
+            }
+        }
+    }
+}
+
+// Goes from 0 to 1 and back once per unit.
+fn triangle(t: f32) -> f32 {
+    1.0 - (2.0 * t.fract() - 1.0).abs()
+}
+
+struct State {
+    format: FrameFormat,
+    interval: FrameInterval,
+    // Control values by id.
+    values: HashMap<u32, i64>
+}
+
+pub struct SyntheticSensor {
+    name: String,
+    controls: Vec<ControlInfo>,
+    state: Mutex<State>
+}
+
+impl SyntheticSensor {
+    // name should start with "synthetic " for the device profile to match.
+    pub fn new(name: &str, pattern: Pattern) -> Self {
+        let menu = PATTERNS.iter()
+            .enumerate()
+            .map(|(i, (_, _, label))| MenuItem {
+                index: i as u32,
+                label: MenuLabel::Name(label.to_string())
+            })
+            .collect();
+        let controls = vec![
+            control(V4L2_CID_EXPOSURE, "Exposure", ControlKind::Integer, (1, 3333), DEFAULT_EXPOSURE, Vec::new()),
+            control(V4L2_CID_GAIN, "Gain", ControlKind::Integer, (16, 256), DEFAULT_GAIN, Vec::new()),
+            control(V4L2_CID_HFLIP, "Horizontal Flip", ControlKind::Boolean, (0, 1), 0, Vec::new()),
+            control(V4L2_CID_VFLIP, "Vertical Flip", ControlKind::Boolean, (0, 1), 0, Vec::new()),
+            control(V4L2_CID_TEST_PATTERN, "Test Pattern", ControlKind::Menu, (0, PATTERNS.len() as i64 - 1), pattern.index() as i64, menu)
+        ];
+        let values = controls.iter().map(|c| (c.id, c.default)).collect();
+        let (width, height, rates) = SIZES[0];
+
+        SyntheticSensor {
+            name: name.to_string(),
+            controls,
+            state: Mutex::new(State {
+                format: frame_format(width, height, CODES[0]),
+                interval: FrameInterval {
+                    numerator: 1,
+                    denominator: rates[0]
+                },
+                values
+            })
+        }
+    }
+
+    // The errno a driver would fail setting id to value with.
+    fn check(&self, id: u32, value: i64) -> Result<(), i32> {
+        let info = self.controls.iter().find(|c| c.id == id).ok_or(libc::EINVAL)?;
+        if value < info.minimum || value > info.maximum {
+            return Err(libc::ERANGE);
+        }
+        Ok(())
+    }
+
+    // One frame into data, which holds height lines of stride bytes.
+    fn render(&self, data: &mut [u8], width: usize, height: usize, stride: usize, format: &RawFormat, time: f32) {
+        let (pattern, scale, hflip, vflip) = {
+            let state = self.state.lock().unwrap();
+            let value = |id: u32| state.values[&id];
+            let light = (value(V4L2_CID_EXPOSURE) * value(V4L2_CID_GAIN)) as f32 / (DEFAULT_EXPOSURE * DEFAULT_GAIN) as f32;
+            (PATTERNS[value(V4L2_CID_TEST_PATTERN) as usize].0, light * WHITE_LEVEL, value(V4L2_CID_HFLIP) != 0, value(V4L2_CID_VFLIP) != 0)
+        };
+
+        let max_value = format.max_value() as f32;
+        let (green_row, green_col) = format.pattern.green_position();
+        let positions = [
+            (format.pattern.red_position(), 0),
+            ((green_row, green_col), 1),
+            ((1 - green_row, 1 - green_col), 1),
+            (format.pattern.blue_position(), 2)
+        ];
+        let (blocks_w, blocks_h) = (width / 2, height / 2);
+        let aspect = width as f32 / height as f32;
+
+        for by in 0..blocks_h {
+            let y = (by as f32 + 0.5) / blocks_h as f32;
+            let y = if vflip { 1.0 - y } else { y };
+            for bx in 0..blocks_w {
+                let x = (bx as f32 + 0.5) / blocks_w as f32;
+                let x = if hflip { 1.0 - x } else { x };
+                let color = pattern.color(x, y, aspect, time);
+
+                for ((row, col), channel) in positions.iter() {
+                    let sample = ((color[*channel] * scale).min(1.0) * max_value) as u16;
+                    let line = &mut data[(by * 2 + row) * stride..];
+                    let col = bx * 2 + col;
+                    if format.bits == 8 {
+                        line[col] = sample as u8;
+                    } else {
+                        line[col * 2..col * 2 + 2].copy_from_slice(&sample.to_le_bytes());
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Sensor for SyntheticSensor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn modes(&self) -> Result<Vec<SensorMode>, Error> {
+        Ok(CODES.iter()
+            .flat_map(|code| SIZES.iter().map(move |(width, height, rates)| SensorMode {
+                code: *code,
+                size: FrameSize::Discrete {
+                    width: *width,
+                    height: *height
+                },
+                intervals: rates.iter()
+                    .map(|fps| FrameInterval {
+                        numerator: 1,
+                        denominator: *fps
+                    })
+                    .collect()
+            }))
+            .collect())
+    }
+
+    fn frame_intervals(&self, code: u32, width: u32, height: u32) -> Result<Vec<FrameInterval>, Error> {
+        let rates = SIZES.iter()
+            .find(|(w, h, _)| CODES.contains(&code) && (*w, *h) == (width, height))
+            .map_or(&[][..], |(_, _, rates)| rates);
+        Ok(rates.iter()
+            .map(|fps| FrameInterval {
+                numerator: 1,
+                denominator: *fps
+            })
+            .collect())
+    }
+
+    // Like a driver, goes with the nearest size it has.
+    fn set_format(&self, width: u32, height: u32, code: u32) -> Result<FrameFormat, Error> {
+        let distance = |(w, h, _): &&(u32, u32, &[u32])| (*w as i64 - width as i64).abs() + (*h as i64 - height as i64).abs();
+        let (width, height, _) = SIZES.iter().min_by_key(distance).unwrap();
+        let code = if CODES.contains(&code) { code } else { CODES[0] };
+
+        let format = frame_format(*width, *height, code);
+        self.state.lock().unwrap().format = format.clone();
+        Ok(format)
+    }
+
+    fn format(&self) -> Result<FrameFormat, Error> {
+        Ok(self.state.lock().unwrap().format.clone())
+    }
+
+    fn set_interval(&self, numerator: u32, denominator: u32) -> Result<(), Error> {
+        if numerator == 0 || denominator == 0 {
+            return Err(Error::Ioctl { request: "VIDIOC_SUBDEV_S_FRAME_INTERVAL", errno: libc::EINVAL });
+        }
+        self.state.lock().unwrap().interval = FrameInterval {
+            numerator,
+            denominator
+        };
+        Ok(())
+    }
+
+    fn set_control(&self, id: u32, value: i32) -> Result<(), Error> {
+        self.check(id, value as i64).map_err(|errno| Error::Ioctl { request: "VIDIOC_S_CTRL", errno })?;
+        self.state.lock().unwrap().values.insert(id, value as i64);
+        Ok(())
+    }
+
+    fn controls(&self) -> io::Result<Vec<ControlInfo>> {
+        Ok(self.controls.clone())
+    }
+
+    fn get_controls(&self, controls: &[&ControlInfo]) -> io::Result<Vec<ControlValue>> {
+        let state = self.state.lock().unwrap();
+        controls.iter()
+            .map(|c| match state.values.get(&c.id) {
+                Some(value) => Ok(c.value(*value)),
+                None => Err(io::Error::from_raw_os_error(libc::EINVAL))
+            })
+            .collect()
+    }
+
+    fn set_controls(&self, values: &[(&ControlInfo, ControlValue)]) -> io::Result<()> {
+        for (control, value) in values.iter() {
+            self.check(control.id, value.as_i64()).map_err(io::Error::from_raw_os_error)?;
+        }
+        let mut state = self.state.lock().unwrap();
+        for (control, value) in values.iter() {
+            state.values.insert(control.id, value.as_i64());
+        }
+        Ok(())
+    }
+}
+
+// A back and a front camera.
+pub struct SyntheticBackend {
+    sensors: Vec<SyntheticSensor>
+}
+
+impl SyntheticBackend {
+    pub fn new(pattern: Pattern) -> Self {
+        SyntheticBackend {
+            sensors: vec![
+                SyntheticSensor::new("synthetic back", pattern),
+                SyntheticSensor::new("synthetic front", pattern)
+            ]
+        }
+    }
+}
+
+impl CameraBackend for SyntheticBackend {
+    fn count(&self) -> usize {
+        self.sensors.len()
+    }
+
+    fn name(&self, index: usize) -> String {
+        self.sensors[index].name.clone()
+    }
+
+    fn sensor(&self, index: usize) -> &dyn Sensor {
+        &self.sensors[index]
+    }
+
+    // Nothing to link, the sensors don't share anything.
+    fn activate(&self, _index: usize) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn reset(&self, _index: usize) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Frames always come unpacked, like from a video node that can't do
+    // anything else.
+    fn stream(&self, index: usize, raw_format: RawFormat, on_frame: &mut dyn FnMut(Frame) -> Result<bool, Error>) -> Result<(), Error> {
+        let sensor = &self.sensors[index];
+        let format = RawFormat::new(raw_format.pattern, raw_format.bits, Packing::Unpacked);
+        let (width, height) = {
+            let format = &sensor.state.lock().unwrap().format;
+            (format.width as usize, format.height as usize)
+        };
+        let stride = format.bytes_per_line(width);
+        let mut data = vec![0; stride * height];
+
+        let start = Instant::now();
+        let mut due = start;
+        loop {
+            let interval = sensor.state.lock().unwrap().interval;
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+            // Late frames push the ones after them back instead of coming in
+            // a burst.
+            due = due.max(now) + Duration::from_secs(interval.numerator as u64) / interval.denominator;
+
+            sensor.render(&mut data, width, height, stride, &format, start.elapsed().as_secs_f32());
+            let frame = Frame {
+                data: &data,
+                width,
+                height,
+                stride,
+                format
+            };
+            if !on_frame(frame)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn control(id: u32, name: &str, kind: ControlKind, range: (i64, i64), default: i64, menu: Vec<MenuItem>) -> ControlInfo {
+    ControlInfo {
+        id,
+        name: name.to_string(),
+        kind,
+        minimum: range.0,
+        maximum: range.1,
+        step: 1,
+        default,
+        flags: 0,
+        menu
+    }
+}
+
+fn frame_format(width: u32, height: u32, code: u32) -> FrameFormat {
+    FrameFormat {
+        width,
+        height,
+        code,
+        field: v4l2_field_V4L2_FIELD_NONE,
+        colorspace: v4l2_colorspace_V4L2_COLORSPACE_RAW,
+        quantization: 0,
+        xfer_func: 0
+    }
+}
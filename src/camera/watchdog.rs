@@ -0,0 +1,55 @@
+// Decides whether a stream that failed gets restarted, and when. Stalls and
+// ioctl errors tend to go away once the pipeline is set up again from
+// scratch, as long as it doesn't keep happening.
+use std::time::Duration;
+use crate::camera::Error;
+
+// Restarts in a row before giving up.
+pub const MAX_RESTARTS: u32 = 3;
+// Frames that have to come through for earlier restarts to be forgiven.
+const HEALTHY_FRAMES: u32 = 30;
+// Wait before the first restart, doubled for every one after it.
+const FIRST_DELAY: Duration = Duration::from_millis(250);
+
+#[derive(Default)]
+pub struct Watchdog {
+    restarts: u32,
+    frames: u32
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Watchdog::default()
+    }
+
+    // Call for every frame that made it.
+    pub fn frame(&mut self) {
+        self.frames += 1;
+        if self.frames >= HEALTHY_FRAMES {
+            self.restarts = 0;
+        }
+    }
+
+    // After the stream failed with error, how long to wait before
+    // restarting it. None when it's not worth trying again.
+    pub fn restart(&mut self, error: &Error) -> Option<Duration> {
+        if !recoverable(error) || self.restarts >= MAX_RESTARTS {
+            return None;
+        }
+
+        let delay = FIRST_DELAY * 2u32.pow(self.restarts);
+        self.restarts += 1;
+        self.frames = 0;
+        Some(delay)
+    }
+
+    // Restarts since frames last came through for a while.
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+}
+
+// A missing device or a format it can't do won't get better by trying again.
+fn recoverable(error: &Error) -> bool {
+    !matches!(error, Error::DeviceNotFound(_) | Error::UnsupportedFormat(_))
+}
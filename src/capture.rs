@@ -0,0 +1,211 @@
+// `camcam capture`, taking photos without the window for scripts, SSH
+// sessions and test rigs. Goes through the same Camera and save code as the
+// shutter button.
+use relm::Channel;
+use serde_json::json;
+use std::{error, io::{self, Write}, sync::Arc};
+
+use camcam::camera::{self, backend::CameraBackend, profile::Mode, synthetic::SyntheticBackend, Camera, MediaBackend, OutputFormat, RawFormat, SaveOptions, WhiteBalance};
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+const USAGE: &str = "Usage: camcam capture [OPTIONS]
+
+  -s, --sensor SENSOR      Camera by index or part of its name, the back one
+                           by default
+  -m, --mode WxH[@FPS]     Sensor mode, the device profile's still mode by
+                           default
+  -f, --format FORMAT      jpeg, dng or both, jpeg by default
+  -r, --raw FOURCC         Raw format to ask for, like BG10 for 10-bit or
+                           pBAA for 10-bit MIPI packed, the device profile's
+                           by default
+  -w, --white-balance WB   auto, white-patch, daylight, cloudy, tungsten,
+                           fluorescent or a color temperature like 5500K,
+                           auto by default
+  -n, --count N            Photos to take, one by default
+  -o, --orientation O      How the phone is held for the EXIF data: normal,
+                           bottom-up, left-up or right-up
+      --json               Print the result as JSON
+      --synthetic[=NAME]   Use the made up cameras, see camcam --synthetic
+  -h, --help               Show this
+
+Each photo is taken once exposure and white balance have settled and saved
+to the pictures directory. The paths of the files are printed one per line,
+everything else goes to stderr.";
+
+struct Options {
+    sensor: Option<String>,
+    mode: Option<(u32, u32, Option<u32>)>,
+    output: OutputFormat,
+    raw: Option<RawFormat>,
+    white_balance: WhiteBalance,
+    count: usize,
+    orientation: String,
+    json: bool
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            sensor: None,
+            mode: None,
+            output: OutputFormat::Jpeg,
+            raw: None,
+            white_balance: WhiteBalance::default(),
+            count: 1,
+            orientation: "normal".to_string(),
+            json: false
+        }
+    }
+}
+
+// Returns the exit status.
+pub fn run<I: Iterator<Item = String>>(args: I) -> i32 {
+    let options = match parse(args) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return 0;
+        },
+        Err(e) => {
+            eprintln!("camcam capture: {}\n\n{}", e, USAGE);
+            return 2;
+        }
+    };
+
+    match capture(&options) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("camcam capture: {}", e);
+            1
+        }
+    }
+}
+
+// None for --help.
+fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Options>> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-s" | "--sensor" => options.sensor = Some(value()?),
+            "-m" | "--mode" => options.mode = Some(parse_mode(&value()?)?),
+            "-f" | "--format" => options.output = match value()?.as_str() {
+                "jpeg" | "jpg" => OutputFormat::Jpeg,
+                "dng" => OutputFormat::Dng,
+                "both" => OutputFormat::Both,
+                other => return Err(format!("unknown format {}", other).into())
+            },
+            "-r" | "--raw" => options.raw = Some(parse_raw(&value()?)?),
+            "-w" | "--white-balance" => options.white_balance = {
+                let name = value()?;
+                WhiteBalance::from_name(&name).ok_or_else(|| format!("unknown white balance {}", name))?
+            },
+            "-n" | "--count" => options.count = value()?.parse().map_err(|_| "--count takes a number")?,
+            "-o" | "--orientation" => options.orientation = value()?,
+            "--json" => options.json = true,
+            "-h" | "--help" => return Ok(None),
+            // Read by synthetic_pattern.
+            _ if arg.starts_with("--synthetic") => (),
+            _ => return Err(format!("unknown option {}", arg).into())
+        }
+    }
+    Ok(Some(options))
+}
+
+// "2592x1944" or "2592x1944@15".
+fn parse_mode(text: &str) -> Result<(u32, u32, Option<u32>)> {
+    let invalid = || format!("modes are given like 2592x1944@15, not {}", text);
+    let (size, fps) = match text.split_once('@') {
+        Some((size, fps)) => (size, Some(fps.parse().map_err(|_| invalid())?)),
+        None => (text, None)
+    };
+    let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+    Ok((width.parse().map_err(|_| invalid())?, height.parse().map_err(|_| invalid())?, fps))
+}
+
+fn parse_raw(text: &str) -> Result<RawFormat> {
+    let format = match text.as_bytes() {
+        [a, b, c, d] => RawFormat::from_fourcc(&[*a, *b, *c, *d]),
+        _ => None
+    };
+    format.ok_or_else(|| format!("{} isn't a raw bayer fourcc, like BA81, BG10 or pBAA", text).into())
+}
+
+fn capture(options: &Options) -> Result<()> {
+    // Only the results, the camera code logs to stderr.
+    let mut stdout = io::stdout();
+
+    let backend: Arc<dyn CameraBackend> = match super::synthetic_pattern() {
+        Some(pattern) => Arc::new(SyntheticBackend::new(pattern)),
+        None => Arc::new(MediaBackend::open()?)
+    };
+    // Nothing is shown, status messages are left unread. Errors come back
+    // from capture_to_files.
+    let (_channel, sender) = Channel::new(|_: camera::CamMsg| ());
+    let mut camera = Camera::new(backend, sender);
+
+    let names = camera.names();
+    let index = match &options.sensor {
+        Some(sensor) => find_sensor(&names, sensor)?,
+        None => 0
+    };
+    camera.select(index);
+    if let Some((width, height, fps)) = options.mode {
+        let fps = fps.unwrap_or(camera.still_mode().fps);
+        camera.set_still_mode(Mode { width, height, fps });
+    }
+    if let Some(raw) = options.raw {
+        camera.set_raw_format(raw);
+    }
+    // For settling as well as saving.
+    camera.set_white_balance(options.white_balance);
+
+    let save_options = SaveOptions {
+        output: options.output,
+        white_balance: options.white_balance,
+        ..SaveOptions::default()
+    };
+    let mut files = Vec::new();
+    for n in 0..options.count {
+        eprintln!("Taking photo {} of {} with {}", n + 1, options.count, names[index]);
+        let saved = camera.capture_to_files(options.orientation.clone(), save_options)?;
+        if saved.is_empty() {
+            return Err("the photo couldn't be saved".into());
+        }
+        if !options.json {
+            for path in saved.iter() {
+                writeln!(stdout, "{}", path.to_string_lossy())?;
+            }
+        }
+        files.extend(saved);
+    }
+
+    if options.json {
+        let result = json!({
+            "sensor": names[index],
+            "files": files.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>()
+        });
+        writeln!(stdout, "{}", serde_json::to_string_pretty(&result)?)?;
+    }
+    Ok(())
+}
+
+// By index, or the only camera whose name has sensor in it.
+fn find_sensor(names: &[String], sensor: &str) -> Result<usize> {
+    if let Ok(index) = sensor.parse::<usize>() {
+        if index >= names.len() {
+            return Err(format!("there are only {} cameras", names.len()).into());
+        }
+        return Ok(index);
+    }
+    let found = names.iter().enumerate()
+        .filter(|(_, name)| name.contains(sensor))
+        .map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+    match found.as_slice() {
+        [index] => Ok(*index),
+        [] => Err(format!("no camera matches {}, there are: {}", sensor, names.join(", ")).into()),
+        _ => Err(format!("more than one camera matches {}: {}", sensor, names.join(", ")).into())
+    }
+}
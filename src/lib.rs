@@ -0,0 +1,3 @@
+// The camera handling, shared by the app and the benchmarks.
+pub mod camera;
+pub mod picture;
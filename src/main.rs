@@ -1,22 +1,31 @@
-use gdk_pixbuf::{Colorspace, Pixbuf, PixbufRotation};
-use gtk::{prelude::{BuilderExtManual}, ApplicationWindow, Builder, Button, ButtonExt, Image, ImageExt, Inhibit, WidgetExt};
+use gdk_pixbuf::{Colorspace, Pixbuf};
+use gtk::{prelude::{BuilderExtManual}, ApplicationWindow, Builder, Button, ButtonExt, ComboBoxExt, ComboBoxText, EventBox, Image, ImageExt, Inhibit, Label, LabelExt, RangeExt, Scale, ScaleExt, WidgetExt};
 use relm::{connect, Channel, Relm, Update, Widget};
 use relm_derive::Msg;
-use std::thread;
+use std::{env, process, sync::Arc, thread};
 
 
-mod camera;
-use camera::{ Camera, CamMsg };
-
-mod picture;
-use picture::Picture;
+use camcam::{camera::{ self, Camera, CamMsg, DemosaicMethod, ExposureMode, FocusMode, FocusStatus, OutputFormat, SaveOptions, WhiteBalance, synthetic::{Pattern, SyntheticBackend} }, picture::Picture};
 
+mod capture;
 mod sensor_proxy;
 use sensor_proxy::SensorProxyProxy;
 
 struct Model<'a> {
     _channel: Channel<CamMsg>,
+    // Orientations from the sensor proxy, see sensor_proxy::watch_orientation.
+    _orientation_channel: Channel<String>,
     camera: Option<Camera>,
+    save_options: SaveOptions,
+    // Take a flat-field frame for lens shading instead of starting the
+    // preview, see Camera::calibrate_lens_shading.
+    calibrate_lens_shading: bool,
+    preview_size: Option<(usize, usize)>,
+    // Size of the last preview frame, to find where taps land on it.
+    picture_size: Option<(i32, i32)>,
+    // Shutter was pressed in single shot focus mode, the photo is taken once
+    // focusing is done.
+    capture_after_focus: bool,
     sensor_proxy: SensorProxyProxy<'a>
 }
 
@@ -31,12 +40,39 @@ enum Msg {
     Unfocus,
     Focus,
     Quit,
-    SwitchCamera
+    SwitchCamera,
+    PreviewResized(i32, i32),
+    OrientationChanged(String),
+    ToggleSettings,
+    ExposureChanged,
+    FocusChanged,
+    FocusUpdate(FocusStatus),
+    WhiteBalanceChanged,
+    SaveOptionsChanged,
+    PreviewTapped(f64, f64),
+    CameraRestarting(u32),
+    CameraError(camera::Error)
 }
 
 struct Widgets {
     window: ApplicationWindow,
-    preview: Image
+    preview: Image,
+    exposure_panel: gtk::Box,
+    exposure_mode: ComboBoxText,
+    exposure_compensation: Scale,
+    // Seconds and ISO/100 as powers of two, so a step is a stop.
+    shutter_speed: Scale,
+    iso: Scale,
+    focus_mode: ComboBoxText,
+    focus_position: Scale,
+    focus_status: Label,
+    white_balance: ComboBoxText,
+    // Kelvin.
+    color_temperature: Scale,
+    // Only what photos are saved as, the preview doesn't change.
+    output_format: ComboBoxText,
+    demosaic_method: ComboBoxText,
+    camera_error: Label
 }
 
 struct MainWin<'a> {
@@ -58,17 +94,44 @@ impl<'a> Update for MainWin<'a> {
             match msg {
                 CamMsg::Ready(cam) => stream.emit(Cam(cam)),
                 CamMsg::Pic(pic) => stream.emit(Pic(pic)),
-                CamMsg::Captured => stream.emit(PhotoDone)
+                CamMsg::Captured => stream.emit(PhotoDone),
+                CamMsg::Focus(status) => stream.emit(FocusUpdate(status)),
+                CamMsg::Restarting { attempt, .. } => stream.emit(CameraRestarting(attempt)),
+                CamMsg::Error(e) => stream.emit(CameraError(e))
             }
         });
 
+        // The preview follows the way the phone is held.
+        let stream = relm.stream().clone();
+        let (orientation_channel, orientation_sender) = Channel::new(move |orientation| {
+            stream.emit(OrientationChanged(orientation))
+        });
         thread::spawn(move || {
-            Camera::detect(sender);
+            let watched = sensor_proxy::watch_orientation(move |orientation| {
+                orientation_sender.send(orientation).expect("Can't send orientation.")
+            });
+            if let Err(e) = watched {
+                eprintln!("Can't follow the device orientation: {}", e);
+            }
+        });
+
+        let synthetic = synthetic_pattern();
+        thread::spawn(move || {
+            match synthetic {
+                Some(pattern) => Camera::with_backend(Arc::new(SyntheticBackend::new(pattern)), sender),
+                None => Camera::detect(sender)
+            }
         });
 
         Model {
             _channel: channel,
+            _orientation_channel: orientation_channel,
             camera: None,
+            save_options: SaveOptions::default(),
+            calibrate_lens_shading: env::args().any(|a| a == "--calibrate-lens-shading"),
+            preview_size: None,
+            picture_size: None,
+            capture_after_focus: false,
             sensor_proxy: proxy
         }
     }
@@ -77,7 +140,22 @@ impl<'a> Update for MainWin<'a> {
         match event {
             Quit => gtk::main_quit(),
             Cam(mut cam) => {
-                cam.start_preview();
+                if let Some((width, height)) = self.model.preview_size {
+                    cam.set_preview_size(width, height);
+                }
+                cam.set_white_balance(self.model.save_options.white_balance);
+                // Changes from here on come as OrientationChanged.
+                if let Ok(orientation) = self.model.sensor_proxy.accelerometer_orientation() {
+                    cam.set_device_orientation(&orientation);
+                }
+                self.update_exposure_limits(&cam);
+                self.update_focus_modes(&cam);
+                if self.model.calibrate_lens_shading {
+                    self.model.calibrate_lens_shading = false;
+                    cam.calibrate_lens_shading();
+                } else {
+                    cam.start_preview();
+                }
                 self.model.camera = Some(cam)
             },
             Pic(pic) => {
@@ -91,19 +169,28 @@ impl<'a> Update for MainWin<'a> {
                     pic.rowstride()
                 );
 
-                let pb = pb.rotate_simple(PixbufRotation::Clockwise).unwrap();
-
+                self.model.picture_size = Some((pic.width(), pic.height()));
                 self.widgets.preview.set_from_pixbuf(Some(&pb));
+                // Frames are coming again.
+                self.widgets.camera_error.set_visible(false);
                 //self.widgets.window.show_all();
             },
             Shutter => {
-                self.model.camera.as_mut().unwrap().stop_preview();
-                let orientation = self.model.sensor_proxy.accelerometer_orientation();
-                let orientation = match orientation {
-                    Ok(o) => o,
-                    Err(_) => "undefined".to_string()
+                let focusing = match self.model.camera.as_ref() {
+                    Some(cam) if cam.focus_mode() == FocusMode::Single => match cam.focus() {
+                        Ok(_) => true,
+                        Err(e) => {
+                            eprintln!("Can't focus, taking the photo anyway: {}", e);
+                            false
+                        }
+                    },
+                    _ => false
                 };
-                self.model.camera.as_ref().unwrap().capture(orientation);
+                if focusing {
+                    self.model.capture_after_focus = true;
+                } else {
+                    self.capture();
+                }
             },
             PhotoDone => {
                 self.model.camera.as_mut().unwrap().start_preview();
@@ -113,20 +200,211 @@ impl<'a> Update for MainWin<'a> {
                 if let Some(cam) = self.model.camera.as_mut() {
                     cam.stop_preview();
                 }
-                println!("Should stop preview.");
             },
             Focus => {
                 self.model.sensor_proxy.claim_accelerometer();
                 if let Some(cam) = self.model.camera.as_mut() {
                     cam.start_preview();
                 }
-                println!("Should start preview.");
             },
             SwitchCamera => {
                 if let Some(cam) = self.model.camera.as_mut() {
                     cam.switch_sensor();
                 }
-                println!("Switch camera.");
+                if let Some(cam) = self.model.camera.as_ref() {
+                    self.update_exposure_limits(cam);
+                    self.update_focus_modes(cam);
+                }
+            },
+            PreviewResized(width, height) => {
+                let size = (width.max(1) as usize, height.max(1) as usize);
+                self.model.preview_size = Some(size);
+                if let Some(cam) = self.model.camera.as_ref() {
+                    cam.set_preview_size(size.0, size.1);
+                }
+            },
+            OrientationChanged(orientation) => {
+                if let Some(cam) = self.model.camera.as_ref() {
+                    cam.set_device_orientation(&orientation);
+                }
+            },
+            ToggleSettings => {
+                let panel = &self.widgets.exposure_panel;
+                panel.set_visible(!panel.get_visible());
+            },
+            ExposureChanged => {
+                let mode = self.exposure_mode();
+                let manual = matches!(mode, ExposureMode::Manual { .. });
+                self.widgets.exposure_compensation.set_sensitive(mode != ExposureMode::Auto && !manual);
+                self.widgets.shutter_speed.set_sensitive(manual);
+                self.widgets.iso.set_sensitive(manual);
+
+                if let Some(cam) = self.model.camera.as_mut() {
+                    if let Err(e) = cam.set_exposure_mode(mode) {
+                        eprintln!("Can't set exposure: {}", e);
+                    }
+                }
+            },
+            FocusChanged => {
+                let mode = self.focus_mode();
+                self.widgets.focus_position.set_sensitive(matches!(mode, FocusMode::Manual(_)));
+
+                if let Some(cam) = self.model.camera.as_mut() {
+                    if let Err(e) = cam.set_focus_mode(mode) {
+                        eprintln!("Can't set focus: {}", e);
+                    }
+                }
+            },
+            FocusUpdate(status) => {
+                let label = &self.widgets.focus_status;
+                match status {
+                    FocusStatus::Busy => label.set_text("Focusing…"),
+                    FocusStatus::Reached => label.set_text("In focus"),
+                    FocusStatus::Failed => label.set_text("Can't focus"),
+                    FocusStatus::Idle => ()
+                }
+                label.set_visible(status != FocusStatus::Idle);
+
+                let done = status == FocusStatus::Reached || status == FocusStatus::Failed;
+                if done && self.model.capture_after_focus {
+                    self.model.capture_after_focus = false;
+                    self.capture();
+                }
+            },
+            WhiteBalanceChanged => {
+                let white_balance = self.white_balance();
+                self.widgets.color_temperature.set_sensitive(matches!(white_balance, WhiteBalance::Kelvin(_)));
+                self.model.save_options.white_balance = white_balance;
+
+                if let Some(cam) = self.model.camera.as_ref() {
+                    cam.set_white_balance(white_balance);
+                }
+            },
+            SaveOptionsChanged => {
+                self.model.save_options = self.save_options();
+            },
+            PreviewTapped(x, y) => {
+                // The frame is centered in the preview, taps around it
+                // don't count.
+                if let Some((width, height)) = self.model.picture_size {
+                    let allocation = self.widgets.preview.get_allocation();
+                    let x = (x - (allocation.width - width) as f64 / 2.0) / width as f64;
+                    let y = (y - (allocation.height - height) as f64 / 2.0) / height as f64;
+                    let on_frame = (0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y);
+
+                    if let (true, Some(cam)) = (on_frame, self.model.camera.as_ref()) {
+                        if let Err(e) = cam.set_focus_point(x as f32, y as f32) {
+                            eprintln!("Can't focus there: {}", e);
+                        }
+                    }
+                }
+            },
+            CameraRestarting(attempt) => {
+                // Hidden again by the next frame.
+                let label = &self.widgets.camera_error;
+                label.set_text(&format!("The camera stopped responding, restarting it ({})…", attempt));
+                label.set_visible(true);
+            },
+            CameraError(e) => {
+                let message = match e.errno() {
+                    Some(libc::EBUSY) => format!("The camera is in use by another program.\n{}", e),
+                    _ => e.to_string()
+                };
+                self.widgets.camera_error.set_text(&message);
+                self.widgets.camera_error.set_visible(true);
+                self.model.capture_after_focus = false;
+            }
+        }
+    }
+}
+
+impl MainWin<'_> {
+    fn capture(&mut self) {
+        // Nothing to take photos with when detecting cameras failed.
+        if self.model.camera.is_none() {
+            return;
+        }
+        self.model.camera.as_mut().unwrap().stop_preview();
+        let orientation = self.model.sensor_proxy.accelerometer_orientation();
+        let orientation = match orientation {
+            Ok(o) => o,
+            Err(_) => "undefined".to_string()
+        };
+        self.model.camera.as_mut().unwrap().capture(orientation, self.model.save_options);
+    }
+
+    fn white_balance(&self) -> WhiteBalance {
+        let widgets = &self.widgets;
+        match widgets.white_balance.get_active_id().as_ref().map(|id| id.as_str()) {
+            Some("white_patch") => WhiteBalance::WhitePatch,
+            Some("daylight") => WhiteBalance::Daylight,
+            Some("cloudy") => WhiteBalance::Cloudy,
+            Some("tungsten") => WhiteBalance::Tungsten,
+            Some("fluorescent") => WhiteBalance::Fluorescent,
+            Some("kelvin") => WhiteBalance::Kelvin(widgets.color_temperature.get_value().round() as u32),
+            _ => WhiteBalance::GrayWorld
+        }
+    }
+
+    fn save_options(&self) -> SaveOptions {
+        let widgets = &self.widgets;
+        let output = match widgets.output_format.get_active_id().as_ref().map(|id| id.as_str()) {
+            Some("dng") => OutputFormat::Dng,
+            Some("both") => OutputFormat::Both,
+            _ => OutputFormat::Jpeg
+        };
+        let demosaic = match widgets.demosaic_method.get_active_id().as_ref().map(|id| id.as_str()) {
+            Some("bilinear") => DemosaicMethod::Bilinear,
+            Some("edge_directed") => DemosaicMethod::EdgeDirected,
+            _ => DemosaicMethod::Malvar
+        };
+        SaveOptions {
+            output,
+            demosaic,
+            ..self.model.save_options
+        }
+    }
+
+    fn focus_mode(&self) -> FocusMode {
+        let widgets = &self.widgets;
+        match widgets.focus_mode.get_active_id().as_ref().map(|id| id.as_str()) {
+            Some("single") => FocusMode::Single,
+            Some("manual") => FocusMode::Manual(widgets.focus_position.get_value() as f32),
+            _ => FocusMode::Continuous
+        }
+    }
+
+    // The front camera has no focus to pick.
+    fn update_focus_modes(&self, cam: &Camera) {
+        let modes = [FocusMode::Continuous, FocusMode::Single, FocusMode::Manual(0.0)];
+        self.widgets.focus_mode.set_sensitive(modes.iter().any(|m| cam.supports_focus(*m)));
+    }
+
+    fn exposure_mode(&self) -> ExposureMode {
+        let widgets = &self.widgets;
+        match widgets.exposure_mode.get_active_id().as_ref().map(|id| id.as_str()) {
+            Some("compensated") => ExposureMode::Compensated(widgets.exposure_compensation.get_value() as f32),
+            Some("manual") => ExposureMode::Manual {
+                time: 2f64.powf(widgets.shutter_speed.get_value()) as f32,
+                iso: (100.0 * 2f64.powf(widgets.iso.get_value())).round() as u32
+            },
+            _ => ExposureMode::Auto
+        }
+    }
+
+    // Sliders cover what the sensor can do. Without manual exposure there's
+    // only auto.
+    fn update_exposure_limits(&self, cam: &Camera) {
+        let widgets = &self.widgets;
+        match cam.exposure_limits() {
+            Some(limits) => {
+                widgets.shutter_speed.set_range(limits.time.0.log2() as f64, limits.time.1.log2() as f64);
+                widgets.iso.set_range((limits.iso.0 as f64 / 100.0).log2(), (limits.iso.1 as f64 / 100.0).log2());
+                widgets.exposure_mode.set_sensitive(true);
+            },
+            None => {
+                widgets.exposure_mode.set_active_id(Some("auto"));
+                widgets.exposure_mode.set_sensitive(false);
             }
         }
     }
@@ -176,6 +454,24 @@ impl Widget for MainWin<'_> {
             return (Some(Msg::Unfocus), Inhibit(false))
         );
 
+        let preview_events: EventBox = builder
+            .get_object("preview_events")
+            .expect("Can't get preview event box.");
+
+        connect!(
+            relm,
+            preview_events,
+            connect_button_press_event(_, event),
+            return (Some(Msg::PreviewTapped(event.get_position().0, event.get_position().1)), Inhibit(false))
+        );
+
+        connect!(
+            relm,
+            preview,
+            connect_size_allocate(_, allocation),
+            Msg::PreviewResized(allocation.width, allocation.height)
+        );
+
         let shutter: Button = builder
             .get_object("shutter")
             .expect("Can't get shutter button.");
@@ -187,6 +483,94 @@ impl Widget for MainWin<'_> {
             Msg::Shutter
         );
 
+        let settings: Button = builder
+            .get_object("setttings_btn")
+            .expect("Can't get settings button.");
+
+        connect!(
+            relm,
+            settings,
+            connect_clicked(_),
+            Msg::ToggleSettings
+        );
+
+        let exposure_panel: gtk::Box = builder
+            .get_object("exposure_panel")
+            .expect("Can't get exposure panel.");
+        let exposure_mode: ComboBoxText = builder
+            .get_object("exposure_mode")
+            .expect("Can't get exposure mode selector.");
+        let exposure_compensation: Scale = builder
+            .get_object("exposure_compensation")
+            .expect("Can't get exposure compensation slider.");
+        let shutter_speed: Scale = builder
+            .get_object("shutter_speed")
+            .expect("Can't get shutter speed slider.");
+        let iso: Scale = builder
+            .get_object("iso")
+            .expect("Can't get ISO slider.");
+
+        exposure_compensation.connect_format_value(|_, stops| format!("{:+.1} EV", stops));
+        shutter_speed.connect_format_value(|_, v| {
+            let time = 2f64.powf(v);
+            if time < 1.0 {
+                format!("1/{:.0}", 1.0 / time)
+            } else {
+                format!("{:.1}s", time)
+            }
+        });
+        iso.connect_format_value(|_, v| format!("ISO {:.0}", 100.0 * 2f64.powf(v)));
+        exposure_compensation.set_sensitive(false);
+        shutter_speed.set_sensitive(false);
+        iso.set_sensitive(false);
+
+        connect!(relm, exposure_mode, connect_changed(_), Msg::ExposureChanged);
+        connect!(relm, exposure_compensation, connect_value_changed(_), Msg::ExposureChanged);
+        connect!(relm, shutter_speed, connect_value_changed(_), Msg::ExposureChanged);
+        connect!(relm, iso, connect_value_changed(_), Msg::ExposureChanged);
+
+        let focus_mode: ComboBoxText = builder
+            .get_object("focus_mode")
+            .expect("Can't get focus mode selector.");
+        let focus_position: Scale = builder
+            .get_object("focus_position")
+            .expect("Can't get focus distance slider.");
+        let focus_status: Label = builder
+            .get_object("focus_status")
+            .expect("Can't get focus status label.");
+        let camera_error: Label = builder
+            .get_object("camera_error")
+            .expect("Can't get camera error label.");
+
+        focus_position.connect_format_value(|_, v| if v < 0.01 { "∞".to_string() } else { format!("{:.0}%", v * 100.0) });
+        focus_position.set_sensitive(false);
+
+        connect!(relm, focus_mode, connect_changed(_), Msg::FocusChanged);
+        connect!(relm, focus_position, connect_value_changed(_), Msg::FocusChanged);
+
+        let white_balance: ComboBoxText = builder
+            .get_object("white_balance")
+            .expect("Can't get white balance selector.");
+        let color_temperature: Scale = builder
+            .get_object("color_temperature")
+            .expect("Can't get color temperature slider.");
+
+        color_temperature.connect_format_value(|_, v| format!("{:.0}K", v));
+        color_temperature.set_sensitive(false);
+
+        connect!(relm, white_balance, connect_changed(_), Msg::WhiteBalanceChanged);
+        connect!(relm, color_temperature, connect_value_changed(_), Msg::WhiteBalanceChanged);
+
+        let output_format: ComboBoxText = builder
+            .get_object("output_format")
+            .expect("Can't get output format selector.");
+        let demosaic_method: ComboBoxText = builder
+            .get_object("demosaic_method")
+            .expect("Can't get demosaic method selector.");
+
+        connect!(relm, output_format, connect_changed(_), Msg::SaveOptionsChanged);
+        connect!(relm, demosaic_method, connect_changed(_), Msg::SaveOptionsChanged);
+
         let camera_switch: Button = builder
             .get_object("camera_switch")
             .expect("Can't get camera switch button.");
@@ -204,12 +588,42 @@ impl Widget for MainWin<'_> {
             model,
             widgets: Widgets {
                 window,
-                preview
+                preview,
+                exposure_panel,
+                exposure_mode,
+                exposure_compensation,
+                shutter_speed,
+                iso,
+                focus_mode,
+                focus_position,
+                focus_status,
+                white_balance,
+                color_temperature,
+                output_format,
+                demosaic_method,
+                camera_error
             }
         }
     }
 }
 
+// --synthetic runs on made up cameras instead of real ones, showing color
+// bars. --synthetic=gradient or --synthetic=moving picks another pattern.
+fn synthetic_pattern() -> Option<Pattern> {
+    let arg = env::args().find(|a| a.starts_with("--synthetic"))?;
+    match arg.strip_prefix("--synthetic=") {
+        Some(name) => Some(Pattern::from_name(name).unwrap_or_else(|| {
+            eprintln!("Unknown synthetic pattern {}, showing color bars.", name);
+            Pattern::default()
+        })),
+        None => Some(Pattern::default())
+    }
+}
+
 fn main() {
+    // `camcam capture` takes photos without the window.
+    if env::args().nth(1).as_deref() == Some("capture") {
+        process::exit(capture::run(env::args().skip(2)));
+    }
     MainWin::run(()).expect("Main win run failed!");
 }
@@ -1,4 +1,8 @@
-use zbus::dbus_proxy;
+use zbus::{dbus_proxy, fdo, zvariant::Value};
+
+const SERVICE: &str = "net.hadess.SensorProxy";
+const PATH: &str = "/net/hadess/SensorProxy";
+const INTERFACE: &str = "net.hadess.SensorProxy";
 
 #[dbus_proxy(
     interface = "net.hadess.SensorProxy",
@@ -52,3 +56,23 @@ trait SensorProxy {
     fn proximity_near(&self) -> zbus::Result<bool>;
 }
 
+// Calls on_change with every orientation the accelerometer reports from now
+// on. iio-sensor-proxy only sends them while the accelerometer is claimed.
+// Blocks for as long as the connection lasts, so it gets a thread and a
+// connection of its own.
+pub fn watch_orientation<F: FnMut(String) + Send + 'static>(mut on_change: F) -> zbus::Result<()> {
+    let connection = zbus::Connection::new_system()?;
+    let properties = fdo::PropertiesProxy::new_for(&connection, SERVICE, PATH)?;
+    properties.connect_properties_changed(move |interface, changed, _| {
+        if interface == INTERFACE {
+            if let Some(Value::Str(orientation)) = changed.get("AccelerometerOrientation") {
+                on_change(orientation.as_str().to_string());
+            }
+        }
+        Ok(())
+    })?;
+
+    loop {
+        properties.next_signal()?;
+    }
+}
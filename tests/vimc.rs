@@ -0,0 +1,102 @@
+// Discovery, format propagation and streaming against the kernel's vimc
+// driver. Needs `sudo modprobe vimc` and access to its device nodes, without
+// them the tests pass without checking anything.
+use v4l_subdev::{MEDIA_BUS_FMT_SBGGR8_1X8, MEDIA_BUS_FMT_SRGGB8_1X8};
+use std::sync::{Arc, Mutex};
+
+use camcam::camera::{
+    backend::CameraBackend,
+    bayer::CfaPattern,
+    discovery::{self, CapturePipeline, EntityKind},
+    MediaBackend,
+    Packing,
+    RawFormat
+};
+
+// The tests set formats on the same sensor.
+static VIMC: Mutex<()> = Mutex::new(());
+
+fn has_vimc(pipelines: &[CapturePipeline]) -> bool {
+    let found = pipelines.iter().any(|p| p.sensor.entity.name == "Sensor A");
+    if !found {
+        eprintln!("No vimc \"Sensor A\" found, skipping. Load it with sudo modprobe vimc.");
+    }
+    found
+}
+
+#[test]
+fn streams_sensor_a() {
+    let _vimc = VIMC.lock().unwrap_or_else(|e| e.into_inner());
+    if !has_vimc(&discovery::discover()) {
+        return;
+    }
+
+    let backend = MediaBackend::open().unwrap();
+    let index = (0..backend.count())
+        .find(|&i| backend.sensor(i).name() == "Sensor A")
+        .unwrap();
+    // The raw capture node is the nearest.
+    assert!(backend.name(index).starts_with("Sensor A -> Raw Capture"), "{}", backend.name(index));
+    backend.activate(index).unwrap();
+
+    let raw_format = RawFormat::from_fourcc(b"BA81").unwrap();
+    for &(code, pattern, width, height) in [
+        (MEDIA_BUS_FMT_SBGGR8_1X8, CfaPattern::Bggr, 640, 480),
+        (MEDIA_BUS_FMT_SRGGB8_1X8, CfaPattern::Rggb, 1280, 720)
+    ].iter() {
+        let format = backend.sensor(index).set_format(width, height, code).unwrap();
+        assert_eq!((format.width, format.height, format.code), (width, height, code));
+
+        let mut frames = 0;
+        backend.stream(index, raw_format, &mut |frame| {
+            assert_eq!((frame.width, frame.height), (width as usize, height as usize));
+            assert_eq!(frame.format.pattern, pattern);
+            assert_eq!(frame.format.bits, 8);
+            assert!(frame.stride >= frame.width);
+            assert!(frame.data.len() >= frame.stride * frame.height);
+            frames += 1;
+            Ok(frames < 3)
+        }).unwrap();
+        assert_eq!(frames, 3);
+        backend.reset(index).unwrap();
+    }
+}
+
+// Sensor A to the RGB/YUV capture node goes through the debayer and the
+// scaler, both with formats to set.
+#[test]
+fn propagates_through_debayer_and_scaler() {
+    let _vimc = VIMC.lock().unwrap_or_else(|e| e.into_inner());
+    let pipelines = discovery::discover();
+    if !has_vimc(&pipelines) {
+        return;
+    }
+
+    let pipelines = pipelines.into_iter().map(Arc::new).collect::<Vec<Arc<CapturePipeline>>>();
+    let index = pipelines.iter()
+        .position(|p| p.sensor.entity.name == "Sensor A" && p.video.entity.name == "RGB/YUV Capture")
+        .unwrap();
+    let pipeline = &pipelines[index];
+
+    let between = pipeline.entities.iter().map(|(e, kind)| (e.name.as_str(), *kind)).collect::<Vec<_>>();
+    assert_eq!(between, [("Debayer A", EntityKind::Processing), ("Scaler", EntityKind::Processing)]);
+    assert_eq!(pipeline.stages.len(), 2);
+    assert_eq!(pipeline.hops.len(), 3);
+    assert_eq!(pipeline.hops[0].source, pipeline.sensor.entity.id);
+
+    discovery::activate(&pipelines, index).unwrap();
+    let sent = pipeline.sensor.set_format(640, 480, MEDIA_BUS_FMT_SBGGR8_1X8).unwrap().format;
+    let out = pipeline.propagate(&sent).unwrap();
+
+    // The debayer took the sensor's format and sends RGB on.
+    let debayer = &pipeline.stages[0];
+    let taken = debayer.subdevice.pad_format(debayer.sink_pad).unwrap().format;
+    assert!(taken.matches(&sent), "{} != {}", taken, sent);
+    assert!(RawFormat::from_mbus_code(out.code, Packing::Unpacked).is_none(), "{} is still raw", out);
+    // What the scaler sends is what the video node gets.
+    let scaler = &pipeline.stages[1];
+    let sends = scaler.subdevice.pad_format(scaler.source_pad).unwrap().format;
+    assert!(sends.matches(&out), "{} != {}", sends, out);
+
+    discovery::deactivate_all(&pipelines).unwrap();
+}